@@ -1,6 +1,4 @@
 extern crate serde;
-extern crate pop3;
-extern crate openssl;
 
 use serde::{
     de::{
@@ -15,61 +13,249 @@ use serde::{
     Serializer,
 };
 use super::{
+    error::MailResult,
     receiving::{
         InboxAdapter,
-    }
+    },
+    sending::{
+        RateLimit,
+        TransportAdapter,
+    },
 };
+use serde_json::{
+    json,
+    Value,
+};
+use super::config::QuoteStyle;
+use super::output;
+use console::Style;
+
+
+pub const FIELDS: &'static [&'static str] = &["pop3_domain", "imap_domain", "smtp_domain", "name", "password", "shortcut", "smtp_user", "smtp_password", "aliases", "quoting", "sendmail", "backend", "location", "rate_limit", "expunge", "color", "prefix", "authserv_id"];
 
+// Colors of accounts without one in merged listings, picked by name so they stay the same
+const TAG_COLORS: &'static [&'static str] = &["cyan", "magenta", "yellow", "green", "blue", "red"];
 
 const POP3_PORT: u16 = 995;
 const IMAP_PORT: u16 = 993;
 
-#[derive(Clone)]
+// When mails marked as deleted are removed from the server
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ExpungePolicy {
+    Immediately,
+    OnClose,
+    // Left marked for other clients to decide
+    Never,
+}
+
+impl ExpungePolicy {
+    pub fn parse(s: &str) -> Option<ExpungePolicy> {
+        match s {
+            "immediately" => Some(ExpungePolicy::Immediately),
+            "on_close" => Some(ExpungePolicy::OnClose),
+            "never" => Some(ExpungePolicy::Never),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ExpungePolicy::Immediately => "immediately",
+            ExpungePolicy::OnClose => "on_close",
+            ExpungePolicy::Never => "never",
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
 pub enum InboxConfig {
     Pop3(String, u16),
     Imap(String, u16),
+    // Backend registered under a protocol name, with its path or url
+    Other(String, String),
+}
+
+// "host:port" into its parts, a host without port gets the default one
+fn split_port(domain: String, default: u16) -> (String, u16) {
+    match domain.rsplit_once(':').and_then(|(host, port)| port.parse::<u16>().ok().map(|port| (host.to_string(), port))) {
+        Some(split) => split,
+        None => (domain, default),
+    }
+}
+
+// Host as written to the account file, with the port only if it isn't the default one
+fn join_port(domain: &String, port: u16, default: u16) -> String {
+    if port == default { domain.clone() } else { format!("{}:{}", domain, port) }
 }
 
 impl InboxConfig {
     pub fn new_pop3(domain: String) -> InboxConfig {
-        return InboxConfig::Pop3(domain, POP3_PORT);
+        let (domain, port) = split_port(domain, POP3_PORT);
+        return InboxConfig::Pop3(domain, port);
     }
 
     pub fn new_imap(domain: String) -> InboxConfig {
-        return InboxConfig::Imap(domain, IMAP_PORT);
+        let (domain, port) = split_port(domain, IMAP_PORT);
+        return InboxConfig::Imap(domain, port);
+    }
+
+    // Name of the backend that opens this inbox
+    pub fn protocol(&self) -> &str {
+        match self {
+            InboxConfig::Pop3(_, _) => "pop3",
+            InboxConfig::Imap(_, _) => "imap",
+            InboxConfig::Other(protocol, _) => protocol.as_str(),
+        }
+    }
+
+    pub fn location(&self) -> String {
+        match self {
+            InboxConfig::Pop3(domain, port) | InboxConfig::Imap(domain, port) => format!("{}:{}", domain, port),
+            InboxConfig::Other(_, location) => location.clone(),
+        }
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub struct Account {
     pub inbox_domain: InboxConfig,
     pub smtp_domain: String,
     pub name: String,
     pub password: String,
     pub shortcut: Option<String>,
+    pub smtp_user: Option<String>,
+    pub smtp_password: Option<String>,
+    pub aliases: Vec<String>,
+    // Overrides the global quoting settings
+    pub quoting: Option<QuoteStyle>,
+    // Local sendmail compatible command used instead of the smtp server
+    pub sendmail: Option<String>,
+    // Sending limits of the provider
+    pub rate_limit: Option<RateLimit>,
+    pub expunge: ExpungePolicy,
+    // Color like "green" or "bold.red" and text that mark the account in merged listings
+    pub color: Option<String>,
+    pub prefix: Option<String>,
+    // Server whose Authentication-Results fields are trusted
+    pub authserv_id: Option<String>,
 }
 
 impl Account {
     pub fn new(inbox_domain: InboxConfig, smtp_domain: String, name: String, password: String, shortcut: Option<String>) -> Account {
         Account {
-            inbox_domain, smtp_domain, name, password, shortcut,
+            inbox_domain, smtp_domain, name, password, shortcut, smtp_user: None, smtp_password: None, aliases: Vec::new(), quoting: None, sendmail: None, rate_limit: None, expunge: ExpungePolicy::OnClose, color: None, prefix: None, authserv_id: None,
+        }
+    }
+
+    // Key under which the account is managed
+    pub fn ident(&self) -> String {
+        match &self.shortcut {
+            Some(s) => s.clone(),
+            None => self.name.clone(),
         }
     }
 
+    // All names the account can be opened with
+    pub fn identifiers(&self) -> Vec<String> {
+        let mut ret = vec![self.ident()];
+        ret.extend(self.aliases.iter().cloned());
+        return ret;
+    }
+
+    // Addresses mail to this account is sent to, the name and the aliases that are addresses
+    pub fn addresses(&self) -> Vec<String> {
+        std::iter::once(self.name.clone()).chain(self.aliases.iter().filter(|a| a.contains('@')).cloned()).collect()
+    }
+
+    // Marks the lines of this account in listings of all accounts
+    pub fn tag(&self) -> String {
+        let text = self.prefix.clone().unwrap_or(self.ident());
+        let color = match &self.color {
+            Some(color) => color.clone(),
+            None => TAG_COLORS[self.ident().bytes().map(|b| b as usize).sum::<usize>() % TAG_COLORS.len()].to_string(),
+        };
+        output::emphasize(text.as_str(), &Style::from_dotted_str(color.as_str()))
+    }
+
+    // Id of the server whose Authentication-Results are trusted, by default the domain of the
+    // inbox server and its hosts, like "example.org" for "imap.example.org"
+    pub fn authserv_id(&self) -> String {
+        match (&self.authserv_id, &self.inbox_domain) {
+            (Some(id), _) => id.clone(),
+            (None, InboxConfig::Pop3(domain, _)) | (None, InboxConfig::Imap(domain, _)) => {
+                let labels: Vec<&str> = domain.split('.').collect();
+                labels[labels.len().saturating_sub(2).min(1)..].join(".")
+            },
+            (None, InboxConfig::Other(_, _)) => String::new(),
+        }
+    }
+
+    // Returns the credentials for the smtp server, falls back to the inbox credentials
+    pub fn smtp_credentials(&self) -> (&String, &String) {
+        (self.smtp_user.as_ref().unwrap_or(&self.name), self.smtp_password.as_ref().unwrap_or(&self.password))
+    }
+
     pub fn print(&self) {
         let inbox_domain = match &self.inbox_domain {
             InboxConfig::Pop3(domain, _) => format!("POP3 Domain:\t{}", domain),
             InboxConfig::Imap(domain, _) => format!("IMAP Domain:\t{}", domain),
+            InboxConfig::Other(protocol, location) => format!("Backend:\t{} ({})", protocol, location),
         };
         println!("Account \"{}\"\n\t{}\n\tSMTP Domain:\t{}\n\tPassword:\t{}\n\tShortcut:\t{}", self.name, inbox_domain, self.smtp_domain, vec!['*'; self.password.len()].into_iter().collect::<String>(), if let Some(sc) = &self.shortcut { sc.clone() } else { String::from("-") });
+        if self.smtp_user.is_some() || self.smtp_password.is_some() {
+            let (user, password) = self.smtp_credentials();
+            println!("\tSMTP User:\t{}\n\tSMTP Password:\t{}", user, vec!['*'; password.len()].into_iter().collect::<String>());
+        }
+        if !self.aliases.is_empty() {
+            println!("\tAliases:\t{}", self.aliases.join(", "));
+        }
+        if let Some(sendmail) = &self.sendmail {
+            println!("\tSendmail:\t{}", sendmail);
+        }
+        if let Some(limit) = &self.rate_limit {
+            let show = |n: Option<u32>| n.map(|n| n.to_string()).unwrap_or(String::from("-"));
+            println!("\tRate Limit:\t{} mails/min, {} recipients/mail", show(limit.messages_per_minute), show(limit.recipients_per_message));
+        }
+        if self.expunge != ExpungePolicy::OnClose {
+            println!("\tExpunge:\t{}", self.expunge.name());
+        }
+        if self.color.is_some() || self.prefix.is_some() {
+            println!("\tTag:\t\t{}", self.tag());
+        }
+        if let Some(id) = &self.authserv_id {
+            println!("\tAuthserv Id:\t{}", id);
+        }
     }
 
-    pub fn get_inbox_adapter(&self) -> std::io::Result<InboxAdapter> {
-        let mut adapter = InboxAdapter::connect(&self.inbox_domain);
-        if let Ok(adptr) = &mut adapter {
-            adptr.login(&self.name, &self.password);
-        }
-        adapter
+    // Passwords are left out
+    pub fn to_json(&self) -> Value {
+        let inbox = match &self.inbox_domain {
+            InboxConfig::Pop3(domain, port) | InboxConfig::Imap(domain, port) => json!({ "protocol": self.inbox_domain.protocol(), "domain": domain, "port": port }),
+            InboxConfig::Other(protocol, location) => json!({ "protocol": protocol, "location": location }),
+        };
+        json!({
+            "name": self.name,
+            "inbox": inbox,
+            "smtp_domain": self.smtp_domain,
+            "shortcut": self.shortcut,
+            "aliases": self.aliases,
+            "sendmail": self.sendmail,
+            "rate_limit": self.rate_limit.as_ref().map(|l| json!({ "messages_per_minute": l.messages_per_minute, "recipients_per_message": l.recipients_per_message })),
+            "expunge": self.expunge.name(),
+            "color": self.color,
+            "prefix": self.prefix,
+            "authserv_id": self.authserv_id,
+        })
+    }
+
+    pub fn get_inbox_adapter(&self) -> MailResult<InboxAdapter> {
+        let mut adapter = InboxAdapter::open(&self.inbox_domain)?;
+        adapter.login(&self.name, &self.password)?;
+        Ok(adapter)
+    }
+
+    pub fn get_transport(&self) -> TransportAdapter {
+        TransportAdapter::for_account(self)
     }
 }
 
@@ -78,10 +264,14 @@ impl Serialize for Account {
         where S: Serializer,
     {
         use serde::ser::SerializeStruct;
-        let mut state = serializer.serialize_struct("Account", 5)?;
+        let mut state = serializer.serialize_struct("Account", 18)?;
         match &self.inbox_domain {
-            InboxConfig::Pop3(domain, _ ) => state.serialize_field("pop3_domain", domain)?,
-            InboxConfig::Imap(domain, _ ) => state.serialize_field("imap_domain", domain)?,
+            InboxConfig::Pop3(domain, port) => state.serialize_field("pop3_domain", &join_port(domain, *port, POP3_PORT))?,
+            InboxConfig::Imap(domain, port) => state.serialize_field("imap_domain", &join_port(domain, *port, IMAP_PORT))?,
+            InboxConfig::Other(protocol, location) => {
+                state.serialize_field("backend", protocol)?;
+                state.serialize_field("location", location)?;
+            },
         };
         state.serialize_field("smtp_domain", &self.smtp_domain)?;
         state.serialize_field("name", &self.name)?;
@@ -89,6 +279,36 @@ impl Serialize for Account {
         if let Some(sc) = &self.shortcut {
             state.serialize_field("shortcut", &sc)?;
         }
+        if let Some(user) = &self.smtp_user {
+            state.serialize_field("smtp_user", user)?;
+        }
+        if let Some(password) = &self.smtp_password {
+            state.serialize_field("smtp_password", password)?;
+        }
+        if !self.aliases.is_empty() {
+            state.serialize_field("aliases", &self.aliases)?;
+        }
+        if let Some(quoting) = &self.quoting {
+            state.serialize_field("quoting", &quoting.to_value())?;
+        }
+        if let Some(sendmail) = &self.sendmail {
+            state.serialize_field("sendmail", sendmail)?;
+        }
+        if let Some(limit) = &self.rate_limit {
+            state.serialize_field("rate_limit", &limit.to_value())?;
+        }
+        if self.expunge != ExpungePolicy::OnClose {
+            state.serialize_field("expunge", self.expunge.name())?;
+        }
+        if let Some(color) = &self.color {
+            state.serialize_field("color", color)?;
+        }
+        if let Some(prefix) = &self.prefix {
+            state.serialize_field("prefix", prefix)?;
+        }
+        if let Some(id) = &self.authserv_id {
+            state.serialize_field("authserv_id", id)?;
+        }
         state.end()
     }
 }
@@ -97,7 +317,7 @@ impl<'a> Deserialize<'a> for Account {
     fn deserialize<D>(deserializer: D) -> Result<Account, D::Error>
         where D: Deserializer<'a>,
     {
-        enum Field { Pop3Domain, ImapDomain, SmtpDomain, Name, Password, Shortcut };
+        enum Field { Pop3Domain, ImapDomain, SmtpDomain, Name, Password, Shortcut, SmtpUser, SmtpPassword, Aliases, Quoting, Sendmail, Backend, Location, RateLimit, Expunge, Color, Prefix, AuthservId };
 
         impl<'a> Deserialize<'a> for Field {
             fn deserialize<D>(deserializer: D) -> Result<Field, D::Error>
@@ -109,7 +329,7 @@ impl<'a> Deserialize<'a> for Account {
                     type Value = Field;
 
                     fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-                        formatter.write_str("`pop3_domain` or `imap_domain` or `smtp_domain` or `name` or `password` or `shortcut`")
+                        formatter.write_str("`pop3_domain` or `imap_domain` or `smtp_domain` or `name` or `password` or `shortcut` or `smtp_user` or `smtp_password` or `aliases` or `quoting` or `sendmail` or `backend` or `location` or `rate_limit` or `expunge` or `color` or `prefix` or `authserv_id`")
                     }
 
                     fn visit_str<E>(self, value: &str) -> Result<Field, E>
@@ -122,6 +342,18 @@ impl<'a> Deserialize<'a> for Account {
                             "name" => Ok(Field::Name),
                             "password" => Ok(Field::Password),
                             "shortcut" => Ok(Field::Shortcut),
+                            "smtp_user" => Ok(Field::SmtpUser),
+                            "smtp_password" => Ok(Field::SmtpPassword),
+                            "aliases" => Ok(Field::Aliases),
+                            "quoting" => Ok(Field::Quoting),
+                            "sendmail" => Ok(Field::Sendmail),
+                            "backend" => Ok(Field::Backend),
+                            "location" => Ok(Field::Location),
+                            "rate_limit" => Ok(Field::RateLimit),
+                            "expunge" => Ok(Field::Expunge),
+                            "color" => Ok(Field::Color),
+                            "prefix" => Ok(Field::Prefix),
+                            "authserv_id" => Ok(Field::AuthservId),
                             _ => Err(de::Error::unknown_field(value, FIELDS)),
                         }
                     }
@@ -149,14 +381,40 @@ impl<'a> Deserialize<'a> for Account {
                 let name = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(2, &self))?;
                 let password = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(3, &self))?;
                 let shortcut = seq.next_element()?;
+                let smtp_user = seq.next_element()?;
+                let smtp_password = seq.next_element()?;
+                let aliases = seq.next_element()?;
+                let quoting: Option<serde_yaml::Value> = seq.next_element()?;
+                let sendmail = seq.next_element()?;
+                let backend = seq.next_element()?;
+                let location: Option<String> = seq.next_element()?;
+                let rate_limit: Option<serde_yaml::Value> = seq.next_element()?;
+                let expunge: Option<String> = seq.next_element()?;
+                let color = seq.next_element()?;
+                let prefix = seq.next_element()?;
+                let authserv_id = seq.next_element()?;
 
-                let inbox_config = match (pop3_domain, imap_domain) {
-                    (Some(domain), None) => InboxConfig::new_pop3(domain),
-                    (None, Some(domain)) => InboxConfig::new_imap(domain),
-                    (_, _) => return Err(de::Error::invalid_length(0, &self)),
+                let inbox_config = match (pop3_domain, imap_domain, backend) {
+                    (Some(domain), None, None) => InboxConfig::new_pop3(domain),
+                    (None, Some(domain), None) => InboxConfig::new_imap(domain),
+                    (None, None, Some(backend)) => InboxConfig::Other(backend, location.unwrap_or_default()),
+                    (_, _, _) => return Err(de::Error::invalid_length(0, &self)),
                 };
 
-                Ok(Account::new(inbox_config, smtp_domain, name, password, shortcut))
+                let mut account = Account::new(inbox_config, smtp_domain, name, password, shortcut);
+                account.smtp_user = smtp_user;
+                account.smtp_password = smtp_password;
+                account.aliases = aliases.unwrap_or(Vec::new());
+                account.quoting = quoting.map(|q| QuoteStyle::from_value(&q));
+                account.sendmail = sendmail;
+                account.rate_limit = rate_limit.map(|l| RateLimit::from_value(&l));
+                if let Some(expunge) = expunge {
+                    account.expunge = ExpungePolicy::parse(expunge.as_str()).ok_or_else(|| de::Error::unknown_variant(expunge.as_str(), &["immediately", "on_close", "never"]))?;
+                }
+                account.color = color;
+                account.prefix = prefix;
+                account.authserv_id = authserv_id;
+                Ok(account)
             }
 
             fn visit_map<V>(self, mut map: V) -> Result<Account, V::Error>
@@ -168,6 +426,18 @@ impl<'a> Deserialize<'a> for Account {
                 let mut name = None;
                 let mut password = None;
                 let mut shortcut = None;
+                let mut smtp_user = None;
+                let mut smtp_password = None;
+                let mut aliases = None;
+                let mut quoting: Option<serde_yaml::Value> = None;
+                let mut sendmail = None;
+                let mut backend = None;
+                let mut location: Option<String> = None;
+                let mut rate_limit: Option<serde_yaml::Value> = None;
+                let mut expunge: Option<String> = None;
+                let mut color = None;
+                let mut prefix = None;
+                let mut authserv_id = None;
 
                 while let Some(key) = map.next_key()? {
                     match key {
@@ -207,22 +477,113 @@ impl<'a> Deserialize<'a> for Account {
                             }
                             shortcut = Some(map.next_value()?);
                         },
+                        Field::SmtpUser => {
+                            if smtp_user.is_some() {
+                                return Err(de::Error::duplicate_field("smtp_user"));
+                            }
+                            smtp_user = Some(map.next_value()?);
+                        },
+                        Field::SmtpPassword => {
+                            if smtp_password.is_some() {
+                                return Err(de::Error::duplicate_field("smtp_password"));
+                            }
+                            smtp_password = Some(map.next_value()?);
+                        },
+                        Field::Aliases => {
+                            if aliases.is_some() {
+                                return Err(de::Error::duplicate_field("aliases"));
+                            }
+                            aliases = Some(map.next_value()?);
+                        },
+                        Field::Quoting => {
+                            if quoting.is_some() {
+                                return Err(de::Error::duplicate_field("quoting"));
+                            }
+                            quoting = Some(map.next_value()?);
+                        },
+                        Field::Sendmail => {
+                            if sendmail.is_some() {
+                                return Err(de::Error::duplicate_field("sendmail"));
+                            }
+                            sendmail = Some(map.next_value()?);
+                        },
+                        Field::Backend => {
+                            if backend.is_some() {
+                                return Err(de::Error::duplicate_field("backend"));
+                            }
+                            backend = Some(map.next_value()?);
+                        },
+                        Field::Location => {
+                            if location.is_some() {
+                                return Err(de::Error::duplicate_field("location"));
+                            }
+                            location = Some(map.next_value()?);
+                        },
+                        Field::RateLimit => {
+                            if rate_limit.is_some() {
+                                return Err(de::Error::duplicate_field("rate_limit"));
+                            }
+                            rate_limit = Some(map.next_value()?);
+                        },
+                        Field::Expunge => {
+                            if expunge.is_some() {
+                                return Err(de::Error::duplicate_field("expunge"));
+                            }
+                            expunge = Some(map.next_value()?);
+                        },
+                        Field::Color => {
+                            if color.is_some() {
+                                return Err(de::Error::duplicate_field("color"));
+                            }
+                            color = Some(map.next_value()?);
+                        },
+                        Field::Prefix => {
+                            if prefix.is_some() {
+                                return Err(de::Error::duplicate_field("prefix"));
+                            }
+                            prefix = Some(map.next_value()?);
+                        },
+                        Field::AuthservId => {
+                            if authserv_id.is_some() {
+                                return Err(de::Error::duplicate_field("authserv_id"));
+                            }
+                            authserv_id = Some(map.next_value()?);
+                        },
                     }
                 }
-                let inbox_domain = match (pop3_domain, imap_domain) {
-                    (Some(domain), None) => InboxConfig::new_pop3(domain),
-                    (None, Some(domain)) => InboxConfig::new_imap(domain),
-                    (_, _) => return Err(de::Error::missing_field("inbox_domain")),
+                let inbox_domain = match (pop3_domain, imap_domain, backend) {
+                    (Some(domain), None, None) => InboxConfig::new_pop3(domain),
+                    (None, Some(domain), None) => InboxConfig::new_imap(domain),
+                    (None, None, Some(backend)) => InboxConfig::Other(backend, location.unwrap_or_default()),
+                    (_, _, _) => return Err(de::Error::missing_field("inbox_domain")),
+                };
+                // Other backends may bring their own sending and authentication
+                let (smtp_domain, password) = match &inbox_domain {
+                    InboxConfig::Other(_, _) => (smtp_domain.unwrap_or_default(), password.unwrap_or_default()),
+                    _ => (
+                        smtp_domain.ok_or_else(|| de::Error::missing_field("smtp_domain"))?,
+                        password.ok_or_else(|| de::Error::missing_field("password"))?,
+                    ),
                 };
-                let smtp_domain = smtp_domain.ok_or_else(|| de::Error::missing_field("smtp_domain"))?;
                 let name = name.ok_or_else(|| de::Error::missing_field("name"))?;
-                let password = password.ok_or_else(|| de::Error::missing_field("password"))?;
 
-                Ok(Account::new(inbox_domain, smtp_domain, name, password, shortcut))
+                let mut account = Account::new(inbox_domain, smtp_domain, name, password, shortcut);
+                account.smtp_user = smtp_user;
+                account.smtp_password = smtp_password;
+                account.aliases = aliases.unwrap_or(Vec::new());
+                account.quoting = quoting.map(|q| QuoteStyle::from_value(&q));
+                account.sendmail = sendmail;
+                account.rate_limit = rate_limit.map(|l| RateLimit::from_value(&l));
+                if let Some(expunge) = expunge {
+                    account.expunge = ExpungePolicy::parse(expunge.as_str()).ok_or_else(|| de::Error::unknown_variant(expunge.as_str(), &["immediately", "on_close", "never"]))?;
+                }
+                account.color = color;
+                account.prefix = prefix;
+                account.authserv_id = authserv_id;
+                Ok(account)
             }
         }
 
-        const FIELDS: &'static [&'static str] = &["pop3_domain", "imap_domain", "smtp_domain", "name", "password", "shortcut"];
         deserializer.deserialize_struct("Account", FIELDS, AccountVisitor)
     }
 }
@@ -0,0 +1,159 @@
+use super::decoder;
+use super::receiving::AddressAlias;
+
+// Parts of a single mailbox while it is read
+struct Mailbox {
+    name: String,
+    address: Option<String>,
+    comment: String,
+}
+
+impl Mailbox {
+    fn new() -> Mailbox {
+        Mailbox {
+            name: String::new(),
+            address: None,
+            comment: String::new(),
+        }
+    }
+
+    // "Name <user@host>", "user@host (Name)" or a bare "user@host"
+    fn finish(self) -> Option<AddressAlias> {
+        let (address, name) = match self.address {
+            Some(address) => (address, self.name),
+            None => (self.name, self.comment),
+        };
+        let address = address.split_whitespace().collect::<String>();
+        if address.is_empty() {
+            return None;
+        }
+        let name = decoder::decode(name.split_whitespace().collect::<Vec<&str>>().join(" "));
+        if name.is_empty() {
+            Some(AddressAlias::OnlyAddress(address))
+        } else {
+            Some(AddressAlias::WithAlias(name, address))
+        }
+    }
+}
+
+// Parses an RFC 5322 address list with display names, quoted strings, comments and groups.
+// Groups ("Team: a@example.org, b@example.org;") are flattened into their members
+pub fn parse_list(s: &str) -> Vec<AddressAlias> {
+    let mut ret = Vec::new();
+    let mut current = Mailbox::new();
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => if let Some(escaped) = chars.next() {
+                            current.name.push(escaped);
+                        },
+                        c => current.name.push(c),
+                    }
+                }
+            },
+            '(' => {
+                let mut depth = 1;
+                let mut comment = String::new();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '(' => depth += 1,
+                        ')' => {
+                            depth -= 1;
+                            if depth == 0 {
+                                break;
+                            }
+                        },
+                        '\\' => if let Some(escaped) = chars.next() {
+                            comment.push(escaped);
+                            continue;
+                        },
+                        _ => {},
+                    }
+                    comment.push(c);
+                }
+                if !current.comment.is_empty() {
+                    current.comment.push(' ');
+                }
+                current.comment.push_str(comment.trim());
+            },
+            '<' => {
+                let mut address = String::new();
+                while let Some(c) = chars.next() {
+                    if c == '>' {
+                        break;
+                    }
+                    address.push(c);
+                }
+                // Obsolete source routes like "<@relay:user@host>" are dropped
+                let address = match address.rfind(':') {
+                    Some(i) if address.starts_with('@') => address[i + 1..].to_string(),
+                    _ => address,
+                };
+                current.address = Some(address);
+            },
+            // Group name, the members follow
+            ':' => current = Mailbox::new(),
+            ',' | ';' => {
+                if let Some(mailbox) = std::mem::replace(&mut current, Mailbox::new()).finish() {
+                    ret.push(mailbox);
+                }
+            },
+            c => current.name.push(c),
+        }
+    }
+    if let Some(mailbox) = current.finish() {
+        ret.push(mailbox);
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (name, address) of every mailbox
+    fn parsed(s: &str) -> Vec<(Option<String>, String)> {
+        parse_list(s).iter().map(|a| (a.get_name(), a.get_address())).collect()
+    }
+
+    fn named(name: &str, address: &str) -> (Option<String>, String) {
+        (Some(name.to_string()), address.to_string())
+    }
+
+    fn bare(address: &str) -> (Option<String>, String) {
+        (None, address.to_string())
+    }
+
+    #[test]
+    fn quoted_names() {
+        assert_eq!(parsed("\"Doe, John\" <john@example.org>, jane@example.org"), vec![named("Doe, John", "john@example.org"), bare("jane@example.org")]);
+        assert_eq!(parsed("\"Say \\\"hi\\\"\" <hi@example.org>"), vec![named("Say \"hi\"", "hi@example.org")]);
+        assert_eq!(parsed("Alice   Example <alice@example.org>"), vec![named("Alice Example", "alice@example.org")]);
+    }
+
+    #[test]
+    fn groups() {
+        assert_eq!(parsed("Team: a@example.org, Bob <b@example.org>;, c@example.org"), vec![bare("a@example.org"), named("Bob", "b@example.org"), bare("c@example.org")]);
+        assert_eq!(parsed("undisclosed-recipients:;"), vec![]);
+    }
+
+    #[test]
+    fn comments() {
+        assert_eq!(parsed("alice@example.org (Alice Example)"), vec![named("Alice Example", "alice@example.org")]);
+        assert_eq!(parsed("Alice <alice@example.org> (work)"), vec![named("Alice", "alice@example.org")]);
+        assert_eq!(parsed("bob@example.org (Bob (the builder))"), vec![named("Bob (the builder)", "bob@example.org")]);
+        assert_eq!(parsed("<@relay.example.org:carol@example.org>"), vec![bare("carol@example.org")]);
+    }
+
+    #[test]
+    fn encoded_words() {
+        assert_eq!(parsed("=?UTF-8?Q?J=C3=B6rg?= <joerg@example.org>"), vec![named("Jörg", "joerg@example.org")]);
+        assert_eq!(parsed("\"=?UTF-8?B?R3LDvMOfZQ==?=\" <gruss@example.org>"), vec![named("Grüße", "gruss@example.org")]);
+        assert_eq!(parsed("=?ISO-8859-1?Q?Andr=E9?= Muster <andre@example.org>"), vec![named("André Muster", "andre@example.org")]);
+    }
+}
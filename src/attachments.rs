@@ -0,0 +1,81 @@
+// Files attached to mails being written, optionally bundled into one zip archive
+use std::{
+    fs,
+    io::{self, Cursor, Write},
+    path::Path,
+};
+
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+fn other_err<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+// Content type by file extension, unknown ones are sent as plain bytes
+fn content_type(name: &str) -> &'static str {
+    let extension = name.rsplit('.').next().unwrap_or("").to_lowercase();
+    match extension.as_str() {
+        "txt" => "text/plain",
+        "html" | "htm" => "text/html",
+        "csv" => "text/csv",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "json" => "application/json",
+        "ics" => "text/calendar",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "doc" => "application/msword",
+        "docx" => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        "xlsx" => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        "odt" => "application/vnd.oasis.opendocument.text",
+        _ => "application/octet-stream",
+    }
+}
+
+#[derive(Clone)]
+pub struct Attachment {
+    pub name: String,
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+impl Attachment {
+    pub fn from_file(path: &Path) -> io::Result<Attachment> {
+        let name = path.file_name().map(|n| n.to_string_lossy().to_string()).ok_or(other_err(format!("\"{}\" is no file", path.display())))?;
+        Ok(Attachment {
+            content_type: content_type(name.as_str()).to_string(),
+            data: fs::read(path)?,
+            name,
+        })
+    }
+
+    // Bundles the files into one deflated archive, files of the same name are numbered
+    pub fn zip(name: &str, files: &[Attachment]) -> io::Result<Attachment> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        let mut names: Vec<String> = Vec::with_capacity(files.len());
+        for file in files.iter() {
+            let mut entry = file.name.clone();
+            let mut n = 1;
+            while names.contains(&entry) {
+                n += 1;
+                entry = match file.name.rfind('.') {
+                    Some(i) if i > 0 => format!("{} ({}){}", &file.name[..i], n, &file.name[i..]),
+                    _ => format!("{} ({})", file.name, n),
+                };
+            }
+            writer.start_file(entry.as_str(), options).map_err(other_err)?;
+            writer.write_all(&file.data)?;
+            names.push(entry);
+        }
+        let data = writer.finish().map_err(other_err)?.into_inner();
+        let name = if name.to_lowercase().ends_with(".zip") { name.to_string() } else { format!("{}.zip", name) };
+        Ok(Attachment { name, content_type: String::from("application/zip"), data })
+    }
+
+    pub fn get_info(&self) -> String {
+        format!("{} ({:.1} KB)", self.name, self.data.len() as f64 / 1024.0)
+    }
+}
@@ -0,0 +1,231 @@
+// SPF, DKIM and DMARC results the receiving server recorded in the Authentication-Results
+// (RFC 8601) and Received-SPF fields
+use console::Style;
+use serde_json::{
+    json,
+    Value,
+};
+
+use super::headers::Headers;
+
+#[derive(Clone, Default)]
+pub struct AuthResults {
+    pub spf: Option<String>,
+    pub dkim: Option<String>,
+    pub dmarc: Option<String>,
+    // Whether a passing SPF or DKIM domain matches the From domain
+    pub aligned: Option<bool>,
+    // Authentication-Results fields by the id of the server that added them, topmost first.
+    // Senders can add such fields too, the results are only taken from a trusted server
+    fields: Vec<(String, String)>,
+    received_spf: Option<String>,
+    from: String,
+}
+
+#[derive(PartialEq)]
+pub enum Verdict {
+    Pass,
+    Fail,
+    Unknown,
+}
+
+impl AuthResults {
+    // The results stay empty until trust names the server whose fields count
+    pub fn parse(headers: &Headers, from: &str) -> AuthResults {
+        let fields = headers.get_all("Authentication-Results").into_iter().map(|value| {
+            let value = strip_comments(value);
+            // The first clause is the id of the server, optionally followed by a version
+            let id = value.split(';').next().and_then(|c| c.split_whitespace().next()).unwrap_or("").to_lowercase();
+            (id, value)
+        }).collect();
+        AuthResults {
+            fields,
+            received_spf: headers.get("Received-SPF").and_then(|v| v.split_whitespace().next()).map(|r| r.to_lowercase()),
+            from: from.to_string(),
+            ..AuthResults::default()
+        }
+    }
+
+    // Takes the results of the topmost field added by the server with the id or a host of its
+    // domain, RFC 8601 section 5. The own server removes fields with its id it didn't add
+    pub fn trust(&mut self, authserv_id: &str) {
+        let trusted = authserv_id.trim().to_lowercase();
+        let field = self.fields.iter()
+            .find(|(id, _)| !trusted.is_empty() && (*id == trusted || id.ends_with(format!(".{}", trusted).as_str())))
+            .map(|(_, value)| value.clone());
+        self.spf = None;
+        self.dkim = None;
+        self.dmarc = None;
+        self.aligned = None;
+        let mut spf_domain = None;
+        let mut dkim_domain = None;
+        if let Some(value) = field {
+            for clause in value.split(';').skip(1) {
+                let mut tokens = clause.split_whitespace();
+                let (method, result) = match tokens.next().and_then(|t| t.find('=').map(|i| (t[..i].to_lowercase(), t[i + 1..].to_lowercase()))) {
+                    Some(pair) => pair,
+                    None => continue,
+                };
+                let props: Vec<&str> = tokens.collect();
+                match method.as_str() {
+                    "spf" if self.spf.is_none() => {
+                        if result == "pass" {
+                            spf_domain = property(&props, &["smtp.mailfrom", "smtp.helo"]);
+                        }
+                        self.spf = Some(result);
+                    },
+                    // A passing signature wins over failing ones
+                    "dkim" if self.dkim.as_ref().map(|r| r != "pass").unwrap_or(true) => {
+                        if result == "pass" {
+                            dkim_domain = property(&props, &["header.d", "header.i"]);
+                        }
+                        self.dkim = Some(result);
+                    },
+                    "dmarc" if self.dmarc.is_none() => self.dmarc = Some(result),
+                    _ => {},
+                }
+            }
+        }
+        if self.spf.is_none() {
+            self.spf = self.received_spf.clone();
+        }
+        let from_domain = domain_of(self.from.as_str());
+        if !from_domain.is_empty() && (self.spf.is_some() || self.dkim.is_some()) {
+            self.aligned = Some([spf_domain, dkim_domain].iter().flatten().any(|d| is_aligned(d, from_domain.as_str())));
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spf.is_none() && self.dkim.is_none() && self.dmarc.is_none()
+    }
+
+    pub fn verdict(&self) -> Verdict {
+        match (self.dmarc.as_ref().map(|d| d.as_str()), self.aligned) {
+            (Some("pass"), _) => Verdict::Pass,
+            (Some("fail"), _) | (_, Some(false)) => Verdict::Fail,
+            (_, Some(true)) => Verdict::Pass,
+            (_, None) => Verdict::Unknown,
+        }
+    }
+
+    fn style(&self) -> Style {
+        match self.verdict() {
+            Verdict::Pass => Style::new().green(),
+            Verdict::Fail => Style::new().bold().red(),
+            Verdict::Unknown => Style::new().yellow(),
+        }
+    }
+
+    // Single character column for listings
+    pub fn indicator(&self) -> String {
+        if self.is_empty() {
+            return String::from(" ");
+        }
+        let mark = match self.verdict() {
+            Verdict::Pass => "+",
+            Verdict::Fail => "!",
+            Verdict::Unknown => "?",
+        };
+        self.style().apply_to(mark).to_string()
+    }
+
+    // Verdict in words for plain output
+    pub fn describe_verdict(&self) -> Option<&'static str> {
+        if self.is_empty() {
+            return None;
+        }
+        Some(match self.verdict() {
+            Verdict::Pass => "sender verified",
+            Verdict::Fail => "sender check failed",
+            Verdict::Unknown => "sender not verified",
+        })
+    }
+
+    pub fn summary(&self) -> String {
+        let result = |r: &Option<String>| r.clone().unwrap_or(String::from("none"));
+        let mut text = format!("SPF {}, DKIM {}, DMARC {}", result(&self.spf), result(&self.dkim), result(&self.dmarc));
+        if self.aligned == Some(false) {
+            text.push_str(", not aligned with the From domain");
+        }
+        self.style().apply_to(text).to_string()
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "spf": self.spf,
+            "dkim": self.dkim,
+            "dmarc": self.dmarc,
+            "aligned": self.aligned,
+            "pass": self.verdict() == Verdict::Pass,
+        })
+    }
+}
+
+// Domain of the first property like "smtp.mailfrom=user@example.org" with one of the names
+fn property(tokens: &Vec<&str>, names: &[&str]) -> Option<String> {
+    tokens.iter()
+        .filter_map(|t| t.find('=').map(|i| (t[..i].to_lowercase(), &t[i + 1..])))
+        .find(|(k, _)| names.contains(&k.as_str()))
+        .map(|(_, v)| domain_of(v))
+}
+
+fn strip_comments(value: &str) -> String {
+    let mut depth = 0;
+    value.chars().filter(|c| {
+        match c {
+            '(' => depth += 1,
+            ')' if depth > 0 => { depth -= 1; return false; },
+            _ => {},
+        }
+        depth == 0
+    }).collect()
+}
+
+// "user@Example.org" and "example.org" both give "example.org"
+fn domain_of(s: &str) -> String {
+    s.rsplit('@').next().unwrap_or("").trim_matches(|c| c == '<' || c == '>' || c == '"').to_lowercase()
+}
+
+// Relaxed alignment, compared by the last two labels as an approximation of the organizational domain
+fn is_aligned(a: &str, b: &str) -> bool {
+    let org = |d: &str| d.rsplit('.').take(2).collect::<Vec<&str>>();
+    !a.is_empty() && org(a) == org(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn results(head: &str, authserv_id: &str) -> AuthResults {
+        let headers = Headers::parse(head);
+        let mut auth = AuthResults::parse(&headers, headers.get("From").map(|f| f.as_str()).unwrap_or(""));
+        auth.trust(authserv_id);
+        auth
+    }
+
+    #[test]
+    fn forged_fields_are_ignored() {
+        let head = "Authentication-Results: mx.example.org; spf=fail smtp.mailfrom=bank.test; dkim=none\r\n\
+            Authentication-Results: mx.example.org; spf=pass smtp.mailfrom=bank.test; dkim=pass header.d=bank.test\r\n\
+            From: Bank <service@bank.test>\r\n";
+        let auth = results(head, "example.org");
+        assert_eq!(auth.spf.as_deref(), Some("fail"));
+        assert!(auth.verdict() == Verdict::Fail);
+        // Fields of other servers are the sender's word only
+        let forged = "Authentication-Results: mx.attacker.test; spf=pass smtp.mailfrom=bank.test; dkim=pass header.d=bank.test\r\n\
+            From: Bank <service@bank.test>\r\n";
+        assert!(results(forged, "example.org").is_empty());
+        assert!(results(forged, "").is_empty());
+    }
+
+    #[test]
+    fn trusted_field_passes() {
+        let head = "Authentication-Results: MX2.Example.org 1; spf=pass smtp.mailfrom=alice@shop.test (sender ok); dmarc=pass\r\n\
+            From: Alice <alice@shop.test>\r\n";
+        let auth = results(head, "example.org");
+        assert_eq!(auth.aligned, Some(true));
+        assert!(auth.verdict() == Verdict::Pass);
+        assert!(results(head, "mx2.example.org").verdict() == Verdict::Pass);
+        assert!(results(head, "other.example.org").is_empty());
+    }
+}
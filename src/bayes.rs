@@ -0,0 +1,174 @@
+extern crate serde_yaml;
+
+// Local spam classifier trained by the user, stored next to the account file. Tokens are
+// counted per class and combined into a score between 0 (ham) and 1 (spam)
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs,
+    io,
+    path::PathBuf,
+};
+
+use serde_yaml::{
+    Mapping,
+    Value,
+};
+
+// Only the tokens furthest from neutral decide the score
+const DECIDING_TOKENS: usize = 15;
+
+pub struct SpamModel {
+    path: PathBuf,
+    spam: HashMap<String, u32>,
+    ham: HashMap<String, u32>,
+    // Number of mails trained per class
+    spam_mails: u32,
+    ham_mails: u32,
+    // Class each mail was learned as by its Message-ID, so moves between folders retrain it
+    learned: HashMap<String, bool>,
+}
+
+// Words of 3 to 30 characters, lowercase. Header fields prefix their words so "free" in the
+// subject counts apart from "free" in the body, senders also give their domain
+pub fn tokenize(text: &[(&str, String)]) -> Vec<String> {
+    let mut ret: Vec<String> = Vec::new();
+    for (field, value) in text.iter() {
+        let value = value.to_lowercase();
+        let mut words: Vec<String> = value.split(|c: char| !(c.is_alphanumeric() || c == '$' || c == '\'' || c == '-'))
+            .map(|w| w.trim_matches(|c| c == '\'' || c == '-'))
+            .filter(|w| (3..=30).contains(&w.chars().count()) && !w.chars().all(|c| c.is_ascii_digit()))
+            .map(|w| w.to_string())
+            .collect();
+        if *field == "from" {
+            words.extend(value.split(|c: char| c.is_whitespace() || c == '<' || c == '>' || c == ',')
+                .filter_map(|w| w.rsplit_once('@').map(|(_, domain)| format!("domain*{}", domain))));
+        }
+        for word in words.into_iter() {
+            let token = if field.is_empty() { word } else { format!("{}*{}", field, word) };
+            if !ret.contains(&token) {
+                ret.push(token);
+            }
+        }
+    }
+    ret
+}
+
+fn counts_from(val: &Value) -> HashMap<String, u32> {
+    val.as_mapping().map(|map| map.iter()
+        .filter_map(|(token, n)| Some((token.as_str()?.to_string(), n.as_u64()? as u32)))
+        .collect()).unwrap_or(HashMap::new())
+}
+
+fn counts_to(counts: &HashMap<String, u32>) -> Value {
+    let mut map = Mapping::new();
+    for (token, n) in counts.iter().filter(|(_, n)| **n > 0) {
+        map.insert(Value::String(token.clone()), Value::from(*n as u64));
+    }
+    Value::Mapping(map)
+}
+
+impl SpamModel {
+    // A missing file is an untrained model
+    pub fn load(path: PathBuf) -> Result<SpamModel, Box<dyn Error>> {
+        let root: Value = match fs::read(&path) {
+            Ok(content) => serde_yaml::from_slice(&content)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Value::Null,
+            Err(e) => return Err(Box::new(e)),
+        };
+        let mails = |key: &str| root.get(key).and_then(|v| v.as_u64()).unwrap_or(0) as u32;
+        Ok(SpamModel {
+            spam: root.get("spam").map(counts_from).unwrap_or(HashMap::new()),
+            ham: root.get("ham").map(counts_from).unwrap_or(HashMap::new()),
+            spam_mails: mails("spam_mails"),
+            ham_mails: mails("ham_mails"),
+            learned: root.get("learned").and_then(|v| v.as_mapping()).map(|map| map.iter()
+                .filter_map(|(id, class)| Some((id.as_str()?.to_string(), class.as_str()? == "spam")))
+                .collect()).unwrap_or(HashMap::new()),
+            path,
+        })
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let mut map = Mapping::new();
+        map.insert(Value::from("spam_mails"), Value::from(self.spam_mails as u64));
+        map.insert(Value::from("ham_mails"), Value::from(self.ham_mails as u64));
+        map.insert(Value::from("spam"), counts_to(&self.spam));
+        map.insert(Value::from("ham"), counts_to(&self.ham));
+        let mut learned = Mapping::new();
+        for (id, spam) in self.learned.iter() {
+            learned.insert(Value::String(id.clone()), Value::from(if *spam { "spam" } else { "ham" }));
+        }
+        map.insert(Value::from("learned"), Value::Mapping(learned));
+        fs::write(&self.path, serde_yaml::to_vec(&Value::Mapping(map))?)?;
+        Ok(())
+    }
+
+    pub fn train(&mut self, tokens: &[String], spam: bool) {
+        let (counts, mails) = if spam { (&mut self.spam, &mut self.spam_mails) } else { (&mut self.ham, &mut self.ham_mails) };
+        *mails += 1;
+        for token in tokens.iter() {
+            *counts.entry(token.clone()).or_insert(0) += 1;
+        }
+    }
+
+    // Takes back an earlier training, the tokens may differ a little when the body isn't loaded
+    fn untrain(&mut self, tokens: &[String], spam: bool) {
+        let (counts, mails) = if spam { (&mut self.spam, &mut self.spam_mails) } else { (&mut self.ham, &mut self.ham_mails) };
+        *mails = mails.saturating_sub(1);
+        for token in tokens.iter() {
+            if let Some(n) = counts.get_mut(token) {
+                *n = n.saturating_sub(1);
+            }
+        }
+    }
+
+    // Trains a mail known by its Message-ID, one learned as the other class before is moved over.
+    // Returns false if it was learned as this class already
+    pub fn learn(&mut self, message_id: &str, tokens: &[String], spam: bool) -> bool {
+        match self.learned.get(message_id) {
+            Some(class) if *class == spam => return false,
+            Some(_) => self.untrain(tokens, !spam),
+            None => {},
+        }
+        self.train(tokens, spam);
+        self.learned.insert(message_id.to_string(), spam);
+        true
+    }
+
+    pub fn learned_as(&self, message_id: &str) -> Option<bool> {
+        self.learned.get(message_id).cloned()
+    }
+
+    // Both classes need training before mails are scored
+    pub fn is_trained(&self) -> bool {
+        self.spam_mails > 0 && self.ham_mails > 0
+    }
+
+    pub fn get_counts(&self) -> (u32, u32) {
+        (self.spam_mails, self.ham_mails)
+    }
+
+    // Spam probability of each token, pulled towards 0.5 while it was seen rarely, combined
+    // in the log domain so long mails don't underflow
+    pub fn score(&self, tokens: &[String]) -> Option<f64> {
+        if !self.is_trained() {
+            return None;
+        }
+        let mut probabilities: Vec<f64> = tokens.iter().filter_map(|token| {
+            let spam = *self.spam.get(token).unwrap_or(&0) as f64;
+            let ham = *self.ham.get(token).unwrap_or(&0) as f64;
+            if spam + ham == 0.0 {
+                return None;
+            }
+            let spam_ratio = (spam / self.spam_mails as f64).min(1.0);
+            let ham_ratio = (ham / self.ham_mails as f64).min(1.0);
+            let p = spam_ratio / (spam_ratio + ham_ratio);
+            let seen = spam + ham;
+            Some(((0.5 + seen * p) / (1.0 + seen)).max(0.01).min(0.99))
+        }).collect();
+        probabilities.sort_by(|a, b| (b - 0.5).abs().partial_cmp(&(a - 0.5).abs()).unwrap_or(std::cmp::Ordering::Equal));
+        let sum: f64 = probabilities.iter().take(DECIDING_TOKENS).map(|p| (p / (1.0 - p)).ln()).sum();
+        Some(1.0 / (1.0 + (-sum).exp()))
+    }
+}
@@ -0,0 +1,104 @@
+// Delivery status notifications (RFC 3464) and the mails they return
+use super::headers::Headers;
+use super::mime_decode;
+use super::util;
+
+pub struct Bounce {
+    // Recipients the delivery failed for
+    pub recipients: Vec<String>,
+    // First status or diagnostic the reporting server gave
+    pub diagnostic: Option<String>,
+    // Header of the returned mail, as far as the report includes it
+    pub original: Option<Headers>,
+}
+
+impl Bounce {
+    pub fn original_id(&self) -> Option<String> {
+        self.original.as_ref().and_then(|h| h.get("Message-ID")).and_then(|v| util::message_ids(v).into_iter().next())
+    }
+
+    pub fn print(&self) {
+        println!("Bounce:\tdelivery failed{}{}", if self.recipients.is_empty() { String::new() } else { format!(" for {}", self.recipients.join(", ")) },
+            self.diagnostic.as_ref().map(|d| format!(" ({})", d)).unwrap_or_default());
+        if let Some(original) = &self.original {
+            let get = |name: &str| original.get(name).cloned().unwrap_or(String::from("-"));
+            println!("Returned:\t{} | {} | to {}", get("Date"), get("Subject"), get("To"));
+        }
+    }
+}
+
+// Reports by content type, or by a mailer daemon as sender for servers that send plain text
+pub fn is_bounce(headers: &Headers) -> bool {
+    let report = headers.get("Content-Type").map(|v| v.to_lowercase())
+        .map(|v| v.contains("multipart/report") && v.contains("delivery-status"))
+        .unwrap_or(false);
+    let daemon = headers.get("From").map(|v| v.to_lowercase())
+        .map(|v| v.contains("mailer-daemon@") || v.contains("postmaster@"))
+        .unwrap_or(false);
+    report || daemon || headers.get("X-Failed-Recipients").is_some()
+}
+
+pub fn parse(headers: &Headers, body: &str) -> Option<Bounce> {
+    if !is_bounce(headers) {
+        return None;
+    }
+    let mut bounce = Bounce { recipients: Vec::new(), diagnostic: None, original: None };
+    walk(headers, body, &mut bounce);
+    for value in headers.get_all("X-Failed-Recipients") {
+        for recipient in value.split(',').map(|r| r.trim()).filter(|r| !r.is_empty()) {
+            if !bounce.recipients.iter().any(|r| r.eq_ignore_ascii_case(recipient)) {
+                bounce.recipients.push(recipient.to_string());
+            }
+        }
+    }
+    // Plain text bounces often quote the returned header somewhere in the text
+    if bounce.original.is_none() {
+        let quoted = body.lines().find(|l| l.to_lowercase().starts_with("message-id:"));
+        if let Some(id) = quoted.and_then(|l| util::message_ids(l).into_iter().next()) {
+            let mut original = Headers::new();
+            original.add("Message-ID", format!("<{}>", id).as_str());
+            bounce.original = Some(original);
+        }
+    }
+    Some(bounce)
+}
+
+fn walk(headers: &Headers, body: &str, bounce: &mut Bounce) {
+    let (mime, params) = match headers.get("Content-Type") {
+        Some(val) => mime_decode::content_type(val),
+        None => (String::from("text/plain"), Default::default()),
+    };
+    match mime.as_str() {
+        m if m.starts_with("multipart/") => if let Some(boundary) = params.get("boundary") {
+            for part in mime_decode::split_multipart(body, boundary.as_str()).into_iter() {
+                let (head, part_body) = mime_decode::split_message(part);
+                walk(&Headers::parse(head), part_body, bounce);
+            }
+        },
+        "message/delivery-status" | "message/global-delivery-status" => {
+            let decoded = String::from_utf8_lossy(&mime_decode::decode_body(body, headers.get("Content-Transfer-Encoding"))).replace("\r\n", "\n");
+            // Fields of the whole report come first, then a block per recipient
+            for block in decoded.split("\n\n").skip(1) {
+                let fields = Headers::parse(block);
+                let failed = fields.get("Action").map(|a| a.trim().eq_ignore_ascii_case("failed")).unwrap_or(true);
+                let recipient = fields.get("Final-Recipient").or(fields.get("Original-Recipient"))
+                    .map(|r| r.splitn(2, ';').last().unwrap_or("").trim().to_string())
+                    .filter(|r| !r.is_empty());
+                if let (true, Some(recipient)) = (failed, recipient) {
+                    bounce.recipients.push(recipient);
+                    if bounce.diagnostic.is_none() {
+                        bounce.diagnostic = fields.get("Diagnostic-Code").or(fields.get("Status")).map(|d| d.splitn(2, ';').last().unwrap_or("").trim().to_string());
+                    }
+                }
+            }
+        },
+        "text/rfc822-headers" => {
+            let decoded = mime_decode::decode_body(body, headers.get("Content-Transfer-Encoding"));
+            bounce.original = Some(Headers::parse(String::from_utf8_lossy(&decoded).as_ref()));
+        },
+        "message/rfc822" | "message/global" => {
+            bounce.original = Some(Headers::parse(mime_decode::split_message(body).0));
+        },
+        _ => {},
+    }
+}
@@ -0,0 +1,278 @@
+// Invitations (iCalendar, RFC 5545) in received mails and the iTIP replies (RFC 5546) answering them
+use std::{
+    io,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use chrono::{
+    DateTime,
+    Local,
+    NaiveDate,
+    NaiveDateTime,
+    TimeZone,
+    Utc,
+};
+use serde_json::{
+    json,
+    Value,
+};
+
+use super::util;
+
+pub struct Property {
+    pub name: String,
+    pub params: Vec<(String, String)>,
+    pub value: String,
+    // Unfolded line as it was received, copied into replies
+    line: String,
+}
+
+impl Property {
+    fn parse(line: &str) -> Option<Property> {
+        // The value starts at the first colon outside of a quoted parameter
+        let mut quoted = false;
+        let colon = line.char_indices().find(|(_, c)| {
+            if *c == '"' {
+                quoted = !quoted;
+            }
+            *c == ':' && !quoted
+        })?.0;
+        let mut head = line[..colon].split(';');
+        let name = head.next()?.trim().to_uppercase();
+        let params = head.filter_map(|p| {
+            let i = p.find('=')?;
+            Some((p[..i].trim().to_uppercase(), p[i + 1..].trim_matches('"').to_string()))
+        }).collect();
+        Some(Property { name, params, value: line[colon + 1..].to_string(), line: line.to_string() })
+    }
+
+    pub fn param(&self, name: &str) -> Option<&String> {
+        self.params.iter().find(|(k, _)| k == name).map(|(_, v)| v)
+    }
+
+    // "mailto:bob@example.org" gives "bob@example.org"
+    fn address(&self) -> String {
+        let value = self.value.trim();
+        match value.get(..7) {
+            Some(scheme) if scheme.eq_ignore_ascii_case("mailto:") => value[7..].to_string(),
+            _ => value.to_string(),
+        }
+    }
+
+    // Common name with the address, like in a From field
+    fn person(&self) -> String {
+        match self.param("CN") {
+            Some(name) => format!("{} <{}>", name, self.address()),
+            None => self.address(),
+        }
+    }
+}
+
+pub struct Event {
+    // REQUEST for invitations, CANCEL, REPLY, PUBLISH for the others
+    pub method: Option<String>,
+    properties: Vec<Property>,
+}
+
+impl Event {
+    fn get(&self, name: &str) -> Option<&Property> {
+        self.properties.iter().find(|p| p.name == name)
+    }
+
+    fn text(&self, name: &str) -> Option<String> {
+        self.get(name).map(|p| unescape(p.value.as_str()))
+    }
+
+    pub fn summary(&self) -> String {
+        self.text("SUMMARY").unwrap_or(String::from("<no summary>"))
+    }
+
+    pub fn organizer(&self) -> Option<String> {
+        self.get("ORGANIZER").map(|p| p.address())
+    }
+
+    pub fn is_invitation(&self) -> bool {
+        self.method.as_ref().map(|m| m.eq_ignore_ascii_case("REQUEST")).unwrap_or(false)
+    }
+
+    // Attendee entries with their participation status
+    pub fn attendees(&self) -> Vec<(String, String)> {
+        self.properties.iter().filter(|p| p.name == "ATTENDEE")
+            .map(|p| (p.address(), p.param("PARTSTAT").cloned().unwrap_or(String::from("NEEDS-ACTION"))))
+            .collect()
+    }
+
+    fn time(&self, name: &str) -> Option<String> {
+        self.get(name).map(|p| format_time(p.value.trim(), p.param("TZID")))
+    }
+
+    pub fn print(&self) {
+        let title = if self.is_invitation() { "Invitation" } else { "Event" };
+        println!("{}:\t{}", title, self.summary());
+        match (self.time("DTSTART"), self.time("DTEND")) {
+            (Some(start), Some(end)) => println!("When:\t{} - {}", start, end),
+            (Some(start), None) => println!("When:\t{}", start),
+            (_, _) => {},
+        }
+        if let Some(location) = self.text("LOCATION") {
+            println!("Where:\t{}", location);
+        }
+        if let Some(organizer) = self.get("ORGANIZER") {
+            println!("Organizer:\t{}", organizer.person());
+        }
+        let attendees = self.attendees();
+        if !attendees.is_empty() {
+            println!("Attendees:\t{}", attendees.iter().map(|(a, s)| format!("{} ({})", a, s.to_lowercase())).collect::<Vec<String>>().join(", "));
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "method": self.method,
+            "summary": self.summary(),
+            "start": self.time("DTSTART"),
+            "end": self.time("DTEND"),
+            "location": self.text("LOCATION"),
+            "organizer": self.organizer(),
+            "attendees": self.attendees().into_iter().map(|(a, s)| json!({ "address": a, "status": s })).collect::<Vec<Value>>(),
+        })
+    }
+
+    // iTIP REPLY with the participation status of the attendee, e.g. "ACCEPTED"
+    pub fn reply(&self, attendee: &str, status: &str) -> String {
+        let now = Utc::now().format("%Y%m%dT%H%M%SZ");
+        let mut lines = vec![
+            String::from("BEGIN:VCALENDAR"),
+            String::from("VERSION:2.0"),
+            String::from("PRODID:-//cli-mail-rs//EN"),
+            String::from("METHOD:REPLY"),
+            String::from("BEGIN:VEVENT"),
+        ];
+        // The organizer matches the reply to the event by these
+        for name in ["UID", "SEQUENCE", "RECURRENCE-ID", "DTSTART", "DTEND", "SUMMARY", "ORGANIZER"].iter() {
+            if let Some(p) = self.get(name) {
+                lines.push(p.line.clone());
+            }
+        }
+        lines.push(format!("DTSTAMP:{}", now));
+        lines.push(format!("ATTENDEE;PARTSTAT={}:mailto:{}", status, attendee));
+        lines.push(String::from("END:VEVENT"));
+        lines.push(String::from("END:VCALENDAR"));
+        lines.iter().map(|l| fold(l.as_str())).collect::<Vec<String>>().join("\r\n") + "\r\n"
+    }
+}
+
+// All events of a calendar object, each with the method of its calendar
+pub fn parse(ics: &str) -> Vec<Event> {
+    let mut ret = Vec::new();
+    let mut method = None;
+    let mut current: Option<Vec<Property>> = None;
+    for line in unfold(ics).iter() {
+        let property = match Property::parse(line.as_str()) {
+            Some(p) => p,
+            None => continue,
+        };
+        match (property.name.as_str(), property.value.trim().to_uppercase().as_str()) {
+            ("METHOD", _) => method = Some(property.value.trim().to_uppercase()),
+            ("BEGIN", "VEVENT") => current = Some(Vec::new()),
+            ("END", "VEVENT") => if let Some(properties) = current.take() {
+                ret.push(Event { method: method.clone(), properties });
+            },
+            (_, _) => if let Some(properties) = current.as_mut() {
+                properties.push(property);
+            },
+        }
+    }
+    ret
+}
+
+// Message answering an invitation, sent to the organizer
+pub fn reply_message(event: &Event, from: &str, status: &str) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let domain = from.rsplit('@').next().unwrap_or("localhost");
+    let boundary = format!("cli-mail-rs-{}", now.as_nanos());
+    let verb = match status {
+        "ACCEPTED" => "Accepted",
+        "TENTATIVE" => "Tentatively accepted",
+        _ => "Declined",
+    };
+    let headers = vec![
+        format!("Date: {}", Local::now().to_rfc2822()),
+        format!("From: {}", from),
+        format!("To: {}", event.organizer().unwrap_or_default()),
+        format!("Subject: {}", util::encode_header(format!("{}: {}", verb, event.summary()).as_str())),
+        format!("Message-ID: <{}.{}@{}>", now.as_nanos(), std::process::id(), domain),
+        String::from("MIME-Version: 1.0"),
+        format!("Content-Type: multipart/alternative; boundary=\"{}\"", boundary),
+    ];
+    let text = format!("{} has {} the invitation \"{}\".", from, verb.to_lowercase(), event.summary());
+    let calendar = base64::encode(event.reply(from, status).as_bytes());
+    let calendar = calendar.as_bytes().chunks(76).map(|c| String::from_utf8_lossy(c).to_string()).collect::<Vec<String>>().join("\r\n");
+    format!("{}\r\n\r\n--{b}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: base64\r\n\r\n{}\r\n--{b}\r\nContent-Type: text/calendar; charset=utf-8; method=REPLY\r\nContent-Transfer-Encoding: base64\r\n\r\n{}\r\n--{b}--\r\n",
+        headers.join("\r\n"), base64::encode(text.as_bytes()), calendar, b = boundary)
+}
+
+// Runs an import command like "khal import --batch" with the path of a temp file holding the ics
+pub fn import(command: &str, ics: &str) -> io::Result<()> {
+    let mut parts = command.split_whitespace();
+    let program = parts.next().ok_or(io::Error::new(io::ErrorKind::Other, "calendar import command is empty"))?;
+    let file = util::temp_file("ics", ics.as_bytes())?;
+    let status = Command::new(program).args(parts).arg(file.path()).status()?;
+    if !status.success() {
+        return Err(io::Error::new(io::ErrorKind::Other, format!("{} exited with {}", program, status)));
+    }
+    Ok(())
+}
+
+// Continuation lines start with a space or tab
+fn unfold(ics: &str) -> Vec<String> {
+    let mut ret: Vec<String> = Vec::new();
+    for line in ics.split('\n').map(|l| l.trim_end_matches('\r')) {
+        match (line.chars().next(), ret.last_mut()) {
+            (Some(' '), Some(last)) | (Some('\t'), Some(last)) => last.push_str(&line[1..]),
+            (Some(_), _) => ret.push(line.to_string()),
+            (None, _) => {},
+        }
+    }
+    ret
+}
+
+// Lines are folded after 75 bytes, without splitting characters
+fn fold(line: &str) -> String {
+    let mut ret = String::new();
+    let mut length = 0;
+    for c in line.chars() {
+        if length + c.len_utf8() > 75 {
+            ret.push_str("\r\n ");
+            length = 1;
+        }
+        ret.push(c);
+        length += c.len_utf8();
+    }
+    ret
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("\\n", "\n").replace("\\N", "\n").replace("\\,", ",").replace("\\;", ";").replace("\\\\", "\\")
+}
+
+// Times in UTC are shown in local time, floating and zoned times as they are with their zone
+fn format_time(value: &str, tzid: Option<&String>) -> String {
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y%m%d") {
+        return date.format("%Y-%m-%d").to_string();
+    }
+    let naive = match NaiveDateTime::parse_from_str(value.trim_end_matches('Z'), "%Y%m%dT%H%M%S") {
+        Ok(naive) => naive,
+        Err(_) => return value.to_string(),
+    };
+    if value.ends_with('Z') {
+        let local: DateTime<Local> = Utc.from_utc_datetime(&naive).with_timezone(&Local);
+        return util::format_date(&local.into());
+    }
+    let shown = util::format_date(&Utc.from_utc_datetime(&naive).into());
+    match tzid {
+        Some(zone) => format!("{} ({})", shown, zone),
+        None => shown,
+    }
+}
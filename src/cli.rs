@@ -0,0 +1,249 @@
+use std::{
+    fs,
+    io::{stdin, Read},
+};
+
+use super::inbox::{
+    InboxManager,
+    MailBuilder,
+};
+use super::sending;
+use super::output;
+
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_USAGE: i32 = 1;
+pub const EXIT_CONFIG: i32 = 2;
+pub const EXIT_FAILED: i32 = 3;
+pub const EXIT_NOT_FOUND: i32 = 4;
+
+const USAGE: &str = "usage:
+    cli-mail-rs list --account <account> [--unread]
+    cli-mail-rs read --account <account> --uid <id>
+    cli-mail-rs send [--account <account>] [--from <address>] --to <address>... [--cc <address>...] --subject <text> [--body-file <file>]
+    cli-mail-rs watch [--account <account>] [--interval <seconds>]
+options:
+    --json    print listings, mails and errors as json
+    --yes     answer all confirmations with yes
+    --trace [file]    log the IMAP and SMTP protocol exchange, credentials redacted
+    --log-level <level>    diagnostics to show (off, error, warn, info, debug, trace)
+    --script <file>    run the commands of a file instead of prompting, stops at the first error
+    --continue    keep running a script after errors";
+
+pub enum Command {
+    List { account: String, unread: bool },
+    Read { account: String, uid: u32 },
+    Send { account: Option<String>, from: Option<String>, to: Vec<String>, cc: Vec<String>, subject: String, body_file: Option<String> },
+    Watch { account: Option<String>, interval: u64 },
+}
+
+const DEFAULT_WATCH_INTERVAL: u64 = 60;
+
+struct Options {
+    values: Vec<(String, String)>,
+    flags: Vec<String>,
+}
+
+impl Options {
+    // Splits "--key value" pairs and "--flag" switches
+    fn parse(args: &[String], flags: &[&str]) -> Result<Options, String> {
+        let mut ret = Options { values: Vec::new(), flags: Vec::new() };
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            if !arg.starts_with("--") {
+                return Err(format!("unexpected argument \"{}\"", arg));
+            }
+            let key = arg[2..].to_string();
+            if flags.contains(&key.as_str()) {
+                ret.flags.push(key);
+            } else {
+                let val = iter.next().ok_or(format!("missing value for \"{}\"", arg))?;
+                ret.values.push((key, val.clone()));
+            }
+        }
+        Ok(ret)
+    }
+
+    fn get(&self, key: &str) -> Option<String> {
+        self.values.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone())
+    }
+
+    fn get_all(&self, key: &str) -> Vec<String> {
+        self.values.iter().filter(|(k, _)| k == key).flat_map(|(_, v)| v.split(',').map(|s| s.trim().to_string()).collect::<Vec<String>>()).collect()
+    }
+
+    fn require(&self, key: &str) -> Result<String, String> {
+        self.get(key).ok_or(format!("missing required option \"--{}\"", key))
+    }
+
+    fn flag(&self, key: &str) -> bool {
+        self.flags.iter().any(|f| f == key)
+    }
+}
+
+// Returns None if no subcommand is given, so the interactive mode should be started
+pub fn parse(args: &[String]) -> Result<Option<Command>, String> {
+    let (cmd, rest) = match args.split_first() {
+        Some((cmd, rest)) => (cmd.as_str(), rest),
+        None => return Ok(None),
+    };
+    let command = match cmd {
+        "list" => {
+            let opts = Options::parse(rest, &["unread"])?;
+            Command::List { account: opts.require("account")?, unread: opts.flag("unread") }
+        },
+        "read" => {
+            let opts = Options::parse(rest, &[])?;
+            let uid = opts.require("uid")?.parse::<u32>().map_err(|_| String::from("option \"--uid\" must be a number"))?;
+            Command::Read { account: opts.require("account")?, uid }
+        },
+        "send" => {
+            let opts = Options::parse(rest, &[])?;
+            let to = opts.get_all("to");
+            if to.is_empty() {
+                return Err(String::from("missing required option \"--to\""));
+            }
+            Command::Send {
+                account: opts.get("account"),
+                from: opts.get("from"),
+                to,
+                cc: opts.get_all("cc"),
+                subject: opts.require("subject")?,
+                body_file: opts.get("body-file"),
+            }
+        },
+        "watch" => {
+            let opts = Options::parse(rest, &[])?;
+            let interval = match opts.get("interval") {
+                Some(val) => val.parse::<u64>().ok().filter(|i| *i > 0).ok_or(String::from("option \"--interval\" must be a positive number"))?,
+                None => DEFAULT_WATCH_INTERVAL,
+            };
+            Command::Watch { account: opts.get("account"), interval }
+        },
+        "help" | "--help" | "-h" => return Err(String::from(USAGE)),
+        _ => return Err(format!("unknown command \"{}\"\n{}", cmd, USAGE)),
+    };
+    Ok(Some(command))
+}
+
+// Runs a single command, returns the exit code
+pub fn run(command: Command, context: &mut InboxManager) -> i32 {
+    match command {
+        Command::List { account, unread } => {
+            let inbox = match context.get_inbox(&account) {
+                Some(inbox) => inbox,
+                None => {
+                    output::error(format!("no account named \"{}\" available!", account).as_str());
+                    return EXIT_CONFIG;
+                }
+            };
+            if let Err(e) = inbox.refresh() {
+                output::error(format!("Could not refresh inbox! [{}]", e).as_str());
+                return EXIT_FAILED;
+            }
+            if unread {
+                inbox.show_unread(false);
+            } else {
+                inbox.show_mails(false);
+            }
+            EXIT_OK
+        },
+        Command::Read { account, uid } => {
+            let keyring = context.keyring();
+            let ca_file = context.settings.smime_ca_file.clone();
+            let inbox = match context.get_inbox(&account) {
+                Some(inbox) => inbox,
+                None => {
+                    output::error(format!("no account named \"{}\" available!", account).as_str());
+                    return EXIT_CONFIG;
+                }
+            };
+            if let Err(e) = inbox.refresh() {
+                output::error(format!("Could not refresh inbox! [{}]", e).as_str());
+                return EXIT_FAILED;
+            }
+            if !inbox.open_by_id(uid) {
+                output::error(format!("no mail with id {} in inbox!", uid).as_str());
+                return EXIT_NOT_FOUND;
+            }
+            if let Err(e) = inbox.decrypt_opened(&keyring) {
+                output::error(format!("Could not decrypt mail! [{}]", e).as_str());
+            }
+            match inbox.get_opened_mail() {
+                Ok(mail) => {
+                    mail.print_verified(&keyring, ca_file.as_ref().map(|f| f.as_str()));
+                    EXIT_OK
+                },
+                Err(e) => {
+                    output::error(format!("Could not open mail! [{}]", e).as_str());
+                    EXIT_FAILED
+                },
+            }
+        },
+        Command::Send { account, from, to, cc, subject, body_file } => {
+            // Body is read from file or stdin
+            let text = match body_file {
+                Some(path) => match fs::read_to_string(path.as_str()) {
+                    Ok(text) => text,
+                    Err(e) => {
+                        output::error(format!("Could not read body file \"{}\" [{}]", path, e).as_str());
+                        return EXIT_USAGE;
+                    }
+                },
+                None => {
+                    let mut text = String::new();
+                    if let Err(e) = stdin().read_to_string(&mut text) {
+                        output::error(format!("Could not read body from stdin [{}]", e).as_str());
+                        return EXIT_USAGE;
+                    }
+                    text
+                },
+            };
+            let sender = match (&account, &from) {
+                (Some(ident), _) => context.get_inbox(ident).map(|i| i.get_account().clone()),
+                (None, Some(addr)) => context.find_sending_account(addr).cloned(),
+                (None, None) => None,
+            };
+            let sender = match sender {
+                Some(acc) => acc,
+                None => {
+                    output::error("no account to send from, use \"--account\" or \"--from\"!");
+                    return EXIT_CONFIG;
+                }
+            };
+
+            let mut builder = MailBuilder::new();
+            builder.from(from.unwrap_or(sender.name.clone()))
+                .to(to)
+                .cc(cc)
+                .subject(subject)
+                .text(text);
+            let mail = match builder.build() {
+                Ok(mail) => mail,
+                Err((_, field)) => {
+                    output::error(format!("mail is missing field \"{}\"", field).as_str());
+                    return EXIT_USAGE;
+                }
+            };
+            let limit = sender.rate_limit.clone().unwrap_or_default();
+            let result = sending::send_mail(&mut sender.get_transport(), &mail, &limit, &mut Vec::new(), &mut |sent, total| {
+                log::info!("sent to {}/{} recipients", sent, total);
+            });
+            match result {
+                Ok(_) => EXIT_OK,
+                Err(e) => {
+                    output::error(format!("Could not send mail! [{}]", e).as_str());
+                    EXIT_FAILED
+                },
+            }
+        },
+        Command::Watch { account, interval } => {
+            match context.watch(account, interval) {
+                Ok(_) => EXIT_OK,
+                Err(e) => {
+                    output::error(e.as_str());
+                    EXIT_CONFIG
+                },
+            }
+        },
+    }
+}
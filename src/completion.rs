@@ -0,0 +1,109 @@
+use rustyline::{
+    completion::{Completer, Pair},
+    highlight::Highlighter,
+    hint::Hinter,
+    validate::Validator,
+    Context,
+    Helper,
+};
+
+// Commands whose arguments are account identifiers
+const ACCOUNT_COMMANDS: &'static [&'static str] = &["inbox", "show-inbox"];
+// Commands whose arguments are mail addresses
+const ADDRESS_COMMANDS: &'static [&'static str] = &["from", "to", "cc", "bcc"];
+// Commands whose arguments are folder names
+const FOLDER_COMMANDS: &'static [&'static str] = &["folder", "move"];
+
+pub struct CommandHelper {
+    pub commands: Vec<String>,
+    pub accounts: Vec<String>,
+    pub folders: Vec<String>,
+    // Known people as (address, name)
+    pub contacts: Vec<(String, Option<String>)>,
+    // Names of the external commands, available in every mode
+    pub plugins: Vec<String>,
+}
+
+impl CommandHelper {
+    pub fn new() -> CommandHelper {
+        CommandHelper {
+            commands: Vec::new(),
+            accounts: Vec::new(),
+            folders: Vec::new(),
+            contacts: Vec::new(),
+            plugins: Vec::new(),
+        }
+    }
+
+    pub fn set_commands(&mut self, commands: Vec<String>) {
+        self.commands = commands;
+        self.commands.sort();
+    }
+
+    fn candidates(&self, command: &str) -> Option<&Vec<String>> {
+        if command == "help" {
+            Some(&self.commands)
+        } else if ACCOUNT_COMMANDS.contains(&command) {
+            Some(&self.accounts)
+        } else if FOLDER_COMMANDS.contains(&command) {
+            Some(&self.folders)
+        } else {
+            None
+        }
+    }
+}
+
+// Addresses are completed from any part of the address or name, shown with the name
+fn matching_people(people: &Vec<(String, Option<String>)>, text: &str) -> Vec<Pair> {
+    let text = text.to_lowercase();
+    people.iter()
+        .filter(|(address, name)| address.to_lowercase().contains(&text) || name.as_ref().map(|n| n.to_lowercase().contains(&text)).unwrap_or(false))
+        .map(|(address, name)| Pair {
+            display: match name {
+                Some(name) => format!("{} <{}>", name, address),
+                None => address.clone(),
+            },
+            replacement: address.clone(),
+        })
+        .collect()
+}
+
+fn matching(options: &Vec<String>, prefix: &str) -> Vec<Pair> {
+    options.iter()
+        .filter(|o| o.starts_with(prefix))
+        .map(|o| Pair { display: o.clone(), replacement: o.clone() })
+        .collect()
+}
+
+impl Completer for CommandHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, _: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let line = &line[..pos];
+        let start = line.rfind(char::is_whitespace).map(|i| i + 1).unwrap_or(0);
+        let word = &line[start..];
+        let command = line.split_whitespace().next().unwrap_or("");
+
+        // First word is the command itself
+        if line[..start].trim().is_empty() {
+            let mut ret = matching(&self.commands, word);
+            ret.extend(matching(&self.plugins, word).into_iter().filter(|p| !self.commands.contains(&p.replacement)));
+            return Ok((start, ret));
+        }
+        if ADDRESS_COMMANDS.contains(&command) {
+            return Ok((start, matching_people(&self.contacts, word)));
+        }
+        match self.candidates(command) {
+            Some(options) => Ok((start, matching(options, word))),
+            None => Ok((start, Vec::new())),
+        }
+    }
+}
+
+impl Hinter for CommandHelper {}
+
+impl Highlighter for CommandHelper {}
+
+impl Validator for CommandHelper {}
+
+impl Helper for CommandHelper {}
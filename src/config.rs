@@ -0,0 +1,466 @@
+extern crate serde_yaml;
+
+use serde_yaml::{
+    Value,
+    Mapping,
+    Number,
+};
+
+use super::account::Account;
+use super::util;
+
+pub const CURRENT_VERSION: u64 = 1;
+
+// Fields of the optional `settings` section
+pub const SETTINGS_FIELDS: &'static [&'static str] = &["spellcheck_on_send", "spellcheck_language", "confirm", "confirm_recipients", "quoting", "log_level", "log_file", "refresh_interval", "send_delay", "passphrase_ttl", "junk_sync_interval", "date_format", "date_locale", "notmuch_maildir", "smime_ca_file", "junk_folder", "trash_folder", "subscribed_folders", "calendar_import", "pdf_command", "remote_images", "language", "plain_output", "hooks", "plugins"];
+
+// Fields of `quoting`, in settings and accounts
+pub const QUOTING_FIELDS: &'static [&'static str] = &["prefix", "top_posting", "attribution", "include_original", "strip_signature", "greeting", "sign_off", "language"];
+
+// Fields of `hooks` in settings
+pub const HOOK_FIELDS: &'static [&'static str] = &["on_new_mail", "on_send", "on_open"];
+
+const DEFAULT_QUOTE_PREFIX: &str = "> ";
+const DEFAULT_ATTRIBUTION: &str = "On {date}, {from} wrote:";
+
+// Greeting, sign-off and attribution of replies in a language, for the fields not set
+pub const REPLY_LANGUAGES: &'static [(&'static str, &'static str, &'static str, &'static str)] = &[
+    ("en", "Hi {first_name},", "Best regards", "On {date}, {from} wrote:"),
+    ("de", "Hallo {first_name},", "Viele Grüße", "Am {date} schrieb {from}:"),
+    ("fr", "Bonjour {first_name},", "Cordialement", "Le {date}, {from} a écrit :"),
+    ("es", "Hola {first_name}:", "Saludos", "El {date}, {from} escribió:"),
+    ("it", "Ciao {first_name},", "Cordiali saluti", "Il {date}, {from} ha scritto:"),
+    ("nl", "Hoi {first_name},", "Met vriendelijke groet", "Op {date} schreef {from}:"),
+];
+
+// Phrases of a language like "de" or "de_AT"
+pub fn reply_phrases(language: &str) -> Option<&'static (&'static str, &'static str, &'static str, &'static str)> {
+    let language = language.to_lowercase();
+    let base = language.split(|c| c == '_' || c == '-').next().unwrap_or("");
+    REPLY_LANGUAGES.iter().find(|(l, _, _, _)| *l == base)
+}
+
+// How replies quote the original, unset fields fall back to the global settings or defaults
+#[derive(Clone, PartialEq)]
+pub struct QuoteStyle {
+    pub prefix: Option<String>,
+    pub top_posting: Option<bool>,
+    // Template with {date}, {from} and {subject}
+    pub attribution: Option<String>,
+    pub include_original: Option<bool>,
+    // Leaves out the signature of the original sender
+    pub strip_signature: Option<bool>,
+    // Put around the quote of a new reply, the greeting with {name} and {first_name} of the sender
+    pub greeting: Option<String>,
+    pub sign_off: Option<String>,
+    // One of REPLY_LANGUAGES, gives greeting, sign-off and attribution where they aren't set
+    pub language: Option<String>,
+}
+
+impl QuoteStyle {
+    pub fn new() -> QuoteStyle {
+        QuoteStyle {
+            prefix: None,
+            top_posting: None,
+            attribution: None,
+            include_original: None,
+            strip_signature: None,
+            greeting: None,
+            sign_off: None,
+            language: None,
+        }
+    }
+
+    // Fields set in `other` take precedence
+    pub fn merged(&self, other: &QuoteStyle) -> QuoteStyle {
+        QuoteStyle {
+            prefix: other.prefix.clone().or(self.prefix.clone()),
+            top_posting: other.top_posting.or(self.top_posting),
+            attribution: other.attribution.clone().or(self.attribution.clone()),
+            include_original: other.include_original.or(self.include_original),
+            strip_signature: other.strip_signature.or(self.strip_signature),
+            greeting: other.greeting.clone().or(self.greeting.clone()),
+            sign_off: other.sign_off.clone().or(self.sign_off.clone()),
+            language: other.language.clone().or(self.language.clone()),
+        }
+    }
+
+    pub fn get_prefix(&self) -> String {
+        self.prefix.clone().unwrap_or(String::from(DEFAULT_QUOTE_PREFIX))
+    }
+
+    pub fn is_top_posting(&self) -> bool {
+        self.top_posting.unwrap_or(true)
+    }
+
+    fn phrases(&self) -> Option<&'static (&'static str, &'static str, &'static str, &'static str)> {
+        reply_phrases(self.language.as_ref()?.as_str())
+    }
+
+    pub fn get_attribution(&self) -> String {
+        self.attribution.clone().or(self.phrases().map(|p| p.3.to_string())).unwrap_or(String::from(DEFAULT_ATTRIBUTION))
+    }
+
+    // None without a greeting or language, an empty greeting turns it off
+    pub fn get_greeting(&self) -> Option<String> {
+        self.greeting.clone().or(self.phrases().map(|p| p.1.to_string())).filter(|g| !g.is_empty())
+    }
+
+    pub fn get_sign_off(&self) -> Option<String> {
+        self.sign_off.clone().or(self.phrases().map(|p| p.2.to_string())).filter(|s| !s.is_empty())
+    }
+
+    pub fn includes_original(&self) -> bool {
+        self.include_original.unwrap_or(true)
+    }
+
+    pub fn strips_signature(&self) -> bool {
+        self.strip_signature.unwrap_or(true)
+    }
+
+    pub fn from_value(val: &Value) -> QuoteStyle {
+        let get = |k: &str| val.as_mapping().and_then(|m| m.get(&key(k)));
+        QuoteStyle {
+            prefix: get("prefix").and_then(|v| v.as_str()).map(|s| s.to_string()),
+            top_posting: get("top_posting").and_then(|v| v.as_bool()),
+            attribution: get("attribution").and_then(|v| v.as_str()).map(|s| s.to_string()),
+            include_original: get("include_original").and_then(|v| v.as_bool()),
+            strip_signature: get("strip_signature").and_then(|v| v.as_bool()),
+            greeting: get("greeting").and_then(|v| v.as_str()).map(|s| s.to_string()),
+            sign_off: get("sign_off").and_then(|v| v.as_str()).map(|s| s.to_string()),
+            language: get("language").and_then(|v| v.as_str()).map(|s| s.to_string()),
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        let mut map = Mapping::new();
+        if let Some(prefix) = &self.prefix {
+            map.insert(key("prefix"), Value::String(prefix.clone()));
+        }
+        if let Some(top) = self.top_posting {
+            map.insert(key("top_posting"), Value::Bool(top));
+        }
+        if let Some(attribution) = &self.attribution {
+            map.insert(key("attribution"), Value::String(attribution.clone()));
+        }
+        if let Some(include) = self.include_original {
+            map.insert(key("include_original"), Value::Bool(include));
+        }
+        if let Some(strip) = self.strip_signature {
+            map.insert(key("strip_signature"), Value::Bool(strip));
+        }
+        if let Some(greeting) = &self.greeting {
+            map.insert(key("greeting"), Value::String(greeting.clone()));
+        }
+        if let Some(sign_off) = &self.sign_off {
+            map.insert(key("sign_off"), Value::String(sign_off.clone()));
+        }
+        if let Some(language) = &self.language {
+            map.insert(key("language"), Value::String(language.clone()));
+        }
+        Value::Mapping(map)
+    }
+}
+
+// Shell commands run on events, the mail is passed in MAIL_* environment variables
+#[derive(Clone, PartialEq)]
+pub struct Hooks {
+    pub on_new_mail: Option<String>,
+    pub on_send: Option<String>,
+    pub on_open: Option<String>,
+}
+
+impl Hooks {
+    pub fn new() -> Hooks {
+        Hooks {
+            on_new_mail: None,
+            on_send: None,
+            on_open: None,
+        }
+    }
+
+    pub fn from_value(val: &Value) -> Hooks {
+        let get = |k: &str| val.as_mapping().and_then(|m| m.get(&key(k))).and_then(|v| v.as_str()).map(|s| s.to_string());
+        Hooks {
+            on_new_mail: get("on_new_mail"),
+            on_send: get("on_send"),
+            on_open: get("on_open"),
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        let mut map = Mapping::new();
+        for (name, command) in [("on_new_mail", &self.on_new_mail), ("on_send", &self.on_send), ("on_open", &self.on_open)].iter() {
+            if let Some(command) = command {
+                map.insert(key(name), Value::String(command.clone()));
+            }
+        }
+        Value::Mapping(map)
+    }
+}
+
+// Global settings, independent of accounts
+#[derive(Clone, PartialEq)]
+pub struct Settings {
+    pub spellcheck_on_send: bool,
+    pub spellcheck_language: Option<String>,
+    // Ask before destructive actions
+    pub confirm: bool,
+    // Ask before sending to more recipients than this
+    pub confirm_recipients: u64,
+    pub quoting: QuoteStyle,
+    // One of logger::LEVELS
+    pub log_level: String,
+    // Diagnostics are written to stderr without it
+    pub log_file: Option<String>,
+    // Seconds between background refreshes, 0 turns them off
+    pub refresh_interval: u64,
+    // Seconds mails wait in the outbox after send, cancel-send takes them back meanwhile
+    pub send_delay: u64,
+    // Seconds the PGP passphrase is kept in memory after unlocking, 0 asks every time
+    pub passphrase_ttl: u64,
+    // Seconds between scans of the junk folders that retrain the spam filter, 0 turns them off
+    pub junk_sync_interval: u64,
+    // strftime format of dates in listings and the read view
+    pub date_format: String,
+    // Month and day names in the language of the system locale
+    pub date_locale: bool,
+    // New mails are delivered into this maildir and indexed by notmuch
+    pub notmuch_maildir: Option<String>,
+    // CA certificates trusted for S/MIME signatures besides the system store
+    pub smime_ca_file: Option<String>,
+    // Folder spam is moved to, found by name on the server without it
+    pub junk_folder: Option<String>,
+    // Folder deleted mails are moved to, found by name on the server without it
+    pub trash_folder: Option<String>,
+    // show-folders lists only the subscribed folders
+    pub subscribed_folders: bool,
+    // Command export-ics passes the calendar file to, like "khal import --batch"
+    pub calendar_import: Option<String>,
+    // Html to PDF converter export runs with the html and the pdf file, like "wkhtmltopdf"
+    pub pdf_command: Option<String>,
+    // Images of html mails are also loaded from the web, which tells senders the mail was read
+    pub remote_images: bool,
+    // Language of the interface texts like "de", LC_ALL, LC_MESSAGES or LANG without it
+    pub language: Option<String>,
+    // Listings and mails as labeled lines without colors or columns, easier on screen readers
+    pub plain_output: bool,
+    pub hooks: Hooks,
+    // External commands as (name, command line), in the order of the file
+    pub plugins: Vec<(String, String)>,
+}
+
+impl Settings {
+    pub fn new() -> Settings {
+        Settings {
+            spellcheck_on_send: false,
+            spellcheck_language: None,
+            confirm: true,
+            confirm_recipients: 10,
+            quoting: QuoteStyle::new(),
+            log_level: String::from("warn"),
+            log_file: None,
+            refresh_interval: 0,
+            send_delay: 0,
+            passphrase_ttl: 300,
+            junk_sync_interval: 0,
+            date_format: String::from(util::DEFAULT_DATE_FORMAT),
+            date_locale: false,
+            notmuch_maildir: None,
+            smime_ca_file: None,
+            junk_folder: None,
+            trash_folder: None,
+            subscribed_folders: false,
+            calendar_import: None,
+            pdf_command: None,
+            remote_images: false,
+            language: None,
+            plain_output: false,
+            hooks: Hooks::new(),
+            plugins: Vec::new(),
+        }
+    }
+
+    fn from_value(val: &Value) -> Settings {
+        let mut ret = Settings::new();
+        let get = |k: &str| val.as_mapping().and_then(|m| m.get(&key(k)));
+        if let Some(on_send) = get("spellcheck_on_send").and_then(|v| v.as_bool()) {
+            ret.spellcheck_on_send = on_send;
+        }
+        ret.spellcheck_language = get("spellcheck_language").and_then(|v| v.as_str()).map(|s| s.to_string());
+        if let Some(confirm) = get("confirm").and_then(|v| v.as_bool()) {
+            ret.confirm = confirm;
+        }
+        if let Some(limit) = get("confirm_recipients").and_then(|v| v.as_u64()) {
+            ret.confirm_recipients = limit;
+        }
+        if let Some(quoting) = get("quoting") {
+            ret.quoting = QuoteStyle::from_value(quoting);
+        }
+        if let Some(level) = get("log_level").and_then(|v| v.as_str()) {
+            ret.log_level = level.to_lowercase();
+        }
+        ret.log_file = get("log_file").and_then(|v| v.as_str()).map(|s| s.to_string());
+        if let Some(interval) = get("refresh_interval").and_then(|v| v.as_u64()) {
+            ret.refresh_interval = interval;
+        }
+        if let Some(delay) = get("send_delay").and_then(|v| v.as_u64()) {
+            ret.send_delay = delay;
+        }
+        if let Some(ttl) = get("passphrase_ttl").and_then(|v| v.as_u64()) {
+            ret.passphrase_ttl = ttl;
+        }
+        if let Some(interval) = get("junk_sync_interval").and_then(|v| v.as_u64()) {
+            ret.junk_sync_interval = interval;
+        }
+        if let Some(format) = get("date_format").and_then(|v| v.as_str()) {
+            ret.date_format = format.to_string();
+        }
+        if let Some(locale) = get("date_locale").and_then(|v| v.as_bool()) {
+            ret.date_locale = locale;
+        }
+        ret.notmuch_maildir = get("notmuch_maildir").and_then(|v| v.as_str()).map(|s| s.to_string());
+        ret.smime_ca_file = get("smime_ca_file").and_then(|v| v.as_str()).map(|s| s.to_string());
+        ret.junk_folder = get("junk_folder").and_then(|v| v.as_str()).map(|s| s.to_string());
+        ret.trash_folder = get("trash_folder").and_then(|v| v.as_str()).map(|s| s.to_string());
+        if let Some(subscribed) = get("subscribed_folders").and_then(|v| v.as_bool()) {
+            ret.subscribed_folders = subscribed;
+        }
+        ret.calendar_import = get("calendar_import").and_then(|v| v.as_str()).map(|s| s.to_string());
+        ret.pdf_command = get("pdf_command").and_then(|v| v.as_str()).map(|s| s.to_string());
+        if let Some(remote) = get("remote_images").and_then(|v| v.as_bool()) {
+            ret.remote_images = remote;
+        }
+        ret.language = get("language").and_then(|v| v.as_str()).map(|s| s.to_string());
+        if let Some(plain) = get("plain_output").and_then(|v| v.as_bool()) {
+            ret.plain_output = plain;
+        }
+        if let Some(hooks) = get("hooks") {
+            ret.hooks = Hooks::from_value(hooks);
+        }
+        if let Some(plugins) = get("plugins").and_then(|v| v.as_mapping()) {
+            ret.plugins = plugins.iter().filter_map(|(k, v)| Some((k.as_str()?.to_string(), v.as_str()?.to_string()))).collect();
+        }
+        return ret;
+    }
+
+    fn to_value(&self) -> Value {
+        let mut map = Mapping::new();
+        map.insert(key("spellcheck_on_send"), Value::Bool(self.spellcheck_on_send));
+        if let Some(lang) = &self.spellcheck_language {
+            map.insert(key("spellcheck_language"), Value::String(lang.clone()));
+        }
+        map.insert(key("confirm"), Value::Bool(self.confirm));
+        map.insert(key("confirm_recipients"), Value::Number(Number::from(self.confirm_recipients)));
+        if self.quoting != QuoteStyle::new() {
+            map.insert(key("quoting"), self.quoting.to_value());
+        }
+        map.insert(key("log_level"), Value::String(self.log_level.clone()));
+        if let Some(file) = &self.log_file {
+            map.insert(key("log_file"), Value::String(file.clone()));
+        }
+        map.insert(key("refresh_interval"), Value::Number(Number::from(self.refresh_interval)));
+        map.insert(key("send_delay"), Value::Number(Number::from(self.send_delay)));
+        map.insert(key("passphrase_ttl"), Value::Number(Number::from(self.passphrase_ttl)));
+        map.insert(key("junk_sync_interval"), Value::Number(Number::from(self.junk_sync_interval)));
+        if self.date_format != util::DEFAULT_DATE_FORMAT {
+            map.insert(key("date_format"), Value::String(self.date_format.clone()));
+        }
+        map.insert(key("date_locale"), Value::Bool(self.date_locale));
+        map.insert(key("remote_images"), Value::Bool(self.remote_images));
+        map.insert(key("plain_output"), Value::Bool(self.plain_output));
+        if self.hooks != Hooks::new() {
+            map.insert(key("hooks"), self.hooks.to_value());
+        }
+        if !self.plugins.is_empty() {
+            let mut plugins = Mapping::new();
+            for (name, command) in self.plugins.iter() {
+                plugins.insert(key(name), Value::String(command.clone()));
+            }
+            map.insert(key("plugins"), Value::Mapping(plugins));
+        }
+        if let Some(maildir) = &self.notmuch_maildir {
+            map.insert(key("notmuch_maildir"), Value::String(maildir.clone()));
+        }
+        if let Some(ca_file) = &self.smime_ca_file {
+            map.insert(key("smime_ca_file"), Value::String(ca_file.clone()));
+        }
+        if let Some(folder) = &self.junk_folder {
+            map.insert(key("junk_folder"), Value::String(folder.clone()));
+        }
+        if let Some(folder) = &self.trash_folder {
+            map.insert(key("trash_folder"), Value::String(folder.clone()));
+        }
+        map.insert(key("subscribed_folders"), Value::Bool(self.subscribed_folders));
+        if let Some(command) = &self.calendar_import {
+            map.insert(key("calendar_import"), Value::String(command.clone()));
+        }
+        if let Some(command) = &self.pdf_command {
+            map.insert(key("pdf_command"), Value::String(command.clone()));
+        }
+        if let Some(language) = &self.language {
+            map.insert(key("language"), Value::String(language.clone()));
+        }
+        Value::Mapping(map)
+    }
+}
+
+fn key(s: &str) -> Value {
+    Value::String(String::from(s))
+}
+
+// Returns the config upgraded to the current version, together with the version it had before
+pub fn migrate(mut root: Value) -> Result<(Value, u64), String> {
+    let version = match &root {
+        // Version 0 was a plain list of accounts
+        Value::Sequence(_) => 0,
+        Value::Mapping(map) => match map.get(&key("version")) {
+            Some(v) => v.as_u64().ok_or(String::from("field `version` must be a positive number"))?,
+            None => return Err(String::from("missing field `version`")),
+        },
+        _ => return Err(String::from("expected a mapping with `version` and `accounts`")),
+    };
+    if version > CURRENT_VERSION {
+        return Err(format!("config version {} is newer than the supported version {}", version, CURRENT_VERSION));
+    }
+
+    let mut current = version;
+    while current < CURRENT_VERSION {
+        root = match current {
+            0 => migrate_v0(root),
+            _ => return Err(format!("no migration from config version {}", current)),
+        };
+        current += 1;
+    }
+    return Ok((root, version));
+}
+
+fn migrate_v0(root: Value) -> Value {
+    let mut map = Mapping::new();
+    map.insert(key("version"), Value::Number(Number::from(1u64)));
+    map.insert(key("accounts"), root);
+    Value::Mapping(map)
+}
+
+pub fn get_accounts(root: &Value) -> Result<Vec<Account>, serde_yaml::Error> {
+    let accounts = root.as_mapping().and_then(|m| m.get(&key("accounts")).cloned()).unwrap_or(Value::Sequence(Vec::new()));
+    serde_yaml::from_value(accounts)
+}
+
+pub fn get_settings(root: &Value) -> Settings {
+    match root.as_mapping().and_then(|m| m.get(&key("settings"))) {
+        Some(val) => Settings::from_value(val),
+        None => Settings::new(),
+    }
+}
+
+pub fn to_yaml(accounts: &Vec<&Account>, settings: &Settings) -> Result<Vec<u8>, serde_yaml::Error> {
+    let mut map = Mapping::new();
+    map.insert(key("version"), Value::Number(Number::from(CURRENT_VERSION)));
+    // Default settings are left out to keep the file short
+    if *settings != Settings::new() {
+        map.insert(key("settings"), settings.to_value());
+    }
+    map.insert(key("accounts"), serde_yaml::to_value(accounts)?);
+    serde_yaml::to_vec(&Value::Mapping(map))
+}
@@ -0,0 +1,222 @@
+extern crate serde_yaml;
+
+use std::{
+    error::Error,
+    fs,
+    io,
+    path::PathBuf,
+};
+
+use serde_json::json;
+use serde_yaml::{
+    Mapping,
+    Value,
+};
+
+use super::output;
+
+#[derive(Clone, PartialEq)]
+pub struct Contact {
+    pub address: String,
+    pub name: Option<String>,
+    // Fingerprint of the PGP key used for this contact
+    pub key: Option<String>,
+}
+
+impl Contact {
+    pub fn new(address: String) -> Contact {
+        Contact {
+            address,
+            name: None,
+            key: None,
+        }
+    }
+
+    fn from_value(val: &Value) -> Option<Contact> {
+        let get = |k: &str| val.as_mapping().and_then(|m| m.get(&key(k))).and_then(|v| v.as_str()).map(|s| s.to_string());
+        let mut contact = Contact::new(get("address")?);
+        contact.name = get("name");
+        contact.key = get("key");
+        Some(contact)
+    }
+
+    // Name or address contains the text, case insensitive
+    pub fn matches(&self, text: &str) -> bool {
+        let text = text.to_lowercase();
+        self.address.to_lowercase().contains(&text) || self.name.as_ref().map(|n| n.to_lowercase().contains(&text)).unwrap_or(false)
+    }
+
+    pub fn get_info(&self) -> String {
+        match &self.name {
+            Some(name) => format!("{} <{}>", name, self.address),
+            None => self.address.clone(),
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "address": self.address,
+            "name": self.name,
+            "key": self.key,
+        })
+    }
+
+    fn to_value(&self) -> Value {
+        let mut map = Mapping::new();
+        map.insert(key("address"), Value::String(self.address.clone()));
+        if let Some(name) = &self.name {
+            map.insert(key("name"), Value::String(name.clone()));
+        }
+        if let Some(fingerprint) = &self.key {
+            map.insert(key("key"), Value::String(fingerprint.clone()));
+        }
+        Value::Mapping(map)
+    }
+}
+
+fn key(s: &str) -> Value {
+    Value::String(String::from(s))
+}
+
+// Named list of addresses, expanded when used as a recipient
+#[derive(Clone, PartialEq)]
+pub struct Group {
+    pub name: String,
+    pub members: Vec<String>,
+}
+
+// Contacts stored next to the account file, addresses are compared case insensitive
+pub struct AddressBook {
+    path: PathBuf,
+    contacts: Vec<Contact>,
+    groups: Vec<Group>,
+}
+
+impl AddressBook {
+    // A missing file is an empty address book. Files without groups may be a plain list of contacts
+    pub fn load(path: PathBuf) -> Result<AddressBook, Box<dyn Error>> {
+        let (contacts, groups) = match fs::read(&path) {
+            Ok(content) => {
+                let root: Value = serde_yaml::from_slice(&content)?;
+                let contacts = match root.as_sequence() {
+                    Some(seq) => Some(seq),
+                    None => root.get("contacts").and_then(|v| v.as_sequence()),
+                };
+                let groups = root.get("groups").and_then(|v| v.as_mapping()).map(|map| map.iter().filter_map(|(name, members)| Some(Group {
+                    name: name.as_str()?.to_string(),
+                    members: members.as_sequence()?.iter().filter_map(|m| m.as_str()).map(|m| m.to_string()).collect(),
+                })).collect());
+                (contacts.map(|seq| seq.iter().filter_map(Contact::from_value).collect()).unwrap_or(Vec::new()), groups.unwrap_or(Vec::new()))
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (Vec::new(), Vec::new()),
+            Err(e) => return Err(Box::new(e)),
+        };
+        Ok(AddressBook { path, contacts, groups })
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let contacts = Value::Sequence(self.contacts.iter().map(|c| c.to_value()).collect());
+        let root = if self.groups.is_empty() {
+            contacts
+        } else {
+            let mut groups = Mapping::new();
+            for group in self.groups.iter() {
+                groups.insert(key(group.name.as_str()), Value::Sequence(group.members.iter().map(|m| Value::String(m.clone())).collect()));
+            }
+            let mut map = Mapping::new();
+            map.insert(key("contacts"), contacts);
+            map.insert(key("groups"), Value::Mapping(groups));
+            Value::Mapping(map)
+        };
+        fs::write(&self.path, serde_yaml::to_vec(&root)?)?;
+        Ok(())
+    }
+
+    pub fn groups(&self) -> &Vec<Group> {
+        &self.groups
+    }
+
+    // Group names are compared case insensitive
+    pub fn group(&self, name: &str) -> Option<&Group> {
+        self.groups.iter().find(|g| g.name.eq_ignore_ascii_case(name))
+    }
+
+    // Adds the addresses to the group, creating it if needed
+    pub fn add_to_group(&mut self, name: &str, addresses: &[String]) {
+        let index = match self.groups.iter().position(|g| g.name.eq_ignore_ascii_case(name)) {
+            Some(i) => i,
+            None => {
+                self.groups.push(Group { name: name.to_string(), members: Vec::new() });
+                self.groups.len() - 1
+            },
+        };
+        let members = &mut self.groups[index].members;
+        for address in addresses.iter() {
+            if !members.iter().any(|m| m.eq_ignore_ascii_case(address)) {
+                members.push(address.clone());
+            }
+        }
+    }
+
+    // Removes the addresses from the group, or the whole group without addresses.
+    // Returns false if there is no such group
+    pub fn remove_from_group(&mut self, name: &str, addresses: &[String]) -> bool {
+        let index = match self.groups.iter().position(|g| g.name.eq_ignore_ascii_case(name)) {
+            Some(i) => i,
+            None => return false,
+        };
+        if addresses.is_empty() {
+            self.groups.remove(index);
+        } else {
+            self.groups[index].members.retain(|m| !addresses.iter().any(|a| a.eq_ignore_ascii_case(m)));
+        }
+        true
+    }
+
+    pub fn get(&self, address: &str) -> Option<&Contact> {
+        self.contacts.iter().find(|c| c.address.eq_ignore_ascii_case(address))
+    }
+
+    // Adds the contact, if the address is not known yet
+    pub fn get_or_insert(&mut self, address: &str) -> &mut Contact {
+        let index = match self.contacts.iter().position(|c| c.address.eq_ignore_ascii_case(address)) {
+            Some(i) => i,
+            None => {
+                self.contacts.push(Contact::new(address.to_string()));
+                self.contacts.len() - 1
+            },
+        };
+        &mut self.contacts[index]
+    }
+
+    pub fn contacts(&self) -> &Vec<Contact> {
+        &self.contacts
+    }
+
+    pub fn contacts_mut(&mut self) -> &mut Vec<Contact> {
+        &mut self.contacts
+    }
+
+    // Numbered listing of the contacts matching the text, or all of them
+    pub fn print(&self, filter: Option<&str>) {
+        let matching: Vec<(usize, &Contact)> = self.contacts.iter().enumerate().filter(|(_, c)| filter.map(|f| c.matches(f)).unwrap_or(true)).collect();
+        if output::is_json() {
+            output::print_json(&serde_json::Value::Array(matching.iter().map(|(_, c)| c.to_json()).collect()));
+            return;
+        }
+        if matching.is_empty() {
+            println!("No contacts found!");
+        }
+        for (i, contact) in matching.iter() {
+            if output::is_plain() {
+                println!("Contact {}: {}{}", i, contact.get_info(), if contact.key.is_some() { ", has key" } else { "" });
+            } else {
+                println!("\t{:>3} {}{}", i, contact.get_info(), if contact.key.is_some() { " [key]" } else { "" });
+            }
+        }
+    }
+
+    pub fn key_for(&self, address: &str) -> Option<&String> {
+        self.get(address).and_then(|c| c.key.as_ref())
+    }
+}
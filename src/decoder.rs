@@ -1,175 +1,201 @@
+use encoding_rs::Encoding;
 
-const START_MARKER_UTF8_Q: &'static [&'static str] = &["=?UTF-8?q?", "=?utf-8?q?"];
-const START_MARKER_UTF8_B: &'static [&'static str] = &["=?UTF-8?B?"];
-const END_MARKER_UTF8: &'static [&'static str] = &["?="];
-
+// Decodes all RFC 2047 encoded words ("=?charset?Q|B?text?=") of a header value,
+// whitespace between adjacent encoded words is dropped
 pub fn decode(field: String) -> String {
-    let mut changed: Vec<((usize, usize), String)> = Vec::new();
-
-    let codes: Vec<(&[& str], & [& str], Box<(dyn Fn(&str) -> String)>)> = vec![
-        ( START_MARKER_UTF8_Q, END_MARKER_UTF8, Box::new(decode_utf8_q) ),
-        ( START_MARKER_UTF8_B, END_MARKER_UTF8, Box::new(decode_utf8_b) ), ];
-
-    let mut start = 0;
-    let mut end = 1;
-    let length = field.len();
-
-    while end <= length {
-        for (start_markers, end_markers, decode_fn) in codes.iter() {
-            if let Some(size) = match_marker(&field[start..end], start_markers) {
-                let inner_min = end;
-                let outer_min = inner_min - size;
-                let (inner_max, outer_max) = match find_marker(&field[end..], end_markers) {
-                    Some((offset, marker_len)) => (inner_min + offset, inner_min + offset + marker_len),
-                    None => (length, length),
-                };
-                // Decode inner
-                let decoded = decode_fn(&field[inner_min..inner_max]);
-                changed.push( ( (outer_min, outer_max), decoded ) );
-
-                // Set indices to new vals
-                start = outer_max;
-                end = outer_max;
-                break;
+    let unfolded = field.replace("\r\n", "").replace('\n', "");
+    let mut ret = String::new();
+    // Whitespace after an encoded word, only kept if no encoded word follows
+    let mut pending = String::new();
+    let mut after_word = false;
+    let mut rest = unfolded.as_str();
+
+    while !rest.is_empty() {
+        if rest.starts_with("=?") {
+            if let Some((decoded, len)) = decode_word(rest) {
+                if !after_word {
+                    ret.push_str(pending.as_str());
+                }
+                pending.clear();
+                ret.push_str(decoded.as_str());
+                after_word = true;
+                rest = &rest[len..];
+                continue;
             }
         }
-        end += 1;
-    }
-
-    // Insert changed into string
-    changed.sort_by(|(a, _), (b, _)| {
-        a.cmp(b)
-    });
-
-    let mut ret = String::new();
-
-    let mut index = 0;
-    for ((start, end), s) in changed.into_iter() {
-        if index < start {
-            ret.push_str(&field[index..start]);
+        let c = rest.chars().next().unwrap_or(' ');
+        if c == ' ' || c == '\t' {
+            pending.push(c);
+        } else {
+            ret.push_str(pending.as_str());
+            pending.clear();
+            ret.push(c);
+            after_word = false;
         }
-        ret.push_str(s.as_str());
-        index = end;
+        rest = &rest[c.len_utf8()..];
     }
-    ret.push_str(&field[index..]);
-
+    ret.push_str(pending.as_str());
     return ret;
 }
 
-fn match_marker(s: &str, markers: &[&str]) -> Option<usize> {
-    for marker in markers.iter() {
-        if s.ends_with(marker) {
-            return Some(marker.len());
-        }
+// Decodes the encoded word at the start of s, returns it with the length it took up
+fn decode_word(s: &str) -> Option<(String, usize)> {
+    let inner = &s[2..];
+    let charset_end = inner.find('?')?;
+    // RFC 2231 allows a language suffix like "utf-8*en"
+    let charset = inner[..charset_end].split('*').next().unwrap_or("");
+    let after_charset = &inner[charset_end + 1..];
+    let mut chars = after_charset.chars();
+    let encoding = chars.next()?.to_ascii_lowercase();
+    if !encoding.is_ascii() || chars.next()? != '?' {
+        return None;
+    }
+    let text_start = charset_end + 3;
+    let text_len = inner.get(text_start..)?.find("?=")?;
+    let text = &inner[text_start..text_start + text_len];
+    if text.contains(' ') {
+        return None;
     }
-    return None;
-}
 
-fn find_marker(field: &str, markers: &[&str]) -> Option<(usize, usize)> {
-    let mut end = 1;
+    let bytes = match encoding {
+        'q' => decode_q(text),
+        'b' => base64::decode(text).ok()?,
+        _ => return None,
+    };
+    Some((decode_charset(&bytes, charset), 2 + text_start + text_len + 2))
+}
 
-    let length = field.len();
-    while end <= length {
-        for marker in markers.iter() {
-            if field[..end].ends_with(marker) {
-                let len = marker.len();
-                return Some((end - len, len));
-            }
+// Collects all bytes first, so multi-byte characters split over several "=XX" stay intact
+fn decode_q(text: &str) -> Vec<u8> {
+    let bytes = text.as_bytes();
+    let mut ret = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'_' => ret.push(b' '),
+            b'=' => {
+                let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok()).and_then(|h| u8::from_str_radix(h, 16).ok());
+                match hex {
+                    Some(byte) => {
+                        ret.push(byte);
+                        i += 2;
+                    },
+                    None => ret.push(b'='),
+                }
+            },
+            b => ret.push(b),
         }
-        end += 1;
+        i += 1;
     }
-    return None;
+    ret
 }
 
-fn decode_utf8_q(field: &str) -> String {
-    let mut buf = String::new();
+// Unknown charsets are read as UTF-8
+pub fn decode_charset(bytes: &[u8], charset: &str) -> String {
+    match Encoding::for_label(charset.trim().as_bytes()) {
+        Some(encoding) => encoding.decode(bytes).0.to_string(),
+        None => String::from_utf8_lossy(bytes).to_string(),
+    }
+}
 
-    let mut processing_hex = false;
-    let mut utf8_buf = String::new();
+use chrono::{
+    DateTime,
+    FixedOffset,
+    NaiveDate,
+    TimeZone,
+};
 
-    for c in field.chars() {
-        if processing_hex {
-            // If buf length lt 2, add char, otherwise push as one byte
-            utf8_buf.push(c);
-            if utf8_buf.len() == 2 {
-                let byte = u8::from_str_radix(utf8_buf.as_str(), 16).unwrap();
-                buf.push_str(String::from_utf8(vec![byte]).unwrap_or_default().as_str());
-                utf8_buf.clear();
-                processing_hex = false;
-            }
-        } else {
-            if c == '=' {
-                processing_hex = true;
-            } else if c == '_' {
-                buf.push(' ');
-            } else {
-                buf.push(c);
-            }
+const MONTHS: [&str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+
+// Removes (possibly nested) comments, e.g. "+0000 (UTC)"
+fn strip_comments(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    let mut depth = 0;
+    let mut escaped = false;
+    for c in s.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if depth > 0 => escaped = true,
+            '(' => depth += 1,
+            ')' if depth > 0 => depth -= 1,
+            _ if depth == 0 => ret.push(c),
+            _ => {},
         }
     }
-    return buf;
+    ret
 }
 
-fn decode_utf8_b(s: &str) -> String {
-    String::from_utf8(base64::decode(s).unwrap_or(Vec::new())).unwrap_or(String::new())
+// Offset in seconds of a numeric ("+0200") or obsolete named ("GMT", "PST", military) zone.
+// Other names like "CEST" are ambiguous, RFC 5322 4.3 says to read them as -0000
+fn zone_offset(zone: &str) -> Option<i32> {
+    if zone.len() == 5 && (zone.starts_with('+') || zone.starts_with('-')) {
+        let value = zone[1..].parse::<i32>().ok()?;
+        let secs = (value / 100) * 3600 + (value % 100) * 60;
+        return Some(if zone.starts_with('-') { -secs } else { secs });
+    }
+    let hours = match zone.to_uppercase().as_str() {
+        "UT" | "UTC" | "GMT" | "Z" => 0,
+        "EDT" => -4,
+        "EST" | "CDT" => -5,
+        "CST" | "MDT" => -6,
+        "MST" | "PDT" => -7,
+        "PST" => -8,
+        // Military zones are unreliable in practice, RFC 5322 says to treat them as -0000 too
+        z if (z.len() == 1 || (3..=5).contains(&z.len())) && z.chars().all(|c| c.is_ascii_alphabetic()) => 0,
+        _ => return None,
+    };
+    Some(hours * 3600)
 }
 
-use datetime::{
-    Offset,
-    OffsetDateTime,
-    Month,
-    LocalDate,
-    LocalTime,
-    LocalDateTime,
-};
+// Parses RFC 5322 dates including the obsolete syntax: optional weekday, single digit days,
+// two digit years, missing seconds, named zones and comments
+pub fn decode_date(s: &str) -> Option<DateTime<FixedOffset>> {
+    let cleaned = strip_comments(s).replace(',', " ");
+    let mut tokens: Vec<&str> = cleaned.split_whitespace().collect();
+    if tokens.first().map(|t| t.chars().all(|c| c.is_ascii_alphabetic())).unwrap_or(false) {
+        tokens.remove(0);
+    }
+    if tokens.len() < 4 {
+        return None;
+    }
 
-pub fn decode_date(s: &str) -> Option<OffsetDateTime> {
-    let tokens: Vec<&str> = s.split_whitespace().collect();
-    // format "Wed, 04 Dec 2019 10:2:8 +0000"
-    let monthdays = match tokens[1].parse::<i8>() {
-        Ok(val) => val,
-        Err(_) => return None,
+    let day = tokens[0].parse::<u32>().ok()?;
+    let month = MONTHS.iter().position(|m| tokens[1].to_lowercase().starts_with(m))? as u32 + 1;
+    let year = match (tokens[2].len(), tokens[2].parse::<i32>().ok()?) {
+        (2, y) if y < 50 => 2000 + y,
+        (2, y) | (3, y) => 1900 + y,
+        (_, y) => y,
     };
-    let month = Month::from_one(match tokens[2].to_lowercase().as_str() {
-        "jan" => 1,
-        "feb" => 2,
-        "mar" => 3,
-        "apr" => 4,
-        "may" => 5,
-        "jun" => 6,
-        "jul" => 7,
-        "aug" => 8,
-        "sep" => 9,
-        "oct" => 10,
-        "nov" => 11,
-        "dec" => 12,
+    let time: Vec<u32> = tokens[3].split(':').map(|t| t.parse::<u32>().ok()).collect::<Option<Vec<u32>>>()?;
+    let (hour, minute, second) = match time.as_slice() {
+        [h, m] => (*h, *m, 0),
+        [h, m, s] => (*h, *m, *s),
         _ => return None,
-    }).unwrap();
-    let year = match tokens[3].parse::<i64>() {
-        Ok(val) => val,
-        Err(_) => return None,
-    };
-    let time_tokens: Vec<&str> = tokens[4].split_terminator(':').collect();
-    let hour = match time_tokens[0].parse::<i8>() {
-        Ok(val) => val,
-        Err(_) => return None,
-    };
-    let minute = match time_tokens[1].parse::<i8>() {
-        Ok(val) => val,
-        Err(_) => return None,
     };
-    let second = match time_tokens[2].parse::<i8>() {
-        Ok(val) => val,
-        Err(_) => return None,
-    };
-    let offset = match tokens[5].parse::<i64>() {
-        Ok(val) => val,
-        Err(_) => return None,
+    // A missing zone is read as UTC
+    let offset = match tokens.get(4) {
+        Some(zone) => zone_offset(zone)?,
+        None => 0,
     };
 
-    let date = LocalDate::ymd(year, month, monthdays).unwrap();
-    let time = LocalTime::hms(hour, minute, second).unwrap();
-    let datetime = LocalDateTime::new(date, time);
-    let offset = Offset::of_hours_and_minutes((offset / 100) as i8, (offset % 100) as i8).unwrap();
-    Some(offset.transform_date(datetime))
+    // Leap seconds are clamped
+    let naive = NaiveDate::from_ymd_opt(year, month, day)?.and_hms_opt(hour, minute, second.min(59))?;
+    FixedOffset::east_opt(offset)?.from_local_datetime(&naive).single()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn obsolete_and_named_zone_dates() {
+        let date = |s: &str| decode_date(s).map(|d| d.to_rfc3339());
+        assert_eq!(date("Tue, 1 Jun 2021 12:00:00 +0200"), Some(String::from("2021-06-01T12:00:00+02:00")));
+        assert_eq!(date("1 Jun 21 12:00 PDT"), Some(String::from("2021-06-01T12:00:00-07:00")));
+        assert_eq!(date("Tue, 1 Jun 2021 12:00:00 GMT (Greenwich)"), Some(String::from("2021-06-01T12:00:00+00:00")));
+        assert_eq!(date("Tue, 1 Jun 2021 12:00:00 CEST"), Some(String::from("2021-06-01T12:00:00+00:00")));
+        assert_eq!(date("Di, 1 Jun 2021 12:00:00 MEZ"), Some(String::from("2021-06-01T12:00:00+00:00")));
+        assert_eq!(date("Tue, 1 Jun 2021 12:00:00 IST"), Some(String::from("2021-06-01T12:00:00+00:00")));
+        assert_eq!(date("Tue, 1 Jun 2021 12:00:00 +2"), None);
+    }
 }
@@ -0,0 +1,198 @@
+use std::{
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use super::account::InboxConfig;
+use super::dns;
+use super::http;
+
+pub struct ServerSettings {
+    pub inbox: Option<InboxConfig>,
+    pub smtp: Option<(String, u16)>,
+    pub source: String,
+}
+
+impl ServerSettings {
+    fn empty(source: &str) -> ServerSettings {
+        ServerSettings {
+            inbox: None,
+            smtp: None,
+            source: String::from(source),
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.inbox.is_some() && self.smtp.is_some()
+    }
+
+    // Fills missing settings from another source
+    fn merge(mut self, other: ServerSettings) -> ServerSettings {
+        if self.inbox.is_none() && self.smtp.is_none() {
+            self.source = other.source.clone();
+        } else if (self.inbox.is_none() && other.inbox.is_some()) || (self.smtp.is_none() && other.smtp.is_some()) {
+            self.source = format!("{} + {}", self.source, other.source);
+        }
+        if self.inbox.is_none() {
+            self.inbox = other.inbox;
+        }
+        if self.smtp.is_none() {
+            self.smtp = other.smtp;
+        }
+        self
+    }
+}
+
+// Tries autoconfig, DNS SRV records and common hostnames (in that order) for the domain of the address
+pub fn discover(address: &str) -> ServerSettings {
+    let domain = match address.rsplit('@').next() {
+        Some(d) if address.contains('@') && !d.is_empty() => d.to_lowercase(),
+        _ => return ServerSettings::empty("none"),
+    };
+
+    let mut settings = ServerSettings::empty("none");
+    let sources: Vec<Box<dyn Fn(&str, &str) -> ServerSettings>> = vec![
+        Box::new(autoconfig),
+        Box::new(srv_records),
+        Box::new(probe_hostnames),
+    ];
+    for source in sources.iter() {
+        settings = settings.merge(source(address, domain.as_str()));
+        log::debug!("server settings after {}: inbox {}, smtp {}", settings.source, settings.inbox.is_some(), settings.smtp.is_some());
+        if settings.is_complete() {
+            break;
+        }
+    }
+    return settings;
+}
+
+fn autoconfig(address: &str, domain: &str) -> ServerSettings {
+    let urls = vec![
+        format!("http://autoconfig.{}/mail/config-v1.1.xml?emailaddress={}", domain, address),
+        format!("https://{}/.well-known/autoconfig/mail/config-v1.1.xml?emailaddress={}", domain, address),
+        format!("https://autoconfig.thunderbird.net/v1.1/{}", domain),
+    ];
+    for url in urls.iter() {
+        log::debug!("trying autoconfig at {}", url);
+        if let Ok(response) = http::get(url.as_str()) {
+            if response.is_success() && response.body.contains("<clientConfig") {
+                let settings = parse_autoconfig(response.body.as_str());
+                if settings.inbox.is_some() || settings.smtp.is_some() {
+                    return settings;
+                }
+            }
+        }
+    }
+    ServerSettings::empty("autoconfig")
+}
+
+fn parse_autoconfig(xml: &str) -> ServerSettings {
+    let mut settings = ServerSettings::empty("autoconfig");
+    // Only implicit TLS is supported by the inbox adapters, so STARTTLS entries are skipped
+    for (attrs, inner) in xml_blocks(xml, "incomingServer").into_iter() {
+        if settings.inbox.is_some() || xml_value(inner, "socketType").as_ref().map(|s| s.as_str()) != Some("SSL") {
+            continue;
+        }
+        let host = match xml_value(inner, "hostname") {
+            Some(h) => h,
+            None => continue,
+        };
+        let port = xml_value(inner, "port").and_then(|p| p.parse::<u16>().ok());
+        if attrs.contains("\"imap\"") {
+            settings.inbox = Some(InboxConfig::Imap(host, port.unwrap_or(993)));
+        } else if attrs.contains("\"pop3\"") {
+            settings.inbox = Some(InboxConfig::Pop3(host, port.unwrap_or(995)));
+        }
+    }
+    for (attrs, inner) in xml_blocks(xml, "outgoingServer").into_iter() {
+        if settings.smtp.is_none() && attrs.contains("\"smtp\"") {
+            if let Some(host) = xml_value(inner, "hostname") {
+                let port = xml_value(inner, "port").and_then(|p| p.parse::<u16>().ok()).unwrap_or(465);
+                settings.smtp = Some((host, port));
+            }
+        }
+    }
+    return settings;
+}
+
+// Returns (attributes, inner content) of every <tag ...>...</tag> block
+fn xml_blocks<'a>(xml: &'a str, tag: &str) -> Vec<(&'a str, &'a str)> {
+    let open = format!("<{}", tag);
+    let close = format!("</{}>", tag);
+    let mut ret = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(open.as_str()) {
+        let after = &rest[start + open.len()..];
+        let attr_end = match after.find('>') {
+            Some(i) => i,
+            None => break,
+        };
+        let body = &after[attr_end + 1..];
+        let end = match body.find(close.as_str()) {
+            Some(i) => i,
+            None => break,
+        };
+        ret.push((&after[..attr_end], &body[..end]));
+        rest = &body[end + close.len()..];
+    }
+    return ret;
+}
+
+fn xml_value(xml: &str, tag: &str) -> Option<String> {
+    xml_blocks(xml, tag).first().map(|(_, inner)| inner.trim().to_string())
+}
+
+fn srv_records(_: &str, domain: &str) -> ServerSettings {
+    let mut settings = ServerSettings::empty("dns srv");
+    if let Some(record) = dns::lookup_srv(format!("_imaps._tcp.{}", domain).as_str()).into_iter().next() {
+        settings.inbox = Some(InboxConfig::Imap(record.target, record.port));
+    } else if let Some(record) = dns::lookup_srv(format!("_pop3s._tcp.{}", domain).as_str()).into_iter().next() {
+        settings.inbox = Some(InboxConfig::Pop3(record.target, record.port));
+    }
+    if let Some(record) = dns::lookup_srv(format!("_submissions._tcp.{}", domain).as_str()).into_iter().next() {
+        settings.smtp = Some((record.target, record.port));
+    } else if let Some(record) = dns::lookup_srv(format!("_submission._tcp.{}", domain).as_str()).into_iter().next() {
+        settings.smtp = Some((record.target, record.port));
+    }
+    return settings;
+}
+
+fn probe_hostnames(_: &str, domain: &str) -> ServerSettings {
+    let mut settings = ServerSettings::empty("hostname guess");
+    for prefix in ["imap", "mail"].iter() {
+        let host = format!("{}.{}", prefix, domain);
+        if can_connect(host.as_str(), 993) {
+            settings.inbox = Some(InboxConfig::new_imap(host));
+            break;
+        }
+    }
+    if settings.inbox.is_none() {
+        for prefix in ["pop", "pop3", "mail"].iter() {
+            let host = format!("{}.{}", prefix, domain);
+            if can_connect(host.as_str(), 995) {
+                settings.inbox = Some(InboxConfig::new_pop3(host));
+                break;
+            }
+        }
+    }
+    'outer: for prefix in ["smtp", "mail"].iter() {
+        let host = format!("{}.{}", prefix, domain);
+        for port in [465, 587].iter() {
+            if can_connect(host.as_str(), *port) {
+                settings.smtp = Some((host, *port));
+                break 'outer;
+            }
+        }
+    }
+    return settings;
+}
+
+fn can_connect(host: &str, port: u16) -> bool {
+    match (host, port).to_socket_addrs() {
+        Ok(mut addrs) => match addrs.next() {
+            Some(addr) => TcpStream::connect_timeout(&addr, Duration::from_secs(2)).is_ok(),
+            None => false,
+        },
+        Err(_) => false,
+    }
+}
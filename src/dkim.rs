@@ -0,0 +1,223 @@
+// DKIM signatures (RFC 6376), checked against the public key the signing domain publishes in DNS
+use std::collections::HashMap;
+
+use openssl::{
+    hash::{hash, MessageDigest},
+    pkey::PKey,
+    sign::Verifier,
+};
+
+use super::dns;
+use super::mime_decode;
+
+pub struct DkimResult {
+    // Signing domain, the d= tag
+    pub domain: String,
+    pub pass: bool,
+    // "pass", "fail", "policy" for signatures we refuse, "temperror" when the key could not be looked up
+    pub status: &'static str,
+    // Why the signature failed
+    pub reason: Option<String>,
+    // Body bytes covered by l= when the signature leaves part of the body unsigned
+    pub signed_length: Option<usize>,
+}
+
+impl DkimResult {
+    pub fn describe(&self) -> String {
+        let partial = match self.signed_length {
+            Some(length) => format!(" (only the first {} bytes of the body are signed)", length),
+            None => String::new(),
+        };
+        match &self.reason {
+            Some(reason) => format!("{} {} ({})", self.domain, self.status, reason),
+            None => format!("{} {}{}", self.domain, self.status, partial),
+        }
+    }
+}
+
+type Failure = (&'static str, String);
+
+fn fail<E: ToString>(e: E) -> Failure {
+    ("fail", e.to_string())
+}
+
+// One result for every DKIM-Signature field of the message
+pub fn verify(raw: &str) -> Vec<DkimResult> {
+    let (head, body) = mime_decode::split_message(raw);
+    let fields = raw_fields(head);
+    fields.iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("DKIM-Signature"))
+        .map(|(_, field)| {
+            let value = field.splitn(2, ':').nth(1).unwrap_or("");
+            let tags = parse_tags(value);
+            let domain = tags.get("d").cloned().unwrap_or(String::from("<unknown domain>"));
+            match check(&tags, field, &fields, body) {
+                Ok(signed_length) => DkimResult { domain, pass: true, status: "pass", reason: None, signed_length },
+                Err((status, reason)) => DkimResult { domain, pass: false, status, reason: Some(reason), signed_length: None },
+            }
+        })
+        .collect()
+}
+
+// Ok holds the l= length when it leaves part of the body unsigned
+fn check(tags: &HashMap<String, String>, signature_field: &str, fields: &Vec<(String, String)>, body: &str) -> Result<Option<usize>, Failure> {
+    let tag = |name: &str| tags.get(name).ok_or_else(|| fail(format!("missing tag {}=", name)));
+    let digest = match tag("a")?.to_lowercase().as_str() {
+        "rsa-sha256" => MessageDigest::sha256(),
+        // RFC 8301: verifiers must not consider rsa-sha1 signatures valid
+        "rsa-sha1" => return Err(("policy", String::from("rsa-sha1 is not accepted"))),
+        other => return Err(fail(format!("unsupported algorithm {}", other))),
+    };
+    // "relaxed/simple" is header/body canonicalization, the body defaults to simple
+    let canonicalization = tags.get("c").map(|c| c.to_lowercase()).unwrap_or(String::from("simple/simple"));
+    let mut modes = canonicalization.split('/');
+    let relaxed_head = modes.next() == Some("relaxed");
+    let relaxed_body = modes.next() == Some("relaxed");
+
+    let mut canonical = canonical_body(body, relaxed_body).into_bytes();
+    let mut signed_length = None;
+    if let Some(length) = tags.get("l").and_then(|l| l.parse::<usize>().ok()) {
+        if length < canonical.len() {
+            signed_length = Some(length);
+        }
+        canonical.truncate(length.min(canonical.len()));
+    }
+    let body_hash = hash(digest, &canonical).map_err(fail)?;
+    if base64::encode(&body_hash[..]) != *tag("bh")? {
+        return Err(fail("body hash mismatch"));
+    }
+
+    // Each listed name takes the next instance of the field from the bottom
+    let mut data = String::new();
+    let mut used: HashMap<String, usize> = HashMap::new();
+    for name in tag("h")?.split(':').map(|n| n.trim().to_lowercase()) {
+        let count = used.entry(name.clone()).or_insert(0);
+        if let Some((_, field)) = fields.iter().rev().filter(|(n, _)| n.to_lowercase() == name).nth(*count) {
+            data.push_str(canonical_header(field, relaxed_head).as_str());
+        }
+        *count += 1;
+    }
+    let own = canonical_header(without_signature(signature_field).as_str(), relaxed_head);
+    data.push_str(own.trim_end_matches("\r\n"));
+
+    let key = public_key(tag("s")?, tag("d")?)?;
+    let signature = base64::decode(tag("b")?).map_err(fail)?;
+    let mut verifier = Verifier::new(digest, &key).map_err(fail)?;
+    verifier.update(data.as_bytes()).map_err(fail)?;
+    if verifier.finish(&signature).map_err(fail)? {
+        Ok(signed_length)
+    } else {
+        Err(fail("signature mismatch"))
+    }
+}
+
+// TXT record at <selector>._domainkey.<domain>
+// A failed or truncated lookup is a temperror, not a missing key
+fn public_key(selector: &str, domain: &str) -> Result<PKey, Failure> {
+    let name = format!("{}._domainkey.{}", selector, domain);
+    let records = dns::lookup_txt(name.as_str()).map_err(|e| ("temperror", format!("lookup of {} failed: {}", name, e)))?;
+    let record = records.into_iter()
+        .map(|txt| parse_tags(txt.as_str()))
+        .find(|tags| tags.contains_key("p"))
+        .ok_or_else(|| fail(format!("no key at {}", name)))?;
+    let p = record.get("p").cloned().unwrap_or_default();
+    if p.is_empty() {
+        return Err(fail("key was revoked"));
+    }
+    let der = base64::decode(&p).map_err(fail)?;
+    PKey::public_key_from_der(&der).map_err(fail)
+}
+
+// "tag=value; tag=value", whitespace is not part of the values
+fn parse_tags(value: &str) -> HashMap<String, String> {
+    value.split(';').filter_map(|t| {
+        let i = t.find('=')?;
+        let val: String = t[i + 1..].chars().filter(|c| !c.is_whitespace()).collect();
+        Some((t[..i].trim().to_string(), val))
+    }).collect()
+}
+
+// Header fields with their folding kept, the text of each ends with CRLF
+fn raw_fields(head: &str) -> Vec<(String, String)> {
+    let mut ret: Vec<(String, String)> = Vec::new();
+    for (number, line) in head.split('\n').map(|l| l.trim_end_matches('\r')).enumerate() {
+        // Mbox separator some servers leave in front of the message
+        if number == 0 && line.starts_with("From ") {
+            continue;
+        }
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some((_, field)) = ret.last_mut() {
+                field.push_str(line);
+                field.push_str("\r\n");
+            }
+        } else if let Some(i) = line.find(':') {
+            ret.push((line[..i].trim_end().to_string(), format!("{}\r\n", line)));
+        }
+    }
+    ret
+}
+
+// The signature field as signed, with an empty b= value
+fn without_signature(field: &str) -> String {
+    let colon = field.find(':').map(|i| i + 1).unwrap_or(0);
+    let tags: Vec<String> = field[colon..].split(';').map(|t| match t.find('=') {
+        Some(i) if t[..i].trim() == "b" => t[..i + 1].to_string(),
+        _ => t.to_string(),
+    }).collect();
+    format!("{}{}", &field[..colon], tags.join(";"))
+}
+
+// Runs of spaces and tabs become a single space, leading and trailing ones are dropped
+fn collapse(s: &str) -> String {
+    s.split(|c| c == ' ' || c == '\t').filter(|p| !p.is_empty()).collect::<Vec<&str>>().join(" ")
+}
+
+fn canonical_header(field: &str, relaxed: bool) -> String {
+    if !relaxed {
+        return field.to_string();
+    }
+    let colon = field.find(':').unwrap_or(field.len());
+    let value = field.get(colon + 1..).unwrap_or("").replace("\r\n", "");
+    format!("{}:{}\r\n", field[..colon].trim_end().to_lowercase(), collapse(value.as_str()))
+}
+
+fn canonical_body(body: &str, relaxed: bool) -> String {
+    let unified = body.replace("\r\n", "\n");
+    let mut lines: Vec<String> = unified.split('\n').map(|l| {
+        if !relaxed {
+            return l.to_string();
+        }
+        // Leading whitespace is reduced, not removed
+        let collapsed = collapse(l);
+        if (l.starts_with(' ') || l.starts_with('\t')) && !collapsed.is_empty() { format!(" {}", collapsed) } else { collapsed }
+    }).collect();
+    while lines.last().map(|l| l.is_empty()).unwrap_or(false) {
+        lines.pop();
+    }
+    if lines.is_empty() {
+        // An empty body is a single CRLF in simple canonicalization
+        return if relaxed { String::new() } else { String::from("\r\n") };
+    }
+    lines.iter().map(|l| format!("{}\r\n", l)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rsa_sha1_is_refused() {
+        let raw = "DKIM-Signature: v=1; a=rsa-sha1; d=example.org; s=sel; h=from; bh=AAAA; b=AAAA\r\nFrom: a@example.org\r\n\r\nHello\r\n";
+        let results = verify(raw);
+        assert_eq!(results.len(), 1);
+        assert!(!results[0].pass);
+        assert_eq!(results[0].status, "policy");
+        assert!(results[0].describe().starts_with("example.org policy"));
+    }
+
+    #[test]
+    fn partial_body_is_shown() {
+        let result = DkimResult { domain: String::from("example.org"), pass: true, status: "pass", reason: None, signed_length: Some(10) };
+        assert_eq!(result.describe(), "example.org pass (only the first 10 bytes of the body are signed)");
+    }
+}
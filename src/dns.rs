@@ -0,0 +1,255 @@
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    time::{Duration, Instant},
+    fs,
+};
+
+use openssl::rand::rand_bytes;
+
+const DEFAULT_NAMESERVER: &str = "8.8.8.8";
+const DNS_PORT: u16 = 53;
+const TIMEOUT: Duration = Duration::from_secs(3);
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+// Looks up SRV records (e.g. "_imaps._tcp.example.org"), sorted by priority
+pub fn lookup_srv(name: &str) -> Vec<SrvRecord> {
+    let (buf, answers) = match query(name, TYPE_SRV) {
+        Ok(res) => res,
+        Err(e) => {
+            log::debug!("srv lookup of {} failed: {}", name, e);
+            return Vec::new();
+        },
+    };
+    let mut records: Vec<SrvRecord> = answers.into_iter().filter_map(|(start, len)| {
+        if len < 7 {
+            return None;
+        }
+        let priority = read_u16(&buf, start)?;
+        let weight = read_u16(&buf, start + 2)?;
+        let port = read_u16(&buf, start + 4)?;
+        let (target, _) = read_name(&buf, start + 6)?;
+        // A target of "." means the service is explicitly not available
+        if target.is_empty() {
+            return None;
+        }
+        Some(SrvRecord { priority, weight, port, target })
+    }).collect();
+    records.sort_by(|a, b| a.priority.cmp(&b.priority).then(b.weight.cmp(&a.weight)));
+    return records;
+}
+
+// Looks up TXT records, the character strings of each record are joined. Errors are temporary
+// failures, a name without records gives none
+pub fn lookup_txt(name: &str) -> io::Result<Vec<String>> {
+    let (buf, answers) = query(name, TYPE_TXT)?;
+    Ok(answers.into_iter().map(|(start, len)| {
+        let mut text = Vec::new();
+        let mut pos = start;
+        while pos < start + len {
+            let size = buf[pos] as usize;
+            let end = (pos + 1 + size).min(start + len);
+            text.extend_from_slice(&buf[pos + 1..end]);
+            pos = end;
+        }
+        String::from_utf8_lossy(&text).to_string()
+    }).collect())
+}
+
+fn nameserver() -> String {
+    let conf = fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+    conf.lines()
+        .map(|l| l.trim())
+        .filter(|l| l.starts_with("nameserver"))
+        .filter_map(|l| l.split_whitespace().nth(1))
+        .next()
+        .map(|s| s.to_string())
+        .unwrap_or(String::from(DEFAULT_NAMESERVER))
+}
+
+fn invalid<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+// Sends a single question and returns the response with (offset, length) of every matching rdata.
+// The id is random and only answers of the asked server are taken, so they are hard to spoof
+fn query(name: &str, qtype: u16) -> io::Result<(Vec<u8>, Vec<(usize, usize)>)> {
+    let mut id = [0u8; 2];
+    rand_bytes(&mut id).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    // One question and the OPT record as additional record
+    let mut packet: Vec<u8> = vec![id[0], id[1], 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01];
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid name {}", name)));
+        }
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&qtype.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    // EDNS0 (RFC 6891) allows 4096 byte answers over UDP, 2048 bit DKIM keys don't fit into 512
+    packet.extend_from_slice(&[0x00, 0x00, 0x29, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+
+    let server = (nameserver().as_str(), DNS_PORT).to_socket_addrs()?.next().ok_or(invalid("no nameserver"))?;
+    let mut buf = query_udp(&packet, server)?;
+    // Truncated answers are asked for again over TCP
+    if buf[2] & 0x02 != 0 {
+        buf = query_tcp(&packet, server)?;
+    }
+    let answers = parse_answers(&buf, qtype)?;
+    Ok((buf, answers))
+}
+
+fn query_udp(packet: &[u8], server: SocketAddr) -> io::Result<Vec<u8>> {
+    let socket = UdpSocket::bind(if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+    socket.send_to(packet, server)?;
+    let deadline = Instant::now() + TIMEOUT;
+    let mut buf = vec![0u8; 4096];
+    loop {
+        let left = deadline.checked_duration_since(Instant::now()).filter(|d| *d > Duration::from_millis(0))
+            .ok_or(io::Error::new(io::ErrorKind::TimedOut, "dns query timed out"))?;
+        socket.set_read_timeout(Some(left))?;
+        let (size, from) = socket.recv_from(&mut buf)?;
+        // Answers from other addresses or to other questions are dropped
+        if from == server && size >= 12 && buf[..2] == packet[..2] {
+            buf.truncate(size);
+            return Ok(buf);
+        }
+    }
+}
+
+// Messages over TCP are prefixed with their length
+fn query_tcp(packet: &[u8], server: SocketAddr) -> io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect_timeout(&server, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    let mut message = (packet.len() as u16).to_be_bytes().to_vec();
+    message.extend_from_slice(packet);
+    stream.write_all(&message)?;
+    let mut length = [0u8; 2];
+    stream.read_exact(&mut length)?;
+    let mut buf = vec![0u8; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut buf)?;
+    if buf.len() < 12 || buf[..2] != packet[..2] {
+        return Err(invalid("dns answer to another question"));
+    }
+    Ok(buf)
+}
+
+// A name that doesn't exist has no answers, other error codes are failures of the server
+fn parse_answers(buf: &[u8], qtype: u16) -> io::Result<Vec<(usize, usize)>> {
+    let mut ret = Vec::new();
+    if buf.len() < 12 {
+        return Err(invalid("dns answer too short"));
+    }
+    match buf[3] & 0x0f {
+        0 => {},
+        3 => return Ok(ret),
+        rcode => return Err(invalid(format!("dns server answered with rcode {}", rcode))),
+    }
+    if buf[2] & 0x02 != 0 {
+        return Err(invalid("dns answer truncated"));
+    }
+    let questions = read_u16(buf, 4).unwrap_or(0);
+    let answers = read_u16(buf, 6).unwrap_or(0);
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = match read_name(buf, pos) {
+            Some((_, next)) => next + 4,
+            None => return Err(invalid("malformed dns answer")),
+        };
+    }
+    for _ in 0..answers {
+        let next = match read_name(buf, pos) {
+            Some((_, next)) => next,
+            None => break,
+        };
+        let (rtype, rdlength) = match (read_u16(buf, next), read_u16(buf, next + 8)) {
+            (Some(t), Some(l)) => (t, l as usize),
+            (_, _) => break,
+        };
+        let rdata = next + 10;
+        if rdata + rdlength > buf.len() {
+            break;
+        }
+        if rtype == qtype {
+            ret.push((rdata, rdlength));
+        }
+        pos = rdata + rdlength;
+    }
+    Ok(ret)
+}
+
+fn read_u16(buf: &[u8], pos: usize) -> Option<u16> {
+    if pos + 1 < buf.len() {
+        Some(((buf[pos] as u16) << 8) | buf[pos + 1] as u16)
+    } else {
+        None
+    }
+}
+
+// Reads a (possibly compressed) domain name, returns the name and the position after it
+fn read_name(buf: &[u8], start: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut pos = start;
+    let mut end = None;
+    let mut jumps = 0;
+    loop {
+        let len = *buf.get(pos)? as usize;
+        if len == 0 {
+            pos += 1;
+            break;
+        } else if len & 0xc0 == 0xc0 {
+            // Compression pointer
+            let pointer = (read_u16(buf, pos)? & 0x3fff) as usize;
+            if end.is_none() {
+                end = Some(pos + 2);
+            }
+            jumps += 1;
+            if jumps > 32 {
+                return None;
+            }
+            pos = pointer;
+        } else {
+            let label = buf.get(pos + 1..pos + 1 + len)?;
+            labels.push(String::from_utf8_lossy(label).to_string());
+            pos += 1 + len;
+        }
+    }
+    Some((labels.join("."), end.unwrap_or(pos)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Answer with the given flags and one TXT record "v=1"
+    fn answer(flags: [u8; 2]) -> Vec<u8> {
+        let mut buf = vec![0x12, 0x34, flags[0], flags[1], 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00];
+        buf.extend_from_slice(b"\x03key\x00\x00\x10\x00\x01");
+        buf.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x10, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x04, 0x03]);
+        buf.extend_from_slice(b"v=1");
+        buf
+    }
+
+    #[test]
+    fn answers_and_failures() {
+        let found = answer([0x81, 0x80]);
+        assert_eq!(parse_answers(&found, TYPE_TXT).unwrap(), vec![(found.len() - 4, 4)]);
+        // No such name
+        assert!(parse_answers(&answer([0x81, 0x83]), TYPE_TXT).unwrap().is_empty());
+        // Server failure and truncation are temporary errors
+        assert!(parse_answers(&answer([0x81, 0x82]), TYPE_TXT).is_err());
+        assert!(parse_answers(&answer([0x83, 0x80]), TYPE_TXT).is_err());
+    }
+}
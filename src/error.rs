@@ -0,0 +1,41 @@
+use std::io;
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum MailError {
+    #[error("could not connect to {host}: {source}")]
+    Connect { host: String, source: io::Error },
+    #[error("login failed for {0}")]
+    Login(String),
+    #[error("inbox is not connected")]
+    NotConnected,
+    #[error("server replied with an error: {0}")]
+    Server(String),
+    #[error("mail {0} could not be fetched")]
+    Fetch(u32),
+    #[error("{0} is not supported by this account")]
+    Unsupported(&'static str),
+    #[error("{0}")]
+    Selection(String),
+    #[error("no {0} found")]
+    NotFound(String),
+    // Candidates as (index, listing line)
+    #[error("\"{query}\" matches {} mails", candidates.len())]
+    Ambiguous { query: String, candidates: Vec<(usize, String)> },
+    #[error("nothing to undo")]
+    NothingToUndo,
+    // Server without UIDPLUS, expunging would also remove mails other clients marked as deleted
+    #[error("the server can only expunge all mails marked as deleted")]
+    PlainExpunge,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl From<imap::error::Error> for MailError {
+    fn from(e: imap::error::Error) -> MailError {
+        MailError::Server(e.to_string())
+    }
+}
+
+pub type MailResult<T> = Result<T, MailError>;
@@ -0,0 +1,30 @@
+// Saves mails as standalone html or PDF files, PDFs are rendered by an external converter
+use std::{
+    io,
+    path::Path,
+    process::Command,
+};
+
+use super::util;
+
+pub const DEFAULT_PDF_COMMAND: &'static str = "wkhtmltopdf --quiet";
+
+// The converter is called with the html file and the target appended
+pub fn write_pdf(command: &str, html: &str, target: &Path) -> io::Result<()> {
+    let mut parts = command.split_whitespace();
+    let program = parts.next().ok_or(io::Error::new(io::ErrorKind::Other, "pdf command is empty"))?;
+    let file = util::temp_file("html", html.as_bytes())?;
+    let status = Command::new(program).args(parts).arg(file.path()).arg(target).status()?;
+    if !status.success() {
+        return Err(io::Error::new(io::ErrorKind::Other, format!("{} exited with {}", program, status)));
+    }
+    Ok(())
+}
+
+// File name from the subject, without characters file systems object to
+pub fn default_name(subject: &str, extension: &str) -> String {
+    let name: String = subject.chars().map(|c| if c.is_alphanumeric() || c == '-' || c == '_' || c == '.' { c } else { '_' }).collect();
+    let name = name.trim_matches(|c| c == '_' || c == '.');
+    let name: String = name.chars().take(60).collect();
+    format!("{}.{}", if name.is_empty() { "mail" } else { name.as_str() }, extension)
+}
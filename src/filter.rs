@@ -0,0 +1,125 @@
+use std::fmt;
+
+use chrono::{
+    DateTime,
+    Datelike,
+    FixedOffset,
+};
+
+use super::receiving::ReceivedMailProxy;
+
+const KEYS: &'static [&'static str] = &["from", "subject", "before", "after"];
+
+// (year, month, day)
+type Day = (i64, i64, i64);
+
+pub struct MailFilter {
+    from: Option<String>,
+    subject: Option<String>,
+    before: Option<Day>,
+    after: Option<Day>,
+}
+
+// Accepts "2020-01-31" and "31.01.2020"
+fn parse_day(s: &str) -> Option<Day> {
+    let (parts, reversed) = if s.contains('-') {
+        (s.split('-').collect::<Vec<&str>>(), false)
+    } else {
+        (s.split('.').collect::<Vec<&str>>(), true)
+    };
+    if parts.len() != 3 {
+        return None;
+    }
+    let mut nums = Vec::new();
+    for part in parts.iter() {
+        nums.push(part.trim().parse::<i64>().ok()?);
+    }
+    if reversed {
+        nums.reverse();
+    }
+    if nums[1] < 1 || nums[1] > 12 || nums[2] < 1 || nums[2] > 31 {
+        return None;
+    }
+    Some((nums[0], nums[1], nums[2]))
+}
+
+fn day_of(date: &DateTime<FixedOffset>) -> Day {
+    (date.year() as i64, date.month() as i64, date.day() as i64)
+}
+
+impl MailFilter {
+    // Parses criteria like ["from:bob@example.org", "subject:weekly", "report", "after:2020-01-01"],
+    // words without a key continue the value before them
+    pub fn parse(tokens: &Vec<String>) -> Result<MailFilter, String> {
+        let mut criteria: Vec<(String, String)> = Vec::new();
+        for token in tokens.iter() {
+            let key = token.find(':').map(|i| &token[..i]).filter(|k| KEYS.contains(k));
+            match (key, criteria.last_mut()) {
+                (Some(key), _) => criteria.push((key.to_string(), token[key.len() + 1..].to_string())),
+                (None, Some((_, value))) => {
+                    value.push(' ');
+                    value.push_str(token.as_str());
+                },
+                (None, None) => return Err(format!("unknown filter \"{}\", use one of from:, subject:, before:, after:", token)),
+            }
+        }
+
+        let mut ret = MailFilter { from: None, subject: None, before: None, after: None };
+        for (key, value) in criteria.into_iter() {
+            match key.as_str() {
+                "from" => ret.from = Some(value.to_lowercase()),
+                "subject" => ret.subject = Some(value.to_lowercase()),
+                "before" => ret.before = Some(parse_day(value.as_str()).ok_or(format!("invalid date \"{}\"", value))?),
+                _ => ret.after = Some(parse_day(value.as_str()).ok_or(format!("invalid date \"{}\"", value))?),
+            }
+        }
+        Ok(ret)
+    }
+
+    pub fn matches(&self, mail: &ReceivedMailProxy) -> bool {
+        let header = match mail.get_header() {
+            Some(header) => header,
+            None => return false,
+        };
+        if let Some(from) = &self.from {
+            if !header.get_from().to_lowercase().contains(from.as_str()) {
+                return false;
+            }
+        }
+        if let Some(subject) = &self.subject {
+            if !header.get_subject().to_lowercase().contains(subject.as_str()) {
+                return false;
+            }
+        }
+        // Mails without date are filtered out as soon as dates are involved
+        if self.before.is_some() || self.after.is_some() {
+            let day = match header.get_date() {
+                Some(date) => day_of(date),
+                None => return false,
+            };
+            if self.before.map(|b| day >= b).unwrap_or(false) || self.after.map(|a| day <= a).unwrap_or(false) {
+                return false;
+            }
+        }
+        return true;
+    }
+}
+
+impl fmt::Display for MailFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(from) = &self.from {
+            parts.push(format!("from:{}", from));
+        }
+        if let Some(subject) = &self.subject {
+            parts.push(format!("subject:{}", subject));
+        }
+        if let Some((y, m, d)) = self.before {
+            parts.push(format!("before:{}-{:0>2}-{:0>2}", y, m, d));
+        }
+        if let Some((y, m, d)) = self.after {
+            parts.push(format!("after:{}-{:0>2}-{:0>2}", y, m, d));
+        }
+        write!(f, "{}", parts.join(" "))
+    }
+}
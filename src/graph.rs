@@ -0,0 +1,469 @@
+// Microsoft Graph mail backend for tenants without IMAP.
+// Accounts use `backend: graph` with `location: <client id>[@<tenant>]` of an app registration
+// that allows public client flows, tokens are obtained with the device code flow
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::DateTime;
+use serde_json::{json, Value};
+
+use super::account::InboxConfig;
+use super::error::{MailError, MailResult};
+use super::headers::Headers;
+use super::http;
+use super::receiving::{InboxAdapter, MailInbox, ReceivedMail, ReceivedMailHeader};
+
+const LOGIN_URL: &'static str = "https://login.microsoftonline.com";
+const GRAPH_URL: &'static str = "https://graph.microsoft.com/v1.0";
+const SCOPES: &'static str = "offline_access Mail.ReadWrite Mail.Send MailboxSettings.ReadWrite";
+const MAX_MESSAGES: usize = 500;
+// Tokens are renewed a bit before they run out
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+#[derive(Clone, PartialEq)]
+pub struct GraphConfig {
+    client_id: String,
+    tenant: String,
+}
+
+impl GraphConfig {
+    // "client-id" or "client-id@tenant", the tenant defaults to "common"
+    pub fn parse(location: &str) -> GraphConfig {
+        let mut parts = location.trim().splitn(2, '@');
+        let client_id = parts.next().unwrap_or("").to_string();
+        let tenant = parts.next().filter(|t| !t.is_empty()).unwrap_or("common").to_string();
+        GraphConfig { client_id, tenant }
+    }
+
+    fn token_url(&self, endpoint: &str) -> String {
+        format!("{}/{}/oauth2/v2.0/{}", LOGIN_URL, self.tenant, endpoint)
+    }
+}
+
+struct Token {
+    access: String,
+    refresh: Option<String>,
+    expires: Instant,
+}
+
+// Tokens of the session by user, so reconnecting doesn't ask for another device login
+static TOKENS: Mutex<Vec<(String, Token)>> = Mutex::new(Vec::new());
+
+fn oauth_error(body: &Value) -> String {
+    body["error_description"].as_str().or(body["error"].as_str()).unwrap_or("unknown error").lines().next().unwrap_or("").to_string()
+}
+
+fn post_form(url: &str, pairs: &[(&str, &str)]) -> MailResult<Value> {
+    let response = http::request("POST", url, &[("Content-Type", "application/x-www-form-urlencoded")], Some(http::form(pairs).as_str()))?;
+    serde_json::from_str(response.body.as_str()).map_err(|e| MailError::Server(format!("invalid token response: {}", e)))
+}
+
+fn token_from(body: &Value) -> Option<Token> {
+    Some(Token {
+        access: body["access_token"].as_str()?.to_string(),
+        refresh: body["refresh_token"].as_str().map(|r| r.to_string()),
+        expires: Instant::now() + Duration::from_secs(body["expires_in"].as_u64().unwrap_or(3600)),
+    })
+}
+
+// Shows the code to enter in the browser and waits until the user signed in
+fn device_login(config: &GraphConfig, user: &str) -> MailResult<Token> {
+    let body = post_form(config.token_url("devicecode").as_str(), &[("client_id", config.client_id.as_str()), ("scope", SCOPES)])?;
+    let device_code = body["device_code"].as_str().ok_or(MailError::Server(oauth_error(&body)))?;
+    match body["message"].as_str() {
+        Some(message) => println!("{}", message),
+        None => println!("Sign in as {} at {} with the code {}", user, body["verification_uri"].as_str().unwrap_or(""), body["user_code"].as_str().unwrap_or("")),
+    }
+    let mut interval = body["interval"].as_u64().unwrap_or(5);
+    let deadline = Instant::now() + Duration::from_secs(body["expires_in"].as_u64().unwrap_or(900));
+
+    while Instant::now() < deadline {
+        thread::sleep(Duration::from_secs(interval));
+        let body = post_form(config.token_url("token").as_str(), &[
+            ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+            ("client_id", config.client_id.as_str()),
+            ("device_code", device_code),
+        ])?;
+        if let Some(token) = token_from(&body) {
+            return Ok(token);
+        }
+        match body["error"].as_str() {
+            Some("authorization_pending") => {},
+            Some("slow_down") => interval += 5,
+            _ => {
+                log::warn!("device login of {} failed: {}", user, oauth_error(&body));
+                return Err(MailError::Login(user.to_string()));
+            },
+        }
+    }
+    Err(MailError::Login(user.to_string()))
+}
+
+fn refresh(config: &GraphConfig, refresh_token: &str) -> MailResult<Option<Token>> {
+    let body = post_form(config.token_url("token").as_str(), &[
+        ("grant_type", "refresh_token"),
+        ("client_id", config.client_id.as_str()),
+        ("refresh_token", refresh_token),
+        ("scope", SCOPES),
+    ])?;
+    if body["access_token"].is_null() {
+        log::info!("token refresh rejected: {}", oauth_error(&body));
+    }
+    Ok(token_from(&body))
+}
+
+// Valid access token for the user, refreshed or newly requested if necessary
+pub fn access_token(config: &GraphConfig, user: &str) -> MailResult<String> {
+    let mut tokens = TOKENS.lock().unwrap();
+    let cached = tokens.iter().position(|(u, _)| u == user);
+    if let Some(i) = cached {
+        let token = &tokens[i].1;
+        if token.expires > Instant::now() + EXPIRY_MARGIN {
+            return Ok(token.access.clone());
+        }
+    }
+    let refresh_token = cached.and_then(|i| tokens[i].1.refresh.clone());
+    let token = match refresh_token.map(|r| refresh(config, r.as_str())).transpose()?.flatten() {
+        Some(token) => token,
+        None => device_login(config, user)?,
+    };
+    let access = token.access.clone();
+    match cached {
+        Some(i) => tokens[i].1 = token,
+        None => tokens.push((user.to_string(), token)),
+    }
+    Ok(access)
+}
+
+struct GraphClient {
+    config: GraphConfig,
+    user: Option<String>,
+}
+
+impl GraphClient {
+    fn request(&self, method: &str, url: &str, content_type: &str, body: Option<&str>) -> MailResult<http::Response> {
+        let user = self.user.as_ref().ok_or(MailError::NotConnected)?;
+        let auth = format!("Bearer {}", access_token(&self.config, user.as_str())?);
+        let url = if url.starts_with("https://") { url.to_string() } else { format!("{}{}", GRAPH_URL, url) };
+        let mut headers = vec![("Authorization", auth.as_str())];
+        if body.is_some() {
+            headers.push(("Content-Type", content_type));
+        }
+        let response = http::request(method, url.as_str(), &headers, body)?;
+        if response.is_success() {
+            return Ok(response);
+        }
+        let message = serde_json::from_str::<Value>(response.body.as_str()).ok()
+            .and_then(|v| v["error"]["message"].as_str().map(|m| m.to_string()))
+            .unwrap_or(response.body.clone());
+        Err(MailError::Server(format!("graph api replied {}: {}", response.status, message)))
+    }
+
+    fn get(&self, url: &str) -> MailResult<Value> {
+        let response = self.request("GET", url, "", None)?;
+        serde_json::from_str(response.body.as_str()).map_err(|e| MailError::Server(format!("invalid graph response: {}", e)))
+    }
+
+    fn send_json(&self, method: &str, url: &str, body: &Value) -> MailResult<()> {
+        self.request(method, url, "application/json", Some(body.to_string().as_str()))?;
+        Ok(())
+    }
+
+    // Follows the next links of paged collections
+    fn collect(&self, url: &str) -> MailResult<Vec<Value>> {
+        let mut ret = Vec::new();
+        let mut next = Some(url.to_string());
+        while let Some(url) = next.take() {
+            let page = self.get(url.as_str())?;
+            ret.extend(page["value"].as_array().cloned().unwrap_or(Vec::new()));
+            if ret.len() < MAX_MESSAGES {
+                next = page["@odata.nextLink"].as_str().map(|n| n.to_string());
+            }
+        }
+        Ok(ret)
+    }
+}
+
+fn address(value: &Value) -> String {
+    let email = &value["emailAddress"];
+    let address = email["address"].as_str().unwrap_or("");
+    match email["name"].as_str().filter(|n| !n.is_empty() && *n != address) {
+        Some(name) => format!("\"{}\" <{}>", name.replace('\\', "\\\\").replace('"', "\\\""), address),
+        None => address.to_string(),
+    }
+}
+
+// Graph delivers header fields as json, they are turned back into headers for the listing
+fn headers_of(message: &Value) -> Headers {
+    let mut headers = Headers::new();
+    headers.add("From", address(&message["from"]).as_str());
+    let to: Vec<String> = message["toRecipients"].as_array().map(|r| r.iter().map(address).collect()).unwrap_or(Vec::new());
+    headers.add("To", to.join(", ").as_str());
+    headers.add("Subject", message["subject"].as_str().unwrap_or(""));
+    if let Some(date) = message["receivedDateTime"].as_str().and_then(|d| DateTime::parse_from_rfc3339(d).ok()) {
+        headers.add("Date", date.to_rfc2822().as_str());
+    }
+    if let Some(id) = message["internetMessageId"].as_str() {
+        headers.add("Message-ID", id);
+    }
+    headers
+}
+
+// Graph message ids are long strings, the listing uses a number handed out once for each of them
+#[derive(Default)]
+struct IdTable {
+    numbers: HashMap<String, u32>,
+    // Graph id of number n at n - 1
+    ids: Vec<String>,
+    // Deleted mails stay in the mailbox until expunge
+    deleted: Vec<u32>,
+}
+
+impl IdTable {
+    fn number(&mut self, graph_id: &str) -> u32 {
+        if let Some(number) = self.numbers.get(graph_id) {
+            return *number;
+        }
+        self.ids.push(graph_id.to_string());
+        let number = self.ids.len() as u32;
+        self.numbers.insert(graph_id.to_string(), number);
+        number
+    }
+}
+
+// Tables by user. They outlive reloads and are shared by all connections of a user, so a number
+// always stands for the same mail
+static ID_TABLES: Mutex<Vec<(String, Arc<Mutex<IdTable>>)>> = Mutex::new(Vec::new());
+
+fn id_table(user: &str) -> Arc<Mutex<IdTable>> {
+    let mut tables = ID_TABLES.lock().unwrap();
+    if let Some((_, table)) = tables.iter().find(|(u, _)| u == user) {
+        return Arc::clone(table);
+    }
+    let table = Arc::new(Mutex::new(IdTable::default()));
+    tables.push((user.to_string(), Arc::clone(&table)));
+    table
+}
+
+pub struct GraphAccount {
+    client: GraphClient,
+    // Set on login
+    table: Arc<Mutex<IdTable>>,
+}
+
+impl GraphAccount {
+    pub fn new(config: GraphConfig) -> GraphAccount {
+        GraphAccount {
+            client: GraphClient { config, user: None },
+            table: Arc::new(Mutex::new(IdTable::default())),
+        }
+    }
+
+    fn graph_id(&self, id: u32) -> MailResult<String> {
+        self.table.lock().unwrap().ids.get((id as usize).wrapping_sub(1)).cloned().ok_or(MailError::Fetch(id))
+    }
+
+    fn folder_id(&self, folder: &str) -> MailResult<String> {
+        let folders = self.client.collect("/me/mailFolders?$top=100")?;
+        folders.iter()
+            .find(|f| f["displayName"].as_str().map(|n| n.eq_ignore_ascii_case(folder)).unwrap_or(false))
+            .and_then(|f| f["id"].as_str().map(|id| id.to_string()))
+            .ok_or(MailError::NotFound(format!("folder \"{}\"", folder)))
+    }
+
+    fn patch(&self, ids: &Vec<u32>, body: &Value) -> MailResult<()> {
+        for id in ids.iter() {
+            self.client.send_json("PATCH", format!("/me/messages/{}", self.graph_id(*id)?).as_str(), body)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn open(config: &InboxConfig) -> MailResult<InboxAdapter> {
+    match config {
+        InboxConfig::Other(_, location) => Ok(InboxAdapter::new(Box::new(GraphAccount::new(GraphConfig::parse(location))))),
+        _ => Err(MailError::Unsupported("graph access to pop3 and imap servers")),
+    }
+}
+
+impl MailInbox for GraphAccount {
+    // The password is not used, the user signs in with the device code flow
+    fn login(&mut self, username: &String, _: &String) -> MailResult<()> {
+        self.client.user = Some(username.clone());
+        access_token(&self.client.config, username.as_str())?;
+        self.table = id_table(username.as_str());
+        Ok(())
+    }
+
+    fn load_inbox(&mut self) -> MailResult<Vec<ReceivedMailHeader>> {
+        let select = "id,subject,from,toRecipients,receivedDateTime,isRead,internetMessageId";
+        let mut messages = self.client.collect(format!("/me/mailFolders/inbox/messages?$top=100&$select={}&$orderby=receivedDateTime%20desc", select).as_str())?;
+        // Unread mails first, then the others
+        messages.sort_by_key(|m| m["isRead"].as_bool().unwrap_or(false));
+
+        let mut table = self.table.lock().unwrap();
+        let mut ret = Vec::new();
+        let mut still_deleted = Vec::new();
+        for message in messages.iter() {
+            let id = match message["id"].as_str() {
+                Some(graph_id) => table.number(graph_id),
+                None => continue,
+            };
+            if table.deleted.contains(&id) {
+                still_deleted.push(id);
+                continue;
+            }
+            ret.push(ReceivedMailHeader::new(id, &headers_of(message)));
+        }
+        table.deleted = still_deleted;
+        log::debug!("{} mails in graph inbox", ret.len());
+        Ok(ret)
+    }
+
+    fn get_mail(&mut self, header: &ReceivedMailHeader) -> MailResult<ReceivedMail> {
+        let id = header.get_id();
+        let response = self.client.request("GET", format!("/me/messages/{}/$value", self.graph_id(id)?).as_str(), "", None)?;
+        Ok(ReceivedMail::from_raw(response.body.as_str()))
+    }
+
+    fn list_folders(&mut self) -> MailResult<Vec<String>> {
+        let folders = self.client.collect("/me/mailFolders?$top=100")?;
+        Ok(folders.iter().filter_map(|f| f["displayName"].as_str().map(|n| n.to_string())).collect())
+    }
+
+    // Graph has no subscriptions, all folders are shown
+    fn list_subscribed(&mut self) -> MailResult<Vec<String>> {
+        self.list_folders()
+    }
+
+    fn subscribe(&mut self, _: &str, _: bool) -> MailResult<()> {
+        Err(MailError::Unsupported("subscribing to folders over graph"))
+    }
+
+    fn delete(&mut self, ids: &Vec<u32>) -> MailResult<()> {
+        for id in ids.iter() {
+            self.graph_id(*id)?;
+            let mut table = self.table.lock().unwrap();
+            if !table.deleted.contains(id) {
+                table.deleted.push(*id);
+            }
+        }
+        Ok(())
+    }
+
+    fn undelete(&mut self, ids: &Vec<u32>) -> MailResult<()> {
+        self.table.lock().unwrap().deleted.retain(|id| !ids.contains(id));
+        Ok(())
+    }
+
+    fn move_to(&mut self, ids: &Vec<u32>, folder: &str) -> MailResult<()> {
+        let destination = json!({ "destinationId": self.folder_id(folder)? });
+        for id in ids.iter() {
+            self.client.send_json("POST", format!("/me/messages/{}/move", self.graph_id(*id)?).as_str(), &destination)?;
+        }
+        Ok(())
+    }
+
+    fn remove_copies(&mut self, folder: &str, message_ids: &Vec<String>) -> MailResult<()> {
+        let folder = self.folder_id(folder)?;
+        for message_id in message_ids.iter() {
+            let filter = http::url_encode(format!("internetMessageId eq '<{}>'", message_id.replace('\'', "''")).as_str());
+            let copies = self.client.collect(format!("/me/mailFolders/{}/messages?$select=id&$filter={}", folder, filter).as_str())?;
+            for copy in copies.iter().filter_map(|c| c["id"].as_str()) {
+                self.client.request("DELETE", format!("/me/messages/{}", copy).as_str(), "", None)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Graph moves instead of copying, the mails are moved back and get new ids
+    fn move_back(&mut self, folder: &str, _: &Vec<u32>, message_ids: &Vec<String>) -> MailResult<bool> {
+        let folder = self.folder_id(folder)?;
+        let destination = json!({ "destinationId": "inbox" });
+        for message_id in message_ids.iter() {
+            let filter = http::url_encode(format!("internetMessageId eq '<{}>'", message_id.replace('\'', "''")).as_str());
+            let moved = self.client.collect(format!("/me/mailFolders/{}/messages?$select=id&$filter={}", folder, filter).as_str())?;
+            for mail in moved.iter().filter_map(|m| m["id"].as_str()) {
+                self.client.send_json("POST", format!("/me/messages/{}/move", mail).as_str(), &destination)?;
+            }
+        }
+        Ok(false)
+    }
+
+    fn set_flag(&mut self, ids: &Vec<u32>, flag: &str, set: bool) -> MailResult<()> {
+        let body = if flag.eq_ignore_ascii_case("\\Seen") {
+            json!({ "isRead": set })
+        } else if flag.eq_ignore_ascii_case("\\Flagged") {
+            json!({ "flag": { "flagStatus": if set { "flagged" } else { "notFlagged" } } })
+        } else {
+            return Err(MailError::Unsupported("this flag"));
+        };
+        self.patch(ids, &body)
+    }
+
+    fn expunge(&mut self) -> MailResult<()> {
+        let deleted = std::mem::replace(&mut self.table.lock().unwrap().deleted, Vec::new());
+        for id in deleted.iter() {
+            self.client.request("DELETE", format!("/me/messages/{}", self.graph_id(*id)?).as_str(), "", None)?;
+        }
+        Ok(())
+    }
+    fn load_folder(&mut self, _: &str) -> MailResult<Vec<ReceivedMailHeader>> {
+        Err(MailError::Unsupported("browsing folders over graph"))
+    }
+
+    fn set_folder_flag(&mut self, _: &str, _: &Vec<u32>, _: &str, _: bool) -> MailResult<()> {
+        Err(MailError::Unsupported("browsing folders over graph"))
+    }
+
+    fn restore(&mut self, _: &str, _: &Vec<u32>) -> MailResult<()> {
+        Err(MailError::Unsupported("browsing folders over graph"))
+    }
+
+    fn empty_folder(&mut self, _: &str) -> MailResult<usize> {
+        Err(MailError::Unsupported("browsing folders over graph"))
+    }
+}
+
+// Sends the rendered mime message through /me/sendMail
+pub struct GraphTransport {
+    client: GraphClient,
+}
+
+impl GraphTransport {
+    pub fn new(location: &str, user: String) -> GraphTransport {
+        GraphTransport {
+            client: GraphClient { config: GraphConfig::parse(location), user: Some(user) },
+        }
+    }
+
+    // Graph takes the recipients from the message, Bcc recipients are added as header again
+    pub fn send(&self, recipients: &Vec<String>, message: &str) -> MailResult<()> {
+        let head = message.split("\r\n\r\n").next().unwrap_or("").to_lowercase();
+        let hidden: Vec<&String> = recipients.iter().filter(|r| !head.contains(r.to_lowercase().as_str())).collect();
+        let message = if hidden.is_empty() {
+            message.to_string()
+        } else {
+            format!("Bcc: {}\r\n{}", hidden.iter().map(|r| r.as_str()).collect::<Vec<&str>>().join(", "), message)
+        };
+        self.client.request("POST", "/me/sendMail", "text/plain", Some(base64::encode(message.as_bytes()).as_str()))?;
+        Ok(())
+    }
+}
+
+// Out of office replies through the mailbox settings
+pub fn set_automatic_replies(location: &str, user: &str, setting: &Value) -> MailResult<()> {
+    let client = GraphClient { config: GraphConfig::parse(location), user: Some(user.to_string()) };
+    client.send_json("PATCH", "/me/mailboxSettings", &json!({ "automaticRepliesSetting": setting }))
+}
+
+// "disabled", "alwaysEnabled" or "scheduled"
+pub fn automatic_replies_status(location: &str, user: &str) -> MailResult<String> {
+    let client = GraphClient { config: GraphConfig::parse(location), user: Some(user.to_string()) };
+    let setting = client.get("/me/mailboxSettings/automaticRepliesSetting")?;
+    Ok(setting["status"].as_str().unwrap_or("disabled").to_string())
+}
@@ -0,0 +1,106 @@
+// Header fields of a message or mime part in their original order, repeated fields (like Received) are kept
+#[derive(Clone)]
+pub struct Headers {
+    fields: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers {
+            fields: Vec::new(),
+        }
+    }
+
+    // Unfolds continuation lines and accepts the obsolete syntax with whitespace before the colon.
+    // Lines that are neither a field nor a continuation are appended to the field before them
+    pub fn parse(head: &str) -> Headers {
+        let mut fields: Vec<(String, String)> = Vec::new();
+        for (number, line) in head.split('\n').map(|l| l.trim_end_matches('\r')).enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            // Mbox separator some servers leave in front of the message
+            if number == 0 && line.starts_with("From ") {
+                continue;
+            }
+            let folded = line.starts_with(' ') || line.starts_with('\t');
+            let field = if folded { None } else { line.find(':').map(|i| (&line[..i], &line[i + 1..])) };
+            match (field, fields.last_mut()) {
+                (Some((name, value)), _) if is_field_name(name.trim_end()) => {
+                    fields.push((name.trim_end().to_string(), value.trim_start().to_string()));
+                },
+                (_, Some((name, value))) => {
+                    if !folded {
+                        log::debug!("malformed header line after \"{}\": {}", name, line);
+                        value.push(' ');
+                    }
+                    value.push_str(line);
+                },
+                (_, None) => log::debug!("header line without field: {}", line),
+            }
+        }
+        for (_, value) in fields.iter_mut() {
+            let trimmed = value.trim_end().len();
+            value.truncate(trimmed);
+        }
+        Headers { fields }
+    }
+
+    // Appends a field, for backends that don't deliver raw headers
+    pub fn add(&mut self, name: &str, value: &str) {
+        self.fields.push((name.to_string(), value.to_string()));
+    }
+
+    // First field with the name, case insensitive
+    pub fn get(&self, name: &str) -> Option<&String> {
+        self.fields.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v)
+    }
+
+    pub fn get_all(&self, name: &str) -> Vec<&String> {
+        self.fields.iter().filter(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v).collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(String, String)> {
+        self.fields.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+// Printable ascii without colon and space, as in RFC 5322
+fn is_field_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b > 32 && b < 127 && b != b':')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn folded_fields() {
+        let headers = Headers::parse("Subject: A long\r\n subject\r\n\tline\r\nTo: a@example.org,\r\n  b@example.org\r\n");
+        assert_eq!(headers.get("subject").map(|s| s.as_str()), Some("A long subject\tline"));
+        assert_eq!(headers.get("To").map(|s| s.as_str()), Some("a@example.org,  b@example.org"));
+        assert_eq!(headers.iter().count(), 2);
+    }
+
+    #[test]
+    fn repeated_fields() {
+        let headers = Headers::parse("Received: from a\nReceived: from b\nSubject: Hi\nreceived: from c\n");
+        assert_eq!(headers.get("Received").map(|s| s.as_str()), Some("from a"));
+        assert_eq!(headers.get_all("RECEIVED"), vec!["from a", "from b", "from c"]);
+        assert!(headers.get_all("Cc").is_empty());
+    }
+
+    #[test]
+    fn malformed_lines() {
+        let headers = Headers::parse("From sender@example.org Mon Jan  1 00:00:00 2024\nno field here\nSubject : obsolete  \nbroken line\nBad Name: value\n");
+        // The mbox separator and the line before any field are dropped
+        assert_eq!(headers.iter().count(), 1);
+        assert_eq!(headers.get("Subject").map(|s| s.as_str()), Some("obsolete   broken line Bad Name: value"));
+        assert!(Headers::parse("").is_empty());
+        assert!(Headers::parse(": no name\n").is_empty());
+    }
+}
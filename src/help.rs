@@ -0,0 +1,179 @@
+use super::output;
+
+// Command name, parameters and description, mirrors the definitions in commands.json
+pub type CommandHelp = (&'static str, &'static str, &'static str);
+
+pub const GLOBAL: &'static [CommandHelp] = &[
+    ("refresh", "", "Loads new mails for all accounts"),
+    ("sync-junk", "", "Retrains the spam filter from mails moved in and out of the junk folders, also done every junk_sync_interval seconds"),
+    ("show-inbox", "[account|all]", "Lists the mails of one or all accounts, with all of them each line starts with the account tag set by color and prefix in the account file"),
+    ("inbox", "<account>", "Opens the inbox of an account"),
+    ("show-servers", "", "Lists all configured accounts"),
+    ("show-drafts", "", "Lists saved drafts"),
+    ("add-server", "", "Adds an account, looking up its server settings"),
+    ("import", "thunderbird <profile-dir> [--mail]", "Adds the accounts of a Thunderbird profile, with --mail its local folders go into notmuch"),
+    ("reload-accounts", "", "Reloads the account file"),
+    ("history", "", "Lists earlier commands, \"!!\", \"!n\" or \"!prefix\" run them again and Ctrl-R searches them"),
+    ("source", "<file> [--continue]", "Runs the commands of a file, stopping at the first error unless --continue is given"),
+    ("encrypt-accounts", "", "Encrypts the account file with a master passphrase"),
+    ("decrypt-accounts", "", "Stores the account file unencrypted"),
+    ("output", "<json|text>", "Switches the output format"),
+    ("trace", "<on [file]|off>", "Logs the IMAP and SMTP protocol exchange to a file"),
+    ("search", "<query>...", "Searches the notmuch database"),
+    ("tag", "<+tag|-tag>... <query>...", "Changes notmuch tags of the mails matching a query"),
+    ("grep", "[-i] <pattern>", "Lists the lines of loaded mails of all accounts matching a regular expression"),
+    ("contacts", "", "Switches to contacts mode to manage the address book"),
+    ("keys", "<list|import <file>|fetch <email>>", "Manages the PGP keyring and the keys of contacts"),
+    ("show-outbox", "", "Lists the mails waiting for the send delay to pass"),
+    ("cancel-send", "[n]", "Takes a mail of the outbox back into write mode, the last one sent without number"),
+    ("lock", "", "Forgets the PGP passphrase kept for passphrase_ttl seconds after unlocking"),
+    ("write", "[template] [key=value...]", "Starts writing a new mail, from templates/<template>.txt next to the account file. Values fill {{key}} placeholders besides {{name}}, {{first_name}}, {{email}}, {{from}}, {{account}} and {{date}}"),
+    ("help", "[command]", "Shows available commands"),
+    ("exit", "", "Quits the program"),
+];
+
+pub const INBOX: &'static [CommandHelp] = &[
+    ("show-unread", "", "Lists unread mails"),
+    ("show-all", "", "Lists the conversations with their number of mails and latest mail"),
+    ("expand", "<thread>", "Lists the mails of a conversation below it, indented by reply"),
+    ("collapse", "", "Hides the mails of the expanded conversation"),
+    ("filter", "[from:<addr>] [subject:<text>] [before:<date>] [after:<date>] | clear", "Narrows the listed mails, dates as yyyy-mm-dd"),
+    ("save-search", "<name> <criteria>...", "Saves filter criteria under a name"),
+    ("search", "<name>", "Filters the mails with a saved search"),
+    ("grep", "[-i] [--all] <pattern>", "Lists the lines of loaded mails matching a regular expression, bodies only of mails opened before"),
+    ("show-searches", "", "Lists the saved searches"),
+    ("delete-search", "<name>", "Deletes a saved search"),
+    ("show-threads", "", "Lists conversations with their number of mails"),
+    ("open-thread", "<n>", "Prints all mails of a conversation in order"),
+    ("open", "<index|#id|text>", "Opens a mail by index, listed id or matching sender or subject"),
+    ("select", "[<mails>|<criteria>...|clear]", "Marks mails with \">\" for the next delete, move or archive without mails, lists them without parameter"),
+    ("delete", "[mails]", "Deletes mails, given as index set like 3-7,12 or by listed ids like #4711, or the selected ones. They are moved to the trash folder if there is one"),
+    ("show-trash", "", "Lists the mails of the trash folder"),
+    ("restore", "<mails>", "Moves mails of the trash listing back into the inbox"),
+    ("empty-trash", "", "Removes all mails of the trash folder for good"),
+    ("move", "[mails] <folder>", "Moves mails or the selected ones to another folder"),
+    ("archive", "[mails]", "Moves mails or the selected ones to the archive folder"),
+    ("rules", "[list|test <index>]", "Lists the rules run on new mails, or shows which of them match a mail"),
+    ("spam", "<mails>", "Marks mails as junk, moves them to the junk folder and trains the local spam filter"),
+    ("not-spam", "<mails>", "Marks mails as not being junk and trains the local spam filter"),
+    ("ham", "<mails>", "Same as not-spam"),
+    ("tag", "<mails> <label>", "Adds a local label to mails, kept on this machine for any account type"),
+    ("untag", "<mails> <label>", "Removes a local label from mails"),
+    ("show-tag", "[label]", "Lists the mails with a local label, or all labels in use"),
+    ("vacation", "[status] | set <from> <until> --message-file <file> [--subject <text>] | off", "Sets up or removes the out of office reply via Sieve or Graph, dates as yyyy-mm-dd"),
+    ("flag", "<mails>", "Flags mails"),
+    ("unflag", "<mails>", "Removes the flag from mails"),
+    ("undo", "", "Reverses the last delete, move, archive, spam or flag"),
+    ("help", "[command]", "Shows available commands"),
+    ("show-folders", "[--all|--subscribed]", "Lists the folders on the server, only the subscribed ones with --subscribed or the subscribed_folders setting"),
+    ("subscribe", "<folder>", "Subscribes to a folder, other clients usually show only those"),
+    ("unsubscribe", "<folder>", "Removes the subscription of a folder"),
+    ("expunge", "", "Removes all mails marked as deleted for good, also those deleted by other clients"),
+    ("exit", "", "Closes the inbox, deleted mails are removed for good unless the account sets expunge to never"),
+];
+
+pub const READ: &'static [CommandHelp] = &[
+    ("show-mail", "", "Prints the opened mail, with the results of checking its PGP, S/MIME and DKIM signatures"),
+    ("copy-address", "", "Copies the sender address to the clipboard"),
+    ("copy-subject", "", "Copies the subject to the clipboard"),
+    ("copy-body", "", "Copies the mail body to the clipboard"),
+    ("find", "<text>", "Searches the mail body, long mails open in the pager at the first match"),
+    ("open-link", "<n>", "Opens a link listed below the mail in the browser"),
+    ("show-headers", "", "Shows all header fields, including the Received chain"),
+    ("structure", "", "Shows the MIME tree of the mail: part numbers, content types, encodings, sizes, file names and attachment numbers"),
+    ("save-part", "<index> [path]", "Decodes a part numbered as structure shows it and writes it to a file or directory, any part like inline images, calendar parts and attached mails"),
+    ("show-raw", "", "Shows the complete message source with all headers"),
+    ("next", "", "Opens the next mail of the listing"),
+    ("prev", "", "Opens the previous mail of the listing"),
+    ("next-unread", "", "Opens the next unread mail of the listing"),
+    ("reply", "", "Writes a reply to the opened mail, starting with the greeting and sign-off set in quoting of the account or settings"),
+    ("list-reply", "", "Writes a reply to the mailing list of the opened mail"),
+    ("edit-as-new", "", "Writes a new mail with the recipients, subject, text and attachments of the opened mail"),
+    ("redirect", "<address>...", "Resends the opened mail unchanged with Resent- headers, the original sender stays visible"),
+    ("accept", "", "Accepts the invitation in the opened mail"),
+    ("tentative", "", "Tentatively accepts the invitation in the opened mail"),
+    ("decline", "", "Declines the invitation in the opened mail"),
+    ("unsubscribe", "", "Leaves the mailing list of the opened mail with its List-Unsubscribe link"),
+    ("lock", "", "Forgets the kept PGP passphrase"),
+    ("preview-attachment", "<n>", "Shows an image attachment in terminals with inline images, its dimensions and size otherwise"),
+    ("show-image", "<n>", "Shows an image listed below an html mail, remote ones only with the remote_images setting"),
+    ("export", "[file] [--format pdf|html]", "Saves the opened mail with its headers as standalone html, or as PDF through the pdf_command setting"),
+    ("export-ics", "[file]", "Saves the calendar data of the opened mail, passes it to the calendar_import command without a file"),
+    ("help", "[command]", "Shows available commands"),
+    ("close", "", "Closes the mail"),
+];
+
+pub const WRITE: &'static [CommandHelp] = &[
+    ("from", "<sender>", "Sets the sender address"),
+    ("to", "<recipient>...", "Sets the recipients, group names and parts of names or addresses are looked up among known contacts"),
+    ("cc", "<recipient>...", "Sets the carbon copy recipients"),
+    ("bcc", "<recipient>...", "Sets the blind carbon copy recipients"),
+    ("subject", "<text>...", "Sets the subject"),
+    ("text", "", "Enters the mail body, finished with '$'"),
+    ("edit", "", "Edits the mail body in $EDITOR"),
+    ("attach", "[--zip] <file>...", "Attaches files, with --zip bundled into one zip archive"),
+    ("zip-attachments", "[attachments] [--name <name>]", "Replaces attachments, given as index set like 0-2 or all of them, by one zip archive"),
+    ("spellcheck", "", "Checks the spelling of the mail body with hunspell"),
+    ("preview", "", "Shows the mail as it will be sent, with placeholders filled"),
+    ("var", "[key=value...]", "Sets values for {{key}} placeholders, lists them without parameter"),
+    ("send", "", "Sends the mail, after the send_delay setting it waits in the outbox"),
+    ("show-outbox", "", "Lists the mails waiting for the send delay to pass"),
+    ("cancel-send", "[n]", "Takes a mail of the outbox back for editing, the last one sent without number"),
+    ("save", "", "Saves the mail as draft"),
+    ("help", "[command]", "Shows available commands"),
+    ("discard", "", "Throws the mail away and leaves write mode"),
+    ("exit", "", "Leaves write mode, keeping the draft"),
+];
+
+pub const CONTACTS: &'static [CommandHelp] = &[
+    ("list", "", "Lists all contacts"),
+    ("search", "<text>...", "Lists the contacts whose name or address contains the text"),
+    ("add", "<email> [name...]", "Adds a contact"),
+    ("edit", "<index> <name|address|key> [value...]", "Changes a field of a contact, without value the name or key is removed"),
+    ("delete", "<index>", "Deletes a contact"),
+    ("group", "<list|add <name> <email>...|remove <name> [email...]>", "Manages contact groups, a group name used as recipient stands for its members"),
+    ("help", "[command]", "Shows available commands"),
+    ("exit", "", "Leaves contacts mode"),
+];
+
+// Prints all commands of a mode, or the details of a single one
+pub fn print_help(table: &[CommandHelp], command: Option<String>) {
+    match command {
+        Some(name) => match table.iter().find(|(cmd, _, _)| *cmd == name.as_str()) {
+            Some((cmd, params, description)) => {
+                println!("{}", tf!("usage: {} {}", cmd, params));
+                println!("\t{}", t!(description));
+            },
+            None => println!("{}", tf!("unknown command \"{}\", type \"help\" for a list of commands", name)),
+        },
+        None => {
+            let width = table.iter().map(|(cmd, params, _)| cmd.len() + params.len() + 1).max().unwrap_or(0);
+            for (cmd, params, description) in table.iter() {
+                let usage = format!("{} {}", cmd, params);
+                if output::is_plain() {
+                    println!("{}: {}", usage.trim_end(), t!(description));
+                } else {
+                    println!("  {:width$}  {}", usage, t!(description), width = width);
+                }
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{init_modes, Mode};
+
+    // The tables are kept by hand, every registered command needs an entry
+    #[test]
+    fn every_command_has_help() {
+        let (_, modes) = init_modes();
+        let tables = [(Mode::Global, GLOBAL), (Mode::Inbox, INBOX), (Mode::Read, READ), (Mode::Write, WRITE), (Mode::Contacts, CONTACTS)];
+        for (mode, table) in tables.iter() {
+            for name in modes.get(mode).unwrap().keys() {
+                assert!(table.iter().any(|(cmd, _, _)| *cmd == name.as_str()), "no help for \"{}\"", name);
+            }
+        }
+    }
+}
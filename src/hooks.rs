@@ -0,0 +1,52 @@
+// Runs the commands configured for events through the shell, without waiting for them.
+// MAIL_EVENT names the event, the other variables describe the mail
+use std::process::Stdio;
+
+use super::config::Hooks;
+use super::util;
+
+#[derive(Clone, Copy)]
+pub enum Event {
+    NewMail,
+    Send,
+    Open,
+}
+
+impl Event {
+    fn name(&self) -> &'static str {
+        match self {
+            Event::NewMail => "new_mail",
+            Event::Send => "send",
+            Event::Open => "open",
+        }
+    }
+
+    fn command<'a>(&self, hooks: &'a Hooks) -> Option<&'a String> {
+        match self {
+            Event::NewMail => hooks.on_new_mail.as_ref(),
+            Event::Send => hooks.on_send.as_ref(),
+            Event::Open => hooks.on_open.as_ref(),
+        }
+    }
+}
+
+pub fn run(hooks: &Hooks, event: Event, account: &str, vars: Vec<(&'static str, String)>) {
+    let command = match event.command(hooks).filter(|c| !c.trim().is_empty()) {
+        Some(command) => command,
+        None => return,
+    };
+    let mut shell = util::shell(command);
+    shell.env("MAIL_EVENT", event.name()).env("MAIL_ACCOUNT", account).envs(vars).stdin(Stdio::null());
+    match shell.spawn() {
+        // Waited for in the background so the hook doesn't linger as zombie
+        Ok(mut child) => {
+            let name = event.name();
+            std::thread::spawn(move || match child.wait() {
+                Ok(status) if !status.success() => log::warn!("{} hook exited with {}", name, status),
+                Err(e) => log::warn!("{} hook failed: {}", name, e),
+                _ => {},
+            });
+        },
+        Err(e) => log::warn!("could not run {} hook: {}", event.name(), e),
+    }
+}
@@ -0,0 +1,146 @@
+use std::{
+    io::{Read, Write, Result, Error, ErrorKind},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use native_tls::TlsConnector;
+
+const MAX_REDIRECTS: usize = 3;
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    // Body as received, for binary content
+    pub data: Vec<u8>,
+}
+
+impl Response {
+    pub fn header(&self, name: &str) -> Option<&String> {
+        self.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v)
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.status >= 200 && self.status < 300
+    }
+}
+
+pub fn get(url: &str) -> Result<Response> {
+    let mut url = url.to_string();
+    for _ in 0..MAX_REDIRECTS {
+        let response = request("GET", url.as_str(), &[], None)?;
+        let location = match response.status {
+            301 | 302 | 307 | 308 => response.header("Location").cloned(),
+            _ => None,
+        };
+        match location {
+            Some(location) => url = location,
+            None => return Ok(response),
+        }
+    }
+    Err(Error::new(ErrorKind::Other, "too many redirects"))
+}
+
+// Minimal HTTP/1.0 client, which avoids having to deal with keep-alive connections
+pub fn request(method: &str, url: &str, headers: &[(&str, &str)], body: Option<&str>) -> Result<Response> {
+    let (tls, host, port, path) = split_url(url).ok_or(Error::new(ErrorKind::InvalidInput, "invalid url"))?;
+
+    let mut req = format!("{} {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: cli-mail-rs\r\nAccept: */*\r\n", method, path, host);
+    for (key, val) in headers.iter() {
+        req.push_str(format!("{}: {}\r\n", key, val).as_str());
+    }
+    if let Some(body) = body {
+        req.push_str(format!("Content-Length: {}\r\n", body.len()).as_str());
+    }
+    req.push_str("\r\n");
+    if let Some(body) = body {
+        req.push_str(body);
+    }
+
+    let addr = (host.as_str(), port).to_socket_addrs()?.next().ok_or(Error::new(ErrorKind::NotFound, "could not resolve host"))?;
+    let stream = TcpStream::connect_timeout(&addr, Duration::from_secs(5))?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let mut raw = Vec::new();
+    if tls {
+        let connector = TlsConnector::new().map_err(|e| Error::new(ErrorKind::Other, e))?;
+        let mut stream = connector.connect(host.as_str(), stream).map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+        stream.write_all(req.as_bytes())?;
+        stream.read_to_end(&mut raw)?;
+    } else {
+        let mut stream = stream;
+        stream.write_all(req.as_bytes())?;
+        stream.read_to_end(&mut raw)?;
+    }
+    parse_response(raw)
+}
+
+fn split_url(url: &str) -> Option<(bool, String, u16, String)> {
+    let (tls, rest) = if url.starts_with("https://") {
+        (true, &url[8..])
+    } else if url.starts_with("http://") {
+        (false, &url[7..])
+    } else {
+        return None;
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rfind(':') {
+        Some(i) => (&authority[..i], authority[i + 1..].parse::<u16>().ok()?),
+        None => (authority, if tls { 443 } else { 80 }),
+    };
+    Some((tls, host.to_string(), port, path.to_string()))
+}
+
+fn parse_response(raw: Vec<u8>) -> Result<Response> {
+    let (head, data) = match raw.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(i) => (String::from_utf8_lossy(&raw[..i]).to_string(), raw[i + 4..].to_vec()),
+        None => (String::from_utf8_lossy(&raw).to_string(), Vec::new()),
+    };
+    let mut lines = head.lines();
+    let status = lines.next()
+        .and_then(|l| l.split_whitespace().nth(1))
+        .and_then(|s| s.parse::<u16>().ok())
+        .ok_or(Error::new(ErrorKind::InvalidData, "invalid http response"))?;
+    let headers = lines.filter_map(|l| {
+        let i = l.find(':')?;
+        Some((l[..i].trim().to_string(), l[i + 1..].trim().to_string()))
+    }).collect();
+    Ok(Response { status, headers, body: String::from_utf8_lossy(&data).to_string(), data })
+}
+
+// Percent encoding for query parameters and form bodies
+pub fn url_encode(s: &str) -> String {
+    s.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+// Invalid escapes are kept as they are
+pub fn url_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut ret = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok()).and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                ret.push(b);
+                i += 3;
+            },
+            (b, _) => {
+                ret.push(b);
+                i += 1;
+            },
+        }
+    }
+    String::from_utf8_lossy(&ret).to_string()
+}
+
+// Body of an application/x-www-form-urlencoded request
+pub fn form(pairs: &[(&str, &str)]) -> String {
+    pairs.iter().map(|(k, v)| format!("{}={}", url_encode(k), url_encode(v))).collect::<Vec<String>>().join("&")
+}
@@ -0,0 +1,135 @@
+extern crate serde_yaml;
+
+// Translations of the interface texts. The English text is its own key, so untranslated texts
+// just stay English. Catalogs map the English texts to translations and are looked up as
+// <language>.yml in the locale directory next to the account file, then among the built-in ones.
+// Placeholders are "{}" in order, translations may use "{0}", "{1}" ... to reorder them
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    env,
+    fmt::Display,
+    fs,
+    path::Path,
+    sync::RwLock,
+};
+
+use serde_yaml::Value;
+
+const BUILTIN: &'static [(&'static str, &'static str)] = &[
+    ("de", include_str!("../locale/de.yml")),
+];
+
+static CATALOG: RwLock<Option<HashMap<String, String>>> = RwLock::new(None);
+
+// Translation of text, or text itself
+pub fn translate(text: &str) -> Cow<'_, str> {
+    match CATALOG.read().unwrap().as_ref().and_then(|c| c.get(text)) {
+        Some(translated) => Cow::Owned(translated.clone()),
+        None => Cow::Borrowed(text),
+    }
+}
+
+// Fills "{}" in order and "{n}" by position, "{{" and "}}" are braces
+pub fn format(template: &str, args: &[&dyn Display]) -> String {
+    let mut ret = String::new();
+    let mut next = 0;
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('{', Some('{')) | ('}', Some('}')) => {
+                chars.next();
+                ret.push(c);
+            },
+            ('{', _) => {
+                let mut index = String::new();
+                while let Some(d) = chars.next_if(|d| *d != '}') {
+                    index.push(d);
+                }
+                chars.next();
+                let i = if index.is_empty() {
+                    next += 1;
+                    next - 1
+                } else {
+                    index.trim().parse::<usize>().unwrap_or(usize::MAX)
+                };
+                if let Some(arg) = args.get(i) {
+                    ret.push_str(arg.to_string().as_str());
+                }
+            },
+            (c, _) => ret.push(c),
+        }
+    }
+    ret
+}
+
+// Candidates like ["de_AT", "de"] from the setting or LC_ALL, LC_MESSAGES and LANG
+fn languages(setting: Option<&str>) -> Vec<String> {
+    let value = match setting {
+        Some(value) => value.to_string(),
+        None => match ["LC_ALL", "LC_MESSAGES", "LANG"].iter().filter_map(|var| env::var(var).ok()).find(|v| !v.is_empty()) {
+            Some(value) => value,
+            None => return Vec::new(),
+        },
+    };
+    let name = value.split(|c| c == '.' || c == '@').next().unwrap_or("").replace('-', "_");
+    let mut ret = vec![name.clone()];
+    if let Some(base) = name.split('_').next().filter(|b| *b != name) {
+        ret.push(base.to_string());
+    }
+    ret.retain(|l| !l.is_empty() && l != "C" && l != "POSIX");
+    ret
+}
+
+fn parse(content: &str) -> Option<HashMap<String, String>> {
+    let root: Value = serde_yaml::from_str(content).ok()?;
+    Some(root.as_mapping()?.iter()
+        .filter_map(|(k, v)| Some((k.as_str()?.to_string(), v.as_str()?.to_string())))
+        .collect())
+}
+
+// Selects the catalog, returns the language used or None for English
+pub fn set_language(setting: Option<&str>, dir: &Path) -> Option<String> {
+    let mut selected = None;
+    for language in languages(setting) {
+        let own = fs::read_to_string(dir.join(format!("{}.yml", language))).ok();
+        let builtin = BUILTIN.iter().find(|(l, _)| *l == language).map(|(_, c)| c.to_string());
+        let content = match own.or(builtin) {
+            Some(content) => content,
+            None => continue,
+        };
+        match parse(content.as_str()) {
+            Some(catalog) => {
+                selected = Some((language, catalog));
+                break;
+            },
+            None => log::warn!("invalid message catalog for language {}", language),
+        }
+    }
+    let ret = selected.as_ref().map(|(l, _)| l.clone());
+    *CATALOG.write().unwrap() = selected.map(|(_, c)| c);
+    ret
+}
+
+// Translated text
+macro_rules! t {
+    ($text:expr) => {
+        $crate::i18n::translate($text)
+    };
+}
+
+// Translated format string filled with the arguments
+macro_rules! tf {
+    ($template:expr, $($arg:expr),+ $(,)?) => {
+        $crate::i18n::format(&$crate::i18n::translate($template), &[$(&$arg as &dyn std::fmt::Display),+])
+    };
+}
+
+// Like tf, the singular or plural sentence picked by count, which fills the first "{}".
+// Both are keys of their own, so catalogs translate complete sentences
+macro_rules! tn {
+    ($count:expr, $singular:expr, $plural:expr $(, $arg:expr)* $(,)?) => {{
+        let count = $count;
+        $crate::i18n::format(&$crate::i18n::translate(if count == 1 { $singular } else { $plural }), &[&count as &dyn std::fmt::Display $(, &$arg as &dyn std::fmt::Display)*])
+    }};
+}
@@ -0,0 +1,118 @@
+// Images shown in the terminal with the inline image protocols of iTerm2 (also WezTerm), kitty
+// or sixel through img2sixel. Other terminals get the format, dimensions and size
+use std::{
+    env,
+    io::{self, Write},
+    process::{Command, Stdio},
+};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Iterm,
+    Kitty,
+    Sixel,
+}
+
+// Guessed from the environment, terminals don't announce it reliably
+pub fn detect() -> Option<Protocol> {
+    let term = env::var("TERM").unwrap_or_default().to_lowercase();
+    let program = env::var("TERM_PROGRAM").unwrap_or_default();
+    if program == "iTerm.app" || program == "WezTerm" {
+        Some(Protocol::Iterm)
+    } else if env::var("KITTY_WINDOW_ID").is_ok() || term.contains("kitty") {
+        Some(Protocol::Kitty)
+    } else if term.contains("sixel") || term.starts_with("foot") || term == "mlterm" || program == "mintty" {
+        Some(Protocol::Sixel)
+    } else {
+        None
+    }
+}
+
+// Format and size in pixels read from the file header
+pub fn dimensions(data: &[u8]) -> Option<(&'static str, u32, u32)> {
+    let be16 = |i: usize| data.get(i..i + 2).map(|b| u16::from_be_bytes([b[0], b[1]]) as u32);
+    let le16 = |i: usize| data.get(i..i + 2).map(|b| u16::from_le_bytes([b[0], b[1]]) as u32);
+    let be32 = |i: usize| data.get(i..i + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]));
+    let le32 = |i: usize| data.get(i..i + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Some(("PNG", be32(16)?, be32(20)?));
+    }
+    if data.starts_with(b"GIF8") {
+        return Some(("GIF", le16(6)?, le16(8)?));
+    }
+    if data.starts_with(b"BM") {
+        return Some(("BMP", le32(18)?, (le32(22)? as i32).unsigned_abs()));
+    }
+    if data.starts_with(b"\xff\xd8") {
+        // Segments up to the start of frame, which holds height and width
+        let mut i = 2;
+        while i + 9 < data.len() {
+            if data[i] != 0xff {
+                return None;
+            }
+            let marker = data[i + 1];
+            if (0xc0..=0xcf).contains(&marker) && marker != 0xc4 && marker != 0xc8 && marker != 0xcc {
+                return Some(("JPEG", be16(i + 7)?, be16(i + 5)?));
+            }
+            i += 2 + be16(i + 2)? as usize;
+        }
+    }
+    if data.starts_with(b"RIFF") && data.get(8..12) == Some(&b"WEBP"[..]) {
+        return match data.get(12..16)? {
+            b"VP8X" => Some(("WebP", 1 + (le32(24)? & 0xffffff), 1 + (le32(27)? & 0xffffff))),
+            b"VP8 " => Some(("WebP", le16(26)? & 0x3fff, le16(28)? & 0x3fff)),
+            b"VP8L" => {
+                let bits = le32(21)?;
+                Some(("WebP", 1 + (bits & 0x3fff), 1 + ((bits >> 14) & 0x3fff)))
+            },
+            _ => None,
+        };
+    }
+    None
+}
+
+pub fn describe(name: &str, data: &[u8]) -> String {
+    let size = if data.len() >= 1024 * 1024 {
+        format!("{:.1} MB", data.len() as f64 / (1024.0 * 1024.0))
+    } else {
+        format!("{:.1} KB", data.len() as f64 / 1024.0)
+    };
+    match dimensions(data) {
+        Some((format, width, height)) => format!("{}: {} image, {}x{} pixels, {}", name, format, width, height, size),
+        None => format!("{}: unknown image format, {}", name, size),
+    }
+}
+
+// Kitty decodes only PNG itself
+pub fn can_show(protocol: Protocol, data: &[u8]) -> bool {
+    protocol != Protocol::Kitty || data.starts_with(b"\x89PNG\r\n\x1a\n")
+}
+
+pub fn show(protocol: Protocol, data: &[u8]) -> io::Result<()> {
+    let mut out = io::stdout();
+    match protocol {
+        Protocol::Iterm => {
+            writeln!(out, "\x1b]1337;File=inline=1;size={};preserveAspectRatio=1:{}\x07", data.len(), base64::encode(data))?;
+        },
+        Protocol::Kitty => {
+            // Payload is sent in chunks of 4096 bytes, m=1 while more follow
+            let encoded = base64::encode(data);
+            let chunks: Vec<&[u8]> = encoded.as_bytes().chunks(4096).collect();
+            for (i, chunk) in chunks.iter().enumerate() {
+                let more = if i + 1 < chunks.len() { 1 } else { 0 };
+                let control = if i == 0 { format!("a=T,f=100,m={}", more) } else { format!("m={}", more) };
+                write!(out, "\x1b_G{};{}\x1b\\", control, String::from_utf8_lossy(chunk))?;
+            }
+            writeln!(out)?;
+        },
+        Protocol::Sixel => {
+            let mut child = Command::new("img2sixel").stdin(Stdio::piped()).spawn()?;
+            child.stdin.as_mut().ok_or(io::Error::new(io::ErrorKind::Other, "could not write to img2sixel"))?.write_all(data)?;
+            let status = child.wait()?;
+            if !status.success() {
+                return Err(io::Error::new(io::ErrorKind::Other, format!("img2sixel exited with {}", status)));
+            }
+        },
+    }
+    out.flush()
+}
@@ -3,33 +3,81 @@ extern crate serde;
 
 use std::{
     collections::HashMap,
-    fs::File,
+    fs,
     error::Error,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use super::account::{
     Account,
+    ExpungePolicy,
+    InboxConfig,
 };
+use super::vault;
+use super::attachments::Attachment;
+use super::sending::TransportAdapter;
+use super::config::{self, Settings, QuoteStyle};
+use super::util;
+use super::i18n;
+use super::hooks::{self, Event};
+use super::notmuch;
+use super::contacts::AddressBook;
+use super::labels::Labels;
+use super::searches::SavedSearches;
+use super::vacation::Schedules;
+use super::pgp::{self, Keyring};
+use super::output;
+use super::threading;
+use super::filter::MailFilter;
+use super::rules::{self, Action, Rule};
+use super::bayes::{self, SpamModel};
+use serde_json::{
+    json,
+    Value,
+};
+use regex::Regex;
+use super::validate::{
+    self,
+    ConfigError,
+};
+use super::error::{MailError, MailResult};
 use super::receiving::{
+    AddressAlias,
     InboxAdapter,
+    ReceivedMailHeader,
     ReceivedMailProxy,
     ReceivedMail,
 };
 
-use datetime::{
-    OffsetDateTime,
-    Offset,
-    LocalDateTime,
+use chrono::{
+    DateTime,
+    FixedOffset,
+    Local,
 };
 
 #[derive(Clone)]
 pub struct MailBuilder {
-    date: Option<OffsetDateTime>,
+    date: Option<DateTime<FixedOffset>>,
     from: Option<String>,
     to: Option<Vec<String>>,
     cc: Option<Vec<String>>,
     bcc: Option<Vec<String>>,
     subject: Option<String>,
     text: Option<String>,
+    // Original text of the mail that is answered, already quoted
+    quote: Option<String>,
+    // Reply is written above the quote
+    top_posting: bool,
+    // Boilerplate of the reply template the draft starts with
+    greeting: Option<String>,
+    sign_off: Option<String>,
+    // Recipients that already got the mail when sending was interrupted
+    sent_to: Vec<String>,
+    // Values for "{{key}}" placeholders given when writing started
+    vars: Vec<(String, String)>,
+    attachments: Vec<Attachment>,
 }
 
 impl MailBuilder {
@@ -42,10 +90,17 @@ impl MailBuilder {
             bcc: None,
             subject: None,
             text: None,
+            quote: None,
+            top_posting: true,
+            greeting: None,
+            sign_off: None,
+            sent_to: Vec::new(),
+            vars: Vec::new(),
+            attachments: Vec::new(),
         }
     }
 
-    pub fn date(&mut self, val: OffsetDateTime) -> &mut MailBuilder {
+    pub fn date(&mut self, val: DateTime<FixedOffset>) -> &mut MailBuilder {
         self.date = Some(val);
         self
     }
@@ -80,39 +135,169 @@ impl MailBuilder {
         self
     }
 
+    pub fn attach(&mut self, val: Attachment) -> &mut MailBuilder {
+        self.attachments.push(val);
+        self
+    }
+
+    pub fn get_attachments(&self) -> &Vec<Attachment> {
+        &self.attachments
+    }
+
+    // Replaces the attachments at the indices, or all of them, by one zip archive.
+    // Returns the number of bundled files
+    pub fn zip_attachments(&mut self, indices: Option<Vec<usize>>, name: &str) -> std::io::Result<usize> {
+        let indices = indices.unwrap_or((0..self.attachments.len()).collect());
+        if let Some(i) = indices.iter().find(|i| **i >= self.attachments.len()) {
+            return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("no attachment [{}]", i)));
+        }
+        let files: Vec<Attachment> = indices.iter().map(|i| self.attachments[*i].clone()).collect();
+        let archive = Attachment::zip(name, &files)?;
+        let mut index = 0;
+        self.attachments.retain(|_| {
+            index += 1;
+            !indices.contains(&(index - 1))
+        });
+        self.attachments.push(archive);
+        Ok(files.len())
+    }
+
+    pub fn quote(&mut self, val: String, top_posting: bool) -> &mut MailBuilder {
+        self.quote = Some(val);
+        self.top_posting = top_posting;
+        self
+    }
+
+    pub fn frame(&mut self, greeting: Option<String>, sign_off: Option<String>) -> &mut MailBuilder {
+        self.greeting = greeting;
+        self.sign_off = sign_off;
+        self
+    }
+
+    // Text to start editing with, the quoted original and the greeting and sign-off around the
+    // space to write in if nothing has been written yet
+    pub fn get_draft_text(&self) -> String {
+        let own = match (&self.greeting, &self.sign_off) {
+            (Some(greeting), Some(sign_off)) => format!("{}\n\n\n\n{}", greeting, sign_off),
+            (Some(greeting), None) => format!("{}\n\n", greeting),
+            (None, Some(sign_off)) => format!("\n\n{}", sign_off),
+            (None, None) => String::new(),
+        };
+        match (&self.text, &self.quote) {
+            (Some(text), _) => text.clone(),
+            (None, Some(quote)) if self.top_posting => format!("{}\n\n{}", own, quote),
+            (None, Some(quote)) => format!("{}\n\n{}", quote, own),
+            (None, None) => own,
+        }
+    }
+
     pub fn build(self) -> Result<Mail, (MailBuilder, String)> {
         let cloned = self.clone();
         let mail = Mail {
-            date: self.date.unwrap_or(Offset::of_hours_and_minutes(1, 0).unwrap().transform_date(LocalDateTime::now())),
+            date: self.date.unwrap_or(Local::now().into()),
             from: self.from.ok_or((cloned.clone(), String::from("from")))?,
             to: self.to.ok_or((cloned.clone(), String::from("to")))?,
             cc: self.cc.unwrap_or(Vec::new()),
             bcc: self.bcc.unwrap_or(Vec::new()),
             subject: self.subject.ok_or((cloned.clone(), String::from("about")))?,
             text: self.text.ok_or((cloned.clone(), String::from("text")))?,
+            attachments: self.attachments,
         };
         Ok(mail)
     }
 
+    pub fn recipient_count(&self) -> usize {
+        [&self.to, &self.cc, &self.bcc].iter().map(|r| r.as_ref().map(|v| v.len()).unwrap_or(0)).sum()
+    }
+
+    // True if nothing has been entered yet
+    pub fn is_empty(&self) -> bool {
+        self.to.is_none() && self.cc.is_none() && self.bcc.is_none() && self.subject.is_none() && self.text.is_none() && self.attachments.is_empty()
+    }
+
+    pub fn get_sent_to(&self) -> &Vec<String> {
+        &self.sent_to
+    }
+
+    pub fn set_sent_to(&mut self, val: Vec<String>) {
+        self.sent_to = val;
+    }
+
+    pub fn get_text(&self) -> Option<&String> {
+        self.text.as_ref()
+    }
+
+    pub fn get_subject(&self) -> Option<&String> {
+        self.subject.as_ref()
+    }
+
+    pub fn get_from(&self) -> Option<&String> {
+        self.from.as_ref()
+    }
+
+    pub fn first_recipient(&self) -> Option<&String> {
+        self.to.as_ref().and_then(|to| to.first())
+    }
+
+    // Later values of a key replace earlier ones
+    pub fn var(&mut self, key: String, value: String) -> &mut MailBuilder {
+        self.vars.retain(|(k, _)| *k != key);
+        self.vars.push((key, value));
+        self
+    }
+
+    pub fn get_vars(&self) -> &Vec<(String, String)> {
+        &self.vars
+    }
+
+    // Replaces placeholders in subject and text, returns the ones without a value
+    pub fn fill(&mut self, vars: &[(String, String)]) -> Vec<String> {
+        let mut unresolved = Vec::new();
+        for field in vec![&mut self.subject, &mut self.text] {
+            if let Some(value) = field {
+                let (filled, missing) = util::fill_placeholders(value.as_str(), vars);
+                *value = filled;
+                for key in missing.into_iter() {
+                    if !unresolved.contains(&key) {
+                        unresolved.push(key);
+                    }
+                }
+            }
+        }
+        unresolved
+    }
+
     pub fn show_preview(&self) {
         let null_str = String::from("<null>");
-        println!("From:\t{}", self.from.clone().unwrap_or(null_str.clone()));
-        println!("To:\t{}", self.to.clone().map(|x| x.join(", ")).unwrap_or(null_str.clone()));
-        println!("Cc:\t{}", self.cc.clone().map(|x| x.join(", ")).unwrap_or(null_str.clone()));
-        println!("Bcc:\t{}", self.bcc.clone().map(|x| x.join(", ")).unwrap_or(null_str.clone()));
-        println!("About:\t{}", self.subject.clone().unwrap_or(null_str.clone()));
-        println!("Text:\n{}", self.text.clone().unwrap_or(null_str.clone()));
+        println!("{}", tf!("From:\t{}", self.from.clone().unwrap_or(null_str.clone())));
+        println!("{}", tf!("To:\t{}", self.to.clone().map(|x| x.join(", ")).unwrap_or(null_str.clone())));
+        println!("{}", tf!("Cc:\t{}", self.cc.clone().map(|x| x.join(", ")).unwrap_or(null_str.clone())));
+        println!("{}", tf!("Bcc:\t{}", self.bcc.clone().map(|x| x.join(", ")).unwrap_or(null_str.clone())));
+        println!("{}", tf!("About:\t{}", self.subject.clone().unwrap_or(null_str.clone())));
+        println!("{}", tf!("Text:\n{}", self.text.clone().unwrap_or(null_str.clone())));
+        if !self.attachments.is_empty() {
+            println!("{}", tf!("Attachments:\t{}", self.attachments.iter().enumerate().map(|(i, a)| format!("[{}] {}", i, a.get_info())).collect::<Vec<String>>().join(", ")));
+        }
     }
 }
 
+// Mail waiting in the outbox for the send delay to pass, cancel-send takes it back
+pub struct PendingMail {
+    pub id: usize,
+    pub builder: MailBuilder,
+    // Time it is sent at, None after sending failed
+    pub due: Option<Instant>,
+}
+
 pub struct Mail {
-    date: OffsetDateTime,
+    date: DateTime<FixedOffset>,
     pub from: String,
     to: Vec<String>,
     cc: Vec<String>,
     bcc: Vec<String>,
     pub subject: String,
     text: String,
+    attachments: Vec<Attachment>,
 }
 
 impl Mail {
@@ -124,29 +309,153 @@ impl Mail {
         return ret;
     }
 
+    // Metadata handed to hook commands
+    pub fn environment(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("MAIL_DATE", util::iso_date(&self.date)),
+            ("MAIL_FROM", self.from.clone()),
+            ("MAIL_TO", self.to.iter().chain(self.cc.iter()).cloned().collect::<Vec<String>>().join(", ")),
+            ("MAIL_SUBJECT", self.subject.clone()),
+        ]
+    }
+
+    // All addresses the mail has to be delivered to
+    pub fn recipients(&self) -> Vec<String> {
+        self.to.iter().chain(self.cc.iter()).chain(self.bcc.iter()).map(|a| util::extract_address(a)).collect()
+    }
+
+    // Renders the mail as RFC 5322 message, Bcc recipients are left out on purpose
+    pub fn to_message(&self) -> String {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let from = util::extract_address(&self.from);
+        let domain = from.rsplit('@').next().unwrap_or("localhost");
+
+        let mut headers = vec![
+            format!("Date: {}", self.date.to_rfc2822()),
+            format!("From: {}", self.from),
+            format!("To: {}", self.to.join(", ")),
+        ];
+        if !self.cc.is_empty() {
+            headers.push(format!("Cc: {}", self.cc.join(", ")));
+        }
+        headers.push(format!("Subject: {}", util::encode_header(self.subject.as_str())));
+        headers.push(format!("Message-ID: <{}.{}@{}>", now.as_nanos(), std::process::id(), domain));
+        headers.push(String::from("MIME-Version: 1.0"));
+
+        let encode = |data: &[u8]| base64::encode(data).as_bytes().chunks(76).map(|c| String::from_utf8_lossy(c).to_string()).collect::<Vec<String>>().join("\r\n");
+        let (encoding, text) = if self.text.is_ascii() {
+            ("7bit", util::to_crlf(self.text.as_str()))
+        } else {
+            ("base64", encode(self.text.as_bytes()))
+        };
+        if self.attachments.is_empty() {
+            headers.push(String::from("Content-Type: text/plain; charset=utf-8"));
+            headers.push(format!("Content-Transfer-Encoding: {}", encoding));
+            return format!("{}\r\n\r\n{}\r\n", headers.join("\r\n"), text);
+        }
+        // Text first, then the files
+        let boundary = format!("=_{}.{}", now.as_nanos(), std::process::id());
+        headers.push(format!("Content-Type: multipart/mixed; boundary=\"{}\"", boundary));
+        let mut parts = vec![format!("Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: {}\r\n\r\n{}", encoding, text)];
+        for attachment in self.attachments.iter() {
+            let name = util::encode_header(attachment.name.as_str());
+            parts.push(format!("Content-Type: {}; name=\"{}\"\r\nContent-Disposition: attachment; filename=\"{}\"\r\nContent-Transfer-Encoding: base64\r\n\r\n{}", attachment.content_type, name, name, encode(&attachment.data)));
+        }
+        let body: String = parts.iter().map(|p| format!("--{}\r\n{}\r\n", boundary, p)).collect();
+        return format!("{}\r\n\r\n{}--{}--\r\n", headers.join("\r\n"), body, boundary);
+    }
+
     pub fn print_all(&self) {
-        println!("From:\t{}", self.from);
-        println!("To:\t{}", self.to.join(", "));
-        println!("Cc:\t{}", self.cc.join(", "));
-        println!("Bcc:\t{}", self.bcc.join(", "));
-        println!("Subject:\t{}", self.subject);
-        println!("Text:\n{}", self.text);
+        println!("{}", tf!("From:\t{}", self.from));
+        println!("{}", tf!("To:\t{}", self.to.join(", ")));
+        println!("{}", tf!("Cc:\t{}", self.cc.join(", ")));
+        println!("{}", tf!("Bcc:\t{}", self.bcc.join(", ")));
+        println!("{}", tf!("Subject:\t{}", self.subject));
+        println!("{}", tf!("Text:\n{}", self.text));
     }
 }
 
+pub enum BulkAction {
+    // Moves to the given trash folder or the one found on the server, deletes in place without one
+    Delete(Option<String>),
+    Move(String),
+    Archive,
+    Flag(bool),
+    // Moves to the given junk folder, or the one found on the server
+    Spam(Option<String>),
+    NotSpam,
+}
+
+// Reverse information of a destructive operation
+enum UndoEntry {
+    // Mails taken out of the listing with their former index, and the folder they were moved to
+    Removed(Vec<(usize, (ReceivedMailProxy, bool))>, Option<String>),
+    Flagged(Vec<u32>, bool),
+}
+
 pub struct Inbox {
     mails: Vec<(ReceivedMailProxy, bool)>,
+    undo: Vec<UndoEntry>,
+    folders: Vec<String>,
+    filter: Option<MailFilter>,
     account: Account,
     opened_mail: Option<usize>,
+    // Thread whose mails are listed below it
+    expanded: Option<usize>,
+    // Ids of the mails bulk commands without mails apply to
+    selection: Vec<u32>,
+    // Mails of the trash folder as last listed by show-trash
+    trash: Vec<ReceivedMailProxy>,
     input: Option<InboxAdapter>,
 }
 
+// Headers and folders fetched by a Loader
+type Fetched = MailResult<(Vec<ReceivedMailHeader>, Vec<String>)>;
+
+// Refresh of an inbox that talks to the server without the context locked. It opens a connection
+// of its own, which is closed after loading unless the inbox has none yet
+pub struct Loader {
+    account: Account,
+    adapter: Option<InboxAdapter>,
+}
+
+impl Loader {
+    fn fetch(&mut self) -> Fetched {
+        self.adapter = Some(self.account.get_inbox_adapter()?);
+        let adapter = self.adapter.as_mut().ok_or(MailError::NotConnected)?;
+        let headers = adapter.load_inbox()?;
+        Ok((headers, adapter.list_folders()?))
+    }
+}
+
+// Row of a mail listing, labeled words instead of columns and marks in plain output.
+// Selected mails get a ">" in front
+fn listing_line(index: usize, mail: &ReceivedMailProxy, selected: bool) -> String {
+    if output::is_plain() {
+        let mut marks = mail.marks();
+        if selected {
+            marks.insert(0, "selected");
+        }
+        let id = mail.get_id().map(|id| format!(" (id {})", id)).unwrap_or_default();
+        format!("Mail {}{}: {}{}", index, id, mail.get_info(), if marks.is_empty() { String::new() } else { format!(", {}", marks.join(", ")) })
+    } else {
+        let id = mail.get_id().map(|id| format!("#{}", id)).unwrap_or_default();
+        format!("\t{}{:>3} {:>7} {} {}", if selected { ">" } else { " " }, index, id, mail.indicator(), mail.get_info())
+    }
+}
+
 impl Inbox {
     pub fn new(account: Account) -> Inbox {
         Inbox {
             mails: Vec::new(),
+            undo: Vec::new(),
+            folders: Vec::new(),
+            filter: None,
             account,
             opened_mail: None,
+            expanded: None,
+            selection: Vec::new(),
+            trash: Vec::new(),
             input: None,
         }
     }
@@ -155,103 +464,862 @@ impl Inbox {
         self.account.name.clone()
     }
 
+    pub fn get_account(&self) -> &Account {
+        &self.account
+    }
+
+    pub fn get_folders(&self) -> &Vec<String> {
+        &self.folders
+    }
+
+    pub fn get_people(&self) -> Vec<AddressAlias> {
+        self.mails.iter().flat_map(|(m, _)| m.get_people()).collect()
+    }
+
     // Returns number of new mails
-    pub fn refresh(&mut self) -> usize {
-        let mut num: usize = 0;
+    pub fn refresh(&mut self) -> MailResult<usize> {
+        Ok(self.refresh_new()?.len())
+    }
+
+    // False until the first refresh connected to the server
+    pub fn is_connected(&self) -> bool {
+        self.input.is_some()
+    }
+
+    // Loads the inbox and returns the indices of mails not known before
+    pub fn refresh_new(&mut self) -> MailResult<Vec<usize>> {
         // Init InboxAdapter, if not yet initiated
         if self.input.is_none() {
-            println!("Initiating InboxAdapter ...");
-            let adapter = self.account.get_inbox_adapter();
-            if let Err(e) = &adapter {
-                println!("Could not refresh inbox for \"{}\" [{}]", self.account.name, e);
+            self.input = Some(self.account.get_inbox_adapter()?);
+        }
+        let adapter = self.input.as_mut().ok_or(MailError::NotConnected)?;
+        let vec = adapter.load_inbox()?;
+        self.folders = adapter.list_folders()?;
+        Ok(self.add_headers(vec))
+    }
+
+    // Loader for a refresh, see InboxManager::take_loaders. POP3 servers lock the maildrop for a
+    // single session, a connected POP3 inbox has no loader and loads over its own connection
+    pub fn loader(&self) -> Option<Loader> {
+        match (&self.account.inbox_domain, self.is_connected()) {
+            (InboxConfig::Pop3(_, _), true) => None,
+            (_, _) => Some(Loader {
+                account: self.account.clone(),
+                adapter: None,
+            }),
+        }
+    }
+
+    // Loads over the connection of the inbox, for those without a loader
+    pub fn fetch(&mut self) -> Fetched {
+        let adapter = self.input.as_mut().ok_or(MailError::NotConnected)?;
+        let headers = adapter.load_inbox()?;
+        Ok((headers, adapter.list_folders()?))
+    }
+
+    // Adds what a loader fetched. Its connection becomes the one of the inbox if there is none yet,
+    // otherwise it is closed
+    pub fn merge(&mut self, loader: Loader, fetched: Fetched) -> MailResult<Vec<usize>> {
+        let (headers, folders) = fetched?;
+        if self.input.is_none() {
+            self.input = loader.adapter;
+        }
+        self.folders = folders;
+        Ok(self.add_headers(headers))
+    }
+
+    fn add_headers(&mut self, vec: Vec<ReceivedMailHeader>) -> Vec<usize> {
+        // Only mails not listed yet are added, so refreshing twice doesn't duplicate them
+        let known: Vec<Option<u32>> = self.mails.iter().map(|(m, _)| m.get_id()).collect();
+        let authserv_id = self.account.authserv_id();
+        let mut loaded: Vec<(ReceivedMailProxy, bool)> = vec.into_iter()
+            .filter(|h| !known.contains(&Some(h.get_id())))
+            .map(|mut x| {
+                x.trust_auth(authserv_id.as_str());
+                (ReceivedMailProxy::from_header(x), true)
+            })
+            .collect();
+        let new_ids: Vec<u32> = loaded.iter().filter_map(|(m, _)| m.get_id()).collect();
+        self.mails.append(&mut loaded);
+        // Later mails go to the end, so the indices of the last listing stay valid until the next one
+        if known.is_empty() {
+            self.sort_mails();
+        }
+
+        self.mails.iter().enumerate().filter(|(_, (m, _))| m.get_id().map(|id| new_ids.contains(&id)).unwrap_or(false)).map(|(i, _)| i).collect()
+    }
+
+    // Sorts the mails appended by refreshes in, done by the listings
+    fn sort_mails(&mut self) {
+        let opened_id = self.opened_mail.and_then(|i| self.mails.get(i)).and_then(|(m, _)| m.get_id());
+        self.mails.sort_by(|(a, _), (b, _)| a.cmp(b));
+        // Mail being read stays open, its index may have moved
+        self.opened_mail = opened_id.and_then(|id| self.mails.iter().position(|(m, _)| m.get_id() == Some(id)));
+    }
+
+    // Runs the first matching rule on each of the new mails. Actions on the server are done here,
+    // returns (rule name, mail info, message id, actions) of the applied rules for the rest
+    pub fn apply_rules(&mut self, rules: &Vec<Rule>, new: &Vec<usize>) -> Vec<(String, String, Option<String>, Vec<Action>)> {
+        let mut ret = Vec::new();
+        let mut removed: Vec<usize> = Vec::new();
+        for index in new.iter() {
+            let (proxy, _) = match self.mails.get(*index) {
+                Some(mail) => mail,
+                None => continue,
+            };
+            let rule = match proxy.get_header().and_then(|h| rules::first_match(rules, h)) {
+                Some(rule) => rule,
+                None => continue,
+            };
+            let id = match proxy.get_id() {
+                Some(id) => vec![id],
+                None => continue,
+            };
+            ret.push((rule.name.clone(), proxy.get_info(), proxy.get_thread_ids().0, rule.actions.clone()));
+            let adapter = match self.input.as_mut() {
+                Some(adapter) => adapter,
+                None => continue,
+            };
+            for action in rule.actions.iter() {
+                let result = match action {
+                    Action::MarkRead => {
+                        let result = adapter.set_flag(&id, "\\Seen", true);
+                        if result.is_ok() {
+                            self.mails[*index].1 = false;
+                        }
+                        result
+                    },
+                    Action::Move(folder) => adapter.move_to(&id, folder.as_str()).map(|_| removed.push(*index)),
+                    Action::Delete => adapter.delete(&id).map(|_| removed.push(*index)),
+                    Action::Tag(_) | Action::Notify => Ok(()),
+                };
+                if let Err(e) = result {
+                    log::warn!("rule \"{}\" could not {} mail {}: {}", rule.name, action, id[0], e);
+                }
             }
-            self.input = adapter.ok();
         }
-        // Load Inbox if Adapter is valid
-        if let Some(adapter) = &mut self.input {
-            println!("Loading with Adapter ...");
-            if let Some(vec) = adapter.load_inbox() {
-                println!("Load inbox successful ...");
-                let mut loaded: Vec<(ReceivedMailProxy, bool)> = vec.into_iter().map(|x| (ReceivedMailProxy::from_header(x), true)).collect();
-                num += loaded.len();
-                self.mails.append(&mut loaded);
+        // Moved and deleted mails leave the listing, from the back so indices stay valid
+        removed.sort();
+        removed.dedup();
+        let opened_id = self.opened_mail.and_then(|i| self.mails.get(i)).and_then(|(m, _)| m.get_id());
+        for index in removed.into_iter().rev() {
+            self.mails.remove(index);
+        }
+        self.opened_mail = opened_id.and_then(|id| self.mails.iter().position(|(m, _)| m.get_id() == Some(id)));
+        ret
+    }
+
+    // Rules matching the mail at the index, nothing is changed
+    pub fn test_rules<'a>(&self, rules: &'a Vec<Rule>, index: usize) -> MailResult<Vec<&'a Rule>> {
+        let (proxy, _) = self.mails.get(index).ok_or(MailError::NotFound(format!("mail with index {}", index)))?;
+        let header = proxy.get_header().ok_or(MailError::NotFound(String::from("mail header")))?;
+        Ok(rules.iter().filter(|r| r.matches(header)).collect())
+    }
+
+    // Annotates the mails at the indices with the score of the spam filter
+    pub fn score_spam(&mut self, model: &SpamModel, new: &Vec<usize>) {
+        for index in new.iter() {
+            if let Some((proxy, _)) = self.mails.get_mut(*index) {
+                let score = model.score(&bayes::tokenize(&proxy.spam_text()));
+                proxy.set_spam_score(score);
             }
         }
-        self.mails.sort_by(|(a, _), (b, _)| a.cmp(b));
+    }
 
-        return num;
+    // Message-ID and tokens of the selected mails for training the spam filter
+    pub fn spam_tokens(&self, set: &str) -> MailResult<Vec<(Option<String>, Vec<String>)>> {
+        Ok(self.select(set)?.into_iter().map(|i| {
+            let proxy = &self.mails[i].0;
+            (proxy.get_thread_ids().0, bayes::tokenize(&proxy.spam_text()))
+        }).collect())
+    }
+
+    // Learns from mails moved by the user: mails of the junk folder as spam unless known so
+    // already, inbox mails learned as spam before as ham. The $Junk and $NotJunk keywords
+    // follow, returns the numbers of mails learned as spam and as ham
+    pub fn sync_junk(&mut self, model: &mut SpamModel, configured: Option<String>) -> MailResult<(usize, usize)> {
+        let folder = configured.unwrap_or(self.junk_folder());
+        let adapter = self.input.as_mut().ok_or(MailError::NotConnected)?;
+        let mut spam = Vec::new();
+        for header in adapter.load_folder(folder.as_str())?.into_iter() {
+            let proxy = ReceivedMailProxy::from_header(header);
+            if let (Some(id), Some(message_id)) = (proxy.get_id(), proxy.get_thread_ids().0) {
+                if model.learn(message_id.as_str(), &bayes::tokenize(&proxy.spam_text()), true) {
+                    spam.push(id);
+                }
+            }
+        }
+        let mut ham = Vec::new();
+        for (proxy, _) in self.mails.iter() {
+            if let (Some(id), Some(message_id)) = (proxy.get_id(), proxy.get_thread_ids().0) {
+                if model.learned_as(message_id.as_str()) == Some(true) && model.learn(message_id.as_str(), &bayes::tokenize(&proxy.spam_text()), false) {
+                    ham.push(id);
+                }
+            }
+        }
+        // Keywords help the server side filter learn, not every server takes them
+        if !spam.is_empty() {
+            if let Err(e) = adapter.set_folder_flag(folder.as_str(), &spam, "$NotJunk", false).and_then(|_| adapter.set_folder_flag(folder.as_str(), &spam, "$Junk", true)) {
+                log::info!("could not set $Junk keyword: {}", e);
+            }
+        }
+        if !ham.is_empty() {
+            if let Err(e) = adapter.set_flag(&ham, "$Junk", false).and_then(|_| adapter.set_flag(&ham, "$NotJunk", true)) {
+                log::info!("could not set $NotJunk keyword: {}", e);
+            }
+        }
+        Ok((spam.len(), ham.len()))
+    }
+
+    pub fn get_mail_proxy(&self, index: usize) -> Option<&ReceivedMailProxy> {
+        self.mails.get(index).map(|(m, _)| m)
     }
 
     pub fn print_account(&self) {
         self.account.print();
     }
 
-    pub fn show_mails(&self, named: bool) {
-        if self.mails.is_empty() {
-            println!("No mails in inbox of \"{}\"", self.get_account_name());
+    pub fn set_filter(&mut self, filter: Option<MailFilter>) {
+        self.filter = filter;
+    }
+
+    pub fn get_filter(&self) -> Option<&MailFilter> {
+        self.filter.as_ref()
+    }
+
+    // Mails passing the listing filter
+    fn visible(&self) -> impl Iterator<Item = (usize, &(ReceivedMailProxy, bool))> {
+        let filter = self.filter.as_ref();
+        self.mails.iter().enumerate().filter(move |(_, (m, _))| filter.map(|f| f.matches(m)).unwrap_or(true))
+    }
+
+    pub fn mails_json(&self, unread_only: bool) -> Vec<Value> {
+        self.visible().filter(|(_, (_, unread))| !unread_only || *unread).map(|(index, (m, unread))| {
+            let mut val = m.to_json();
+            val["index"] = json!(index);
+            val["unread"] = json!(unread);
+            val["account"] = json!(self.get_account_name());
+            val
+        }).collect()
+    }
+
+    pub fn show_mails(&mut self, named: bool) {
+        self.sort_mails();
+        if output::is_json() {
+            output::print_json(&Value::Array(self.mails_json(false)));
+            return;
+        }
+        let visible: Vec<(usize, &ReceivedMailProxy)> = self.visible().map(|(i, (m, _))| (i, m)).collect();
+        if visible.is_empty() {
+            println!("{}", tf!("No mails in inbox of \"{}\"", self.get_account_name()));
         } else {
             if named {
                 println!("\"{}\"", self.get_account_name());
             }
-            self.mails.iter().for_each(|(m, _)| println!("\t{}", m.get_info()));
+            visible.iter().for_each(|(i, m)| println!("{}", listing_line(*i, m, self.is_selected(m))));
+        }
+        if let Some(filter) = &self.filter {
+            println!("{}", tf!("(filtered by {})", filter));
         }
     }
 
-    pub fn show_unread(&self, named: bool) {
-        let unread: Vec<&ReceivedMailProxy> = self.mails.iter().filter(|(_, unread)| *unread).map(|(m, _)| m).collect();
+    // Listing within all accounts, every line starts with the account tag
+    pub fn show_mails_tagged(&mut self) {
+        self.sort_mails();
+        let tag = self.account.tag();
+        let visible: Vec<(usize, &ReceivedMailProxy)> = self.visible().map(|(i, (m, _))| (i, m)).collect();
+        if visible.is_empty() {
+            println!("{} {}", tag, tf!("No mails in inbox of \"{}\"", self.get_account_name()));
+        }
+        visible.iter().for_each(|(i, m)| println!("{} {}", tag, listing_line(*i, m, self.is_selected(m)).trim_start()));
+        if let Some(filter) = &self.filter {
+            println!("{} {}", tag, tf!("(filtered by {})", filter));
+        }
+    }
+
+    pub fn show_unread(&mut self, named: bool) {
+        self.sort_mails();
+        if output::is_json() {
+            output::print_json(&Value::Array(self.mails_json(true)));
+            return;
+        }
+        let unread: Vec<(usize, &ReceivedMailProxy)> = self.visible().filter(|(_, (_, unread))| *unread).map(|(i, (m, _))| (i, m)).collect();
         if unread.is_empty() {
-            println!("No unread mails in inbox!");
+            println!("{}", t!("No unread mails in inbox!"));
         } else {
             if named {
                 println!("\"{}\"", self.get_account_name());
             }
-            unread.iter().for_each(|m| println!("\t{}", m.get_info()));
+            unread.iter().for_each(|(i, m)| println!("{}", listing_line(*i, m, self.is_selected(m))));
+        }
+        if let Some(filter) = &self.filter {
+            println!("{}", tf!("(filtered by {})", filter));
         }
     }
 
-    pub fn open_mail(&mut self, ident: String) {
-        // Check if ident is int
-        let index;
-        if let Ok(id) = ident.parse::<usize>() {
-            if id < self.mails.len() {
-                index = Some(id);
-            } else {
-                index = None;
+    // Listing of the mails whose Message-ID is among the ids
+    pub fn show_labeled(&self, label: &str, ids: &Vec<String>) {
+        let labeled: Vec<(usize, &ReceivedMailProxy)> = self.mails.iter().enumerate()
+            .filter(|(_, (m, _))| m.get_thread_ids().0.map(|id| ids.contains(&id)).unwrap_or(false))
+            .map(|(i, (m, _))| (i, m))
+            .collect();
+        if output::is_json() {
+            output::print_json(&Value::Array(labeled.iter().map(|(i, m)| {
+                let mut val = m.to_json();
+                val["index"] = json!(i);
+                val["label"] = json!(label);
+                val
+            }).collect()));
+            return;
+        }
+        if labeled.is_empty() {
+            println!("{}", tf!("No mails labeled \"{}\" in inbox of \"{}\"", label, self.get_account_name()));
+        }
+        labeled.iter().for_each(|(i, m)| println!("{}", listing_line(*i, m, self.is_selected(m))));
+    }
+
+    // Index and line of every cached line matching, mails hidden by the filter included
+    pub fn grep(&self, pattern: &Regex) -> Vec<(usize, String)> {
+        self.mails.iter().enumerate()
+            .flat_map(|(i, (m, _))| m.cached_lines().into_iter().filter(|l| pattern.is_match(l)).map(move |l| (i, l)))
+            .collect()
+    }
+
+    pub fn find_message(&self, message_id: &str) -> Option<(usize, String)> {
+        self.mails.iter().position(|(m, _)| m.get_thread_ids().0.as_ref().map(|id| id.as_str()) == Some(message_id))
+            .map(|i| (i, self.mails[i].0.get_info()))
+    }
+
+    // Indices of a set like "3-7,12,#4711", "#" marks the id shown in the listing which, unlike
+    // the index, stays the same when mails are added or sorted
+    pub fn select(&self, set: &str) -> MailResult<Vec<usize>> {
+        let mut ret = Vec::new();
+        let mut positions = Vec::new();
+        for part in set.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+            match part.strip_prefix('#') {
+                Some(id) => {
+                    let id = id.parse::<u32>().map_err(|_| MailError::Selection(format!("\"{}\" is not a valid id", part)))?;
+                    ret.push(self.mails.iter().position(|(m, _)| m.get_id() == Some(id)).ok_or(MailError::NotFound(format!("mail with id {}", id)))?);
+                },
+                None => positions.push(part),
+            }
+        }
+        if !positions.is_empty() || ret.is_empty() {
+            ret.extend(util::parse_index_set(positions.join(",").as_str(), self.mails.len()).map_err(MailError::Selection)?);
+        }
+        ret.sort();
+        ret.dedup();
+        Ok(ret)
+    }
+
+    fn is_selected(&self, mail: &ReceivedMailProxy) -> bool {
+        mail.get_id().map(|id| self.selection.contains(&id)).unwrap_or(false)
+    }
+
+    // Adds mails to the selection, returns its new size
+    pub fn add_to_selection(&mut self, indices: &[usize]) -> usize {
+        for id in indices.iter().filter_map(|i| self.mails.get(*i)).filter_map(|(m, _)| m.get_id()) {
+            if !self.selection.contains(&id) {
+                self.selection.push(id);
+            }
+        }
+        self.selection.len()
+    }
+
+    // Indices of the mails passing a filter, hidden ones included
+    pub fn matching(&self, filter: &MailFilter) -> Vec<usize> {
+        self.mails.iter().enumerate().filter(|(_, (m, _))| filter.matches(m)).map(|(i, _)| i).collect()
+    }
+
+    // Selected mails still in the inbox as set for bulk, None if nothing is selected
+    pub fn selection_set(&self) -> Option<String> {
+        let ids: Vec<String> = self.selection.iter()
+            .filter(|id| self.mails.iter().any(|(m, _)| m.get_id() == Some(**id)))
+            .map(|id| format!("#{}", id))
+            .collect();
+        if ids.is_empty() { None } else { Some(ids.join(",")) }
+    }
+
+    pub fn clear_selection(&mut self) {
+        self.selection.clear();
+    }
+
+    // Listing of the selected mails
+    pub fn show_selection(&self) {
+        let selected: Vec<(usize, &ReceivedMailProxy)> = self.mails.iter().enumerate().filter(|(_, (m, _))| self.is_selected(m)).map(|(i, (m, _))| (i, m)).collect();
+        if output::is_json() {
+            output::print_json(&Value::Array(selected.iter().map(|(i, m)| {
+                let mut val = m.to_json();
+                val["index"] = json!(i);
+                val
+            }).collect()));
+            return;
+        }
+        if selected.is_empty() {
+            println!("{}", t!("No mails selected!"));
+        }
+        selected.iter().for_each(|(i, m)| println!("{}", listing_line(*i, m, true)));
+    }
+
+    // Message ids of the mails in an index set, and the number of mails without one
+    pub fn message_ids(&self, set: &str) -> MailResult<(Vec<String>, usize)> {
+        let indices = self.select(set)?;
+        let ids: Vec<String> = indices.iter().filter_map(|i| self.mails[*i].0.get_thread_ids().0).collect();
+        let missing = indices.len() - ids.len();
+        Ok((ids, missing))
+    }
+
+    // Applies an action to all mails of an index set like "3-7,12", returns the number of affected mails
+    pub fn bulk(&mut self, set: &str, action: BulkAction) -> MailResult<usize> {
+        let indices = self.select(set)?;
+        let ids: Vec<u32> = indices.iter().filter_map(|i| self.mails[*i].0.get_id()).collect();
+        let archive = self.archive_folder();
+        let junk = self.junk_folder();
+        let trash = self.trash_folder();
+        let deleting = if let BulkAction::Delete(_) = action { true } else { false };
+        let adapter = self.input.as_mut().ok_or(MailError::NotConnected)?;
+        let target = match action {
+            BulkAction::Delete(folder) => match folder.or(trash) {
+                Some(folder) => {
+                    adapter.move_to(&ids, folder.as_str())?;
+                    Some(folder)
+                },
+                None => {
+                    adapter.delete(&ids)?;
+                    None
+                },
+            },
+            BulkAction::Move(folder) => {
+                adapter.move_to(&ids, folder.as_str())?;
+                Some(folder)
+            },
+            BulkAction::Archive => {
+                adapter.move_to(&ids, archive.as_str())?;
+                Some(archive)
+            },
+            BulkAction::Flag(set) => {
+                adapter.set_flag(&ids, "\\Flagged", set)?;
+                self.undo.push(UndoEntry::Flagged(ids, set));
+                return Ok(indices.len());
+            },
+            BulkAction::Spam(folder) => {
+                // Keywords help the server side filter learn, not every server takes them
+                if let Err(e) = adapter.set_flag(&ids, "$Junk", true) {
+                    log::info!("could not set $Junk keyword: {}", e);
+                }
+                let folder = folder.unwrap_or(junk);
+                adapter.move_to(&ids, folder.as_str())?;
+                Some(folder)
+            },
+            BulkAction::NotSpam => {
+                adapter.set_flag(&ids, "$Junk", false)?;
+                adapter.set_flag(&ids, "$NotJunk", true)?;
+                return Ok(indices.len());
+            },
+        };
+        // Remove from the back so indices stay valid
+        let mut removed = Vec::with_capacity(indices.len());
+        for i in indices.iter().rev() {
+            removed.push((*i, self.mails.remove(*i)));
+        }
+        removed.reverse();
+        self.undo.push(UndoEntry::Removed(removed, target));
+        self.opened_mail = None;
+        // Moved mails are gone from the inbox already, only those deleted without a trash
+        // folder are expunged
+        if deleting && self.account.expunge == ExpungePolicy::Immediately {
+            self.input.as_mut().ok_or(MailError::NotConnected)?.expunge()?;
+            // Expunged mails can't be restored
+            self.undo.pop();
+        }
+        Ok(indices.len())
+    }
+
+    // Reverses the last destructive operation, returns the translated sentence saying what has been undone
+    pub fn undo(&mut self) -> MailResult<String> {
+        let adapter = self.input.as_mut().ok_or(MailError::NotConnected)?;
+        let entry = self.undo.pop().ok_or(MailError::NothingToUndo)?;
+        match entry {
+            UndoEntry::Flagged(ids, set) => {
+                if let Err(e) = adapter.set_flag(&ids, "\\Flagged", !set) {
+                    self.undo.push(UndoEntry::Flagged(ids, set));
+                    return Err(e);
+                }
+                Ok(match set {
+                    true => tn!(ids.len(), "Undone: unflagged {} mail.", "Undone: unflagged {} mails."),
+                    false => tn!(ids.len(), "Undone: flagged {} mail.", "Undone: flagged {} mails."),
+                })
+            },
+            UndoEntry::Removed(removed, target) => {
+                let ids: Vec<u32> = removed.iter().filter_map(|(_, (m, _))| m.get_id()).collect();
+                let result = match &target {
+                    Some(folder) => {
+                        let message_ids: Vec<String> = removed.iter().filter_map(|(_, (m, _))| m.get_thread_ids().0).collect();
+                        adapter.move_back(folder.as_str(), &ids, &message_ids)
+                    },
+                    None => adapter.undelete(&ids).map(|_| true),
+                };
+                let kept_ids = match result {
+                    Ok(kept_ids) => kept_ids,
+                    Err(e) => {
+                        self.undo.push(UndoEntry::Removed(removed, target));
+                        return Err(e);
+                    },
+                };
+                let count = removed.len();
+                if kept_ids {
+                    for (index, mail) in removed.into_iter() {
+                        let index = index.min(self.mails.len());
+                        self.mails.insert(index, mail);
+                    }
+                } else {
+                    // Mails moved back got new ids, they are listed again from the server
+                    self.refresh_new()?;
+                }
+                self.opened_mail = None;
+                Ok(match target {
+                    Some(folder) => tn!(count, "Undone: restored {} mail from \"{}\".", "Undone: restored {} mails from \"{}\".", folder),
+                    None => tn!(count, "Undone: restored {} mail.", "Undone: restored {} mails."),
+                })
+            },
+        }
+    }
+
+    // Removes the mails deleted in this session for good. They can't be restored afterwards
+    pub fn expunge(&mut self) -> MailResult<()> {
+        let adapter = self.input.as_mut().ok_or(MailError::NotConnected)?;
+        adapter.expunge()?;
+        self.undo.clear();
+        Ok(())
+    }
+
+    // After the user agreed, see MailError::PlainExpunge
+    pub fn allow_plain_expunge(&mut self) {
+        if let Some(adapter) = self.input.as_mut() {
+            adapter.allow_plain_expunge();
+        }
+    }
+
+    // Leaving the inbox removes deleted mails for good, unless the account keeps them marked.
+    // They stay undoable if expunging fails
+    pub fn close(&mut self) -> MailResult<()> {
+        let pending = self.undo.iter().any(|e| if let UndoEntry::Removed(_, _) = e { true } else { false });
+        if let (true, Some(adapter)) = (pending && self.account.expunge == ExpungePolicy::OnClose, &mut self.input) {
+            adapter.expunge()?;
+        }
+        self.undo.clear();
+        Ok(())
+    }
+
+    // Lists all folders of the server or only the subscribed ones
+    pub fn show_folders(&mut self, subscribed: bool) -> MailResult<()> {
+        let adapter = self.input.as_mut().ok_or(MailError::NotConnected)?;
+        let folders = if subscribed { adapter.list_subscribed()? } else { adapter.list_folders()? };
+        if output::is_json() {
+            output::print_json(&json!(folders));
+            return Ok(());
+        }
+        folders.iter().for_each(|f| println!("\t{}", f));
+        Ok(())
+    }
+
+    pub fn subscribe(&mut self, folder: &str, subscribe: bool) -> MailResult<()> {
+        if !self.folders.iter().any(|f| f == folder) {
+            return Err(MailError::NotFound(format!("folder \"{}\"", folder)));
+        }
+        let adapter = self.input.as_mut().ok_or(MailError::NotConnected)?;
+        adapter.subscribe(folder, subscribe)
+    }
+
+    fn archive_folder(&self) -> String {
+        self.folders.iter()
+            .find(|f| f.eq_ignore_ascii_case("Archive") || f.to_lowercase().ends_with("/archive") || f.to_lowercase().ends_with(".archive"))
+            .cloned()
+            .unwrap_or(String::from("Archive"))
+    }
+
+    fn junk_folder(&self) -> String {
+        self.folders.iter()
+            .find(|f| ["junk", "spam", "junk e-mail"].iter().any(|name| {
+                let lower = f.to_lowercase();
+                lower == *name || lower.ends_with(format!("/{}", name).as_str()) || lower.ends_with(format!(".{}", name).as_str())
+            }))
+            .cloned()
+            .unwrap_or(String::from("Junk"))
+    }
+
+    fn trash_folder(&self) -> Option<String> {
+        self.folders.iter()
+            .find(|f| ["trash", "deleted items", "deleted messages"].iter().any(|name| {
+                let lower = f.to_lowercase();
+                lower == *name || lower.ends_with(format!("/{}", name).as_str()) || lower.ends_with(format!(".{}", name).as_str())
+            }))
+            .cloned()
+    }
+
+    // The given trash folder or the one found on the server
+    fn require_trash(&self, configured: Option<String>) -> MailResult<String> {
+        configured.or(self.trash_folder()).ok_or(MailError::NotFound(String::from("trash folder")))
+    }
+
+    // Loads and lists the mails of the trash folder, their indices are used by restore
+    pub fn show_trash(&mut self, configured: Option<String>) -> MailResult<()> {
+        let folder = self.require_trash(configured)?;
+        let adapter = self.input.as_mut().ok_or(MailError::NotConnected)?;
+        let authserv_id = self.account.authserv_id();
+        let mut trash: Vec<ReceivedMailProxy> = adapter.load_folder(folder.as_str())?.into_iter().map(|mut header| {
+            header.trust_auth(authserv_id.as_str());
+            ReceivedMailProxy::from_header(header)
+        }).collect();
+        trash.sort();
+        self.trash = trash;
+        if output::is_json() {
+            output::print_json(&Value::Array(self.trash.iter().enumerate().map(|(i, m)| {
+                let mut val = m.to_json();
+                val["index"] = json!(i);
+                val
+            }).collect()));
+            return Ok(());
+        }
+        if self.trash.is_empty() {
+            println!("{}", tf!("\"{}\" is empty!", folder));
+        }
+        self.trash.iter().enumerate().for_each(|(i, m)| println!("{}", listing_line(i, m, false)));
+        Ok(())
+    }
+
+    // Moves mails of the last trash listing back into the inbox, returns their number
+    pub fn restore(&mut self, set: &str, configured: Option<String>) -> MailResult<usize> {
+        let folder = self.require_trash(configured)?;
+        if self.trash.is_empty() {
+            return Err(MailError::NotFound(String::from("mail in the trash, list it with show-trash")));
+        }
+        let indices = util::parse_index_set(set, self.trash.len()).map_err(MailError::Selection)?;
+        let ids: Vec<u32> = indices.iter().filter_map(|i| self.trash[*i].get_id()).collect();
+        let adapter = self.input.as_mut().ok_or(MailError::NotConnected)?;
+        adapter.restore(folder.as_str(), &ids)?;
+        self.trash.retain(|m| m.get_id().map(|id| !ids.contains(&id)).unwrap_or(true));
+        // Restored mails get new ids in the inbox
+        self.refresh_new()?;
+        Ok(ids.len())
+    }
+
+    // Removes all mails of the trash folder for good, returns their number
+    pub fn empty_trash(&mut self, configured: Option<String>) -> MailResult<usize> {
+        let folder = self.require_trash(configured)?;
+        let adapter = self.input.as_mut().ok_or(MailError::NotConnected)?;
+        let count = adapter.empty_folder(folder.as_str())?;
+        self.trash.clear();
+        // Deletions moved to the trash can't be undone anymore
+        self.undo.retain(|e| match e {
+            UndoEntry::Removed(_, Some(target)) => *target != folder,
+            _ => true,
+        });
+        Ok(count)
+    }
+
+    fn threads(&self) -> Vec<Vec<usize>> {
+        threading::group(&self.mails.iter().map(|(m, _)| m.get_thread_ids()).collect())
+    }
+
+    pub fn show_threads(&mut self) {
+        self.sort_mails();
+        let threads = self.threads();
+        if output::is_json() {
+            let val: Vec<Value> = threads.iter().enumerate().map(|(n, t)| json!({
+                "thread": n,
+                "count": t.len(),
+                "unread": t.iter().filter(|i| self.mails[**i].1).count(),
+                "mails": t.iter().map(|i| self.mails[*i].0.get_id()).collect::<Vec<Option<u32>>>(),
+            })).collect();
+            output::print_json(&Value::Array(val));
+            return;
+        }
+        if threads.is_empty() {
+            println!("{}", tf!("No mails in inbox of \"{}\"", self.get_account_name()));
+        }
+        for (n, thread) in threads.iter().enumerate() {
+            let unread = thread.iter().any(|i| self.mails[*i].1);
+            // Latest mail of the conversation represents it
+            if let Some(latest) = thread.last().map(|i| &self.mails[*i].0) {
+                if output::is_plain() {
+                    println!("Thread {}: {} mails{}, latest {}", n, thread.len(), if unread { ", unread" } else { "" }, latest.get_info());
+                } else {
+                    println!("\t{:>3} {} ({:>2}) {}", n, if unread { "*" } else { " " }, thread.len(), latest.get_info());
+                }
+            }
+            if self.expanded == Some(n) {
+                self.show_thread_tree(n);
             }
+        }
+    }
+
+    // Mails of an expanded thread with their indices, replies indented below the mail they answer
+    fn show_thread_tree(&self, n: usize) {
+        let tree = threading::build(&self.mails.iter().map(|(m, _)| m.get_thread_ids()).collect());
+        if let Some(thread) = tree.get(n) {
+            for (index, depth) in thread.iter() {
+                let (mail, unread) = &self.mails[*index];
+                if output::is_plain() {
+                    println!("Mail {}, reply level {}{}: {}", index, depth, if *unread { ", unread" } else { "" }, mail.get_info());
+                } else {
+                    println!("\t      {}{:>3} {} {}", "  ".repeat(*depth), index, if *unread { "*" } else { " " }, mail.get_info());
+                }
+            }
+        }
+    }
+
+    // One row per conversation, unless the listing is filtered or printed as json
+    pub fn show_conversations(&mut self) {
+        if self.filter.is_some() || output::is_json() {
+            self.show_mails(false);
         } else {
-            // Check how many chars match with MailHead
-            let id = self.mails.iter().map(|(m, _)| {
-                m.get_info().chars().zip(ident.chars()).enumerate().find(|(_, (m, o))| m != o).map_or(0, |(i, _)| i);
-            }).enumerate().max_by(|(_, a), (_, b)| a.cmp(b)).map(|(i, _)| i);
-            index = id;
+            self.show_threads();
         }
-        self.opened_mail = index;
+    }
 
-        // Set mail unread false
-        if let Some(id) = self.opened_mail {
-            self.mails.get_mut(id).unwrap().1 = false;
+    // Lists the mails of a thread below it in the conversation listing
+    pub fn expand(&mut self, n: usize) -> MailResult<()> {
+        if n >= self.threads().len() {
+            return Err(MailError::NotFound(format!("thread {}", n)));
         }
+        self.expanded = Some(n);
+        Ok(())
+    }
+
+    pub fn collapse(&mut self) {
+        self.expanded = None;
     }
 
-    pub fn get_opened_mail(&mut self) -> Option<&ReceivedMail> {
-        let opened_mail = self.opened_mail.clone();
-        return if let Some(ident) = opened_mail {
-            match &mut self.input {
-                Some(adapter) => self.mails.get_mut(ident).unwrap().0.get_mail(adapter),
-                None => None,
+    // Prints all mails of a conversation in order
+    pub fn open_thread(&mut self, n: usize) -> MailResult<()> {
+        let thread = self.threads().into_iter().nth(n).ok_or(MailError::NotFound(format!("thread {}", n)))?;
+        let adapter = self.input.as_mut().ok_or(MailError::NotConnected)?;
+        for (pos, index) in thread.iter().enumerate() {
+            if pos > 0 && !output::is_json() {
+                println!("\n----------------------------------------\n");
             }
+            let (proxy, unread) = &mut self.mails[*index];
+            proxy.get_mail(adapter)?.print_all();
+            *unread = false;
+        }
+        self.opened_mail = thread.last().cloned();
+        Ok(())
+    }
+
+    // Opens a mail by index, "#" and its id, or the one whose sender or subject matches the text best
+    pub fn open_mail(&mut self, ident: String) -> MailResult<usize> {
+        let index = match ident.parse::<usize>() {
+            Ok(index) if index < self.mails.len() => index,
+            Ok(index) => return Err(MailError::NotFound(format!("mail with index {}", index))),
+            Err(_) if ident.starts_with('#') && ident[1..].parse::<u32>().is_ok() => self.select(ident.as_str())?[0],
+            Err(_) => self.match_mail(ident.as_str())?,
+        };
+        self.opened_mail = Some(index);
+        self.mails[index].1 = false;
+        Ok(index)
+    }
+
+    // Only the mails of the best kind of match count: exact, then substring, then fuzzy
+    fn match_mail(&self, text: &str) -> MailResult<usize> {
+        let scored: Vec<(usize, u32)> = self.visible().filter_map(|(i, (m, _))| {
+            let (from, subject) = match m.get_header() {
+                Some(header) => (header.get_from(), header.get_subject().clone()),
+                None => return None,
+            };
+            util::match_score(subject.as_str(), text).max(util::match_score(from.as_str(), text)).map(|score| (i, score))
+        }).collect();
+        let best = scored.iter().map(|(_, score)| *score).max().ok_or(MailError::NotFound(format!("mail matching \"{}\"", text)))?;
+        let candidates: Vec<usize> = scored.into_iter().filter(|(_, score)| *score == best).map(|(i, _)| i).collect();
+        match candidates.as_slice() {
+            [index] => Ok(*index),
+            _ => Err(MailError::Ambiguous {
+                query: text.to_string(),
+                candidates: candidates.iter().map(|i| (*i, self.mails[*i].0.get_info())).collect(),
+            }),
+        }
+    }
+
+    // Opens the next (or previous) mail of the listing, returns false if there is none
+    pub fn open_adjacent(&mut self, forward: bool, unread_only: bool) -> bool {
+        let current = match self.opened_mail {
+            Some(current) => current,
+            None => return false,
+        };
+        let candidates: Vec<usize> = self.visible().filter(|(_, (_, unread))| !unread_only || *unread).map(|(i, _)| i).collect();
+        let next = if forward {
+            candidates.into_iter().find(|i| *i > current)
         } else {
-            None
+            candidates.into_iter().rev().find(|i| *i < current)
+        };
+        match next {
+            Some(index) => {
+                self.opened_mail = Some(index);
+                self.mails[index].1 = false;
+                true
+            },
+            None => false,
         }
     }
+
+    pub fn open_by_id(&mut self, id: u32) -> bool {
+        self.opened_mail = self.mails.iter().position(|(m, _)| m.get_id() == Some(id));
+        if let Some(index) = self.opened_mail {
+            self.mails[index].1 = false;
+        }
+        return self.opened_mail.is_some();
+    }
+
+    pub fn get_opened_mail(&mut self) -> MailResult<&ReceivedMail> {
+        let index = self.opened_mail.ok_or(MailError::NotFound(String::from("opened mail")))?;
+        self.fetch_mail(index)
+    }
+
+    // Decrypts the opened mail if it is PGP encrypted, asking for the passphrase of the key
+    pub fn decrypt_opened(&mut self, keyring: &Keyring) -> MailResult<()> {
+        let index = self.opened_mail.ok_or(MailError::NotFound(String::from("opened mail")))?;
+        if !self.fetch_mail(index)?.is_encrypted() {
+            return Ok(());
+        }
+        let passphrase = pgp::cached_passphrase().unwrap_or_else(|| util::read_password(&t!("Key passphrase: ")));
+        let (proxy, _) = self.mails.get_mut(index).ok_or(MailError::NotFound(format!("mail with index {}", index)))?;
+        let result = proxy.decrypt(keyring, passphrase.as_str());
+        // A wrong passphrase isn't kept
+        match result {
+            Ok(_) => pgp::remember_passphrase(passphrase),
+            Err(_) => {
+                pgp::lock();
+            },
+        }
+        result
+    }
+
+    // Full mail at a listing index, loaded from the server on first access
+    pub fn fetch_mail(&mut self, index: usize) -> MailResult<&ReceivedMail> {
+        let adapter = self.input.as_mut().ok_or(MailError::NotConnected)?;
+        let (proxy, _) = self.mails.get_mut(index).ok_or(MailError::NotFound(format!("mail with index {}", index)))?;
+        proxy.get_mail(adapter)
+    }
 }
 
 pub struct InboxManager {
     account_file: String,
     accounts: HashMap<String, Inbox>,
+    aliases: HashMap<String, String>,
+    passphrase: Option<String>,
     drafts_folder: String,
+    pub settings: Settings,
     pub opened_inbox: Option<String>,
     pub current_mail_writing: Option<MailBuilder>,
+    // Transports by account, smtp connections stay open between sends
+    transports: HashMap<String, TransportAdapter>,
+    outbox: Vec<PendingMail>,
+    next_pending: usize,
+    last_junk_sync: Option<Instant>,
+    // Messages of background tasks waiting for the next prompt
+    notices: Vec<String>,
+    // False while the account file couldn't be read, saving would overwrite the accounts in it
+    loaded_ok: bool,
 }
 
 impl InboxManager {
@@ -259,58 +1327,836 @@ impl InboxManager {
         InboxManager {
             account_file,
             accounts: HashMap::new(),
+            aliases: HashMap::new(),
+            passphrase: None,
             drafts_folder: String::new(),
+            settings: Settings::new(),
             opened_inbox: None,
             current_mail_writing: None,
+            transports: HashMap::new(),
+            outbox: Vec::new(),
+            next_pending: 1,
+            last_junk_sync: None,
+            notices: Vec::new(),
+            loaded_ok: false,
+        }
+    }
+
+    // Asks the user, unless confirmations are turned off in the settings. The prompt is translated
+    pub fn confirm(&self, prompt: &str) -> bool {
+        !self.settings.confirm || util::confirm(&t!(prompt))
+    }
+
+    // Path of a file stored next to the account file
+    pub fn data_path(&self, name: &str) -> PathBuf {
+        Path::new(&self.account_file).with_file_name(name)
+    }
+
+    // Mail started from templates/<name>.txt, a first line "Subject: ..." sets the subject
+    pub fn load_template(&self, name: &str) -> Result<MailBuilder, Box<dyn Error>> {
+        let content = fs::read_to_string(self.data_path("templates").join(format!("{}.txt", name)))?;
+        let mut builder = MailBuilder::new();
+        let text = match content.lines().next().and_then(|l| l.strip_prefix("Subject:")) {
+            Some(subject) => {
+                builder.subject(subject.trim().to_string());
+                content.splitn(2, '\n').nth(1).unwrap_or("").trim_start_matches(|c| c == '\r' || c == '\n').to_string()
+            },
+            None => content,
+        };
+        builder.text(text);
+        Ok(builder)
+    }
+
+    // Values for the placeholders of a mail: the given ones first, then the first recipient as
+    // found in the address book and the sending account
+    pub fn template_vars(&self, mail: &MailBuilder) -> Vec<(String, String)> {
+        let mut vars = mail.get_vars().clone();
+        let var = |key: &str, value: String| (key.to_string(), value);
+        if let Some(recipient) = mail.first_recipient() {
+            let address = util::extract_address(recipient);
+            let name = self.address_book().ok()
+                .and_then(|book| book.get(address.as_str()).and_then(|c| c.name.clone()))
+                .unwrap_or(address.split('@').next().unwrap_or("").to_string());
+            vars.push(var("first_name", name.split_whitespace().next().unwrap_or("").to_string()));
+            vars.push(var("name", name));
+            vars.push(var("email", address));
+        }
+        if let Some(from) = mail.get_from() {
+            vars.push(var("from", util::extract_address(from)));
+            if let Some(account) = self.find_sending_account(from) {
+                vars.push(var("account", account.ident()));
+            }
+        }
+        vars.push(var("date", Local::now().format("%Y-%m-%d").to_string()));
+        vars
+    }
+
+    pub fn address_book(&self) -> Result<AddressBook, Box<dyn Error>> {
+        AddressBook::load(self.data_path("contacts.yml"))
+    }
+
+    pub fn labels(&self) -> Result<Labels, Box<dyn Error>> {
+        Labels::load(self.data_path("labels.yml"))
+    }
+
+    pub fn searches(&self) -> Result<SavedSearches, Box<dyn Error>> {
+        SavedSearches::load(self.data_path("searches.yml"))
+    }
+
+    pub fn vacations(&self) -> Result<Schedules, Box<dyn Error>> {
+        Schedules::load(self.data_path("vacation.yml"))
+    }
+
+    pub fn rules(&self) -> Result<Vec<Rule>, Box<dyn Error>> {
+        rules::load(self.data_path("rules.yml"))
+    }
+
+    pub fn spam_model(&self) -> Result<SpamModel, Box<dyn Error>> {
+        SpamModel::load(self.data_path("spam-model.yml"))
+    }
+
+    // Trains the spam filter with the mails of the opened inbox, returns how many were learned
+    pub fn train_spam(&mut self, set: &str, spam: bool) -> Result<usize, Box<dyn Error>> {
+        let mut model = self.spam_model()?;
+        let mails = self.get_opened_inbox().ok_or(MailError::NotConnected)?.spam_tokens(set)?;
+        for (message_id, tokens) in mails.iter() {
+            match message_id {
+                Some(id) => {
+                    model.learn(id.as_str(), tokens, spam);
+                },
+                None => model.train(tokens, spam),
+            }
+        }
+        model.save()?;
+        Ok(mails.len())
+    }
+
+    // Retrains the spam filter from the junk folders of the accounts, see Inbox::sync_junk
+    pub fn sync_junk(&mut self, keys: &Vec<String>) -> Vec<(String, MailResult<(usize, usize)>)> {
+        self.last_junk_sync = Some(Instant::now());
+        let mut model = match self.spam_model() {
+            Ok(model) => model,
+            Err(e) => {
+                log::warn!("could not load spam model: {}", e);
+                return Vec::new();
+            },
+        };
+        let junk = self.settings.junk_folder.clone();
+        let ret: Vec<(String, MailResult<(usize, usize)>)> = keys.iter()
+            .filter_map(|key| self.accounts.get_mut(key).filter(|inbox| inbox.is_connected()).map(|inbox| (key.clone(), inbox.sync_junk(&mut model, junk.clone()))))
+            .collect();
+        for (key, result) in ret.iter() {
+            match result {
+                Ok((spam, ham)) => log::info!("junk sync of \"{}\" learned {} spam and {} ham mails", key, spam, ham),
+                Err(e) => log::warn!("junk sync of \"{}\" failed: {}", key, e),
+            }
+        }
+        if let Err(e) = model.save() {
+            log::warn!("could not save spam model: {}", e);
+        }
+        ret
+    }
+
+    pub fn keyring(&self) -> Keyring {
+        let keyring = Keyring::new(self.data_path("keyring"));
+        match self.address_book() {
+            Ok(book) => keyring.with_contacts(book),
+            Err(_) => keyring,
         }
     }
 
+    pub fn is_file_encrypted(&self) -> bool {
+        fs::read(self.account_file.clone()).map(|c| vault::is_encrypted(&c)).unwrap_or(false)
+    }
+
+    // Sets the master passphrase used for reading and writing the account file
+    pub fn set_passphrase(&mut self, passphrase: Option<String>) {
+        self.passphrase = passphrase;
+    }
+
+    pub fn has_passphrase(&self) -> bool {
+        self.passphrase.is_some()
+    }
+
+    // Whether the account file can be read with the passphrase set
+    pub fn can_decrypt(&self) -> bool {
+        self.read_account_file().is_ok()
+    }
+
+    fn read_account_file(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let content = fs::read(self.account_file.clone())?;
+        if !vault::is_encrypted(&content) {
+            return Ok(content);
+        }
+        match &self.passphrase {
+            Some(passphrase) => vault::decrypt(&content, passphrase.as_str()).ok_or("wrong passphrase or corrupted account file".into()),
+            None => Err("account file is encrypted, no passphrase given".into()),
+        }
+    }
+
+    fn parse_file(&self) -> Result<(Vec<Account>, HashMap<String, String>, Settings), Box<dyn Error>> {
+        let content = self.read_account_file()?;
+        let text = String::from_utf8_lossy(&content).to_string();
+        let root: serde_yaml::Value = match serde_yaml::from_str(text.as_str()) {
+            Ok(root) => root,
+            Err(_) => return Err(Box::new(ConfigError { diagnostics: validate::validate_config(text.as_str()) })),
+        };
+        // Upgrade files of older versions
+        let (root, version) = config::migrate(root)?;
+        let text = if version < config::CURRENT_VERSION {
+            serde_yaml::to_string(&root)?
+        } else {
+            text
+        };
+        let mut diagnostics = validate::validate_config(text.as_str());
+        if version < config::CURRENT_VERSION {
+            // Lines of the upgraded text mean nothing to the user, point into their file
+            validate::remap_lines(&mut diagnostics, text.as_str(), String::from_utf8_lossy(&content).as_ref());
+        }
+        if !diagnostics.is_empty() {
+            return Err(Box::new(ConfigError { diagnostics }));
+        }
+        let accounts = config::get_accounts(&root)?;
+        let settings = config::get_settings(&root);
+        if version < config::CURRENT_VERSION {
+            // Keep the original file as backup before overwriting it
+            let backup = format!("{}.v{}.bak", self.account_file, version);
+            fs::copy(self.account_file.clone(), backup.clone())?;
+            self.write_account_file(text.into_bytes())?;
+            println!("{}", tf!("Account file migrated from version {} to {}, backup stored at \"{}\"", version, config::CURRENT_VERSION, backup));
+        }
+        // Check for identifiers used by more than one account
+        let mut aliases: HashMap<String, String> = HashMap::new();
+        for account in accounts.iter() {
+            for ident in account.identifiers().into_iter() {
+                if let Some(other) = aliases.get(&ident) {
+                    return Err(format!("identifier \"{}\" is used by accounts \"{}\" and \"{}\"", ident, other, account.ident()).into());
+                }
+                aliases.insert(ident, account.ident());
+            }
+        }
+        return Ok((accounts, aliases, settings));
+    }
+
     pub fn load_file(&mut self) -> Result<(), Box<dyn Error>>  {
-        let file = File::open(self.account_file.clone())?;
-        let accounts: Vec<Account> = serde_yaml::from_reader(file)?;
+        let parsed = self.parse_file();
+        // Without a file nothing is lost by writing a new one
+        self.loaded_ok = parsed.is_ok() || !Path::new(&self.account_file).exists();
+        let (accounts, aliases, settings) = parsed?;
         self.accounts = HashMap::with_capacity(accounts.len());
-        for account in accounts.clone().into_iter() {
-            let ident = match account.shortcut.clone() {
-                Some(s) => s,
-                None => account.name.clone(),
-            };
-            self.accounts.insert(ident, Inbox::new(account));
+        for account in accounts.into_iter() {
+            self.accounts.insert(account.ident(), Inbox::new(account));
         }
+        self.aliases = aliases;
+        self.settings = settings;
+        util::set_date_format(self.settings.date_format.as_str(), self.settings.date_locale);
+        i18n::set_language(self.settings.language.as_deref(), self.data_path("locale").as_path());
+        output::set_plain(self.settings.plain_output);
+        pgp::set_passphrase_ttl(self.settings.passphrase_ttl);
         return Ok(());
     }
 
-    pub fn refresh(&mut self) {
-        println!("Refreshing inboxes ...");
-        // Refresh available account inboxes
+    // Re-reads the account file, keeps inboxes (and their sessions) of unchanged accounts
+    // Returns number of (added, removed, changed) accounts
+    pub fn reload_file(&mut self) -> Result<(usize, usize, usize), Box<dyn Error>> {
+        let (accounts, aliases, settings) = self.parse_file()?;
+        self.loaded_ok = true;
+        // Credentials may have changed
+        self.transports.clear();
+        let mut previous = std::mem::replace(&mut self.accounts, HashMap::with_capacity(accounts.len()));
+        let (mut added, mut changed) = (0, 0);
+        for account in accounts.into_iter() {
+            let key = account.ident();
+            match previous.remove(&key) {
+                Some(inbox) if inbox.account == account => {
+                    self.accounts.insert(key, inbox);
+                },
+                Some(_) => {
+                    changed += 1;
+                    self.accounts.insert(key, Inbox::new(account));
+                },
+                None => {
+                    added += 1;
+                    self.accounts.insert(key, Inbox::new(account));
+                },
+            }
+        }
+        self.aliases = aliases;
+        self.settings = settings;
+        util::set_date_format(self.settings.date_format.as_str(), self.settings.date_locale);
+        i18n::set_language(self.settings.language.as_deref(), self.data_path("locale").as_path());
+        output::set_plain(self.settings.plain_output);
+        pgp::set_passphrase_ttl(self.settings.passphrase_ttl);
+        // Close inbox, if its account is gone
+        if let Some(opened) = &self.opened_inbox {
+            if !self.accounts.contains_key(opened) {
+                self.opened_inbox = None;
+            }
+        }
+        return Ok((added, previous.len(), changed));
+    }
+
+    pub fn save_file(&self) -> Result<(), Box<dyn Error>> {
+        if !self.loaded_ok {
+            return Err("the account file could not be loaded, it is not overwritten".into());
+        }
+        let accounts: Vec<&Account> = self.accounts.values().map(|inbox| &inbox.account).collect();
+        self.write_account_file(config::to_yaml(&accounts, &self.settings)?)
+    }
+
+    fn write_account_file(&self, mut content: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        if let Some(passphrase) = &self.passphrase {
+            content = vault::encrypt(&content, passphrase.as_str())?;
+        }
+        // Written next to the file and renamed over it, an interrupted write leaves the old file intact
+        let tmp = format!("{}.tmp", self.account_file);
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&tmp)?;
+        file.write_all(&content)?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.account_file.clone())?;
+        return Ok(());
+    }
+
+    // Returns false if an account with the same identifier is already present
+    pub fn add_account(&mut self, account: Account) -> bool {
+        let identifiers = account.identifiers();
+        if identifiers.iter().any(|ident| self.aliases.contains_key(ident)) {
+            return false;
+        }
+        for ident in identifiers.into_iter() {
+            self.aliases.insert(ident, account.ident());
+        }
+        self.accounts.insert(account.ident(), Inbox::new(account));
+        return true;
+    }
+
+    pub fn get_inbox(&mut self, ident: &String) -> Option<&mut Inbox> {
+        let key = self.resolve(ident)?;
+        self.accounts.get_mut(&key)
+    }
+
+    // Finds the account to send from, by login name or identifier
+    // Quoting style for replies from an account, account settings override the global ones
+    pub fn quote_style(&self, ident: &String) -> QuoteStyle {
+        let account = self.resolve(ident).and_then(|k| self.accounts.get(&k)).and_then(|i| i.account.quoting.as_ref());
+        match account {
+            Some(style) => self.settings.quoting.merged(style),
+            None => self.settings.quoting.clone(),
+        }
+    }
+
+    // Transport of an account, kept so the following sends reuse the smtp connection. It is taken
+    // out for sending without the context and returned afterwards
+    pub fn take_transport(&mut self, account: &Account) -> TransportAdapter {
+        self.transports.remove(&account.ident()).unwrap_or_else(|| account.get_transport())
+    }
+
+    pub fn return_transport(&mut self, account: &Account, transport: TransportAdapter) {
+        self.transports.insert(account.ident(), transport);
+    }
+
+    // Message of a background task, printed before the next prompt
+    pub fn notify(&mut self, text: String) {
+        self.notices.push(text);
+    }
+
+    pub fn take_notices(&mut self) -> Vec<String> {
+        std::mem::replace(&mut self.notices, Vec::new())
+    }
+
+    // Puts a mail into the outbox until the delay passed, returns its number
+    pub fn queue_mail(&mut self, builder: MailBuilder, delay: Duration) -> usize {
+        let id = self.next_pending;
+        self.next_pending += 1;
+        self.outbox.push(PendingMail { id, builder, due: Some(Instant::now() + delay) });
+        id
+    }
+
+    // Takes a mail out of the outbox to send it, unless it was cancelled meanwhile
+    pub fn take_pending(&mut self, id: usize) -> Option<MailBuilder> {
+        let index = self.outbox.iter().position(|p| p.id == id && p.due.is_some())?;
+        Some(self.outbox.remove(index).builder)
+    }
+
+    // All mails still waiting, to send them right away
+    pub fn take_all_pending(&mut self) -> Vec<MailBuilder> {
+        let (pending, failed): (Vec<PendingMail>, Vec<PendingMail>) = self.outbox.drain(..).partition(|p| p.due.is_some());
+        self.outbox = failed;
+        pending.into_iter().map(|p| p.builder).collect()
+    }
+
+    // Mails kept in the outbox after sending failed
+    pub fn take_failed(&mut self) -> Vec<MailBuilder> {
+        let (failed, pending): (Vec<PendingMail>, Vec<PendingMail>) = self.outbox.drain(..).partition(|p| p.due.is_none());
+        self.outbox = pending;
+        failed.into_iter().map(|p| p.builder).collect()
+    }
+
+    // Takes a mail back for editing, the last one queued without number
+    pub fn cancel_pending(&mut self, id: Option<usize>) -> Option<MailBuilder> {
+        let index = match id {
+            Some(id) => self.outbox.iter().position(|p| p.id == id)?,
+            None => self.outbox.len().checked_sub(1)?,
+        };
+        Some(self.outbox.remove(index).builder)
+    }
+
+    // Keeps a mail that could not be sent in the outbox
+    pub fn keep_failed(&mut self, builder: MailBuilder) -> usize {
+        let id = self.next_pending;
+        self.next_pending += 1;
+        self.outbox.push(PendingMail { id, builder, due: None });
+        id
+    }
+
+    pub fn show_outbox(&self) {
+        let state = |p: &PendingMail| match p.due {
+            Some(due) => tf!("sending in {} s", due.saturating_duration_since(Instant::now()).as_secs()),
+            None => t!("failed").to_string(),
+        };
+        if output::is_json() {
+            output::print_json(&Value::Array(self.outbox.iter().map(|p| json!({
+                "id": p.id,
+                "to": p.builder.first_recipient(),
+                "subject": p.builder.get_subject(),
+                "state": state(p),
+            })).collect()));
+            return;
+        }
+        if self.outbox.is_empty() {
+            println!("{}", t!("Outbox is empty!"));
+        }
+        for p in self.outbox.iter() {
+            println!("\t{:>3} {} | {} ({})", p.id, p.builder.first_recipient().cloned().unwrap_or_default(), p.builder.get_subject().cloned().unwrap_or_default(), state(p));
+        }
+    }
+
+    pub fn find_sending_account(&self, address: &String) -> Option<&Account> {
+        let address = util::extract_address(address);
+        self.accounts.values().map(|inbox| &inbox.account).find(|acc| acc.name.eq_ignore_ascii_case(address.as_str()))
+            .or(self.resolve(&address).and_then(|key| self.accounts.get(&key)).map(|inbox| &inbox.account))
+    }
+
+    // Matches of the opened inbox, or of all accounts, as (account, index, line)
+    pub fn grep(&self, pattern: &Regex, all: bool) -> Vec<(String, usize, String)> {
+        let mut keys: Vec<&String> = match (&self.opened_inbox, all) {
+            (Some(opened), false) => vec![opened],
+            _ => self.accounts.keys().collect(),
+        };
+        keys.sort();
+        keys.into_iter()
+            .filter_map(|key| self.accounts.get(key).map(|inbox| (key, inbox)))
+            .flat_map(|(key, inbox)| inbox.grep(pattern).into_iter().map(move |(i, line)| (key.clone(), i, line)))
+            .collect()
+    }
+
+    pub fn account_tag(&self, key: &str) -> String {
+        self.accounts.get(key).map(|inbox| inbox.account.tag()).unwrap_or(key.to_string())
+    }
+
+    // All names accounts can be referred to with
+    pub fn get_identifiers(&self) -> Vec<String> {
+        let mut ret: Vec<String> = self.aliases.keys().cloned().collect();
+        ret.sort();
+        return ret;
+    }
+
+    // Address book contacts followed by the people harvested from loaded mails, as (address, name).
+    // Each address is listed once, with the first name known for it
+    pub fn get_known_people(&self) -> Vec<(String, Option<String>)> {
+        let mut ret: Vec<(String, Option<String>)> = match self.address_book() {
+            Ok(book) => book.contacts().iter().map(|c| (c.address.clone(), c.name.clone())).collect(),
+            Err(e) => {
+                log::warn!("could not read address book: {}", e);
+                Vec::new()
+            },
+        };
+        let mut harvested: Vec<AddressAlias> = self.accounts.values().flat_map(|inbox| inbox.get_people()).collect();
+        harvested.sort_by_key(|a| a.get_address().to_lowercase());
+        for person in harvested.into_iter() {
+            let address = person.get_address();
+            match ret.iter_mut().find(|(a, _)| a.eq_ignore_ascii_case(&address)) {
+                Some((_, name)) => if name.is_none() {
+                    *name = person.get_name();
+                },
+                None => ret.push((address, person.get_name())),
+            }
+        }
+        return ret;
+    }
+
+    // Known people whose address or name contains the text, case insensitive
+    pub fn match_people(&self, text: &str) -> Vec<(String, Option<String>)> {
+        let text = text.to_lowercase();
+        self.get_known_people().into_iter()
+            .filter(|(address, name)| address.to_lowercase().contains(&text) || name.as_ref().map(|n| n.to_lowercase().contains(&text)).unwrap_or(false))
+            .collect()
+    }
+
+    pub fn get_folders(&self) -> Vec<String> {
+        let opened = self.opened_inbox.as_ref().and_then(|key| self.accounts.get(key));
+        match opened {
+            Some(inbox) => inbox.get_folders().clone(),
+            None => Vec::new(),
+        }
+    }
+
+    // Resolves a shortcut or alias to the key of its account
+    pub fn resolve(&self, ident: &String) -> Option<String> {
+        self.aliases.get(ident).cloned()
+    }
+
+    // Takes the loaders of the accounts with the keys they belong to, and the keys of the accounts
+    // connecting for the first time. Mails found then aren't new to the user. Accounts without a
+    // loader are fetched right away, they come back with an empty loader
+    fn take_loaders(&mut self, keys: &Vec<String>) -> (Vec<(String, Loader)>, Vec<(String, Option<Loader>, Fetched)>, Vec<String>) {
+        let first_load: Vec<String> = keys.iter().filter(|k| self.accounts.get(*k).map(|i| !i.is_connected()).unwrap_or(false)).cloned().collect();
+        let mut loaders = Vec::new();
+        let mut fetched = Vec::new();
+        for key in keys.iter() {
+            let inbox = match self.accounts.get_mut(key) {
+                Some(inbox) => inbox,
+                None => continue,
+            };
+            match inbox.loader() {
+                Some(loader) => loaders.push((key.clone(), loader)),
+                None => fetched.push((key.clone(), Some(Loader { account: inbox.account.clone(), adapter: None }), inbox.fetch())),
+            }
+        }
+        (loaders, fetched, first_load)
+    }
+
+    // Every loader fetches on its own thread, so a slow server doesn't hold up the others.
+    // A panicking loader only loses its connection, the inbox isn't touched
+    fn fetch_all(loaders: Vec<(String, Loader)>) -> Vec<(String, Option<Loader>, Fetched)> {
+        let workers: Vec<_> = loaders.into_iter().map(|(key, mut loader)| {
+            log::info!("refreshing account \"{}\"", key);
+            (key, std::thread::spawn(move || {
+                let fetched = loader.fetch();
+                (loader, fetched)
+            }))
+        }).collect();
+        workers.into_iter().map(|(key, worker)| match worker.join() {
+            Ok((loader, fetched)) => (key, Some(loader), fetched),
+            Err(_) => {
+                log::error!("refresh of account \"{}\" panicked", key);
+                (key, None, Err(MailError::NotConnected))
+            },
+        }).collect()
+    }
+
+    fn refresh_accounts(&mut self, keys: &Vec<String>) -> Vec<(String, MailResult<Vec<usize>>)> {
+        let (loaders, mut fetched, first_load) = self.take_loaders(keys);
+        fetched.extend(InboxManager::fetch_all(loaders));
+        self.finish_refresh(keys, fetched, &first_load)
+    }
+
+    // Merges the fetched headers into the inboxes, then runs everything new mails trigger
+    fn finish_refresh(&mut self, keys: &Vec<String>, fetched: Vec<(String, Option<Loader>, Fetched)>, first_load: &Vec<String>) -> Vec<(String, MailResult<Vec<usize>>)> {
+        let mut ret = Vec::new();
+        for (key, loader, fetched) in fetched.into_iter() {
+            // The account may have been removed or reloaded while loading
+            let result = match (self.accounts.get_mut(&key), loader) {
+                (Some(inbox), Some(loader)) => inbox.merge(loader, fetched),
+                (Some(_), None) => Err(MailError::NotConnected),
+                (None, _) => continue,
+            };
+            ret.push((key, result));
+        }
+        if let Some(maildir) = self.settings.notmuch_maildir.clone() {
+            self.deliver_to_notmuch(maildir.as_str(), &ret);
+        }
+        let sync_due = self.settings.junk_sync_interval > 0 && self.last_junk_sync
+            .map(|last| last.elapsed() >= Duration::from_secs(self.settings.junk_sync_interval)).unwrap_or(true);
+        if sync_due {
+            self.sync_junk(keys);
+        }
+        self.score_spam(&ret);
+        self.run_rules(&ret, &first_load);
+        self.report_bounces(&ret);
+        self.run_new_mail_hooks(&ret, &first_load);
+        ret
+    }
+
+    // New delivery status notifications are announced, they mean a sent mail didn't arrive
+    fn report_bounces(&self, results: &Vec<(String, MailResult<Vec<usize>>)>) {
+        for (key, result) in results.iter() {
+            let (inbox, new) = match (self.accounts.get(key), result) {
+                (Some(inbox), Ok(new)) => (inbox, new),
+                (_, _) => continue,
+            };
+            for mail in new.iter().filter_map(|i| inbox.get_mail_proxy(*i)).filter(|m| m.is_bounce()) {
+                println!("{}", tf!("[bounce] {}: {}", key, mail.get_info()));
+            }
+        }
+    }
+
+    fn run_new_mail_hooks(&self, results: &Vec<(String, MailResult<Vec<usize>>)>, first_load: &Vec<String>) {
+        if self.settings.hooks.on_new_mail.is_none() {
+            return;
+        }
+        for (key, result) in results.iter().filter(|(k, _)| !first_load.contains(k)) {
+            let (inbox, new) = match (self.accounts.get(key), result) {
+                (Some(inbox), Ok(new)) => (inbox, new),
+                (_, _) => continue,
+            };
+            for header in new.iter().filter_map(|i| inbox.get_mail_proxy(*i)).filter_map(|m| m.get_header()) {
+                hooks::run(&self.settings.hooks, Event::NewMail, key, header.environment());
+            }
+        }
+    }
+
+    // Account key, index and listing line of a loaded mail
+    pub fn find_message(&self, message_id: &str) -> Option<(String, usize, String)> {
+        self.accounts.iter().find_map(|(key, inbox)| inbox.find_message(message_id).map(|(i, info)| (key.clone(), i, info)))
+    }
+
+    // New mails get their spam score before the rules look at it, an untrained filter scores nothing
+    fn score_spam(&mut self, results: &Vec<(String, MailResult<Vec<usize>>)>) {
+        let model = match self.spam_model() {
+            Ok(model) if model.is_trained() => model,
+            Ok(_) => return,
+            Err(e) => {
+                log::warn!("could not load spam model: {}", e);
+                return;
+            },
+        };
+        for (key, result) in results.iter() {
+            if let (Some(inbox), Ok(new)) = (self.accounts.get_mut(key), result) {
+                inbox.score_spam(&model, new);
+            }
+        }
+    }
+
+    // Applies the rules file to new mails, tags go to notmuch. Like the hooks, the first
+    // load of an account is skipped, its mails are not new
+    fn run_rules(&mut self, results: &Vec<(String, MailResult<Vec<usize>>)>, first_load: &Vec<String>) {
+        let rules = match self.rules() {
+            Ok(rules) if rules.is_empty() => return,
+            Ok(rules) => rules,
+            Err(e) => {
+                log::warn!("could not load rules: {}", e);
+                return;
+            },
+        };
+        let tagging = self.settings.notmuch_maildir.is_some();
+        for (key, result) in results.iter().filter(|(k, _)| !first_load.contains(k)) {
+            let (inbox, new) = match (self.accounts.get_mut(key), result) {
+                (Some(inbox), Ok(new)) => (inbox, new),
+                (_, _) => continue,
+            };
+            for (rule, info, message_id, actions) in inbox.apply_rules(&rules, new).into_iter() {
+                log::info!("rule \"{}\" applied to mail of \"{}\": {}", rule, key, info);
+                for action in actions.iter() {
+                    match (action, &message_id) {
+                        (Action::Notify, _) => println!("[{}] {}: {}", rule, key, info),
+                        (Action::Tag(tag), Some(id)) if tagging => if let Err(e) = notmuch::tag(&[tag.as_str()], format!("id:{}", id).as_str()) {
+                            log::warn!("rule \"{}\" could not tag {}: {}", rule, id, e);
+                        },
+                        (Action::Tag(_), _) => log::warn!("rule \"{}\" tags mails, but notmuch is not set up", rule),
+                        (_, _) => {},
+                    }
+                }
+            }
+        }
+    }
+
+    // Hands new mails to notmuch, failures are only logged so refreshing keeps working
+    fn deliver_to_notmuch(&mut self, maildir: &str, results: &Vec<(String, MailResult<Vec<usize>>)>) {
+        let mut delivered = 0;
+        let mut stored = notmuch::Index::load(self.data_path("notmuch-index"));
+        for (key, result) in results.iter() {
+            let (inbox, new) = match (self.accounts.get_mut(key), result) {
+                (Some(inbox), Ok(new)) => (inbox, new),
+                (_, _) => continue,
+            };
+            for index in new.iter() {
+                // Mails known by their Message-ID are not fetched again
+                let (id, message_id) = match inbox.get_mail_proxy(*index).and_then(|m| Some((m.get_id()?, m.get_thread_ids().0))) {
+                    Some(mail) => mail,
+                    None => continue,
+                };
+                if message_id.map(|m| stored.contains(m.as_str())).unwrap_or(false) {
+                    continue;
+                }
+                let result = inbox.fetch_mail(*index)
+                    .map_err(|e| e.to_string())
+                    .and_then(|mail| stored.deliver(maildir, mail.get_raw().as_bytes()).map_err(|e| e.to_string()));
+                match result {
+                    Ok(true) => delivered += 1,
+                    Ok(false) => {},
+                    Err(e) => log::warn!("could not deliver mail {} of \"{}\" to notmuch: {}", id, key, e),
+                }
+            }
+        }
+        if delivered > 0 {
+            log::info!("delivered {} mails to {}", delivered, maildir);
+            if let Err(e) = notmuch::index() {
+                log::warn!("notmuch new failed: {}", e);
+            }
+        }
+    }
+
+    // Refresh of the shared context, it is only locked to hand out the loaders and to merge what
+    // they fetched, not while the servers are contacted
+    pub fn refresh_shared(handle: &Arc<Mutex<InboxManager>>) -> Vec<(String, MailResult<Vec<usize>>)> {
+        let (keys, loaders, mut fetched, first_load) = {
+            let mut context = handle.lock().unwrap();
+            let keys: Vec<String> = context.accounts.keys().cloned().collect();
+            let (loaders, fetched, first_load) = context.take_loaders(&keys);
+            (keys, loaders, fetched, first_load)
+        };
+        fetched.extend(InboxManager::fetch_all(loaders));
+        handle.lock().unwrap().finish_refresh(&keys, fetched, &first_load)
+    }
+
+    pub fn refresh(handle: &Arc<Mutex<InboxManager>>) {
+        println!("{}", t!("Refreshing inboxes ..."));
         let mut total_count: usize = 0;
-        for (key, acc) in self.accounts.iter_mut() {
-            println!("Refresh account \"{}\"", key);
-            let count = acc.refresh();
-            total_count += count;
+        for (key, result) in InboxManager::refresh_shared(handle).into_iter() {
+            match result {
+                Ok(new) => total_count += new.len(),
+                Err(e) => output::error(tf!("Could not refresh inbox for \"{}\" [{}]", key, e).as_str()),
+            }
+        }
+        println!("{}", tn!(total_count, "{} new mail loaded!", "{} new mails loaded!"));
+    }
+
+    // Quiet refresh for the background worker, returns the number of new mails
+    pub fn poll(handle: &Arc<Mutex<InboxManager>>) -> usize {
+        InboxManager::refresh_shared(handle).into_iter().map(|(key, result)| match result {
+            Ok(new) => new.len(),
+            Err(e) => {
+                log::warn!("background refresh of \"{}\" failed: {}", key, e);
+                0
+            },
+        }).sum()
+    }
+
+    // Scans the junk folders of the connected accounts now instead of after junk_sync_interval
+    pub fn sync_junk_now(&mut self) {
+        let keys: Vec<String> = self.accounts.keys().cloned().collect();
+        let results = self.sync_junk(&keys);
+        if results.is_empty() {
+            println!("{}", t!("No connected account, refresh first!"));
+            return;
+        }
+        for (key, result) in results.into_iter() {
+            match result {
+                Ok((spam, ham)) => println!("{}", tf!("{}: {} mails learned as spam, {} as ham", key, spam, ham)),
+                Err(e) => output::error(tf!("Could not sync the junk folder of \"{}\" [{}]", key, e).as_str()),
+            }
+        }
+        if let Ok(model) = self.spam_model() {
+            let (spam, ham) = model.get_counts();
+            println!("{}", tf!("The spam filter was trained with {} spam and {} ham mails.", spam, ham));
+        }
+    }
+
+    // Keeps polling all (or one) accounts, printing a line for every newly arrived mail
+    pub fn watch(&mut self, account: Option<String>, interval: u64) -> Result<(), String> {
+        let keys: Vec<String> = match &account {
+            Some(ident) => vec![self.resolve(ident).ok_or(format!("no account named \"{}\" available!", ident))?],
+            None => self.accounts.keys().cloned().collect(),
+        };
+        // First round only fetches the mails already there
+        for (key, result) in self.refresh_accounts(&keys).into_iter() {
+            if let Err(e) = result {
+                output::error(tf!("Could not refresh inbox for \"{}\" [{}]", key, e).as_str());
+            }
+        }
+        loop {
+            std::thread::sleep(std::time::Duration::from_secs(interval));
+            for (key, result) in self.refresh_accounts(&keys).into_iter() {
+                let new = match result {
+                    Ok(new) => new,
+                    Err(e) => {
+                        output::error(tf!("Could not refresh inbox for \"{}\" [{}]", key, e).as_str());
+                        continue;
+                    },
+                };
+                let inbox = match self.accounts.get(&key) {
+                    Some(inbox) => inbox,
+                    None => continue,
+                };
+                for index in new.into_iter() {
+                    let mail = match inbox.get_mail_proxy(index) {
+                        Some(mail) => mail,
+                        None => continue,
+                    };
+                    if output::is_json() {
+                        let mut val = mail.to_json();
+                        val["account"] = json!(key);
+                        println!("{}", val);
+                    } else {
+                        println!("{} | {}", key, mail.get_info());
+                    }
+                }
+            }
+            use std::io::Write;
+            let _ = std::io::stdout().flush();
         }
-        println!("{} new mails loaded!", total_count);
     }
 
-    pub fn show_inbox(&self, ident: Option<String>) {
+    pub fn show_inbox(&mut self, ident: Option<String>) {
         if let Some(key) = ident {
-            let account = self.accounts.get(&key);
+            let account = self.resolve(&key).and_then(|k| self.accounts.get_mut(&k));
             if let Some(account) = account {
                 account.show_mails(true);
             } else {
-                println!("no account named \"{}\" available!", key);
+                output::error(tf!("no account named \"{}\" available!", key).as_str());
             }
+        } else if output::is_json() {
+            let mails: Vec<Value> = self.accounts.values_mut().flat_map(|a| {
+                a.sort_mails();
+                a.mails_json(false)
+            }).collect();
+            output::print_json(&Value::Array(mails));
         } else {
-            // Show all inboxes
-            self.accounts.iter().for_each(|(_, a)| a.show_mails(true));
+            // Show all inboxes, tagged so lines can be told apart
+            let mut keys: Vec<String> = self.accounts.keys().cloned().collect();
+            keys.sort();
+            keys.iter().for_each(|k| if let Some(a) = self.accounts.get_mut(k) {
+                a.show_mails_tagged();
+            });
         }
     }
 
     pub fn show_servers(&self) {
-        println!("Displaying info for {} server{} ...", self.accounts.len(), match self.accounts.len() != 1 {
+        // Vacation responders are listed as far as they were set up from here
+        let vacations = match self.vacations() {
+            Ok(vacations) => Some(vacations),
+            Err(e) => {
+                output::error(tf!("Could not read vacation responders! [{}]", e).as_str());
+                None
+            },
+        };
+        let vacation = |a: &Inbox| vacations.as_ref().and_then(|v| v.get(a.account.ident().as_str()));
+        if output::is_json() {
+            output::print_json(&Value::Array(self.accounts.values().map(|a| {
+                let mut json = a.account.to_json();
+                json["vacation"] = vacation(a).map(|v| json!({ "from": v.from.to_string(), "until": v.until.to_string(), "state": v.state() })).unwrap_or(Value::Null);
+                json
+            }).collect()));
+            return;
+        }
+        println!("{}", tf!("Displaying info for {} server{} ...", self.accounts.len(), match self.accounts.len() != 1 {
             true => "s",
             false => "",
+        }));
+        self.accounts.iter().for_each(|(_, a)| {
+            a.print_account();
+            if let Some(v) = vacation(a) {
+                println!("{}", tf!("\tVacation:\t{}", v.describe()));
+            }
         });
-        self.accounts.iter().for_each(|(_, a)| a.print_account());
     }
 
     pub fn show_drafts(&self) {
@@ -318,9 +2164,10 @@ impl InboxManager {
     }
 
     pub fn open_inbox(&mut self, ident: String) -> bool {
-        let valid = self.accounts.contains_key(&ident);
+        let key = self.resolve(&ident);
+        let valid = key.is_some();
         if valid {
-            self.opened_inbox = Some(ident);
+            self.opened_inbox = key;
         }
         return valid;
     }
@@ -0,0 +1,93 @@
+extern crate serde_yaml;
+
+// Local labels stored next to the account file, mails are known by their Message-ID so
+// labels work the same for every account type
+use std::{
+    error::Error,
+    fs,
+    io,
+    path::PathBuf,
+};
+
+use serde_yaml::{
+    Mapping,
+    Value,
+};
+
+pub struct Labels {
+    path: PathBuf,
+    // Label and the message ids carrying it, in the order labels were created
+    labels: Vec<(String, Vec<String>)>,
+}
+
+impl Labels {
+    // A missing file has no labels
+    pub fn load(path: PathBuf) -> Result<Labels, Box<dyn Error>> {
+        let labels = match fs::read(&path) {
+            Ok(content) => {
+                let root: Value = serde_yaml::from_slice(&content)?;
+                root.as_mapping().map(|map| map.iter().filter_map(|(label, ids)| Some((
+                    label.as_str()?.to_string(),
+                    ids.as_sequence()?.iter().filter_map(|id| id.as_str()).map(|id| id.to_string()).collect(),
+                ))).collect()).unwrap_or(Vec::new())
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(Box::new(e)),
+        };
+        Ok(Labels { path, labels })
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let mut map = Mapping::new();
+        for (label, ids) in self.labels.iter().filter(|(_, ids)| !ids.is_empty()) {
+            map.insert(Value::String(label.clone()), Value::Sequence(ids.iter().map(|id| Value::String(id.clone())).collect()));
+        }
+        fs::write(&self.path, serde_yaml::to_vec(&Value::Mapping(map))?)?;
+        Ok(())
+    }
+
+    // Returns the number of mails that didn't have the label yet
+    pub fn add(&mut self, label: &str, ids: &[String]) -> usize {
+        let index = match self.labels.iter().position(|(l, _)| l == label) {
+            Some(i) => i,
+            None => {
+                self.labels.push((label.to_string(), Vec::new()));
+                self.labels.len() - 1
+            },
+        };
+        let tagged = &mut self.labels[index].1;
+        let before = tagged.len();
+        for id in ids.iter() {
+            if !tagged.contains(id) {
+                tagged.push(id.clone());
+            }
+        }
+        tagged.len() - before
+    }
+
+    // Returns the number of mails the label was taken from
+    pub fn remove(&mut self, label: &str, ids: &[String]) -> usize {
+        match self.labels.iter_mut().find(|(l, _)| l == label) {
+            Some((_, tagged)) => {
+                let before = tagged.len();
+                tagged.retain(|id| !ids.contains(id));
+                before - tagged.len()
+            },
+            None => 0,
+        }
+    }
+
+    pub fn ids(&self, label: &str) -> Vec<String> {
+        self.labels.iter().find(|(l, _)| l == label).map(|(_, ids)| ids.clone()).unwrap_or(Vec::new())
+    }
+
+    // Labels of a mail, in the order they were created
+    pub fn of(&self, id: &str) -> Vec<String> {
+        self.labels.iter().filter(|(_, ids)| ids.iter().any(|i| i == id)).map(|(l, _)| l.clone()).collect()
+    }
+
+    // Labels in use with the number of mails carrying them
+    pub fn counts(&self) -> Vec<(String, usize)> {
+        self.labels.iter().filter(|(_, ids)| !ids.is_empty()).map(|(l, ids)| (l.clone(), ids.len())).collect()
+    }
+}
@@ -0,0 +1,62 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    sync::Mutex,
+};
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+pub const LEVELS: &'static [&'static str] = &["off", "error", "warn", "info", "debug", "trace"];
+
+// Diagnostics go to stderr, or to the log file once one is configured
+struct Logger {
+    file: Mutex<Option<File>>,
+}
+
+static LOGGER: Logger = Logger { file: Mutex::new(None) };
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format!("[{} {}] {}", record.level(), record.target(), record.args());
+        match self.file.lock().unwrap().as_mut() {
+            Some(file) => {
+                let _ = writeln!(file, "{}", line);
+            },
+            None => eprintln!("{}", line),
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = self.file.lock().unwrap().as_mut() {
+            let _ = file.flush();
+        }
+    }
+}
+
+pub fn parse_level(level: &str) -> Option<LevelFilter> {
+    level.parse::<LevelFilter>().ok()
+}
+
+// Registers the logger, only warnings and errors are shown until configured otherwise
+pub fn init() {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(LevelFilter::Warn);
+    }
+}
+
+pub fn configure(level: LevelFilter, path: Option<&str>) -> io::Result<()> {
+    let file = match path {
+        Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+        None => None,
+    };
+    *LOGGER.file.lock().unwrap() = file;
+    log::set_max_level(level);
+    Ok(())
+}
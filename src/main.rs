@@ -4,15 +4,65 @@ extern crate clitc;
 extern crate console;
 extern crate mime;
 
+#[macro_use]
+mod i18n;
 mod inbox;
 mod account;
 mod receiving;
 mod util;
 mod decoder;
+mod dns;
+mod http;
+mod discovery;
+mod vault;
+mod validate;
+mod config;
+mod mime_decode;
+mod sending;
+mod cli;
+mod output;
+mod completion;
+mod help;
+mod threading;
+mod filter;
+mod spellcheck;
+mod trace;
+mod error;
+mod address;
+mod headers;
+mod logger;
+mod graph;
+mod notmuch;
+mod contacts;
+mod pgp;
+mod smime;
+mod dkim;
+mod authres;
+mod rules;
+mod calendar;
+mod labels;
+mod searches;
+mod bounce;
+mod vacation;
+mod thunderbird;
+mod export;
+mod image;
+mod hooks;
+mod plugins;
+mod attachments;
+mod bayes;
+#[cfg(any(test, feature = "testing"))]
+mod testing;
 
 use console::{
     Style
 };
+use regex::RegexBuilder;
+use rustyline::{
+    Config,
+    Editor,
+    error::ReadlineError,
+};
 
 use std::{
     collections::HashMap,
@@ -21,17 +71,29 @@ use std::{
     sync::{Arc, Mutex},
     cmp::{Eq, PartialEq},
     hash::Hash,
+    path::Path,
 };
 use clitc::{
     events::{Event, EventHandler, WhitespaceSplitter},
     params::{CliParameters},
 };
 use inbox::{
+    BulkAction,
+    Inbox,
     InboxManager,
     MailBuilder,
 };
+use account::{
+    Account,
+    InboxConfig,
+};
+use completion::CommandHelper;
+use contacts::AddressBook;
+use error::{MailError, MailResult};
 
 const GLOBAL_PROMPT: &str = "cli-mail-rs";
+const TRACE_FILE: &str = "cli-mail-rs-trace.log";
+const HISTORY_SIZE: usize = 1000;
 
 #[derive(Clone, Hash)]
 enum Mode {
@@ -40,6 +102,7 @@ enum Mode {
     Inbox,
     Write,
     Read,
+    Contacts,
 }
 
 impl Mode {
@@ -58,10 +121,24 @@ impl Mode {
             Inbox => ("#", 2),
             Write => ("µ", 3),
             Read => ("λ", 4),
+            Contacts => ("@", 5),
         };
         ret.push_str(s);
         return (ret, code);
     }
+
+    // Told to plugins
+    pub fn name(&self) -> &'static str {
+        use Mode::*;
+        match self {
+            Exit => "exit",
+            Global => "global",
+            Inbox => "inbox",
+            Write => "write",
+            Read => "read",
+            Contacts => "contacts",
+        }
+    }
 }
 
 impl Eq for Mode {}
@@ -75,6 +152,7 @@ impl PartialEq for Mode {
             (Inbox, Inbox) => true,
             (Write, Write) => true,
             (Read, Read) => true,
+            (Contacts, Contacts) => true,
             (_, _) => false,
         }
     }
@@ -83,6 +161,825 @@ impl PartialEq for Mode {
 type ContextHandle = Arc<Mutex<InboxManager>>;
 type Emitter = (Mode, Option<String>);
 
+// Presents an inbox error after the sentence saying what failed, with a hint on what to do
+// where there is one
+fn report_error(failure: &str, e: &MailError) {
+    let hint = match e {
+        MailError::Connect { .. } | MailError::NotConnected => " Use \"refresh\" to connect again.",
+        MailError::Login(_) => " Check the credentials and use \"reload-accounts\".",
+        _ => "",
+    };
+    output::error(format!("{} [{}]{}", t!(failure), e, t!(hint)).as_str());
+}
+
+// Runs f again after the user agreed to expunge all deleted mails, on servers that can't
+// expunge only the mails of this session
+fn with_plain_expunge<T, F: FnMut(&mut Inbox) -> MailResult<T>>(inbox: &mut Inbox, mut f: F) -> MailResult<T> {
+    match f(inbox) {
+        Err(MailError::PlainExpunge) => {
+            if !util::confirm(&t!("The server can't expunge single mails, mails other clients marked as deleted are removed too. Continue?")) {
+                return Err(MailError::PlainExpunge);
+            }
+            inbox.allow_plain_expunge();
+            f(inbox)
+        },
+        result => result,
+    }
+}
+
+// Runs f on the mail opened in the current inbox, reporting if it can't be loaded
+fn with_opened_mail<F: FnOnce(&receiving::ReceivedMail)>(handle: &ContextHandle, f: F) {
+    let mut context = handle.lock().unwrap();
+    if let Some(inbox) = context.get_opened_inbox() {
+        match inbox.get_opened_mail() {
+            Ok(mail) => f(mail),
+            Err(e) => report_error("Could not load mail!", &e),
+        }
+    }
+}
+
+// Runs an external command of the settings and shows what it printed
+fn run_plugin(handle: &ContextHandle, command: &str, mode: &Mode, args: &[String]) {
+    let mut raw = None;
+    if *mode == Mode::Read {
+        with_opened_mail(handle, |mail| raw = Some(mail.get_raw().clone()));
+    }
+    let account = handle.lock().unwrap().opened_inbox.clone();
+    match plugins::run(command, args, mode.name(), account.as_deref(), raw.as_deref()) {
+        Ok(out) => {
+            print!("{}", out);
+            if !out.is_empty() && !out.ends_with('\n') {
+                println!();
+            }
+        },
+        Err(e) => output::error(tf!("Could not run plugin \"{}\"! [{}]", command, e).as_str()),
+    }
+}
+
+// Regex search over the cached headers and bodies, "-i" ignores case and "--all" looks
+// at every account instead of the opened inbox
+fn grep(handle: &ContextHandle, words: Vec<String>, all: bool) {
+    let ignore_case = words.iter().any(|w| w == "-i");
+    let all = all || words.iter().any(|w| w == "--all");
+    let pattern = words.iter().filter(|w| *w != "-i" && *w != "--all").cloned().collect::<Vec<String>>().join(" ");
+    if pattern.is_empty() {
+        println!("{}", t!("usage: grep [-i] [--all] <pattern>"));
+        return;
+    }
+    let regex = match RegexBuilder::new(pattern.as_str()).case_insensitive(ignore_case).build() {
+        Ok(regex) => regex,
+        Err(e) => {
+            output::error(tf!("Invalid pattern! [{}]", e).as_str());
+            return;
+        },
+    };
+    let matches = handle.lock().unwrap().grep(&regex, all);
+    if output::is_json() {
+        output::print_json(&serde_json::Value::Array(matches.iter().map(|(account, index, line)| serde_json::json!({ "account": account, "index": index, "line": line })).collect()));
+        return;
+    }
+    if matches.is_empty() {
+        println!("{}", t!("No cached mail matches, mails not opened yet are only searched by sender, recipients and subject."));
+        return;
+    }
+    let highlight = Style::new().reverse();
+    let context = handle.lock().unwrap();
+    for (account, index, line) in matches.iter() {
+        let marked = regex.replace_all(line.trim(), |c: &regex::Captures| output::emphasize(&c[0], &highlight));
+        if output::is_plain() {
+            println!("Account {}, mail {}: {}", account, index, marked);
+        } else {
+            println!("{}:{}: {}", context.account_tag(account), index, marked);
+        }
+    }
+}
+
+// Copies a value of the opened mail to the clipboard
+fn copy_from_mail(handle: &ContextHandle, what: &str, get: fn(&receiving::ReceivedMail) -> String) {
+    with_opened_mail(handle, |mail| {
+        match util::copy_to_clipboard(get(mail)) {
+            Ok(_) => println!("{}", tf!("Copied {} to clipboard!", what)),
+            Err(e) => println!("{}", tf!("Could not copy to clipboard! [{}]", e)),
+        }
+    });
+}
+
+// Points from a bounce to the returned mail, if it is loaded in any inbox
+fn print_original(context: &InboxManager, original: Option<String>) {
+    if output::is_json() {
+        return;
+    }
+    if let Some((account, index, info)) = original.and_then(|id| context.find_message(id.as_str())) {
+        println!("{}", tf!("Original:\tmail {} of \"{}\": {}", index, account, info));
+    }
+}
+
+// Lists the images of a displayed html mail below it, terminals with image support show them
+fn show_inline_images(mail: &receiving::ReceivedMail, remote: bool) {
+    if !mail.shows_html() || output::is_json() {
+        return;
+    }
+    let images = mail.get_inline_images(remote);
+    if images.is_empty() {
+        return;
+    }
+    let protocol = image::detect();
+    println!("{}", t!("\nImages:"));
+    for (i, (name, data)) in images.iter().enumerate() {
+        match data {
+            Some(data) => {
+                println!("\t[{}] {}", i, image::describe(name.as_str(), data));
+                if let Some(protocol) = protocol.filter(|p| image::can_show(*p, data)) {
+                    if let Err(e) = image::show(protocol, data) {
+                        output::error(tf!("Could not show image! [{}]", e).as_str());
+                    }
+                }
+            },
+            None if remote => println!("{}", tf!("\t[{}] {} (not available)", i, name)),
+            None => println!("{}", tf!("\t[{}] {} (remote images are off)", i, name)),
+        }
+    }
+}
+
+// Moves to a neighbouring mail of the listing and prints it, returns the new prompt path
+fn open_adjacent(handle: &ContextHandle, forward: bool, unread_only: bool) -> Option<String> {
+    let mut context = handle.lock().unwrap();
+    let keyring = context.keyring();
+    let ca_file = context.settings.smime_ca_file.clone();
+    let remote = context.settings.remote_images;
+    let inbox = context.get_opened_inbox()?;
+    if !inbox.open_adjacent(forward, unread_only) {
+        println!("{}", t!(match (forward, unread_only) {
+            (true, true) => "No next unread mail!",
+            (true, false) => "No next mail!",
+            (false, true) => "No previous unread mail!",
+            (false, false) => "No previous mail!",
+        }));
+        return None;
+    }
+    if let Err(e) = inbox.decrypt_opened(&keyring) {
+        report_error("Could not decrypt mail!", &e);
+    }
+    let (info, original, vars) = match inbox.get_opened_mail() {
+        Ok(mail) => {
+            mail.print_verified(&keyring, ca_file.as_ref().map(|f| f.as_str()));
+            show_inline_images(mail, remote);
+            (mail.get_info(), mail.get_bounce().and_then(|b| b.original_id()), mail.environment())
+        },
+        Err(e) => {
+            report_error("Could not open mail!", &e);
+            return None;
+        },
+    };
+    print_original(&context, original);
+    hooks::run(&context.settings.hooks, hooks::Event::Open, context.opened_inbox.as_deref().unwrap_or(""), vars);
+    Some(info)
+}
+
+// Answers the invitation in the opened mail with an iTIP reply to its organizer
+fn rsvp(handle: &ContextHandle, status: &str) {
+    let mut context = handle.lock().unwrap();
+    let inbox = match context.get_opened_inbox() {
+        Some(inbox) => inbox,
+        None => return,
+    };
+    let account = inbox.get_account().clone();
+    let mail = match inbox.get_opened_mail() {
+        Ok(mail) => mail,
+        Err(e) => {
+            report_error("Could not load mail!", &e);
+            return;
+        },
+    };
+    let event = match mail.get_events().into_iter().find(|e| e.is_invitation()) {
+        Some(event) => event,
+        None => {
+            println!("{}", t!("Mail contains no invitation!"));
+            return;
+        },
+    };
+    let organizer = match event.organizer() {
+        Some(organizer) => organizer,
+        None => {
+            println!("{}", t!("Invitation has no organizer to answer!"));
+            return;
+        },
+    };
+    // Answered as the invited address, the account name otherwise
+    let own = account.addresses();
+    let attendee = event.attendees().into_iter().map(|(a, _)| a)
+        .find(|a| own.iter().any(|o| o.eq_ignore_ascii_case(a)))
+        .unwrap_or(account.name.clone());
+    let message = calendar::reply_message(&event, attendee.as_str(), status);
+    match sending::MailTransport::send(&mut account.get_transport(), attendee.as_str(), &vec![organizer.clone()], message.as_str()) {
+        Ok(_) => println!("{}", tf!("Sent {} to {}!", status.to_lowercase(), organizer)),
+        Err(e) => println!("{}", tf!("Could not send reply! [{}]", e)),
+    }
+}
+
+// Resends the opened mail unchanged, unlike forwarding the recipients see the original sender
+fn redirect(handle: &ContextHandle, words: Vec<String>) {
+    let mut context = handle.lock().unwrap();
+    let recipients = resolve_recipients(&context, words);
+    if recipients.is_empty() {
+        println!("{}", t!("usage: redirect <address>..."));
+        return;
+    }
+    let confirm = context.settings.confirm;
+    let inbox = match context.get_opened_inbox() {
+        Some(inbox) => inbox,
+        None => return,
+    };
+    let account = inbox.get_account().clone();
+    let mut transport = account.get_transport();
+    // Graph takes the recipients from the headers of the message
+    if let sending::TransportAdapter::Graph(_) = transport {
+        report_error("Could not redirect the mail!", &MailError::Unsupported("redirecting"));
+        return;
+    }
+    let mail = match inbox.get_opened_mail() {
+        Ok(mail) => mail,
+        Err(e) => {
+            report_error("Could not load mail!", &e);
+            return;
+        },
+    };
+    if confirm && !util::confirm(tf!("Redirect \"{}\" to {}?", mail.get_subject(), recipients.join(", ")).as_str()) {
+        return;
+    }
+    // Resent-To shows the contacts as resolved, the envelope takes the bare addresses
+    let message = mail.redirect_message(account.name.as_str(), &recipients);
+    let addresses: Vec<String> = recipients.iter().map(|r| util::extract_address(r.as_str())).collect();
+    match sending::MailTransport::send(&mut transport, account.name.as_str(), &addresses, message.as_str()) {
+        Ok(_) => println!("{}", tf!("Redirected mail to {}!", recipients.join(", "))),
+        Err(e) => println!("{}", tf!("Could not redirect mail! [{}]", e)),
+    }
+}
+
+// Words without "@" are group names or looked up among the known people, asking which
+// one is meant when several match
+fn resolve_recipients(context: &InboxManager, words: Vec<String>) -> Vec<String> {
+    let book = context.address_book().ok();
+    let mut ret = Vec::new();
+    for word in words.into_iter() {
+        if word.contains('@') {
+            ret.push(word);
+            continue;
+        }
+        if let Some(group) = book.as_ref().and_then(|b| b.group(word.as_str())) {
+            ret.extend(group.members.iter().cloned());
+            continue;
+        }
+        let format = |(address, name): &(String, Option<String>)| match name {
+            Some(name) => format!("{} <{}>", name, address),
+            None => address.clone(),
+        };
+        let matches = context.match_people(word.as_str());
+        match matches.len() {
+            0 => println!("{}", tf!("No contact matches \"{}\"!", word)),
+            1 => ret.push(format(&matches[0])),
+            _ => {
+                println!("{}", tf!("Several contacts match \"{}\":", word));
+                for (i, person) in matches.iter().enumerate() {
+                    if output::is_plain() {
+                        println!("Contact {}: {}", i, format(person));
+                    } else {
+                        println!("\t{:>3} {}", i, format(person));
+                    }
+                }
+                match util::read_line(&t!("Recipient number: ")).parse::<usize>().ok().and_then(|i| matches.get(i)) {
+                    Some(person) => ret.push(format(person)),
+                    None => println!("{}", tf!("Skipped \"{}\"!", word)),
+                }
+            },
+        }
+    }
+    ret
+}
+
+// Runs f on the address book, saving it afterwards if f returns true
+fn with_address_book<F: FnOnce(&mut AddressBook) -> bool>(handle: &ContextHandle, f: F) {
+    let context = handle.lock().unwrap();
+    let mut book = match context.address_book() {
+        Ok(book) => book,
+        Err(e) => {
+            output::error(tf!("Could not read address book! [{}]", e).as_str());
+            return;
+        },
+    };
+    if f(&mut book) {
+        if let Err(e) = book.save() {
+            output::error(tf!("Could not save address book! [{}]", e).as_str());
+        }
+    }
+}
+
+// Adds or removes a local label on the mails of an index set in the opened inbox
+fn label_command(handle: &ContextHandle, words: Vec<String>, add: bool) {
+    let (set, label) = match (words.get(0), words.get(1)) {
+        (Some(set), Some(label)) if words.len() == 2 => (set, label),
+        _ => {
+            println!("{}", t!(if add { "usage: tag <mails> <label>" } else { "usage: untag <mails> <label>" }));
+            return;
+        },
+    };
+    let mut context = handle.lock().unwrap();
+    let mut labels = match context.labels() {
+        Ok(labels) => labels,
+        Err(e) => {
+            output::error(tf!("Could not read labels! [{}]", e).as_str());
+            return;
+        },
+    };
+    let (ids, missing) = match context.get_opened_inbox().map(|inbox| inbox.message_ids(set.as_str())) {
+        Some(Ok(ids)) => ids,
+        Some(Err(e)) => {
+            report_error("Could not label mails!", &e);
+            return;
+        },
+        None => return,
+    };
+    if missing > 0 {
+        println!("{}", tn!(missing, "{} mail without Message-ID can't be labeled!", "{} mails without Message-ID can't be labeled!"));
+    }
+    let num = if add { labels.add(label.as_str(), &ids) } else { labels.remove(label.as_str(), &ids) };
+    if let Err(e) = labels.save() {
+        output::error(tf!("Could not save labels! [{}]", e).as_str());
+        return;
+    }
+    println!("{}", tn!(num, "{} mail changed!", "{} mails changed!"));
+}
+
+// Creates accounts for the mail servers of a Thunderbird profile, with "--mail" the local mbox
+// folders are also copied into the notmuch maildir
+fn import_thunderbird(handle: &ContextHandle, profile: &Path, with_mail: bool) {
+    let prefs = match std::fs::read_to_string(profile.join("prefs.js")) {
+        Ok(content) => thunderbird::parse_prefs(content.as_str()),
+        Err(e) => {
+            output::error(tf!("Could not read prefs.js of {}! [{}]", profile.display(), e).as_str());
+            return;
+        },
+    };
+    let maildir = handle.lock().unwrap().settings.notmuch_maildir.clone();
+    if with_mail && maildir.is_none() {
+        println!("{}", t!("Mails are imported into notmuch, set \"notmuch_maildir\" in the settings!"));
+        return;
+    }
+    let imported = thunderbird::accounts(&prefs);
+    println!("{}", tn!(imported.len(), "Found {} account in the profile.", "Found {} accounts in the profile."));
+    let mut added = 0;
+    for found in imported.into_iter() {
+        println!("{}", tf!("{} server {} for {}", found.inbox.protocol().to_uppercase(), found.inbox.location(), found.user));
+        if handle.lock().unwrap().resolve(&found.user).is_some() {
+            println!("{}", t!("\talready configured, skipped"));
+            continue;
+        }
+        // Thunderbird keeps the passwords encrypted, so they are asked for
+        let password = util::read_password(tf!("Password for {} (empty to skip): ", found.user).as_str());
+        if password.is_empty() {
+            continue;
+        }
+        let smtp = match found.smtp {
+            Some(smtp) => smtp,
+            None => util::read_line(&t!("SMTP domain: ")),
+        };
+        let mut account = Account::new(found.inbox, smtp, found.user, password, None);
+        account.smtp_user = found.smtp_user;
+        account.aliases.extend(found.email);
+        if handle.lock().unwrap().add_account(account) {
+            added += 1;
+        } else {
+            println!("{}", t!("\tan account with this name or alias already exists, skipped"));
+        }
+    }
+    if added > 0 {
+        match handle.lock().unwrap().save_file() {
+            Ok(_) => println!("{}", tn!(added, "{} account added!", "{} accounts added!")),
+            Err(e) => println!("{}", tf!("Could not save account file! [{}]", e)),
+        }
+    }
+    let maildir = match (with_mail, maildir) {
+        (true, Some(maildir)) => maildir,
+        (_, _) => return,
+    };
+    let mut count = 0;
+    let mut stored = notmuch::Index::load(handle.lock().unwrap().data_path("notmuch-index"));
+    for dir in thunderbird::mail_directories(profile, &prefs) {
+        let folders = match thunderbird::mbox_folders(&dir) {
+            Ok(folders) => folders,
+            Err(e) => {
+                output::error(tf!("Could not list the folders in {}! [{}]", dir.display(), e).as_str());
+                continue;
+            },
+        };
+        let server = dir.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        for (folder, path) in folders.into_iter() {
+            let content = match std::fs::read(&path) {
+                Ok(content) => content,
+                Err(e) => {
+                    output::error(tf!("Could not read {}! [{}]", path.display(), e).as_str());
+                    continue;
+                },
+            };
+            let mails = thunderbird::split_mbox(&content);
+            let mut new = 0;
+            let kept: Vec<(usize, &Vec<u8>)> = mails.iter().enumerate().filter(|(_, m)| !thunderbird::is_expunged(m)).collect();
+            for &(i, mail) in kept.iter() {
+                match stored.deliver(maildir.as_str(), mail) {
+                    Ok(true) => new += 1,
+                    Ok(false) => {},
+                    Err(e) => {
+                        output::error(tf!("Could not store mail {} of {}! [{}]", i + 1, folder, e).as_str());
+                        break;
+                    },
+                }
+            }
+            println!("{}", tf!("{}/{}: {} of {} mails imported", server, folder, new, kept.len()));
+            count += new;
+        }
+    }
+    if count > 0 {
+        if let Err(e) = notmuch::index() {
+            output::error(tf!("notmuch new failed! [{}]", e).as_str());
+        }
+    }
+    println!("{}", tn!(count, "{} mail imported!", "{} mails imported!"));
+}
+
+// vacation [status] | vacation set <from> <until> --message-file <file> [--subject <text>...] | vacation off
+fn vacation_command(handle: &ContextHandle, words: Vec<String>) {
+    let mut context = handle.lock().unwrap();
+    let account = match context.get_opened_inbox() {
+        Some(inbox) => inbox.get_account().clone(),
+        None => return,
+    };
+    let ident = account.ident();
+    let mut schedules = match context.vacations() {
+        Ok(schedules) => schedules,
+        Err(e) => {
+            output::error(tf!("Could not read vacation responders! [{}]", e).as_str());
+            return;
+        },
+    };
+    match words.get(0).map(|w| w.as_str()) {
+        None | Some("status") => {
+            match schedules.get(ident.as_str()) {
+                Some(schedule) => println!("{}", tf!("Vacation responder set for {}", schedule.describe())),
+                None => println!("{}", t!("No vacation responder set up from here.")),
+            }
+            match vacation::is_installed(&account) {
+                Ok(installed) => println!("{}", t!(if installed { "The server has a vacation responder switched on." } else { "The server has no vacation responder switched on." })),
+                Err(e) => report_error("Could not ask the server for its vacation responder!", &e),
+            }
+        },
+        Some("set") => {
+            let date = |w: Option<&String>| w.and_then(|d| chrono::NaiveDate::parse_from_str(d, "%Y-%m-%d").ok());
+            let (from, until) = match (date(words.get(1)), date(words.get(2))) {
+                (Some(from), Some(until)) if from <= until => (from, until),
+                _ => {
+                    println!("{}", t!("usage: vacation set <from> <until> --message-file <file> [--subject <text>...], dates as yyyy-mm-dd"));
+                    return;
+                },
+            };
+            let mut file = None;
+            let mut subject: Option<Vec<String>> = None;
+            let mut rest = words[3..].iter();
+            while let Some(word) = rest.next() {
+                match word.as_str() {
+                    "--message-file" => file = rest.next().cloned(),
+                    "--subject" => subject = Some(Vec::new()),
+                    w => match subject.as_mut() {
+                        Some(subject) => subject.push(w.to_string()),
+                        None => {
+                            println!("{}", tf!("unknown parameter \"{}\"!", w));
+                            return;
+                        },
+                    },
+                }
+            }
+            let message = match file.map(|f| std::fs::read_to_string(&f).map_err(|e| (f, e))) {
+                Some(Ok(message)) => message,
+                Some(Err((f, e))) => {
+                    output::error(tf!("Could not read {}! [{}]", f, e).as_str());
+                    return;
+                },
+                None => {
+                    println!("{}", t!("vacation set needs --message-file with the reply text!"));
+                    return;
+                },
+            };
+            let vacation = vacation::Vacation { from, until, subject: subject.map(|s| s.join(" ")).filter(|s| !s.is_empty()), message };
+            let previous = match vacation::install(&account, &vacation) {
+                Ok(previous) => previous,
+                Err(e) => {
+                    report_error("Could not set up the vacation responder!", &e);
+                    return;
+                },
+            };
+            // Keeps the script that was active before a responder that is replaced
+            let previous = previous.or(schedules.get(ident.as_str()).and_then(|s| s.previous.clone()));
+            schedules.set(ident.as_str(), vacation::Schedule { from, until, previous });
+            if let Err(e) = schedules.save() {
+                output::error(tf!("Could not save vacation responders! [{}]", e).as_str());
+            }
+            println!("{}", tf!("Vacation responder set for {} to {}!", from, until));
+        },
+        Some("off") => {
+            let previous = schedules.get(ident.as_str()).and_then(|s| s.previous.clone());
+            if let Err(e) = vacation::remove(&account, previous.as_ref()) {
+                report_error("Could not remove the vacation responder!", &e);
+                return;
+            }
+            schedules.remove(ident.as_str());
+            if let Err(e) = schedules.save() {
+                output::error(tf!("Could not save vacation responders! [{}]", e).as_str());
+            }
+            match previous {
+                Some(previous) => println!("{}", tf!("Vacation responder removed, script {} is active again!", previous)),
+                None => println!("{}", t!("Vacation responder removed!")),
+            }
+        },
+        Some(other) => println!("{}", tf!("unknown vacation command \"{}\"!", other)),
+    }
+}
+
+// Unsubscribes from the list the opened mail came from, preferring the one-click POST over a
+// mailto request and opening the web page only as last resort
+fn unsubscribe(handle: &ContextHandle) {
+    let mut context = handle.lock().unwrap();
+    let confirm = context.settings.confirm;
+    let inbox = match context.get_opened_inbox() {
+        Some(inbox) => inbox,
+        None => return,
+    };
+    let account = inbox.get_account().clone();
+    let (links, one_click, list) = match inbox.get_opened_mail() {
+        Ok(mail) => {
+            let (links, one_click) = mail.get_unsubscribe();
+            (links, one_click, mail.get_from_address())
+        },
+        Err(e) => {
+            report_error("Could not load mail!", &e);
+            return;
+        },
+    };
+    let http = links.iter().find(|l| l.starts_with("https://") || l.starts_with("http://"));
+    let mailto = links.iter().find(|l| l.to_lowercase().starts_with("mailto:"));
+    if http.is_none() && mailto.is_none() {
+        println!("{}", t!("Mail has no unsubscribe link!"));
+        return;
+    }
+    if confirm && !util::confirm(tf!("Unsubscribe from {}?", list).as_str()) {
+        return;
+    }
+    match (http, mailto) {
+        (Some(url), _) if one_click => {
+            let body = http::form(&[("List-Unsubscribe", "One-Click")]);
+            match http::request("POST", url.as_str(), &[("Content-Type", "application/x-www-form-urlencoded")], Some(body.as_str())) {
+                Ok(response) if response.is_success() => println!("{}", t!("Unsubscribed!")),
+                Ok(response) => println!("{}", tf!("Unsubscribing failed, server answered {}!", response.status)),
+                Err(e) => println!("{}", tf!("Could not unsubscribe! [{}]", e)),
+            }
+        },
+        (_, Some(mailto)) => {
+            // mailto:list-request@example.org?subject=unsubscribe&body=...
+            let target = &mailto[7..];
+            let (address, query) = match target.find('?') {
+                Some(i) => (&target[..i], &target[i + 1..]),
+                None => (target, ""),
+            };
+            let param = |name: &str| query.split('&')
+                .filter_map(|p| p.find('=').map(|i| (&p[..i], &p[i + 1..])))
+                .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                .map(|(_, v)| http::url_decode(v));
+            let mut builder = MailBuilder::new();
+            builder.from(account.name.clone())
+                .to(vec![http::url_decode(address)])
+                .subject(param("subject").unwrap_or(String::from("unsubscribe")))
+                .text(param("body").unwrap_or(String::from("unsubscribe")));
+            let result = match builder.build() {
+                Ok(mail) => sending::send_mail(&mut account.get_transport(), &mail, &Default::default(), &mut Vec::new(), &mut |_, _| {}),
+                Err(_) => return,
+            };
+            match result {
+                Ok(_) => println!("{}", tf!("Sent unsubscribe request to {}!", http::url_decode(address))),
+                Err(e) => println!("{}", tf!("Could not send unsubscribe request! [{}]", e)),
+            }
+        },
+        (Some(url), None) => {
+            println!("{}", t!("List only offers a web page to unsubscribe, opening it."));
+            if let Err(e) = util::open_url(url.as_str()) {
+                println!("{}", tf!("Could not open link! [{}]", e));
+            }
+        },
+        (None, None) => {},
+    }
+}
+
+// Runs a bulk action on the opened inbox, mails are given as index set like "3-7,12,#4711"
+fn bulk_command(handle: &ContextHandle, set: Option<String>, action: BulkAction) {
+    let mut context = handle.lock().unwrap();
+    // Without mails the selection is used and cleared afterwards
+    let selection = if set.is_none() { context.get_opened_inbox().and_then(|inbox| inbox.selection_set()) } else { None };
+    let set = match set.or(selection.clone()) {
+        Some(set) => set,
+        None => {
+            println!("{}", t!("command needs mail indices like \"3-7,12\" or ids like \"#4711\" as parameter, or selected mails!"));
+            return;
+        }
+    };
+    let mut count = 0;
+    if let BulkAction::Delete(_) = action {
+        count = context.get_opened_inbox().and_then(|inbox| inbox.select(set.as_str()).ok()).map(|i| i.len()).unwrap_or(0);
+        if count > 0 && !context.confirm(tn!(count, "Delete {} mail?", "Delete {} mails?").as_str()) {
+            return;
+        }
+    }
+    // Marking junk teaches the local spam filter before the mails leave the listing
+    let training = match action { BulkAction::Spam(_) => Some(true), BulkAction::NotSpam => Some(false), _ => None };
+    if let Some(spam) = training {
+        if let Err(e) = context.train_spam(set.as_str(), spam) {
+            output::error(tf!("Could not train the spam filter! [{}]", e).as_str());
+        }
+    }
+    if let Some(inbox) = context.get_opened_inbox() {
+        let result = match inbox.bulk(set.as_str(), action) {
+            // Mails are deleted already, only expunging them waits for the user
+            Err(MailError::PlainExpunge) => with_plain_expunge(inbox, |inbox| inbox.expunge()).map(|_| count),
+            result => result,
+        };
+        match result {
+            Ok(num) => {
+                println!("{}", tn!(num, "{} mail changed!", "{} mails changed!"));
+                if selection.is_some() {
+                    inbox.clear_selection();
+                }
+            },
+            Err(e) => report_error("Could not change mails!", &e),
+        }
+    }
+}
+
+// Sends a mail through the account of its sender. On failure the mail is returned with the
+// recipients that already got it
+fn deliver(context: &mut InboxManager, builder: MailBuilder) -> Result<(), MailBuilder> {
+    let hooks = context.settings.hooks.clone();
+    let (account, mut transport) = match checkout_transport(context, &builder) {
+        Ok(checked_out) => checked_out,
+        Err(reason) => {
+            println!("{}", reason);
+            return Err(builder);
+        },
+    };
+    let result = send_over(&account, &mut transport, &hooks, builder, &mut |text| println!("{}", text));
+    context.return_transport(&account, transport);
+    result
+}
+
+// Like deliver, for mails sent in the background. The context is only locked to take out the
+// transport and to put it back, messages wait for the next prompt
+fn deliver_unlocked(handle: &ContextHandle, builder: MailBuilder) -> Result<(), MailBuilder> {
+    let (checked_out, hooks) = {
+        let mut context = handle.lock().unwrap();
+        (checkout_transport(&mut context, &builder), context.settings.hooks.clone())
+    };
+    let mut notices = Vec::new();
+    let result = match checked_out {
+        Ok((account, mut transport)) => {
+            let result = send_over(&account, &mut transport, &hooks, builder, &mut |text| notices.push(text));
+            handle.lock().unwrap().return_transport(&account, transport);
+            result
+        },
+        Err(reason) => {
+            notices.push(reason);
+            Err(builder)
+        },
+    };
+    let mut context = handle.lock().unwrap();
+    for text in notices.into_iter() {
+        context.notify(text);
+    }
+    result
+}
+
+// Account of the sender and its transport, taken out of the context while sending
+fn checkout_transport(context: &mut InboxManager, builder: &MailBuilder) -> Result<(Account, sending::TransportAdapter), String> {
+    let mail = builder.clone().build().map_err(|(_, field)| tf!("Mail is missing field \"{}\"!", field))?;
+    let account = context.find_sending_account(&mail.from).cloned().ok_or(tf!("No account found for sender \"{}\"!", mail.from))?;
+    let transport = context.take_transport(&account);
+    Ok((account, transport))
+}
+
+fn send_over(account: &Account, transport: &mut sending::TransportAdapter, hooks: &config::Hooks, mut builder: MailBuilder, report: &mut dyn FnMut(String)) -> Result<(), MailBuilder> {
+    let mail = match builder.clone().build() {
+        Ok(mail) => mail,
+        Err((builder, field)) => {
+            report(tf!("Mail is missing field \"{}\"!", field));
+            return Err(builder);
+        }
+    };
+    let recipients = builder.recipient_count();
+    let limit = account.rate_limit.clone().unwrap_or_default();
+    let mut done = builder.get_sent_to().clone();
+    if !done.is_empty() {
+        report(tf!("Resuming, {} recipients already got the mail.", done.len()));
+    }
+    let batched = limit.recipients_per_message.map(|n| (n as usize) < recipients).unwrap_or(false);
+    let result = sending::send_mail(transport, &mail, &limit, &mut done, &mut |sent, total| {
+        if batched {
+            report(tf!("Sent to {}/{} recipients...", sent, total));
+        }
+    });
+    match result {
+        Ok(_) => {
+            report(t!("Mail sent!").to_string());
+            hooks::run(hooks, hooks::Event::Send, account.name.as_str(), mail.environment());
+            Ok(())
+        },
+        Err(e) => {
+            report(tf!("Could not send mail! [{}]", e));
+            if !done.is_empty() {
+                report(tf!("{} of {} recipients got the mail, send again to resume.", done.len(), recipients));
+            }
+            builder.set_sent_to(done);
+            Err(builder)
+        },
+    }
+}
+
+// Takes a mail of the outbox back into write mode, returns whether it did
+fn cancel_send(handle: &ContextHandle, id: Option<String>) -> bool {
+    let mut context = handle.lock().unwrap();
+    if context.current_mail_writing.is_some() {
+        println!("{}", t!("Send or discard the mail being written first!"));
+        return false;
+    }
+    let id = match id.map(|id| id.parse::<usize>()) {
+        Some(Ok(id)) => Some(id),
+        Some(Err(_)) => {
+            println!("{}", t!("command cancel-send takes the number of a mail in the outbox!"));
+            return false;
+        },
+        None => None,
+    };
+    match context.cancel_pending(id) {
+        Some(builder) => {
+            context.current_mail_writing = Some(builder);
+            println!("{}", t!("Sending cancelled, the mail is back in write mode."));
+            true
+        },
+        None => {
+            println!("{}", t!("No such mail in the outbox!"));
+            false
+        },
+    }
+}
+
+// Mails waiting in the outbox are sent right away before leaving. Mails that failed before are
+// tried again if the user wants, they are lost otherwise
+fn flush_outbox(handle: &ContextHandle) {
+    let mut context = handle.lock().unwrap();
+    let pending = context.take_all_pending();
+    if !pending.is_empty() {
+        println!("{}", tn!(pending.len(), "Sending {} mail of the outbox before leaving...", "Sending {} mails of the outbox before leaving..."));
+    }
+    for builder in pending.into_iter() {
+        let _ = deliver(&mut context, builder);
+    }
+    let failed = context.take_failed();
+    if failed.is_empty() {
+        return;
+    }
+    for builder in failed.iter() {
+        output::error(tf!("Mail \"{}\" could not be sent!", builder.get_subject().cloned().unwrap_or_default()).as_str());
+    }
+    if !util::confirm(&tn!(failed.len(), "{} mail of the outbox could not be sent, try again before leaving?", "{} mails of the outbox could not be sent, try again before leaving?")) {
+        return;
+    }
+    for builder in failed.into_iter() {
+        if deliver(&mut context, builder).is_err() {
+            output::error(t!("The mail is lost!").as_ref());
+        }
+    }
+}
+
+// Polls all accounts in the background, the context is only locked to merge new headers
+fn start_auto_refresh(handle: &ContextHandle) {
+    let interval = handle.lock().unwrap().settings.refresh_interval;
+    if interval == 0 {
+        return;
+    }
+    let handle = Arc::clone(handle);
+    std::thread::spawn(move || loop {
+        std::thread::sleep(std::time::Duration::from_secs(interval));
+        let count = InboxManager::poll(&handle);
+        // Printing now would break into the line being typed
+        if count > 0 {
+            handle.lock().unwrap().notify(tn!(count, "{} new mail arrived!", "{} new mails arrived!"));
+        }
+    });
+}
+
 fn init_modes() -> (Arc<Mutex<Option<Emitter>>>, HashMap<Mode, HashMap<String, Event<ContextHandle, Emitter>>>) {
     let mut states: HashMap<Mode, HashMap<String, Event<ContextHandle, Emitter>>> = HashMap::new();
     let handle = Arc::new(Mutex::new(None));
@@ -91,9 +988,11 @@ fn init_modes() -> (Arc<Mutex<Option<Emitter>>>, HashMap<Mode, HashMap<String, E
     {
         let mut global = HashMap::new();
         global.insert(String::from("refresh"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, _| {
-            // Parse args
-            let mut context = handle.lock().unwrap();
-            context.refresh();
+            InboxManager::refresh(handle);
+        })));
+
+        global.insert(String::from("sync-junk"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, _| {
+            handle.lock().unwrap().sync_junk_now();
         })));
 
         global.insert(String::from("show-inbox"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
@@ -105,7 +1004,7 @@ fn init_modes() -> (Arc<Mutex<Option<Emitter>>>, HashMap<Mode, HashMap<String, E
                 }
             }
             //  call Display inboxes from context
-            let context = handle.lock().unwrap();
+            let mut context = handle.lock().unwrap();
             context.show_inbox(account);
         })));
 
@@ -119,10 +1018,10 @@ fn init_modes() -> (Arc<Mutex<Option<Emitter>>>, HashMap<Mode, HashMap<String, E
                     let mut emitter = emit_handle.lock().unwrap();
                     *emitter = Some((Mode::Inbox, Some(val)));
                 } else {
-                    println!("no account named \"{}\" available!", val);
+                    println!("{}", tf!("no account named \"{}\" available!", val));
                 }
             } else {
-                println!("inbox command needs valid account as parameter!");
+                println!("{}", t!("inbox command needs valid account as parameter!"));
             }
         })));
 
@@ -133,66 +1032,830 @@ fn init_modes() -> (Arc<Mutex<Option<Emitter>>>, HashMap<Mode, HashMap<String, E
 
         global.insert(String::from("show-drafts"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
             // ToDo: Show Drafts functionality
-            println!("show-drafts not yet implemented!");
+            println!("{}", t!("show-drafts not yet implemented!"));
         })));
 
-        global.insert(String::from("add-server"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
-            // ToDo: Add Server functionality
-            println!("add-server not yet implemented!");
+        global.insert(String::from("add-server"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, _| {
+            let address = util::read_line(&t!("Mail address: "));
+            let password = util::read_password(&t!("Password: "));
+            println!("{}", tf!("Looking up server settings for \"{}\" ...", address));
+            let settings = discovery::discover(address.as_str());
+
+            // Let user confirm or override the discovered settings
+            let inbox = match &settings.inbox {
+                Some(config) => {
+                    println!("{}", tf!("Found {} server {} [{}]", config.protocol().to_uppercase(), config.location(), settings.source));
+                    let domain = util::read_line(&tf!("{} domain (empty to accept): ", config.protocol().to_uppercase()));
+                    match config {
+                        _ if domain.is_empty() => config.clone(),
+                        InboxConfig::Pop3(_, _) => InboxConfig::new_pop3(domain),
+                        _ => InboxConfig::new_imap(domain),
+                    }
+                },
+                None => {
+                    println!("{}", t!("No inbox server found!"));
+                    InboxConfig::new_imap(util::read_line(&t!("IMAP domain: ")))
+                },
+            };
+            let smtp = match &settings.smtp {
+                Some((domain, port)) => {
+                    println!("{}", tf!("Found SMTP server {}:{} [{}]", domain, port, settings.source));
+                    let input = util::read_line(&t!("SMTP domain (empty to accept): "));
+                    // Other ports than 465 are kept as "host:port", see sending::split_host
+                    match input.is_empty() {
+                        true if *port == sending::SMTPS_PORT => domain.clone(),
+                        true => format!("{}:{}", domain, port),
+                        false => input,
+                    }
+                },
+                None => util::read_line(&t!("SMTP domain: ")),
+            };
+            let shortcut = util::read_line(&t!("Shortcut (optional): "));
+            let shortcut = if shortcut.is_empty() { None } else { Some(shortcut) };
+
+            let account = Account::new(inbox, smtp, address, password, shortcut);
+            let mut context = handle.lock().unwrap();
+            if context.add_account(account) {
+                match context.save_file() {
+                    Ok(_) => println!("{}", t!("Account added!")),
+                    Err(e) => println!("{}", tf!("Could not save account file! [{}]", e)),
+                }
+            } else {
+                println!("{}", t!("An account with this name or shortcut already exists!"));
+            }
+        })));
+
+        global.insert(String::from("import"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            let words: Vec<String> = match args.get(&String::from("args")) {
+                Some(clitc::params::ParamValue::Array(vec)) => vec.clone(),
+                Some(other) => vec![other.to_string()],
+                None => Vec::new(),
+            };
+            let with_mail = words.iter().any(|w| w == "--mail");
+            let words: Vec<&String> = words.iter().filter(|w| *w != "--mail").collect();
+            match (words.get(0).map(|w| w.as_str()), words.get(1)) {
+                (Some("thunderbird"), Some(profile)) if words.len() == 2 => import_thunderbird(handle, Path::new(profile.as_str()), with_mail),
+                _ => println!("{}", t!("usage: import thunderbird <profile-dir> [--mail]")),
+            }
+        })));
+
+        global.insert(String::from("reload-accounts"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, _| {
+            let mut context = handle.lock().unwrap();
+            match context.reload_file() {
+                Ok((added, removed, changed)) => println!("{}", tf!("Accounts reloaded! ({} added, {} removed, {} changed)", added, removed, changed)),
+                Err(e) => println!("{}", tf!("Could not reload account file! [{}]", e)),
+            }
+        })));
+
+        global.insert(String::from("encrypt-accounts"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, _| {
+            let passphrase = util::read_password(&t!("New master passphrase: "));
+            if passphrase.is_empty() || passphrase != util::read_password(&t!("Repeat master passphrase: ")) {
+                println!("{}", t!("Passphrases are empty or do not match!"));
+                return;
+            }
+            let mut context = handle.lock().unwrap();
+            context.set_passphrase(Some(passphrase));
+            match context.save_file() {
+                Ok(_) => println!("{}", t!("Account file encrypted!")),
+                Err(e) => println!("{}", tf!("Could not save account file! [{}]", e)),
+            }
+        })));
+
+        global.insert(String::from("decrypt-accounts"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, _| {
+            let mut context = handle.lock().unwrap();
+            if !context.has_passphrase() {
+                println!("{}", t!("Account file is not encrypted!"));
+                return;
+            }
+            context.set_passphrase(None);
+            match context.save_file() {
+                Ok(_) => println!("{}", t!("Account file stored unencrypted!")),
+                Err(e) => println!("{}", tf!("Could not save account file! [{}]", e)),
+            }
         })));
 
-        global.insert(String::from("write"), Event::<ContextHandle, Emitter>::Emit(Arc::clone(&handle), Rc::new(|handle, emit_handle, _| {
+        global.insert(String::from("trace"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|_, args| {
+            match args.get(&String::from("state")).map(|x| x.to_string()).as_ref().map(|x| x.as_str()) {
+                Some("on") => {
+                    let path = args.get(&String::from("file")).map(|x| x.to_string()).unwrap_or(String::from(TRACE_FILE));
+                    match trace::start(path.as_str()) {
+                        Ok(_) => println!("{}", tf!("Tracing IMAP and SMTP traffic to \"{}\", credentials are redacted", path)),
+                        Err(e) => println!("{}", tf!("Could not open trace file \"{}\" [{}]", path, e)),
+                    }
+                },
+                Some("off") => {
+                    trace::stop();
+                    println!("{}", t!("Tracing stopped!"));
+                },
+                _ => println!("{}", t!(if trace::is_active() { "trace is on, use \"trace on [file]\" or \"trace off\"" } else { "trace is off, use \"trace on [file]\" or \"trace off\"" })),
+            }
+        })));
+
+        global.insert(String::from("output"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|_, args| {
+            match args.get(&String::from("format")).map(|x| x.to_string()).as_ref().map(|x| x.as_str()) {
+                Some("json") => output::set_json(true),
+                Some("text") => output::set_json(false),
+                _ => println!("{}", t!("output command needs \"json\" or \"text\" as parameter!")),
+            }
+        })));
+
+        global.insert(String::from("write"), Event::<ContextHandle, Emitter>::Emit(Arc::clone(&handle), Rc::new(|handle, emit_handle, args| {
+            let words: Vec<String> = match args.get(&String::from("args")) {
+                Some(clitc::params::ParamValue::Array(vec)) => vec.clone(),
+                Some(other) => vec![other.to_string()],
+                None => Vec::new(),
+            };
+            {
+                let mut context = handle.lock().unwrap();
+                // "key=value" words fill placeholders, another word names a template
+                let (vars, names): (Vec<&String>, Vec<&String>) = words.iter().partition(|w| w.contains('='));
+                let template = names.first().map(|name| context.load_template(name.as_str()));
+                let builder = match template {
+                    Some(Ok(builder)) => Some(builder),
+                    Some(Err(e)) => {
+                        output::error(tf!("Could not load template \"{}\"! [{}]", names[0], e).as_str());
+                        return;
+                    },
+                    None => None,
+                };
+                if context.current_mail_writing.is_none() || builder.is_some() {
+                    context.current_mail_writing = Some(builder.unwrap_or(MailBuilder::new()));
+                }
+                if let Some(mail) = &mut context.current_mail_writing {
+                    for word in vars.into_iter() {
+                        let mut parts = word.splitn(2, '=');
+                        mail.var(parts.next().unwrap_or("").to_string(), parts.next().unwrap_or("").to_string());
+                    }
+                }
+            }
             // Emit Write Emitter switch
             let mut emitter = emit_handle.lock().unwrap();
             *emitter = Some((Mode::Write, None));
         })));
-
-        global.insert(String::from("exit"), Event::<ContextHandle, Emitter>::Emit(Arc::clone(&handle), Rc::new(|handle, emit_handle, _| {
-            // Emit Exit signal
-            let mut emitter = emit_handle.lock().unwrap();
-            *emitter = Some((Mode::Exit, None));
+
+        global.insert(String::from("lock"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|_, _| {
+            if pgp::lock() {
+                println!("{}", t!("Passphrase forgotten, the next encrypted mail asks for it again."));
+            } else {
+                println!("{}", t!("No passphrase kept!"));
+            }
+        })));
+        global.insert(String::from("show-outbox"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, _| {
+            handle.lock().unwrap().show_outbox();
+        })));
+        global.insert(String::from("cancel-send"), Event::<ContextHandle, Emitter>::Emit(Arc::clone(&handle), Rc::new(|handle, emit_handle, args| {
+            if cancel_send(handle, args.get(&String::from("args")).map(|x| x.to_string())) {
+                let mut emitter = emit_handle.lock().unwrap();
+                *emitter = Some((Mode::Write, None));
+            }
+        })));
+        global.insert(String::from("exit"), Event::<ContextHandle, Emitter>::Emit(Arc::clone(&handle), Rc::new(|handle, emit_handle, _| {
+            // Emit Exit signal
+            let mut emitter = emit_handle.lock().unwrap();
+            *emitter = Some((Mode::Exit, None));
+        })));
+        global.insert(String::from("search"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            let query = match args.get(&String::from("query")) {
+                Some(clitc::params::ParamValue::Array(vec)) => vec.join(" "),
+                Some(other) => other.to_string(),
+                None => String::new(),
+            };
+            if handle.lock().unwrap().settings.notmuch_maildir.is_none() {
+                println!("{}", t!("notmuch is not set up, set \"notmuch_maildir\" in the settings!"));
+                return;
+            }
+            if query.is_empty() {
+                println!("{}", t!("command search needs a notmuch query!"));
+                return;
+            }
+            match notmuch::search(query.as_str(), output::is_json()) {
+                Ok(result) if result.trim().is_empty() => println!("{}", t!("No mails found!")),
+                Ok(result) => util::page(result.trim_end()),
+                Err(e) => output::error(tf!("Could not search with notmuch! [{}]", e).as_str()),
+            }
+        })));
+
+        global.insert(String::from("grep"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            let words: Vec<String> = match args.get(&String::from("args")) {
+                Some(clitc::params::ParamValue::Array(vec)) => vec.clone(),
+                Some(other) => vec![other.to_string()],
+                None => Vec::new(),
+            };
+            grep(handle, words, true);
+        })));
+
+        global.insert(String::from("tag"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            let words: Vec<String> = match args.get(&String::from("args")) {
+                Some(clitc::params::ParamValue::Array(vec)) => vec.clone(),
+                Some(other) => vec![other.to_string()],
+                None => Vec::new(),
+            };
+            if handle.lock().unwrap().settings.notmuch_maildir.is_none() {
+                println!("{}", t!("notmuch is not set up, set \"notmuch_maildir\" in the settings!"));
+                return;
+            }
+            // Leading "+tag" and "-tag" words are the changes, the rest is the query
+            let split = words.iter().position(|w| !(w.starts_with('+') || w.starts_with('-'))).unwrap_or(words.len());
+            let changes: Vec<&str> = words[..split].iter().map(|w| w.as_str()).collect();
+            let query = words[split..].join(" ");
+            if changes.is_empty() || query.is_empty() {
+                println!("{}", t!("usage: tag <+tag|-tag>... <query>"));
+                return;
+            }
+            match notmuch::tag(&changes, query.as_str()) {
+                Ok(_) => println!("{}", t!("Tags changed!")),
+                Err(e) => output::error(tf!("Could not tag with notmuch! [{}]", e).as_str()),
+            }
+        })));
+
+        global.insert(String::from("contacts"), Event::<ContextHandle, Emitter>::Emit(Arc::clone(&handle), Rc::new(|_, emit_handle, _| {
+            let mut emitter = emit_handle.lock().unwrap();
+            *emitter = Some((Mode::Contacts, Some(String::from("contacts"))));
+        })));
+
+        global.insert(String::from("keys"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            let words: Vec<String> = match args.get(&String::from("args")) {
+                Some(clitc::params::ParamValue::Array(vec)) => vec.clone(),
+                Some(other) => vec![other.to_string()],
+                None => Vec::new(),
+            };
+            let context = handle.lock().unwrap();
+            let keyring = context.keyring();
+            let keys = match (words.get(0).map(|s| s.as_str()), words.get(1)) {
+                (None, _) | (Some("list"), None) => {
+                    let book = context.address_book().ok();
+                    match keyring.list() {
+                        Ok(keys) if keys.is_empty() => println!("{}", t!("No keys in the keyring!")),
+                        Ok(keys) => for key in keys {
+                            println!("{}{}", key.fingerprint, if key.valid { "" } else { " (invalid)" });
+                            for uid in &key.uids {
+                                println!("    {}", uid);
+                            }
+                            // Contacts using this key
+                            if let Some(book) = &book {
+                                for contact in book.contacts().iter().filter(|c| c.key.as_ref() == Some(&key.fingerprint)) {
+                                    println!("{}", tf!("    used for {}", contact.address));
+                                }
+                            }
+                        },
+                        Err(e) => output::error(tf!("Could not list keys! [{}]", e).as_str()),
+                    }
+                    return;
+                },
+                (Some("import"), Some(file)) => match keyring.import(file.as_str()) {
+                    Ok(fingerprints) => fingerprints.iter().filter_map(|f| keyring.find(f).ok().flatten()).collect::<Vec<pgp::Key>>(),
+                    Err(e) => {
+                        output::error(tf!("Could not import keys from \"{}\"! [{}]", file, e).as_str());
+                        return;
+                    },
+                },
+                (Some("fetch"), Some(address)) => match keyring.fetch(address.as_str()) {
+                    Ok(keys) => keys,
+                    Err(e) => {
+                        output::error(tf!("Could not fetch key for {}! [{}]", address, e).as_str());
+                        return;
+                    },
+                },
+                (_, _) => {
+                    println!("{}", t!("usage: keys list | keys import <file> | keys fetch <email>"));
+                    return;
+                },
+            };
+            if keys.is_empty() {
+                println!("{}", t!("No new keys!"));
+                return;
+            }
+            // Associate the addresses of the new keys with their contacts
+            let mut book = match context.address_book() {
+                Ok(book) => book,
+                Err(e) => {
+                    output::error(tf!("Could not read address book! [{}]", e).as_str());
+                    return;
+                },
+            };
+            for key in keys.iter().filter(|k| k.valid) {
+                println!("{}", tf!("Key {}", key.fingerprint));
+                for address in key.addresses() {
+                    book.get_or_insert(address.as_str()).key = Some(key.fingerprint.clone());
+                    println!("{}", tf!("    assigned to {}", address));
+                }
+            }
+            if let Err(e) = book.save() {
+                output::error(tf!("Could not save address book! [{}]", e).as_str());
+            }
+        })));
+
+        global.insert(String::from("help"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|_, args| {
+            help::print_help(help::GLOBAL, args.get(&String::from("command")).map(|x| x.to_string()));
+        })));
+        states.insert(Mode::Global, global);
+    }
+
+    // Inbox Emitter
+    {
+        let mut inbox = HashMap::new();
+        inbox.insert(String::from("show-unread"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, _| {
+            let mut context = handle.lock().unwrap();
+            if let Some(inbox) = context.get_opened_inbox() {
+                inbox.show_unread(false);
+            }
+        })));
+        inbox.insert(String::from("show-all"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, _| {
+            let mut context = handle.lock().unwrap();
+            if let Some(inbox) = context.get_opened_inbox() {
+                inbox.show_conversations();
+            }
+        })));
+        inbox.insert(String::from("expand"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            let index = match args.get(&String::from("args")) {
+                Some(clitc::params::ParamValue::Array(vec)) => vec.get(0).and_then(|x| x.parse::<usize>().ok()),
+                Some(other) => other.to_string().parse::<usize>().ok(),
+                None => None,
+            };
+            let index = match index {
+                Some(index) => index,
+                None => {
+                    println!("{}", t!("command expand needs a thread number as parameter!"));
+                    return;
+                },
+            };
+            let mut context = handle.lock().unwrap();
+            if let Some(inbox) = context.get_opened_inbox() {
+                match inbox.expand(index) {
+                    Ok(_) => inbox.show_threads(),
+                    Err(e) => report_error("Could not expand thread!", &e),
+                }
+            }
+        })));
+        inbox.insert(String::from("collapse"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, _| {
+            let mut context = handle.lock().unwrap();
+            if let Some(inbox) = context.get_opened_inbox() {
+                inbox.collapse();
+                inbox.show_threads();
+            }
+        })));
+        inbox.insert(String::from("filter"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            let criteria = match args.get(&String::from("criteria")) {
+                Some(clitc::params::ParamValue::Array(vec)) => vec.clone(),
+                _ => Vec::new(),
+            };
+            let mut context = handle.lock().unwrap();
+            if let Some(inbox) = context.get_opened_inbox() {
+                if criteria.is_empty() {
+                    match inbox.get_filter() {
+                        Some(filter) => println!("{}", tf!("Filtered by {}", filter)),
+                        None => println!("{}", t!("No filter set!")),
+                    }
+                    return;
+                }
+                if criteria.len() == 1 && criteria[0] == "clear" {
+                    inbox.set_filter(None);
+                    println!("{}", t!("Filter cleared!"));
+                    return;
+                }
+                match filter::MailFilter::parse(&criteria) {
+                    Ok(filter) => {
+                        inbox.set_filter(Some(filter));
+                        inbox.show_mails(false);
+                    },
+                    Err(e) => println!("{}", e),
+                }
+            }
+        })));
+        inbox.insert(String::from("save-search"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            let words: Vec<String> = match args.get(&String::from("args")) {
+                Some(clitc::params::ParamValue::Array(vec)) => vec.clone(),
+                Some(other) => vec![other.to_string()],
+                None => Vec::new(),
+            };
+            if words.len() < 2 {
+                println!("{}", t!("usage: save-search <name> <criteria>..."));
+                return;
+            }
+            // Only expressions the filter understands are saved
+            if let Err(e) = filter::MailFilter::parse(&words[1..].to_vec()) {
+                println!("{}", e);
+                return;
+            }
+            let context = handle.lock().unwrap();
+            let result = context.searches().and_then(|mut searches| {
+                searches.set(words[0].as_str(), words[1..].join(" ").as_str());
+                searches.save()
+            });
+            match result {
+                Ok(_) => println!("{}", tf!("Saved search {}!", words[0])),
+                Err(e) => output::error(tf!("Could not save search! [{}]", e).as_str()),
+            }
+        })));
+        inbox.insert(String::from("delete-search"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            let name = match args.get(&String::from("args")) {
+                Some(clitc::params::ParamValue::Array(vec)) if vec.len() == 1 => vec[0].clone(),
+                Some(clitc::params::ParamValue::Array(_)) | None => {
+                    println!("{}", t!("usage: delete-search <name>"));
+                    return;
+                },
+                Some(other) => other.to_string(),
+            };
+            let context = handle.lock().unwrap();
+            let result = context.searches().and_then(|mut searches| {
+                let found = searches.remove(name.as_str());
+                searches.save().map(|_| found)
+            });
+            match result {
+                Ok(true) => println!("{}", tf!("Deleted search {}!", name)),
+                Ok(false) => println!("{}", tf!("No search named {}!", name)),
+                Err(e) => output::error(tf!("Could not save searches! [{}]", e).as_str()),
+            }
+        })));
+        inbox.insert(String::from("grep"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            let words: Vec<String> = match args.get(&String::from("args")) {
+                Some(clitc::params::ParamValue::Array(vec)) => vec.clone(),
+                Some(other) => vec![other.to_string()],
+                None => Vec::new(),
+            };
+            grep(handle, words, false);
+        })));
+        inbox.insert(String::from("search"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            let name = match args.get(&String::from("args")) {
+                Some(clitc::params::ParamValue::Array(vec)) => vec.join(" "),
+                Some(other) => other.to_string(),
+                None => String::new(),
+            };
+            let mut context = handle.lock().unwrap();
+            let expression = match context.searches() {
+                Ok(searches) => searches.get(name.as_str()).cloned(),
+                Err(e) => {
+                    output::error(tf!("Could not read searches! [{}]", e).as_str());
+                    return;
+                },
+            };
+            let expression = match expression {
+                Some(expression) => expression,
+                None => {
+                    println!("{}", tf!("No search named \"{}\", see show-searches!", name));
+                    return;
+                },
+            };
+            if let Some(inbox) = context.get_opened_inbox() {
+                match filter::MailFilter::parse(&expression.split_whitespace().map(|w| w.to_string()).collect()) {
+                    Ok(filter) => {
+                        inbox.set_filter(Some(filter));
+                        inbox.show_mails(false);
+                    },
+                    Err(e) => println!("{}", e),
+                }
+            }
+        })));
+        inbox.insert(String::from("show-searches"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, _| {
+            match handle.lock().unwrap().searches() {
+                Ok(searches) => {
+                    if searches.is_empty() {
+                        println!("{}", t!("No saved searches!"));
+                    }
+                    for (name, expression) in searches.iter() {
+                        println!("\t{}: {}", name, expression);
+                    }
+                },
+                Err(e) => output::error(tf!("Could not read searches! [{}]", e).as_str()),
+            }
+        })));
+        inbox.insert(String::from("show-threads"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, _| {
+            let mut context = handle.lock().unwrap();
+            if let Some(inbox) = context.get_opened_inbox() {
+                inbox.show_threads();
+            }
+        })));
+        inbox.insert(String::from("open-thread"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            let index = args.get(&String::from("index")).and_then(|x| x.to_string().parse::<usize>().ok());
+            if let Some(index) = index {
+                let mut context = handle.lock().unwrap();
+                if let Some(inbox) = context.get_opened_inbox() {
+                    if let Err(e) = inbox.open_thread(index) {
+                        report_error("Could not open thread!", &e);
+                    }
+                }
+            } else {
+                println!("{}", t!("command open-thread needs a thread number as parameter!"));
+            }
+        })));
+        inbox.insert(String::from("delete"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            let folder = handle.lock().unwrap().settings.trash_folder.clone();
+            bulk_command(handle, args.get(&String::from("mails")).map(|x| x.to_string()), BulkAction::Delete(folder));
+        })));
+        inbox.insert(String::from("show-trash"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, _args| {
+            let mut context = handle.lock().unwrap();
+            let folder = context.settings.trash_folder.clone();
+            if let Some(inbox) = context.get_opened_inbox() {
+                if let Err(e) = inbox.show_trash(folder) {
+                    report_error("Could not list the trash!", &e);
+                }
+            }
+        })));
+        inbox.insert(String::from("restore"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            let set = match args.get(&String::from("mails")) {
+                Some(set) => set.to_string(),
+                None => {
+                    println!("{}", t!("command restore needs indices of the trash listing like \"3-7,12\" as parameter!"));
+                    return;
+                }
+            };
+            let mut context = handle.lock().unwrap();
+            let folder = context.settings.trash_folder.clone();
+            if let Some(inbox) = context.get_opened_inbox() {
+                match with_plain_expunge(inbox, |inbox| inbox.restore(set.as_str(), folder.clone())) {
+                    Ok(num) => println!("{}", tn!(num, "{} mail restored!", "{} mails restored!")),
+                    Err(e) => report_error("Could not restore mails!", &e),
+                }
+            }
+        })));
+        inbox.insert(String::from("empty-trash"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, _args| {
+            let mut context = handle.lock().unwrap();
+            if !context.confirm("Remove all mails of the trash for good?") {
+                return;
+            }
+            let folder = context.settings.trash_folder.clone();
+            if let Some(inbox) = context.get_opened_inbox() {
+                match inbox.empty_trash(folder) {
+                    Ok(num) => println!("{}", tn!(num, "{} mail removed for good!", "{} mails removed for good!")),
+                    Err(e) => report_error("Could not empty the trash!", &e),
+                }
+            }
+        })));
+        inbox.insert(String::from("move"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            match (args.get(&String::from("mails")), args.get(&String::from("folder"))) {
+                (mails, Some(folder)) => bulk_command(handle, mails.map(|x| x.to_string()), BulkAction::Move(folder.to_string())),
+                // "move <folder>" moves the selection
+                (Some(folder), None) => bulk_command(handle, None, BulkAction::Move(folder.to_string())),
+                (None, None) => println!("{}", t!("command move needs a folder as parameter!")),
+            }
+        })));
+        inbox.insert(String::from("select"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            let words: Vec<String> = match args.get(&String::from("args")) {
+                Some(clitc::params::ParamValue::Array(vec)) => vec.clone(),
+                Some(other) => vec![other.to_string()],
+                None => Vec::new(),
+            };
+            let mut context = handle.lock().unwrap();
+            let inbox = match context.get_opened_inbox() {
+                Some(inbox) => inbox,
+                None => return,
+            };
+            if words.is_empty() {
+                inbox.show_selection();
+                return;
+            }
+            if words.len() == 1 && words[0] == "clear" {
+                inbox.clear_selection();
+                println!("{}", t!("Selection cleared!"));
+                return;
+            }
+            // Criteria like from:<addr> select what they match, otherwise indices and ids are given
+            let indices = if words.iter().any(|w| w.contains(':')) {
+                match filter::MailFilter::parse(&words) {
+                    Ok(filter) => inbox.matching(&filter),
+                    Err(e) => {
+                        println!("{}", e);
+                        return;
+                    },
+                }
+            } else {
+                match inbox.select(words.join(",").as_str()) {
+                    Ok(indices) => indices,
+                    Err(e) => {
+                        report_error("Could not select mails!", &e);
+                        return;
+                    },
+                }
+            };
+            let count = inbox.add_to_selection(&indices);
+            println!("{}", tn!(count, "{} mail selected, delete, move and archive without mails apply to it.", "{} mails selected, delete, move and archive without mails apply to them."));
+        })));
+        inbox.insert(String::from("archive"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            bulk_command(handle, args.get(&String::from("mails")).map(|x| x.to_string()), BulkAction::Archive);
+        })));
+        inbox.insert(String::from("spam"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            let folder = handle.lock().unwrap().settings.junk_folder.clone();
+            bulk_command(handle, args.get(&String::from("mails")).map(|x| x.to_string()), BulkAction::Spam(folder));
+        })));
+        for name in ["not-spam", "ham"].iter() {
+            inbox.insert(String::from(*name), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+                bulk_command(handle, args.get(&String::from("mails")).map(|x| x.to_string()), BulkAction::NotSpam);
+            })));
+        }
+        inbox.insert(String::from("tag"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            let words: Vec<String> = match args.get(&String::from("args")) {
+                Some(clitc::params::ParamValue::Array(vec)) => vec.clone(),
+                Some(other) => vec![other.to_string()],
+                None => Vec::new(),
+            };
+            label_command(handle, words, true);
+        })));
+        inbox.insert(String::from("untag"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            let words: Vec<String> = match args.get(&String::from("args")) {
+                Some(clitc::params::ParamValue::Array(vec)) => vec.clone(),
+                Some(other) => vec![other.to_string()],
+                None => Vec::new(),
+            };
+            label_command(handle, words, false);
+        })));
+        inbox.insert(String::from("show-tag"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            let label = match args.get(&String::from("args")) {
+                Some(clitc::params::ParamValue::Array(vec)) => vec.get(0).cloned(),
+                Some(other) => Some(other.to_string()),
+                None => None,
+            };
+            let mut context = handle.lock().unwrap();
+            let labels = match context.labels() {
+                Ok(labels) => labels,
+                Err(e) => {
+                    output::error(tf!("Could not read labels! [{}]", e).as_str());
+                    return;
+                },
+            };
+            match label {
+                Some(label) => if let Some(inbox) = context.get_opened_inbox() {
+                    inbox.show_labeled(label.as_str(), &labels.ids(label.as_str()));
+                },
+                // Without a label all labels in use are listed
+                None => {
+                    if labels.counts().is_empty() {
+                        println!("{}", t!("No labels in use!"));
+                    }
+                    for (label, count) in labels.counts() {
+                        println!("\t{} ({})", label, count);
+                    }
+                },
+            }
+        })));
+        inbox.insert(String::from("vacation"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            let words: Vec<String> = match args.get(&String::from("args")) {
+                Some(clitc::params::ParamValue::Array(vec)) => vec.clone(),
+                Some(other) => vec![other.to_string()],
+                None => Vec::new(),
+            };
+            vacation_command(handle, words);
+        })));
+        inbox.insert(String::from("flag"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            bulk_command(handle, args.get(&String::from("mails")).map(|x| x.to_string()), BulkAction::Flag(true));
+        })));
+        inbox.insert(String::from("unflag"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            bulk_command(handle, args.get(&String::from("mails")).map(|x| x.to_string()), BulkAction::Flag(false));
+        })));
+        inbox.insert(String::from("open"), Event::<ContextHandle, Emitter>::Emit(Arc::clone(&handle), Rc::new(|ctx_handle, emit_handle, args| {
+            // Subjects may be given as several words
+            let param = match args.get(&String::from("ident")) {
+                Some(clitc::params::ParamValue::Array(vec)) => Some(vec.join(" ")),
+                Some(other) => Some(other.to_string()),
+                None => None,
+            };
+            if let Some(param) = param {
+                let mut context = ctx_handle.lock().unwrap();
+                let keyring = context.keyring();
+                if let Some(inbox) = context.get_opened_inbox() {
+                    match inbox.open_mail(param.clone()) {
+                        Ok(_) => {},
+                        Err(MailError::Ambiguous { query, candidates }) => {
+                            println!("{}", tf!("Several mails match \"{}\", open one by its index:", query));
+                            candidates.iter().for_each(|(i, info)| println!("\t{:>3} {}", i, info));
+                            return;
+                        },
+                        Err(e) => {
+                            report_error("Could not open mail!", &e);
+                            return;
+                        },
+                    }
+                    if let Err(e) = inbox.decrypt_opened(&keyring) {
+                        report_error("Could not decrypt mail!", &e);
+                    }
+                    match inbox.get_opened_mail() {
+                        Ok(mail) => {
+                            // change mode to read
+                            let mut emitter = emit_handle.lock().unwrap();
+                            *emitter = Some((Mode::Read, Some(mail.get_info())));
+                        },
+                        Err(e) => report_error("Could not open mail!", &e),
+                    }
+                }
+            } else {
+                println!("{}", t!("command open needs valid parameter!"));
+            }
+        })));
+        inbox.insert(String::from("rules"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            let words: Vec<String> = match args.get(&String::from("args")) {
+                Some(clitc::params::ParamValue::Array(vec)) => vec.clone(),
+                Some(other) => vec![other.to_string()],
+                None => Vec::new(),
+            };
+            let mut context = handle.lock().unwrap();
+            let rules = match context.rules() {
+                Ok(rules) => rules,
+                Err(e) => {
+                    output::error(tf!("Could not read rules! [{}]", e).as_str());
+                    return;
+                },
+            };
+            match (words.get(0).map(|s| s.as_str()), words.get(1).and_then(|i| i.parse::<usize>().ok())) {
+                (None, _) | (Some("list"), _) => {
+                    if rules.is_empty() {
+                        println!("{}", tf!("No rules in {}!", context.data_path("rules.yml").display()));
+                    }
+                    for rule in rules.iter() {
+                        println!("{}: {}", rule.name, rule.describe_actions());
+                    }
+                },
+                // Dry run, shows what would be done with the mail
+                (Some("test"), Some(index)) => if let Some(inbox) = context.get_opened_inbox() {
+                    match inbox.test_rules(&rules, index) {
+                        Ok(matching) if matching.is_empty() => println!("{}", tf!("No rule matches mail {}!", index)),
+                        Ok(matching) => {
+                            for (n, rule) in matching.iter().enumerate() {
+                                println!("{}: {}{}", rule.name, rule.describe_actions(), if n == 0 { "" } else { " (not applied, an earlier rule matches)" });
+                            }
+                        },
+                        Err(e) => report_error("Could not test rules!", &e),
+                    }
+                },
+                (_, _) => println!("{}", t!("usage: rules [list] | rules test <mail index>")),
+            }
+        })));
+        inbox.insert(String::from("undo"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, _| {
+            let mut context = handle.lock().unwrap();
+            if let Some(inbox) = context.get_opened_inbox() {
+                match with_plain_expunge(inbox, |inbox| inbox.undo()) {
+                    Ok(done) => println!("{}", done),
+                    Err(e) => report_error("Could not undo!", &e),
+                }
+            }
         })));
-        states.insert(Mode::Global, global);
-    }
-
-    // Inbox Emitter
-    {
-        let mut inbox = HashMap::new();
-        inbox.insert(String::from("show-unread"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, _| {
+        inbox.insert(String::from("show-folders"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            let words: Vec<String> = match args.get(&String::from("args")) {
+                Some(clitc::params::ParamValue::Array(vec)) => vec.clone(),
+                Some(other) => vec![other.to_string()],
+                None => Vec::new(),
+            };
             let mut context = handle.lock().unwrap();
+            // The setting decides unless a flag is given
+            let subscribed = if words.iter().any(|w| w == "--all") {
+                false
+            } else {
+                context.settings.subscribed_folders || words.iter().any(|w| w == "--subscribed")
+            };
             if let Some(inbox) = context.get_opened_inbox() {
-                inbox.show_unread(false);
+                if let Err(e) = inbox.show_folders(subscribed) {
+                    report_error("Could not list folders!", &e);
+                }
             }
         })));
-        inbox.insert(String::from("show-all"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, _| {
+        for (name, subscribe) in [("subscribe", true), ("unsubscribe", false)].iter().cloned() {
+            inbox.insert(String::from(name), Event::<ContextHandle, Emitter>::Callback(Rc::new(move |handle, args| {
+                let folder = match args.get(&String::from("args")) {
+                    Some(clitc::params::ParamValue::Array(vec)) => vec.join(" "),
+                    Some(other) => other.to_string(),
+                    None => String::new(),
+                };
+                if folder.is_empty() {
+                    println!("{}", tf!("command {} needs a folder as parameter!", name));
+                    return;
+                }
+                let mut context = handle.lock().unwrap();
+                if let Some(inbox) = context.get_opened_inbox() {
+                    match inbox.subscribe(folder.as_str(), subscribe) {
+                        Ok(_) if subscribe => println!("{}", tf!("Subscribed to \"{}\"!", folder)),
+                        Ok(_) => println!("{}", tf!("Unsubscribed from \"{}\"!", folder)),
+                        Err(e) => report_error(if subscribe { "Could not subscribe!" } else { "Could not unsubscribe!" }, &e),
+                    }
+                }
+            })));
+        }
+        inbox.insert(String::from("expunge"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, _| {
             let mut context = handle.lock().unwrap();
+            if !context.confirm("Remove all mails marked as deleted for good?") {
+                return;
+            }
             if let Some(inbox) = context.get_opened_inbox() {
-                inbox.show_mails(false);
+                match with_plain_expunge(inbox, |inbox| inbox.expunge()) {
+                    Ok(_) => println!("{}", t!("Deleted mails removed!")),
+                    Err(e) => report_error("Could not expunge deleted mails!", &e),
+                }
             }
         })));
-        inbox.insert(String::from("open"), Event::<ContextHandle, Emitter>::Emit(Arc::clone(&handle), Rc::new(|ctx_handle, emit_handle, args| {
-            let param = args.get(&String::from("ident")).map(|x| x.to_string());
-            if let Some(param) = param {
+        inbox.insert(String::from("exit"), Event::<ContextHandle, Emitter>::Emit(Arc::clone(&handle), Rc::new(|ctx_handle, emit_handle, _| {
+            // Deleted mails can't be restored after leaving the inbox
+            {
                 let mut context = ctx_handle.lock().unwrap();
                 if let Some(inbox) = context.get_opened_inbox() {
-                    inbox.open_mail(param.clone());
-                    if let Some(mail) = inbox.get_opened_mail() {
-                        // change mode to read
-                        let mut emitter = emit_handle.lock().unwrap();
-                        *emitter = Some((Mode::Read, Some(mail.get_info())));
-                    } else {
-                        println!("Could not open mail!");
+                    if let Err(e) = with_plain_expunge(inbox, |inbox| inbox.close()) {
+                        report_error("Could not expunge deleted mails!", &e);
                     }
                 }
-            } else {
-                println!("command open needs valid parameter!");
             }
-        })));
-        inbox.insert(String::from("exit"), Event::<ContextHandle, Emitter>::Emit(Arc::clone(&handle), Rc::new(|_, emit_handle, _| {
             // Emit mode change -> global signal
             let mut emitter = emit_handle.lock().unwrap();
             *emitter = Some((Mode::Global, Some(GLOBAL_PROMPT.to_string())));
         })));
+        inbox.insert(String::from("help"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|_, args| {
+            help::print_help(help::INBOX, args.get(&String::from("command")).map(|x| x.to_string()));
+        })));
         states.insert(Mode::Inbox, inbox);
     }
 
@@ -200,11 +1863,274 @@ fn init_modes() -> (Arc<Mutex<Option<Emitter>>>, HashMap<Mode, HashMap<String, E
     {
         let mut read = HashMap::new();
         read.insert(String::from("show-mail"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|ctx_handle, args| {
-            let mut context = ctx_handle.lock().unwrap();
-            if let Some(inbox) = context.get_opened_inbox() {
-                if let Some(mail) = inbox.get_opened_mail() {
-                    mail.print_all();
+            let (keyring, ca_file, remote) = {
+                let context = ctx_handle.lock().unwrap();
+                (context.keyring(), context.settings.smime_ca_file.clone(), context.settings.remote_images)
+            };
+            let mut original = None;
+            let mut vars = None;
+            with_opened_mail(ctx_handle, |mail| {
+                mail.print_verified(&keyring, ca_file.as_ref().map(|f| f.as_str()));
+                show_inline_images(mail, remote);
+                original = mail.get_bounce().and_then(|b| b.original_id());
+                vars = Some(mail.environment());
+            });
+            let context = ctx_handle.lock().unwrap();
+            print_original(&context, original);
+            if let Some(vars) = vars {
+                hooks::run(&context.settings.hooks, hooks::Event::Open, context.opened_inbox.as_deref().unwrap_or(""), vars);
+            }
+        })));
+
+        read.insert(String::from("copy-address"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|ctx_handle, _| {
+            copy_from_mail(ctx_handle, "address", |mail| mail.get_from_address());
+        })));
+        read.insert(String::from("copy-subject"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|ctx_handle, _| {
+            copy_from_mail(ctx_handle, "subject", |mail| mail.get_subject().clone());
+        })));
+        read.insert(String::from("copy-body"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|ctx_handle, _| {
+            copy_from_mail(ctx_handle, "body", |mail| mail.get_body().clone());
+        })));
+        read.insert(String::from("find"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|ctx_handle, args| {
+            let pattern = match args.get(&String::from("text")) {
+                Some(clitc::params::ParamValue::Array(vec)) => vec.join(" "),
+                Some(other) => other.to_string(),
+                None => String::new(),
+            };
+            if pattern.is_empty() {
+                println!("{}", t!("command find needs a text to search for!"));
+                return;
+            }
+            with_opened_mail(ctx_handle, |mail| mail.find(pattern.as_str()));
+        })));
+        read.insert(String::from("open-link"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|ctx_handle, args| {
+            let index = match args.get(&String::from("index")).and_then(|x| x.to_string().parse::<usize>().ok()) {
+                Some(index) => index,
+                None => {
+                    println!("{}", t!("command open-link needs a link number as parameter!"));
+                    return;
+                }
+            };
+            with_opened_mail(ctx_handle, |mail| {
+                match mail.get_links().get(index) {
+                    Some(url) => if let Err(e) = util::open_url(url.as_str()) {
+                        println!("{}", tf!("Could not open link! [{}]", e));
+                    },
+                    None => println!("{}", tf!("No link with number {}!", index)),
+                }
+            });
+        })));
+        read.insert(String::from("accept"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|ctx_handle, _| {
+            rsvp(ctx_handle, "ACCEPTED");
+        })));
+        read.insert(String::from("tentative"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|ctx_handle, _| {
+            rsvp(ctx_handle, "TENTATIVE");
+        })));
+        read.insert(String::from("decline"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|ctx_handle, _| {
+            rsvp(ctx_handle, "DECLINED");
+        })));
+        read.insert(String::from("unsubscribe"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|ctx_handle, _| {
+            unsubscribe(ctx_handle);
+        })));
+        read.insert(String::from("export-ics"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|ctx_handle, args| {
+            let file = match args.get(&String::from("args")) {
+                Some(clitc::params::ParamValue::Array(vec)) => vec.get(0).cloned(),
+                Some(other) => Some(other.to_string()),
+                None => None,
+            };
+            let command = ctx_handle.lock().unwrap().settings.calendar_import.clone();
+            with_opened_mail(ctx_handle, |mail| {
+                if mail.get_calendar().is_empty() {
+                    println!("{}", t!("Mail contains no calendar data!"));
+                    return;
+                }
+                let ics = mail.get_calendar().join("\r\n");
+                match (file.as_ref(), command.as_ref()) {
+                    (Some(file), _) => match std::fs::write(file, ics.as_bytes()) {
+                        Ok(_) => println!("{}", tf!("Saved calendar to {}!", file)),
+                        Err(e) => output::error(tf!("Could not write {}! [{}]", file, e).as_str()),
+                    },
+                    (None, Some(command)) => match calendar::import(command.as_str(), ics.as_str()) {
+                        Ok(_) => println!("{}", t!("Imported calendar!")),
+                        Err(e) => output::error(tf!("Could not import calendar! [{}]", e).as_str()),
+                    },
+                    (None, None) => println!("{}", t!("command export-ics needs a file name or the calendar_import setting!")),
+                }
+            });
+        })));
+        read.insert(String::from("redirect"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|ctx_handle, args| {
+            let words: Vec<String> = match args.get(&String::from("args")) {
+                Some(clitc::params::ParamValue::Array(vec)) => vec.clone(),
+                Some(other) => vec![other.to_string()],
+                None => Vec::new(),
+            };
+            redirect(ctx_handle, words);
+        })));
+        read.insert(String::from("preview-attachment"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|ctx_handle, args| {
+            let index = match args.get(&String::from("index")).and_then(|x| x.to_string().parse::<usize>().ok()) {
+                Some(index) => index,
+                None => {
+                    println!("{}", t!("command preview-attachment needs an attachment number as parameter!"));
+                    return;
+                },
+            };
+            with_opened_mail(ctx_handle, |mail| {
+                let part = match mail.get_attachment(index) {
+                    Some(part) => part,
+                    None => {
+                        println!("{}", tf!("Mail has no attachment [{}]!", index));
+                        return;
+                    },
+                };
+                if !part.mime.starts_with("image/") && image::dimensions(&part.data).is_none() {
+                    println!("{}", tf!("{} is no image ({})!", part.name, part.mime));
+                    return;
+                }
+                println!("{}", image::describe(part.name.as_str(), &part.data));
+                if let Some(protocol) = image::detect().filter(|p| image::can_show(*p, &part.data)) {
+                    if let Err(e) = image::show(protocol, &part.data) {
+                        output::error(tf!("Could not show image! [{}]", e).as_str());
+                    }
                 }
+            });
+        })));
+        read.insert(String::from("lock"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|_, _| {
+            if pgp::lock() {
+                println!("{}", t!("Passphrase forgotten, the next encrypted mail asks for it again."));
+            } else {
+                println!("{}", t!("No passphrase kept!"));
+            }
+        })));
+        read.insert(String::from("show-image"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|ctx_handle, args| {
+            let index = match args.get(&String::from("index")).and_then(|x| x.to_string().parse::<usize>().ok()) {
+                Some(index) => index,
+                None => {
+                    println!("{}", t!("command show-image needs an image number as parameter!"));
+                    return;
+                },
+            };
+            let remote = ctx_handle.lock().unwrap().settings.remote_images;
+            with_opened_mail(ctx_handle, |mail| {
+                match mail.get_inline_images(remote).into_iter().nth(index) {
+                    Some((name, Some(data))) => {
+                        println!("{}", image::describe(name.as_str(), &data));
+                        match image::detect().filter(|p| image::can_show(*p, &data)) {
+                            Some(protocol) => if let Err(e) = image::show(protocol, &data) {
+                                output::error(tf!("Could not show image! [{}]", e).as_str());
+                            },
+                            None => println!("{}", t!("The terminal can't show this image!")),
+                        }
+                    },
+                    Some((name, None)) => println!("{}", tf!("Image {} is not available!", name)),
+                    None => println!("{}", tf!("Mail has no image [{}]!", index)),
+                }
+            });
+        })));
+        read.insert(String::from("export"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|ctx_handle, args| {
+            let words: Vec<String> = match args.get(&String::from("args")) {
+                Some(clitc::params::ParamValue::Array(vec)) => vec.clone(),
+                Some(other) => vec![other.to_string()],
+                None => Vec::new(),
+            };
+            let mut format = None;
+            let mut file = None;
+            let mut rest = words.iter();
+            while let Some(word) = rest.next() {
+                match word.as_str() {
+                    "--format" => format = rest.next().map(|f| f.to_lowercase()),
+                    w if file.is_none() => file = Some(w.to_string()),
+                    _ => {
+                        println!("{}", t!("usage: export [file] --format pdf|html"));
+                        return;
+                    },
+                }
+            }
+            // Without --format the extension of the file decides, html otherwise
+            let format = format.or(file.as_ref().filter(|f| f.to_lowercase().ends_with(".pdf")).map(|_| String::from("pdf"))).unwrap_or(String::from("html"));
+            if format != "pdf" && format != "html" {
+                println!("{}", t!("export only knows the formats pdf and html!"));
+                return;
+            }
+            let command = ctx_handle.lock().unwrap().settings.pdf_command.clone().unwrap_or(String::from(export::DEFAULT_PDF_COMMAND));
+            with_opened_mail(ctx_handle, |mail| {
+                let file = file.unwrap_or(export::default_name(mail.get_subject(), format.as_str()));
+                let html = mail.to_html();
+                let result = match format.as_str() {
+                    "pdf" => export::write_pdf(command.as_str(), html.as_str(), Path::new(&file)),
+                    _ => std::fs::write(&file, html.as_bytes()),
+                };
+                match result {
+                    Ok(_) => println!("{}", tf!("Exported mail to {}!", file)),
+                    Err(e) => output::error(tf!("Could not export mail to {}! [{}]", file, e).as_str()),
+                }
+            });
+        })));
+        read.insert(String::from("show-headers"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|ctx_handle, _| {
+            with_opened_mail(ctx_handle, |mail| mail.print_headers());
+        })));
+        read.insert(String::from("structure"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|ctx_handle, _| {
+            with_opened_mail(ctx_handle, |mail| mail.print_structure());
+        })));
+        read.insert(String::from("save-part"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|ctx_handle, args| {
+            let words: Vec<String> = match args.get(&String::from("args")) {
+                Some(clitc::params::ParamValue::Array(vec)) => vec.clone(),
+                Some(other) => vec![other.to_string()],
+                None => Vec::new(),
+            };
+            let index = match words.first() {
+                Some(index) => index.clone(),
+                None => {
+                    println!("{}", t!("command save-part needs a part number like \"1.2\" as parameter, see structure!"));
+                    return;
+                },
+            };
+            with_opened_mail(ctx_handle, |mail| {
+                let part = match mail.get_part(index.as_str()) {
+                    Some(part) => part,
+                    None => {
+                        println!("{}", tf!("Mail has no part [{}]!", index));
+                        return;
+                    },
+                };
+                // Only the file name of the part is used, it comes from the sender
+                let name = Path::new(&part.name).file_name().map(|n| n.to_string_lossy().to_string())
+                    .unwrap_or(format!("part-{}.{}", index, mime_decode::extension(part.mime.as_str())));
+                let path = match words.get(1).map(|p| Path::new(p)) {
+                    Some(dir) if dir.is_dir() => dir.join(name),
+                    Some(path) => path.to_path_buf(),
+                    None => Path::new(&name).to_path_buf(),
+                };
+                match std::fs::write(&path, &part.data) {
+                    Ok(_) => println!("{}", tf!("Saved part {} ({}, {} bytes) to {}!", index, part.mime, part.data.len(), path.display())),
+                    Err(e) => output::error(tf!("Could not save part to {}! [{}]", path.display(), e).as_str()),
+                }
+            });
+        })));
+        read.insert(String::from("show-raw"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|ctx_handle, _| {
+            with_opened_mail(ctx_handle, |mail| {
+                if output::is_json() {
+                    output::print_json(&serde_json::json!({ "raw": mail.get_raw() }));
+                } else {
+                    util::page(mail.get_raw().as_str());
+                }
+            });
+        })));
+        read.insert(String::from("next"), Event::<ContextHandle, Emitter>::Emit(Arc::clone(&handle), Rc::new(|ctx_handle, emit_handle, _| {
+            if let Some(path) = open_adjacent(ctx_handle, true, false) {
+                let mut emitter = emit_handle.lock().unwrap();
+                *emitter = Some((Mode::Read, Some(path)));
+            }
+        })));
+        read.insert(String::from("prev"), Event::<ContextHandle, Emitter>::Emit(Arc::clone(&handle), Rc::new(|ctx_handle, emit_handle, _| {
+            if let Some(path) = open_adjacent(ctx_handle, false, false) {
+                let mut emitter = emit_handle.lock().unwrap();
+                *emitter = Some((Mode::Read, Some(path)));
+            }
+        })));
+        read.insert(String::from("next-unread"), Event::<ContextHandle, Emitter>::Emit(Arc::clone(&handle), Rc::new(|ctx_handle, emit_handle, _| {
+            if let Some(path) = open_adjacent(ctx_handle, true, true) {
+                let mut emitter = emit_handle.lock().unwrap();
+                *emitter = Some((Mode::Read, Some(path)));
             }
         })));
 
@@ -213,13 +2139,22 @@ fn init_modes() -> (Arc<Mutex<Option<Emitter>>>, HashMap<Mode, HashMap<String, E
             {
                 // set from, to and about on reply mail
                 let mut context = ctx_handle.lock().unwrap();
+                let style = match &context.opened_inbox {
+                    Some(ident) => context.quote_style(ident),
+                    None => context.settings.quoting.clone(),
+                };
                 if let Some(inbox) = context.get_opened_inbox() {
                     let name = inbox.get_account_name();
-                    if let Some(recv_mail) = inbox.get_opened_mail().clone() {
-                        // Craft reply MailBuilder
-                        let reply = recv_mail.create_reply();
-                        context.current_mail_writing = Some(reply);
-                        prompt_path = Some(name);
+                    let account = inbox.get_account();
+                    let own_addresses = account.addresses();
+                    match inbox.get_opened_mail() {
+                        Ok(recv_mail) => {
+                            // Craft reply MailBuilder
+                            let reply = recv_mail.create_reply(&style, &own_addresses);
+                            context.current_mail_writing = Some(reply);
+                            prompt_path = Some(name);
+                        },
+                        Err(e) => report_error("Could not load mail!", &e),
                     }
                 }
             }
@@ -228,6 +2163,69 @@ fn init_modes() -> (Arc<Mutex<Option<Emitter>>>, HashMap<Mode, HashMap<String, E
             *emitter = Some((Mode::Write, prompt_path));
         })));
 
+        read.insert(String::from("list-reply"), Event::<ContextHandle, Emitter>::Emit(Arc::clone(&handle), Rc::new(|ctx_handle, emit_handle, _| {
+            let mut prompt_path = None;
+            {
+                // set from, to and about on reply mail
+                let mut context = ctx_handle.lock().unwrap();
+                let style = match &context.opened_inbox {
+                    Some(ident) => context.quote_style(ident),
+                    None => context.settings.quoting.clone(),
+                };
+                if let Some(inbox) = context.get_opened_inbox() {
+                    let name = inbox.get_account_name();
+                    let account = inbox.get_account();
+                    let own_addresses = account.addresses();
+                    match inbox.get_opened_mail() {
+                        Ok(recv_mail) => {
+                            match recv_mail.create_list_reply(&style, &own_addresses) {
+                                Some(reply) => {
+                                    context.current_mail_writing = Some(reply);
+                                    prompt_path = Some(name);
+                                },
+                                None => println!("{}", t!("Mail has no list posting address!")),
+                            }
+                        },
+                        Err(e) => report_error("Could not load mail!", &e),
+                    }
+                }
+            }
+            // Only a started reply switches to write mode
+            if prompt_path.is_some() {
+                let mut emitter = emit_handle.lock().unwrap();
+                *emitter = Some((Mode::Write, prompt_path));
+            }
+        })));
+
+        read.insert(String::from("edit-as-new"), Event::<ContextHandle, Emitter>::Emit(Arc::clone(&handle), Rc::new(|ctx_handle, emit_handle, _| {
+            let mut prompt_path = None;
+            {
+                let mut context = ctx_handle.lock().unwrap();
+                if let Some(inbox) = context.get_opened_inbox() {
+                    let name = inbox.get_account_name();
+                    let own_addresses = inbox.get_account().addresses();
+                    match inbox.get_opened_mail() {
+                        Ok(recv_mail) => {
+                            let mut copy = recv_mail.create_copy(&own_addresses);
+                            // Attachments are taken over as they are
+                            for index in 0..recv_mail.get_attachments().len() {
+                                if let Some(part) = recv_mail.get_attachment(index) {
+                                    copy.attach(attachments::Attachment { name: part.name, content_type: part.mime, data: part.data });
+                                }
+                            }
+                            context.current_mail_writing = Some(copy);
+                            prompt_path = Some(name);
+                        },
+                        Err(e) => report_error("Could not load mail!", &e),
+                    }
+                }
+            }
+            if prompt_path.is_some() {
+                let mut emitter = emit_handle.lock().unwrap();
+                *emitter = Some((Mode::Write, prompt_path));
+            }
+        })));
+
         read.insert(String::from("close"), Event::<ContextHandle, Emitter>::Emit(Arc::clone(&handle), Rc::new(|ctx_handle, emit_handle, args| {
             // Change mode to global or inbox (if open)
             let emitted;
@@ -244,6 +2242,9 @@ fn init_modes() -> (Arc<Mutex<Option<Emitter>>>, HashMap<Mode, HashMap<String, E
             let mut emitter = emit_handle.lock().unwrap();
             *emitter = Some(emitted);
         })));
+        read.insert(String::from("help"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|_, args| {
+            help::print_help(help::READ, args.get(&String::from("command")).map(|x| x.to_string()));
+        })));
         states.insert(Mode::Read, read);
     }
 
@@ -271,6 +2272,7 @@ fn init_modes() -> (Arc<Mutex<Option<Emitter>>>, HashMap<Mode, HashMap<String, E
                 };
 
                 let mut context = handle.lock().unwrap();
+                let recipients = resolve_recipients(&context, recipients);
                 if let Some(mail) = &mut context.current_mail_writing {
                     mail.to(recipients);
                 }
@@ -284,6 +2286,7 @@ fn init_modes() -> (Arc<Mutex<Option<Emitter>>>, HashMap<Mode, HashMap<String, E
                 };
 
                 let mut context = handle.lock().unwrap();
+                let recipients = resolve_recipients(&context, recipients);
                 if let Some(mail) = &mut context.current_mail_writing {
                     mail.cc(recipients);
                 }
@@ -297,6 +2300,7 @@ fn init_modes() -> (Arc<Mutex<Option<Emitter>>>, HashMap<Mode, HashMap<String, E
                 };
 
                 let mut context = handle.lock().unwrap();
+                let recipients = resolve_recipients(&context, recipients);
                 if let Some(mail) = &mut context.current_mail_writing {
                     mail.bcc(recipients);
                 }
@@ -317,7 +2321,7 @@ fn init_modes() -> (Arc<Mutex<Option<Emitter>>>, HashMap<Mode, HashMap<String, E
         })));
         write.insert(String::from("text"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
             use std::io::{stdin, stdout, Write};
-            println!("Enter Text. Finish with '$'.");
+            println!("{}", t!("Enter Text. Finish with '$'."));
             let mut lines = Vec::new();
             loop {
                 print!("~ ");
@@ -338,27 +2342,422 @@ fn init_modes() -> (Arc<Mutex<Option<Emitter>>>, HashMap<Mode, HashMap<String, E
                 mail.text(content);
             }
         })));
-        write.insert(String::from("send"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
-            // ToDo: Send functionality
-            println!("send not yet implemented!");
+        write.insert(String::from("edit"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, _| {
+            let draft = {
+                let context = handle.lock().unwrap();
+                match &context.current_mail_writing {
+                    Some(mail) => mail.get_draft_text(),
+                    None => return,
+                }
+            };
+            // Lock is not held while the editor is open
+            match util::edit_text(draft.as_str()) {
+                Ok(text) => {
+                    let mut context = handle.lock().unwrap();
+                    if let Some(mail) = &mut context.current_mail_writing {
+                        mail.text(text.trim_end().to_string());
+                    }
+                },
+                Err(e) => println!("{}", tf!("Could not edit text! [{}]", e)),
+            }
+        })));
+        write.insert(String::from("spellcheck"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, _| {
+            let context = handle.lock().unwrap();
+            let text = match context.current_mail_writing.as_ref().and_then(|m| m.get_text()) {
+                Some(text) => text.clone(),
+                None => {
+                    println!("{}", t!("Mail has no text yet!"));
+                    return;
+                }
+            };
+            match spellcheck::check(text.as_str(), context.settings.spellcheck_language.as_ref()) {
+                Ok(misspellings) => spellcheck::print_report(text.as_str(), &misspellings),
+                Err(e) => println!("{}", tf!("Could not run hunspell! [{}]", e)),
+            }
+        })));
+        write.insert(String::from("send"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, _| {
+            let mut context = handle.lock().unwrap();
+            let mut builder = match context.current_mail_writing.clone() {
+                Some(builder) => builder,
+                None => {
+                    println!("{}", t!("No mail to send!"));
+                    return;
+                }
+            };
+            let recipients = builder.recipient_count();
+            if recipients as u64 > context.settings.confirm_recipients && !context.confirm(tf!("Send mail to {} recipients?", recipients).as_str()) {
+                return;
+            }
+            if context.settings.spellcheck_on_send {
+                if let Some(text) = builder.get_text() {
+                    match spellcheck::check(text.as_str(), context.settings.spellcheck_language.as_ref()) {
+                        Ok(misspellings) if !misspellings.is_empty() => {
+                            spellcheck::print_report(text.as_str(), &misspellings);
+                            if !util::confirm(&t!("Send anyway?")) {
+                                return;
+                            }
+                        },
+                        Ok(_) => {},
+                        Err(e) => println!("{}", tf!("Could not run hunspell! [{}]", e)),
+                    }
+                }
+            }
+            let unresolved = builder.fill(&context.template_vars(&builder));
+            if !unresolved.is_empty() && !context.confirm(tf!("No value for {}, send anyway?", unresolved.iter().map(|k| format!("{{{{{}}}}}", k)).collect::<Vec<String>>().join(", ")).as_str()) {
+                return;
+            }
+            // Missing fields are reported before the mail goes into the outbox
+            if let Err((_, field)) = builder.clone().build() {
+                println!("{}", tf!("Mail is missing field \"{}\"!", field));
+                return;
+            }
+            let delay = context.settings.send_delay;
+            if delay == 0 {
+                match deliver(&mut context, builder) {
+                    Ok(_) => context.current_mail_writing = None,
+                    Err(failed) => if let Some(mail) = &mut context.current_mail_writing {
+                        mail.set_sent_to(failed.get_sent_to().clone());
+                    },
+                }
+                return;
+            }
+            let id = context.queue_mail(builder, std::time::Duration::from_secs(delay));
+            context.current_mail_writing = None;
+            println!("{}", tf!("Mail {} is sent in {} s, cancel-send {} takes it back.", id, delay, id));
+            let handle = Arc::clone(handle);
+            std::thread::spawn(move || {
+                std::thread::sleep(std::time::Duration::from_secs(delay));
+                let builder = handle.lock().unwrap().take_pending(id);
+                if let Some(builder) = builder {
+                    if let Err(failed) = deliver_unlocked(&handle, builder) {
+                        let mut context = handle.lock().unwrap();
+                        let id = context.keep_failed(failed);
+                        context.notify(tf!("Mail {} stays in the outbox, cancel-send {} takes it back.", id, id));
+                    }
+                }
+            });
+        })));
+        write.insert(String::from("attach"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            let words: Vec<String> = match args.get(&String::from("args")) {
+                Some(clitc::params::ParamValue::Array(vec)) => vec.clone(),
+                Some(other) => vec![other.to_string()],
+                None => Vec::new(),
+            };
+            let zip = words.iter().any(|w| w == "--zip");
+            let files: Vec<&String> = words.iter().filter(|w| *w != "--zip").collect();
+            if files.is_empty() {
+                println!("{}", t!("usage: attach [--zip] <file>..."));
+                return;
+            }
+            let mut attachments = Vec::with_capacity(files.len());
+            for file in files.iter() {
+                match attachments::Attachment::from_file(Path::new(file.as_str())) {
+                    Ok(attachment) => attachments.push(attachment),
+                    Err(e) => {
+                        output::error(tf!("Could not read \"{}\"! [{}]", file, e).as_str());
+                        return;
+                    },
+                }
+            }
+            let mut context = handle.lock().unwrap();
+            let mail = match &mut context.current_mail_writing {
+                Some(mail) => mail,
+                None => return,
+            };
+            if zip {
+                // Archive is named after the first file
+                let name = Path::new(files[0].as_str()).file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or(String::from("attachments"));
+                let size: usize = attachments.iter().map(|a| a.data.len()).sum();
+                match attachments::Attachment::zip(name.as_str(), &attachments) {
+                    Ok(archive) => {
+                        println!("{}", tn!(attachments.len(), "Attached {} file as {}, {} KB before.", "Attached {} files as {}, {} KB before.", archive.get_info(), format!("{:.1}", size as f64 / 1024.0)));
+                        mail.attach(archive);
+                    },
+                    Err(e) => output::error(tf!("Could not create the zip archive! [{}]", e).as_str()),
+                }
+            } else {
+                for attachment in attachments.into_iter() {
+                    println!("{}", tf!("Attached {}", attachment.get_info()));
+                    mail.attach(attachment);
+                }
+            }
+        })));
+        write.insert(String::from("zip-attachments"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            let words: Vec<String> = match args.get(&String::from("args")) {
+                Some(clitc::params::ParamValue::Array(vec)) => vec.clone(),
+                Some(other) => vec![other.to_string()],
+                None => Vec::new(),
+            };
+            // "--name <name>" sets the archive name, another word the attachments as index set
+            let name = words.iter().position(|w| w == "--name").and_then(|i| words.get(i + 1)).cloned().unwrap_or(String::from("attachments"));
+            let set = words.iter().enumerate().find(|(i, w)| *w != "--name" && (*i == 0 || words[i - 1] != "--name")).map(|(_, w)| w.clone());
+            let mut context = handle.lock().unwrap();
+            if let Some(mail) = &mut context.current_mail_writing {
+                if mail.get_attachments().is_empty() {
+                    println!("{}", t!("Mail has no attachments!"));
+                    return;
+                }
+                let indices = match set.map(|s| util::parse_index_set(s.as_str(), mail.get_attachments().len())) {
+                    Some(Ok(indices)) => Some(indices),
+                    Some(Err(e)) => {
+                        println!("{}", e);
+                        return;
+                    },
+                    None => None,
+                };
+                let before: usize = mail.get_attachments().iter().map(|a| a.data.len()).sum();
+                match mail.zip_attachments(indices, name.as_str()) {
+                    Ok(count) => {
+                        let after: usize = mail.get_attachments().iter().map(|a| a.data.len()).sum();
+                        println!("{}", tn!(count, "Bundled {} attachment, {} KB instead of {} KB.", "Bundled {} attachments, {} KB instead of {} KB.", format!("{:.1}", after as f64 / 1024.0), format!("{:.1}", before as f64 / 1024.0)));
+                    },
+                    Err(e) => output::error(tf!("Could not create the zip archive! [{}]", e).as_str()),
+                }
+            }
+        })));
+        write.insert(String::from("show-outbox"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, _| {
+            handle.lock().unwrap().show_outbox();
+        })));
+        write.insert(String::from("cancel-send"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            cancel_send(handle, args.get(&String::from("args")).map(|x| x.to_string()));
         })));
         write.insert(String::from("save"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
             // ToDo: Save functionality
-            println!("save not yet implemented!");
+            println!("{}", t!("save not yet implemented!"));
+        })));
+        write.insert(String::from("discard"), Event::<ContextHandle, Emitter>::Emit(Arc::clone(&handle), Rc::new(|handle, emit_handle, _| {
+            {
+                let mut context = handle.lock().unwrap();
+                if !context.confirm("Discard the mail?") {
+                    return;
+                }
+                context.current_mail_writing = None;
+            }
+            let mut emitter = emit_handle.lock().unwrap();
+            *emitter = Some((Mode::Global, Some(GLOBAL_PROMPT.to_string())));
         })));
-        write.insert(String::from("exit"), Event::<ContextHandle, Emitter>::Emit(Arc::clone(&handle), Rc::new(|_, emit_handle, args| {
+        write.insert(String::from("exit"), Event::<ContextHandle, Emitter>::Emit(Arc::clone(&handle), Rc::new(|handle, emit_handle, args| {
+            {
+                let context = handle.lock().unwrap();
+                let unsaved = context.current_mail_writing.as_ref().map(|m| !m.is_empty()).unwrap_or(false);
+                if unsaved && !context.confirm("Mail is not saved, leave Write mode anyway?") {
+                    return;
+                }
+            }
             // Switch to mode global
             let mut emitter = emit_handle.lock().unwrap();
             *emitter = Some((Mode::Global, Some(GLOBAL_PROMPT.to_string())));
         })));
         write.insert(String::from("preview"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, _| {
+            let context = handle.lock().unwrap();
+            if let Some(mail) = &context.current_mail_writing {
+                // Placeholders are shown filled, the draft keeps them until sending
+                let mut filled = mail.clone();
+                let unresolved = filled.fill(&context.template_vars(mail));
+                filled.show_preview();
+                if !unresolved.is_empty() {
+                    println!("{}", tf!("No value for {}, set it with var <key>=<value>.", unresolved.iter().map(|k| format!("{{{{{}}}}}", k)).collect::<Vec<String>>().join(", ")));
+                }
+            }
+        })));
+        write.insert(String::from("var"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            let words: Vec<String> = match args.get(&String::from("args")) {
+                Some(clitc::params::ParamValue::Array(vec)) => vec.clone(),
+                Some(other) => vec![other.to_string()],
+                None => Vec::new(),
+            };
             let mut context = handle.lock().unwrap();
-            if let Some(mail) = &mut context.current_mail_writing {
-                mail.show_preview();
+            let mail = match &mut context.current_mail_writing {
+                Some(mail) => mail,
+                None => return,
+            };
+            if words.is_empty() {
+                mail.get_vars().iter().for_each(|(k, v)| println!("\t{}={}", k, v));
+                return;
+            }
+            for word in words.iter() {
+                match word.find('=') {
+                    Some(i) => {
+                        mail.var(word[..i].to_string(), word[i + 1..].to_string());
+                    },
+                    None => println!("{}", tf!("\"{}\" is not of the form <key>=<value>!", word)),
+                }
             }
         })));
+        write.insert(String::from("help"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|_, args| {
+            help::print_help(help::WRITE, args.get(&String::from("command")).map(|x| x.to_string()));
+        })));
         states.insert(Mode::Write, write);
     }
+    {
+        // Contacts Mode
+        let mut contacts: HashMap<String, Event<ContextHandle, Emitter>> = HashMap::new();
+        contacts.insert(String::from("list"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, _| {
+            with_address_book(handle, |book| {
+                book.print(None);
+                false
+            });
+        })));
+        contacts.insert(String::from("search"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            let text = match args.get(&String::from("args")) {
+                Some(clitc::params::ParamValue::Array(vec)) => vec.join(" "),
+                Some(other) => other.to_string(),
+                None => String::new(),
+            };
+            if text.is_empty() {
+                println!("{}", t!("command search needs a text to search for!"));
+                return;
+            }
+            with_address_book(handle, |book| {
+                book.print(Some(text.as_str()));
+                false
+            });
+        })));
+        contacts.insert(String::from("add"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            let words: Vec<String> = match args.get(&String::from("args")) {
+                Some(clitc::params::ParamValue::Array(vec)) => vec.clone(),
+                Some(other) => vec![other.to_string()],
+                None => Vec::new(),
+            };
+            let address = match words.get(0) {
+                Some(address) if address.contains('@') => address.clone(),
+                _ => {
+                    println!("{}", t!("usage: add <email> [name...]"));
+                    return;
+                },
+            };
+            with_address_book(handle, |book| {
+                if book.get(address.as_str()).is_some() {
+                    println!("{}", tf!("{} is already a contact!", address));
+                    return false;
+                }
+                let contact = book.get_or_insert(address.as_str());
+                if words.len() > 1 {
+                    contact.name = Some(words[1..].join(" "));
+                }
+                println!("{}", tf!("Added {}!", contact.get_info()));
+                true
+            });
+        })));
+        contacts.insert(String::from("edit"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            let words: Vec<String> = match args.get(&String::from("args")) {
+                Some(clitc::params::ParamValue::Array(vec)) => vec.clone(),
+                Some(other) => vec![other.to_string()],
+                None => Vec::new(),
+            };
+            let (index, field) = match (words.get(0).and_then(|i| i.parse::<usize>().ok()), words.get(1)) {
+                (Some(index), Some(field)) => (index, field.clone()),
+                _ => {
+                    println!("{}", t!("usage: edit <index> <name|address|key> [value...]"));
+                    return;
+                },
+            };
+            // No value clears the name or key
+            let value = if words.len() > 2 { Some(words[2..].join(" ")) } else { None };
+            with_address_book(handle, |book| {
+                let duplicate = value.as_ref().map(|v| book.contacts().iter().enumerate().any(|(i, c)| i != index && c.address.eq_ignore_ascii_case(v))).unwrap_or(false);
+                let contact = match book.contacts_mut().get_mut(index) {
+                    Some(contact) => contact,
+                    None => {
+                        println!("{}", tf!("No contact with index {}!", index));
+                        return false;
+                    },
+                };
+                match (field.as_str(), value) {
+                    ("name", value) => contact.name = value,
+                    ("key", value) => contact.key = value,
+                    ("address", Some(_)) if duplicate => {
+                        println!("{}", t!("Another contact has this address!"));
+                        return false;
+                    },
+                    ("address", Some(address)) if address.contains('@') => contact.address = address,
+                    (_, _) => {
+                        println!("{}", t!("usage: edit <index> <name|address|key> [value...]"));
+                        return false;
+                    },
+                }
+                println!("{}", tf!("Changed {}!", contact.get_info()));
+                true
+            });
+        })));
+        contacts.insert(String::from("delete"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            let index = match args.get(&String::from("args")).and_then(|x| match x {
+                clitc::params::ParamValue::Array(vec) => vec.get(0).cloned(),
+                other => Some(other.to_string()),
+            }).and_then(|i| i.parse::<usize>().ok()) {
+                Some(index) => index,
+                None => {
+                    println!("{}", t!("command delete needs a contact index as parameter!"));
+                    return;
+                },
+            };
+            let confirm = handle.lock().unwrap().settings.confirm;
+            with_address_book(handle, |book| {
+                let info = match book.contacts().get(index) {
+                    Some(contact) => contact.get_info(),
+                    None => {
+                        println!("{}", tf!("No contact with index {}!", index));
+                        return false;
+                    },
+                };
+                if confirm && !util::confirm(tf!("Delete {}?", info).as_str()) {
+                    return false;
+                }
+                book.contacts_mut().remove(index);
+                println!("{}", tf!("Deleted {}!", info));
+                true
+            });
+        })));
+        contacts.insert(String::from("group"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|handle, args| {
+            let words: Vec<String> = match args.get(&String::from("args")) {
+                Some(clitc::params::ParamValue::Array(vec)) => vec.clone(),
+                Some(other) => vec![other.to_string()],
+                None => Vec::new(),
+            };
+            with_address_book(handle, |book| {
+                match (words.get(0).map(|s| s.as_str()), words.get(1)) {
+                    (None, _) | (Some("list"), None) => {
+                        if book.groups().is_empty() {
+                            println!("{}", t!("No contact groups!"));
+                        }
+                        for group in book.groups().iter() {
+                            println!("{}: {}", group.name, group.members.join(", "));
+                        }
+                        false
+                    },
+                    (Some("add"), Some(name)) if words.len() > 2 => {
+                        book.add_to_group(name.as_str(), &words[2..]);
+                        println!("{}", tf!("Added {} addresses to group {}!", words.len() - 2, name));
+                        true
+                    },
+                    (Some("remove"), Some(name)) => {
+                        if !book.remove_from_group(name.as_str(), &words[2..]) {
+                            println!("{}", tf!("No group named {}!", name));
+                            return false;
+                        }
+                        if words.len() > 2 {
+                            println!("{}", tf!("Removed {} addresses from group {}!", words.len() - 2, name));
+                        } else {
+                            println!("{}", tf!("Removed group {}!", name));
+                        }
+                        true
+                    },
+                    (_, _) => {
+                        println!("{}", t!("usage: group [list] | group add <name> <email>... | group remove <name> [email...]"));
+                        false
+                    },
+                }
+            });
+        })));
+        contacts.insert(String::from("exit"), Event::<ContextHandle, Emitter>::Emit(Arc::clone(&handle), Rc::new(|_, emit_handle, _| {
+            let mut emitter = emit_handle.lock().unwrap();
+            *emitter = Some((Mode::Global, Some(GLOBAL_PROMPT.to_string())));
+        })));
+        contacts.insert(String::from("help"), Event::<ContextHandle, Emitter>::Callback(Rc::new(|_, args| {
+            help::print_help(help::CONTACTS, args.get(&String::from("command")).map(|x| x.to_string()));
+        })));
+        states.insert(Mode::Contacts, contacts);
+    }
 
     return (handle, states);
 }
@@ -373,68 +2772,346 @@ fn styling(code: u8) -> Style {
         3 => Style::new().bold().cyan(),
         // Write Mode
         4 => Style::new().bold().magenta(),
+        // Contacts Mode
+        5 => Style::new().bold().blue(),
         _ => Style::new(),
     }
 }
 
-fn input(prompt: String, code: u8) -> String {
-    use std::io::{stdin, stdout, Write};
-    let mut buf = String::new();
-    print!("{} ", styling(code).apply_to(prompt));
-    let _  = stdout().flush();
-    stdin().read_line(&mut buf).expect("Could not read user input");
-    buf = buf.trim().to_string();
-    return buf;
+// Outcome of one command line
+struct Executed {
+    // No error was reported
+    ok: bool,
+    exit: bool,
+    // Commands of the mode switched to
+    commands: Option<Vec<String>>,
+    prompt: (String, u8),
+}
+
+// Scripts may source others, up to this depth
+const SCRIPT_DEPTH: usize = 8;
+
+// Runs the commands of a file, one per line with "#" starting comments. Stops at the first
+// error unless told to keep going, returns whether all commands succeeded and if one exited
+fn run_script<F: FnMut(String) -> Executed>(path: &Path, keep_going: bool, execute: &mut F, depth: usize) -> std::io::Result<(bool, bool)> {
+    let content = std::fs::read_to_string(path)?;
+    let mut ok = true;
+    for (n, line) in content.lines().enumerate().map(|(n, l)| (n + 1, l.trim())) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (success, exit) = if words[0] == "source" && words.len() > 1 && depth < SCRIPT_DEPTH {
+            let nested = path.parent().unwrap_or(Path::new("")).join(words[1]);
+            match run_script(&nested, keep_going || words.contains(&"--continue"), execute, depth + 1) {
+                Ok(result) => result,
+                Err(e) => {
+                    output::error(tf!("Could not read script \"{}\"! [{}]", nested.display(), e).as_str());
+                    (false, false)
+                },
+            }
+        } else {
+            let executed = execute(line.to_string());
+            (executed.ok, executed.exit)
+        };
+        ok &= success;
+        if exit {
+            return Ok((ok, true));
+        }
+        if !success && !keep_going {
+            output::error(tf!("Script stopped at line {} of \"{}\"", n, path.display()).as_str());
+            return Ok((false, false));
+        }
+    }
+    Ok((ok, false))
+}
+
+// Returns None when input is closed (Ctrl-D)
+fn input(editor: &mut Editor<CommandHelper>, prompt: String, code: u8) -> Option<String> {
+    match editor.readline(format!("{} ", styling(code).apply_to(prompt)).as_str()) {
+        Ok(line) => {
+            // Lines starting with a space are kept out of the history
+            let private = line.starts_with(' ');
+            let line = line.trim().to_string();
+            let history: Vec<String> = editor.history().iter().cloned().collect();
+            let line = match util::expand_history(line.as_str(), &history) {
+                Ok(Some(expanded)) => {
+                    println!("{}", expanded);
+                    expanded
+                },
+                Ok(None) => line,
+                Err(e) => {
+                    output::error(e.as_str());
+                    return Some(String::new());
+                },
+            };
+            if line == "history" {
+                history.iter().enumerate().for_each(|(i, entry)| println!("{:>5}  {}", i + 1, entry));
+                return Some(String::new());
+            }
+            if !line.is_empty() && !private {
+                editor.add_history_entry(line.as_str());
+            }
+            Some(line)
+        },
+        // Ctrl-C only discards the current line
+        Err(ReadlineError::Interrupted) => Some(String::new()),
+        Err(ReadlineError::Eof) => None,
+        Err(e) => {
+            output::error(tf!("Could not read user input [{}]", e).as_str());
+            None
+        },
+    }
 }
 
 fn main() {
-    let cli_params = CliParameters::from_reader(File::open("D:/Dateien/tobias/data/cli-mail-rs/commands.json")
-        .expect("Could not open command file"))
-        .expect("Could not parse command file");
+    logger::init();
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(pos) = args.iter().position(|a| a == "--json") {
+        args.remove(pos);
+        output::set_json(true);
+    }
+    if let Some(pos) = args.iter().position(|a| a == "--yes" || a == "-y") {
+        args.remove(pos);
+        util::set_assume_yes(true);
+    }
+    if let Some(pos) = args.iter().position(|a| a == "--trace") {
+        args.remove(pos);
+        let path = if pos < args.len() && !args[pos].starts_with('-') { args.remove(pos) } else { String::from(TRACE_FILE) };
+        if let Err(e) = trace::start(path.as_str()) {
+            output::error(tf!("Could not open trace file \"{}\" [{}]", path, e).as_str());
+        }
+    }
+    let mut script = None;
+    if let Some(pos) = args.iter().position(|a| a == "--script") {
+        args.remove(pos);
+        if pos < args.len() {
+            script = Some(args.remove(pos));
+        } else {
+            output::error("--script needs a file with commands");
+            std::process::exit(cli::EXIT_USAGE);
+        }
+    }
+    let keep_going = match args.iter().position(|a| a == "--continue") {
+        Some(pos) => {
+            args.remove(pos);
+            true
+        },
+        None => false,
+    };
+    // Given level wins over the one of the settings
+    let mut log_level = None;
+    if let Some(pos) = args.iter().position(|a| a == "--log-level") {
+        args.remove(pos);
+        let level = if pos < args.len() { args.remove(pos) } else { String::new() };
+        match logger::parse_level(level.as_str()) {
+            Some(level) => log_level = Some(level),
+            None => {
+                output::error(tf!("--log-level needs one of {}", logger::LEVELS.join(", ")).as_str());
+                std::process::exit(cli::EXIT_USAGE);
+            },
+        }
+    }
+    let one_shot = match cli::parse(&args) {
+        Ok(command) => command,
+        Err(e) => {
+            output::error(e.as_str());
+            std::process::exit(cli::EXIT_USAGE);
+        }
+    };
+
     let mut context = InboxManager::new(String::from("D:/Dateien/tobias/data/cli-mail-rs/accounts.yml"));
+    if context.is_file_encrypted() {
+        // The accounts can't be used (or saved) without the right passphrase
+        let mut attempts = 0;
+        loop {
+            context.set_passphrase(Some(util::read_password(&t!("Master passphrase: "))));
+            if context.can_decrypt() {
+                break;
+            }
+            attempts += 1;
+            if attempts >= 3 {
+                output::error(&t!("Wrong master passphrase, giving up!"));
+                std::process::exit(cli::EXIT_CONFIG);
+            }
+            output::error(&t!("Wrong master passphrase, try again!"));
+        }
+    }
     match context.load_file() {
         Ok(_) => {},
-        Err(e) => println!("Could not load account file! [{}]", e),
+        Err(e) => {
+            output::error(tf!("Could not load account file! [{}]", e).as_str());
+            if one_shot.is_some() {
+                std::process::exit(cli::EXIT_CONFIG);
+            }
+        },
+    };
+    let level = log_level.or(logger::parse_level(context.settings.log_level.as_str())).unwrap_or(log::LevelFilter::Warn);
+    if let Err(e) = logger::configure(level, context.settings.log_file.as_ref().map(|f| f.as_str())) {
+        output::error(tf!("Could not open log file! [{}]", e).as_str());
+    }
+
+    // Run single command without entering interactive mode
+    if let Some(command) = one_shot {
+        std::process::exit(cli::run(command, &mut context));
+    }
+
+    let cli_params = match File::open("D:/Dateien/tobias/data/cli-mail-rs/commands.json").map(|f| CliParameters::from_reader(f)) {
+        Ok(Ok(params)) => params,
+        Ok(Err(e)) => {
+            output::error(format!("Could not parse command file! [{:?}]", e).as_str());
+            std::process::exit(cli::EXIT_CONFIG);
+        },
+        Err(e) => {
+            output::error(tf!("Could not open command file! [{}]", e).as_str());
+            std::process::exit(cli::EXIT_CONFIG);
+        },
     };
 
-    let mut event_handler = EventHandler::new(cli_params, WhitespaceSplitter, true, Arc::new(Mutex::new(context)));
+    let history_file = context.data_path("history.txt");
+    let context: ContextHandle = Arc::new(Mutex::new(context));
+    start_auto_refresh(&context);
+    let mut event_handler = EventHandler::new(cli_params, WhitespaceSplitter, true, Arc::clone(&context));
 
     let mut cur_mode = Mode::Global;
     let mut prompt_path = Some(GLOBAL_PROMPT.to_string());
+    let mut prompt = cur_mode.get_prompt(prompt_path.clone());
 
     let (handle, mut modes) = init_modes();
     let start_mode = modes.remove(&cur_mode).unwrap();
+    let mut helper = CommandHelper::new();
+    let mut mode_commands: Vec<String> = start_mode.keys().cloned().collect();
+    helper.set_commands(mode_commands.clone());
     event_handler.attach(start_mode);
 
-
-    // User input loop
-    loop {
-        let prompt = cur_mode.get_prompt(prompt_path.clone());
-        match event_handler.pass_command(input(prompt.0, prompt.1)) {
+    // Runs one command line and switches the mode if the command emitted a change
+    let plugin_handle = Arc::clone(&context);
+    let mut execute = move |line: String| -> Executed {
+        let errors = output::error_count();
+        let mut ret = Executed { ok: true, exit: false, commands: None, prompt: (String::new(), 0) };
+        // Commands of the mode win over plugins of the same name
+        let words: Vec<String> = line.split_whitespace().map(|w| w.to_string()).collect();
+        let plugin = words.first().filter(|w| !mode_commands.contains(w)).and_then(|w| {
+            let context = plugin_handle.lock().unwrap();
+            context.settings.plugins.iter().find(|(name, _)| name == w).map(|(_, command)| command.clone())
+        });
+        if let Some(command) = plugin {
+            run_plugin(&plugin_handle, command.as_str(), &cur_mode, &words[1..]);
+            ret.ok = output::error_count() == errors;
+            ret.prompt = cur_mode.get_prompt(prompt_path.clone());
+            return ret;
+        }
+        match event_handler.pass_command(line) {
             Ok(_) => {},
-            Err(e) => println!("{}", e),
+            Err(e) => output::error(format!("{}", e).as_str()),
         };
-
         {
             let mut mode_change = handle.lock().unwrap();
             // Check if mode change has been emitted
             if let Some((mode_ident, path)) = &*mode_change {
                 // Check if that mode exists
-                if let Some(next_mode) = modes.remove(&mode_ident) {
+                if *mode_ident == cur_mode {
+                    // Staying in the same mode only changes the prompt
+                    prompt_path = path.clone();
+                } else if let Some(next_mode) = modes.remove(&mode_ident) {
+                    mode_commands = next_mode.keys().cloned().collect();
+                    ret.commands = Some(mode_commands.clone());
                     // Swap used modes & prompts, update current mode ident
                     let former_mode = event_handler.disattach();
                     event_handler.attach(next_mode);
-                    modes.insert(cur_mode, former_mode);
+                    modes.insert(cur_mode.clone(), former_mode);
                     cur_mode = mode_ident.clone();
                     prompt_path = path.clone();
                 } else if *mode_ident == Mode::Exit {
-                    break;
+                    ret.exit = true;
                 }
                 // Update emitter value
                 *mode_change = None;
             }
         }
+        ret.ok = output::error_count() == errors;
+        ret.prompt = cur_mode.get_prompt(prompt_path.clone());
+        ret
+    };
+
+    // Commands of a file instead of the prompt
+    if let Some(file) = script {
+        let code = match run_script(Path::new(&file), keep_going, &mut execute, 0) {
+            Ok((true, _)) => cli::EXIT_OK,
+            Ok((false, _)) => cli::EXIT_FAILED,
+            Err(e) => {
+                output::error(tf!("Could not read script \"{}\"! [{}]", file, e).as_str());
+                cli::EXIT_NOT_FOUND
+            },
+        };
+        flush_outbox(&context);
+        std::process::exit(code);
+    }
+
+    let config = Config::builder().max_history_size(HISTORY_SIZE).history_ignore_dups(true).build();
+    let mut editor = Editor::<CommandHelper>::with_config(config);
+    editor.set_helper(Some(helper));
+    if history_file.exists() {
+        if let Err(e) = editor.load_history(&history_file) {
+            output::error(tf!("Could not read command history! [{}]", e).as_str());
+        }
+    }
+
+    // User input loop
+    loop {
+        // Refresh completion candidates, they may have changed with the last command
+        if let Some(helper) = editor.helper_mut() {
+            let mut context = context.lock().unwrap();
+            // What happened in the background meanwhile
+            for text in context.take_notices().into_iter() {
+                println!("{}", text);
+            }
+            helper.accounts = context.get_identifiers();
+            helper.folders = context.get_folders();
+            helper.contacts = context.get_known_people();
+            helper.plugins = context.settings.plugins.iter().map(|(name, _)| name.clone()).collect();
+        }
+        let line = match input(&mut editor, prompt.0.clone(), prompt.1) {
+            Some(line) => line,
+            None => break,
+        };
+        if line.is_empty() {
+            continue;
+        }
+        let mut commands = None;
+        let mut exit = false;
+        {
+            let mut record = |line: String| {
+                let executed = execute(line);
+                commands = executed.commands.clone().or(commands.take());
+                prompt = executed.prompt.clone();
+                exit |= executed.exit;
+                executed
+            };
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words[0] == "source" {
+                match words.get(1) {
+                    Some(file) => if let Err(e) = run_script(Path::new(file), words.contains(&"--continue"), &mut record, 0) {
+                        output::error(tf!("Could not read script \"{}\"! [{}]", file, e).as_str());
+                    },
+                    None => println!("{}", t!("usage: source <file> [--continue]")),
+                }
+            } else {
+                record(line.clone());
+            }
+        }
+        // Saved after every command, so a crash doesn't lose the session
+        if let Err(e) = editor.save_history(&history_file) {
+            log::warn!("could not save command history: {}", e);
+        }
+        if let (Some(commands), Some(helper)) = (commands, editor.helper_mut()) {
+            helper.set_commands(commands);
+        }
+        if exit {
+            break;
+        }
     }
 
     // handling exit
+    flush_outbox(&context);
 }
@@ -1,10 +1,377 @@
-use mime::{
-    Mime,
-    Name,
-    Params,
-};
-
-fn get_multipart(content: &str) -> Option<Vec<Mime>>
-{
-    
+use std::collections::HashMap;
+
+use super::headers::Headers;
+use super::util;
+
+pub struct Content {
+    pub text: String,
+    // The text part is format=flowed (RFC 3676)
+    pub flowed: bool,
+    pub html: String,
+    pub attachments: Vec<String>,
+    // text/calendar parts, inline or attached
+    pub calendar: Vec<String>,
+}
+
+impl Content {
+    pub fn new() -> Content {
+        Content {
+            text: String::new(),
+            flowed: false,
+            html: String::new(),
+            attachments: Vec::new(),
+            calendar: Vec::new(),
+        }
+    }
+}
+
+// Splits a raw message (or part) into header block and body
+pub fn split_message(raw: &str) -> (&str, &str) {
+    let crlf = raw.find("\r\n\r\n").map(|i| (i, i + 4));
+    let lf = raw.find("\n\n").map(|i| (i, i + 2));
+    let split = match (crlf, lf) {
+        (Some(a), Some(b)) => if a.0 < b.0 { Some(a) } else { Some(b) },
+        (a, b) => a.or(b),
+    };
+    match split {
+        Some((head_end, body_start)) => (&raw[..head_end], &raw[body_start..]),
+        None => (raw, ""),
+    }
+}
+
+// Returns the lowercase mime type and its parameters, e.g. ("text/plain", {"charset": "utf-8"})
+pub fn content_type(value: &str) -> (String, HashMap<String, String>) {
+    let mut tokens = value.split(';');
+    let mime = tokens.next().unwrap_or("").trim().to_lowercase();
+    let params = tokens.filter_map(|t| {
+        let i = t.find('=')?;
+        Some((t[..i].trim().to_lowercase(), t[i + 1..].trim().trim_matches('"').to_string()))
+    }).collect();
+    return (mime, params);
+}
+
+pub fn decode_body(body: &str, encoding: Option<&String>) -> Vec<u8> {
+    match encoding.map(|e| e.trim().to_lowercase()).as_ref().map(|e| e.as_str()) {
+        Some("base64") => {
+            let cleaned: String = body.chars().filter(|c| !c.is_whitespace()).collect();
+            base64::decode(&cleaned).unwrap_or(Vec::new())
+        },
+        Some("quoted-printable") => decode_quoted_printable(body),
+        _ => body.as_bytes().to_vec(),
+    }
+}
+
+pub fn decode_quoted_printable(s: &str) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut ret = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'=' {
+            // Soft line break
+            if bytes.get(i + 1) == Some(&b'\r') && bytes.get(i + 2) == Some(&b'\n') {
+                i += 3;
+                continue;
+            } else if bytes.get(i + 1) == Some(&b'\n') {
+                i += 2;
+                continue;
+            }
+            let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok()).and_then(|h| u8::from_str_radix(h, 16).ok());
+            if let Some(byte) = hex {
+                ret.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        ret.push(bytes[i]);
+        i += 1;
+    }
+    return ret;
+}
+
+// Returns the body of every part between the boundaries
+pub fn split_multipart<'a>(body: &'a str, boundary: &str) -> Vec<&'a str> {
+    let delimiter = format!("--{}", boundary);
+    let mut parts = Vec::new();
+    let mut rest = match body.find(delimiter.as_str()) {
+        Some(i) => &body[i + delimiter.len()..],
+        None => return parts,
+    };
+    // Closing delimiter is followed by "--"
+    while !rest.starts_with("--") {
+        let start = rest.find('\n').map(|i| i + 1).unwrap_or(rest.len());
+        let end = rest[start..].find(delimiter.as_str()).map(|i| start + i).unwrap_or(rest.len());
+        let part = rest[start..end].trim_end_matches('\n').trim_end_matches('\r');
+        parts.push(part);
+        if end >= rest.len() {
+            break;
+        }
+        rest = &rest[end + delimiter.len()..];
+    }
+    return parts;
+}
+
+// A leaf part with its decoded content
+pub struct Part {
+    pub name: String,
+    pub mime: String,
+    // Content-ID without the angle brackets, html refers to it with "cid:"
+    pub content_id: Option<String>,
+    pub data: Vec<u8>,
+}
+
+// Mime type, its parameters, the file name and whether it is marked as attachment
+fn describe(headers: &Headers) -> (String, HashMap<String, String>, Option<String>, bool) {
+    let (mime, params) = match headers.get("Content-Type") {
+        Some(val) => content_type(val),
+        None => (String::from("text/plain"), HashMap::new()),
+    };
+    let disposition = headers.get("Content-Disposition").map(|d| content_type(d));
+    let filename = disposition.as_ref().and_then(|(_, p)| p.get("filename").cloned()).or(params.get("name").cloned());
+    let is_attachment = disposition.as_ref().map(|(d, _)| d == "attachment").unwrap_or(false);
+    (mime, params, filename, is_attachment)
+}
+
+// Walks through the mime tree and collects text, html and attachment names
+pub fn extract_content(headers: &Headers, body: &str, content: &mut Content) {
+    let (mime, params, filename, is_attachment) = describe(headers);
+
+    if mime.starts_with("multipart/") {
+        if let Some(boundary) = params.get("boundary") {
+            for part in split_multipart(body, boundary.as_str()).into_iter() {
+                let (head, part_body) = split_message(part);
+                let part_headers = if head.trim().is_empty() { Headers::new() } else { Headers::parse(head) };
+                extract_content(&part_headers, part_body, content);
+            }
+        }
+    } else if mime == "text/calendar" || mime == "application/ics" {
+        let decoded = decode_body(body, headers.get("Content-Transfer-Encoding"));
+        content.calendar.push(String::from_utf8_lossy(&decoded).to_string());
+        if is_attachment || filename.is_some() {
+            content.attachments.push(filename.unwrap_or(String::from("invite.ics")));
+        }
+    } else if is_attachment || (filename.is_some() && !mime.starts_with("text/")) {
+        content.attachments.push(filename.unwrap_or(String::from("<unnamed>")));
+    } else if mime == "text/plain" && content.text.is_empty() {
+        let decoded = decode_body(body, headers.get("Content-Transfer-Encoding"));
+        content.text = String::from_utf8_lossy(&decoded).to_string();
+        content.flowed = params.get("format").map(|f| f.eq_ignore_ascii_case("flowed")).unwrap_or(false);
+    } else if mime == "text/html" && content.html.is_empty() {
+        let decoded = decode_body(body, headers.get("Content-Transfer-Encoding"));
+        content.html = String::from_utf8_lossy(&decoded).to_string();
+    }
+}
+
+// Whether extract_content lists a leaf part as attachment
+fn is_listed(mime: &str, filename: &Option<String>, is_attachment: bool) -> bool {
+    if mime == "text/calendar" || mime == "application/ics" {
+        is_attachment || filename.is_some()
+    } else {
+        is_attachment || (filename.is_some() && !mime.starts_with("text/"))
+    }
+}
+
+// A node of the mime tree
+pub struct PartInfo {
+    // Section number like "1.2", empty for the multipart container of the whole mail
+    pub index: String,
+    pub depth: usize,
+    pub mime: String,
+    pub encoding: Option<String>,
+    // Decoded size in bytes, multipart containers have none
+    pub size: Option<usize>,
+    pub filename: Option<String>,
+    // Position in the attachment list, as preview-attachment takes it
+    pub attachment: Option<usize>,
+}
+
+// Walks through the mime tree and numbers the parts like IMAP sections, a mail of a single
+// part is part 1
+pub fn structure(headers: &Headers, body: &str, index: String, depth: usize, parts: &mut Vec<PartInfo>) {
+    let (mime, params, filename, is_attachment) = describe(headers);
+    let encoding = headers.get("Content-Transfer-Encoding").map(|e| e.trim().to_lowercase());
+    if mime.starts_with("multipart/") {
+        parts.push(PartInfo { index: index.clone(), depth, mime, encoding, size: None, filename, attachment: None });
+        if let Some(boundary) = params.get("boundary") {
+            for (n, part) in split_multipart(body, boundary.as_str()).into_iter().enumerate() {
+                let (head, part_body) = split_message(part);
+                let part_headers = if head.trim().is_empty() { Headers::new() } else { Headers::parse(head) };
+                let child = if index.is_empty() { (n + 1).to_string() } else { format!("{}.{}", index, n + 1) };
+                structure(&part_headers, part_body, child, depth + 1, parts);
+            }
+        }
+        return;
+    }
+    let attachment = if is_listed(mime.as_str(), &filename, is_attachment) {
+        Some(parts.iter().filter(|p| p.attachment.is_some()).count())
+    } else {
+        None
+    };
+    parts.push(PartInfo {
+        index: if index.is_empty() { String::from("1") } else { index },
+        size: Some(decode_body(body, encoding.as_ref()).len()),
+        depth, mime, encoding, filename, attachment,
+    });
+}
+
+// Part by its section number as structure shows it, like "2.1". Containers and nested
+// messages come as they are, leaves decoded. The name is empty for parts without file name
+pub fn find_part(headers: &Headers, body: &str, index: &str) -> Option<Part> {
+    let path = index.split('.').map(|n| n.trim().parse::<usize>().ok().filter(|n| *n > 0)).collect::<Option<Vec<usize>>>()?;
+    let (mime, _, _, _) = describe(headers);
+    if !mime.starts_with("multipart/") {
+        return if path == [1] { Some(whole_part(headers, body)) } else { None };
+    }
+    section(headers, body, &path)
+}
+
+fn section(headers: &Headers, body: &str, path: &[usize]) -> Option<Part> {
+    let (n, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return Some(whole_part(headers, body)),
+    };
+    let (mime, params, _, _) = describe(headers);
+    if !mime.starts_with("multipart/") {
+        return None;
+    }
+    let part = split_multipart(body, params.get("boundary")?.as_str()).into_iter().nth(n - 1)?;
+    let (head, part_body) = split_message(part);
+    let part_headers = if head.trim().is_empty() { Headers::new() } else { Headers::parse(head) };
+    section(&part_headers, part_body, rest)
+}
+
+fn whole_part(headers: &Headers, body: &str) -> Part {
+    let (mime, _, filename, _) = describe(headers);
+    Part {
+        name: filename.unwrap_or_default(),
+        content_id: headers.get("Content-ID").and_then(|id| util::message_ids(id).into_iter().next()),
+        data: decode_body(body, headers.get("Content-Transfer-Encoding")),
+        mime,
+    }
+}
+
+// File extension for parts saved without a file name
+pub fn extension(mime: &str) -> &str {
+    match mime {
+        "message/rfc822" => "eml",
+        "text/plain" => "txt",
+        "text/html" => "html",
+        "text/calendar" | "application/ics" => "ics",
+        "image/jpeg" => "jpg",
+        "image/svg+xml" => "svg",
+        "application/pgp-signature" | "application/pgp-encrypted" => "asc",
+        "application/pkcs7-signature" => "p7s",
+        _ => match mime.split('/').nth(1) {
+            Some(sub) if !sub.is_empty() && sub.len() <= 5 && sub.chars().all(|c| c.is_ascii_alphanumeric()) => sub,
+            _ => "bin",
+        },
+    }
+}
+
+// Parts extract_content lists as attachments, in the same order
+pub fn attachment_parts(headers: &Headers, body: &str, parts: &mut Vec<Part>) {
+    let (mime, params, filename, is_attachment) = describe(headers);
+    if mime.starts_with("multipart/") {
+        if let Some(boundary) = params.get("boundary") {
+            for part in split_multipart(body, boundary.as_str()).into_iter() {
+                let (head, part_body) = split_message(part);
+                let part_headers = if head.trim().is_empty() { Headers::new() } else { Headers::parse(head) };
+                attachment_parts(&part_headers, part_body, parts);
+            }
+        }
+        return;
+    }
+    let calendar = mime == "text/calendar" || mime == "application/ics";
+    if is_listed(mime.as_str(), &filename, is_attachment) {
+        parts.push(Part {
+            name: filename.unwrap_or(String::from(if calendar { "invite.ics" } else { "<unnamed>" })),
+            content_id: headers.get("Content-ID").and_then(|id| util::message_ids(id).into_iter().next()),
+            data: decode_body(body, headers.get("Content-Transfer-Encoding")),
+            mime,
+        });
+    }
+}
+
+// Parts with a Content-ID, which html parts refer to for inline images
+pub fn related_parts(headers: &Headers, body: &str, parts: &mut Vec<Part>) {
+    let (mime, params, filename, _) = describe(headers);
+    if mime.starts_with("multipart/") {
+        if let Some(boundary) = params.get("boundary") {
+            for part in split_multipart(body, boundary.as_str()).into_iter() {
+                let (head, part_body) = split_message(part);
+                let part_headers = if head.trim().is_empty() { Headers::new() } else { Headers::parse(head) };
+                related_parts(&part_headers, part_body, parts);
+            }
+        }
+        return;
+    }
+    let id = headers.get("Content-ID").and_then(|id| util::message_ids(id).into_iter().next().or(Some(id.trim().to_string())));
+    if let Some(id) = id.filter(|id| !id.is_empty()) {
+        parts.push(Part {
+            name: filename.unwrap_or(id.clone()),
+            content_id: Some(id),
+            data: decode_body(body, headers.get("Content-Transfer-Encoding")),
+            mime,
+        });
+    }
+}
+
+// Signed part (as CRLF text) and detached signature of a PGP/MIME signed message,
+// also found inside other multipart containers
+pub fn find_pgp_signed(headers: &Headers, body: &str) -> Option<(String, Vec<u8>)> {
+    let (mime, params) = content_type(headers.get("Content-Type")?);
+    if !mime.starts_with("multipart/") {
+        return None;
+    }
+    let parts = split_multipart(body, params.get("boundary")?.as_str());
+    let protocol = params.get("protocol").map(|p| p.to_lowercase());
+    if mime == "multipart/signed" && protocol.as_ref().map(|p| p.as_str()) == Some("application/pgp-signature") {
+        let (sig_head, sig_body) = split_message(parts.get(1)?);
+        let sig_headers = Headers::parse(sig_head);
+        let signature = decode_body(sig_body, sig_headers.get("Content-Transfer-Encoding"));
+        return Some((util::to_crlf(parts.get(0)?), signature));
+    }
+    parts.into_iter().find_map(|part| {
+        let (head, part_body) = split_message(part);
+        if head.trim().is_empty() { None } else { find_pgp_signed(&Headers::parse(head), part_body) }
+    })
+}
+
+// The S/MIME signed entity (headers and body) of a message, a detached signature
+// in multipart/signed or an opaque application/pkcs7-mime one
+pub fn find_smime_signed(entity: &str) -> Option<&str> {
+    let (head, body) = split_message(entity);
+    let headers = Headers::parse(head);
+    let (mime, params) = content_type(headers.get("Content-Type")?);
+    let protocol = params.get("protocol").map(|p| p.to_lowercase()).unwrap_or_default();
+    let smime_type = params.get("smime-type").map(|t| t.to_lowercase()).unwrap_or_default();
+    if mime == "multipart/signed" && (protocol == "application/pkcs7-signature" || protocol == "application/x-pkcs7-signature") {
+        return Some(entity);
+    }
+    if (mime == "application/pkcs7-mime" || mime == "application/x-pkcs7-mime") && smime_type == "signed-data" {
+        return Some(entity);
+    }
+    if !mime.starts_with("multipart/") {
+        return None;
+    }
+    split_multipart(body, params.get("boundary")?.as_str()).into_iter().find_map(find_smime_signed)
+}
+
+// Encrypted payload of a PGP/MIME encrypted message
+pub fn find_pgp_encrypted(headers: &Headers, body: &str) -> Option<Vec<u8>> {
+    let (mime, params) = content_type(headers.get("Content-Type")?);
+    let protocol = params.get("protocol").map(|p| p.to_lowercase());
+    if mime != "multipart/encrypted" || protocol.as_ref().map(|p| p.as_str()) != Some("application/pgp-encrypted") {
+        return None;
+    }
+    let parts = split_multipart(body, params.get("boundary")?.as_str());
+    let (head, part_body) = split_message(parts.get(1)?);
+    Some(decode_body(part_body, Headers::parse(head).get("Content-Transfer-Encoding")))
+}
+
+// Armored block of an inline signed message
+pub fn find_inline_signed(text: &str) -> Option<&str> {
+    let start = text.find("-----BEGIN PGP SIGNED MESSAGE-----")?;
+    let end_marker = "-----END PGP SIGNATURE-----";
+    let end = text[start..].find(end_marker)? + start + end_marker.len();
+    Some(&text[start..end])
 }
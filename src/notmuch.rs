@@ -0,0 +1,149 @@
+// Integration with an existing notmuch database: received mails are delivered into its maildir
+// and indexed with "notmuch new", tag and search commands are passed through
+use std::{
+    collections::HashSet,
+    env,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use openssl::{
+    hash::{hash, MessageDigest},
+    rand::rand_bytes,
+};
+
+use super::headers::Headers;
+use super::mime_decode;
+use super::util;
+
+const SUBDIRS: &'static [&'static str] = &["tmp", "new", "cur"];
+
+fn maildir_path(maildir: &str) -> PathBuf {
+    match (maildir.strip_prefix("~/"), env::var("HOME")) {
+        (Some(rest), Ok(home)) => PathBuf::from(home).join(rest),
+        (_, _) => PathBuf::from(maildir),
+    }
+}
+
+// Mails stored in the maildir by their Message-ID, a hash of the content for mails without one.
+// Kept in a file of its own, checking a mail doesn't read the maildir
+pub struct Index {
+    path: PathBuf,
+    keys: HashSet<String>,
+}
+
+impl Index {
+    // A missing file is an empty index
+    pub fn load(path: PathBuf) -> Index {
+        let keys = fs::read_to_string(&path).map(|c| c.lines().map(|l| l.to_string()).collect()).unwrap_or_default();
+        Index { path, keys }
+    }
+
+    pub fn contains(&self, message_id: &str) -> bool {
+        self.keys.contains(message_id)
+    }
+
+    // Stores the mail unless it is in the index already, returns whether it was stored
+    pub fn deliver(&mut self, maildir: &str, raw: &[u8]) -> io::Result<bool> {
+        let key = mail_key(raw)?;
+        if self.keys.contains(&key) {
+            return Ok(false);
+        }
+        store(maildir, raw)?;
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(file, "{}", key)?;
+        self.keys.insert(key);
+        Ok(true)
+    }
+}
+
+fn mail_key(raw: &[u8]) -> io::Result<String> {
+    let text = String::from_utf8_lossy(raw);
+    let (head, _) = mime_decode::split_message(text.as_ref());
+    if let Some(id) = Headers::parse(head).get("Message-ID").and_then(|v| util::message_ids(v).into_iter().next()) {
+        return Ok(id);
+    }
+    let digest = hash(MessageDigest::sha256(), raw).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    Ok(format!("sha256:{}", digest.iter().map(|b| format!("{:02x}", b)).collect::<String>()))
+}
+
+// Line ends become plain newlines, the bytes are stored as they are otherwise
+fn store(maildir: &str, raw: &[u8]) -> io::Result<()> {
+    let root = maildir_path(maildir);
+    for sub in SUBDIRS.iter() {
+        fs::create_dir_all(root.join(sub))?;
+    }
+    let mut content = Vec::with_capacity(raw.len());
+    for (i, b) in raw.iter().enumerate() {
+        if *b != b'\r' || raw.get(i + 1) != Some(&b'\n') {
+            content.push(*b);
+        }
+    }
+    // "<time>.<random>.cli-mail-rs" like other maildir deliveries
+    let mut random = [0u8; 8];
+    rand_bytes(&mut random).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let unique = format!("{}.{}.cli-mail-rs", time, random.iter().map(|b| format!("{:02x}", b)).collect::<String>());
+    // Written to tmp first, so notmuch never sees half a mail
+    let tmp = root.join("tmp").join(unique.as_str());
+    fs::write(&tmp, content)?;
+    fs::rename(&tmp, root.join("new").join(unique))
+}
+
+fn run(args: &[&str]) -> io::Result<String> {
+    log::debug!("running notmuch {}", args.join(" "));
+    let output = Command::new("notmuch").args(args).output()?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    } else {
+        Err(io::Error::new(io::ErrorKind::Other, String::from_utf8_lossy(&output.stderr).trim().to_string()))
+    }
+}
+
+// Indexes newly delivered mails
+pub fn index() -> io::Result<()> {
+    run(&["new", "--quiet"]).map(|_| ())
+}
+
+pub fn search(query: &str, json: bool) -> io::Result<String> {
+    if json {
+        run(&["search", "--format=json", query])
+    } else {
+        run(&["search", query])
+    }
+}
+
+// Changes are given as "+tag" or "-tag"
+pub fn tag(changes: &[&str], query: &str) -> io::Result<()> {
+    let mut args = vec!["tag"];
+    args.extend(changes.iter());
+    args.push("--");
+    args.push(query);
+    run(&args).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mails_are_stored_once() {
+        let dir = env::temp_dir().join(format!("cli-mail-rs-notmuch-{}", std::process::id()));
+        let maildir = dir.join("mail").to_string_lossy().to_string();
+        let mut index = Index::load(dir.join("index"));
+        let mail = b"Message-ID: <a@example.org>\r\nSubject: Hi\r\n\r\nHello\r\n";
+        let anonymous = b"Subject: Hi\r\n\r\nHello\r\n";
+        assert!(index.deliver(maildir.as_str(), mail).unwrap());
+        assert!(index.deliver(maildir.as_str(), anonymous).unwrap());
+        assert!(!index.deliver(maildir.as_str(), anonymous).unwrap());
+        // The index is read back on the next load
+        let mut index = Index::load(dir.join("index"));
+        assert!(index.contains("a@example.org"));
+        assert!(!index.deliver(maildir.as_str(), mail).unwrap());
+        assert_eq!(fs::read_dir(dir.join("mail").join("new")).unwrap().count(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
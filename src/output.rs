@@ -0,0 +1,64 @@
+extern crate serde_json;
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use console::Style;
+
+use serde_json::{
+    json,
+    Value,
+};
+
+// Switched by `--json` or the `output` command
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+pub fn set_json(enabled: bool) {
+    JSON_OUTPUT.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_json() -> bool {
+    JSON_OUTPUT.load(Ordering::Relaxed)
+}
+
+pub fn print_json(value: &Value) {
+    println!("{}", serde_json::to_string_pretty(value).unwrap_or_default());
+}
+
+// Switched by the `plain_output` setting: no colors or aligned columns, every value is labeled
+static PLAIN_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+pub fn set_plain(enabled: bool) {
+    PLAIN_OUTPUT.store(enabled, Ordering::Relaxed);
+    console::set_colors_enabled(!enabled);
+    console::set_colors_enabled_stderr(!enabled);
+}
+
+pub fn is_plain() -> bool {
+    PLAIN_OUTPUT.load(Ordering::Relaxed)
+}
+
+// Highlighted text, in brackets when colors are off
+pub fn emphasize(text: &str, style: &Style) -> String {
+    if is_plain() {
+        format!("[{}]", text)
+    } else {
+        style.apply_to(text).to_string()
+    }
+}
+
+// Errors reported so far, scripts stop when a command adds one
+static ERRORS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn error_count() -> usize {
+    ERRORS.load(Ordering::Relaxed)
+}
+
+// Errors go to stderr, as object in json mode
+pub fn error(message: &str) {
+    ERRORS.fetch_add(1, Ordering::Relaxed);
+    if is_json() {
+        eprintln!("{}", json!({ "error": message }));
+    } else {
+        eprintln!("{}", message);
+    }
+}
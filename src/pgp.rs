@@ -0,0 +1,326 @@
+// PGP through gpg, with a keyring of its own next to the account file
+use std::{
+    fs,
+    io,
+    path::PathBuf,
+    process::{Command, Output, Stdio},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use super::contacts::AddressBook;
+use super::util;
+
+// Passphrase of the last successful unlock and when it expires, so reading an encrypted
+// thread asks only once
+static PASSPHRASE: Mutex<Option<(String, Instant)>> = Mutex::new(None);
+static PASSPHRASE_TTL: AtomicU64 = AtomicU64::new(300);
+
+// Seconds a passphrase is kept, 0 asks every time
+pub fn set_passphrase_ttl(secs: u64) {
+    PASSPHRASE_TTL.store(secs, Ordering::Relaxed);
+    if secs == 0 {
+        lock();
+    }
+}
+
+pub fn cached_passphrase() -> Option<String> {
+    let mut cached = PASSPHRASE.lock().unwrap();
+    match &*cached {
+        Some((passphrase, expires)) if Instant::now() < *expires => Some(passphrase.clone()),
+        _ => {
+            *cached = None;
+            None
+        },
+    }
+}
+
+pub fn remember_passphrase(passphrase: String) {
+    let ttl = PASSPHRASE_TTL.load(Ordering::Relaxed);
+    if ttl > 0 {
+        *PASSPHRASE.lock().unwrap() = Some((passphrase, Instant::now() + Duration::from_secs(ttl)));
+    }
+}
+
+// Forgets the passphrase, returns whether one was kept
+pub fn lock() -> bool {
+    PASSPHRASE.lock().unwrap().take().is_some()
+}
+
+pub struct Key {
+    pub fingerprint: String,
+    pub uids: Vec<String>,
+    // Revoked, expired or disabled keys are listed but not used
+    pub valid: bool,
+}
+
+impl Key {
+    // Addresses of the user ids, "Name <user@host>" gives "user@host"
+    pub fn addresses(&self) -> Vec<String> {
+        self.uids.iter().filter_map(|uid| {
+            let address = match (uid.rfind('<'), uid.rfind('>')) {
+                (Some(start), Some(end)) if start < end => &uid[start + 1..end],
+                (_, _) => uid.as_str(),
+            };
+            if address.contains('@') { Some(address.to_lowercase()) } else { None }
+        }).collect()
+    }
+}
+
+// Outcome of checking a signature, with the signer's user id or key id
+pub enum Verification {
+    Good(String),
+    Bad(String),
+    // No public key for the signature in the keyring
+    Unknown(String),
+    // Good signature, but not by the key associated with the sender's contact
+    OtherKey(String),
+}
+
+impl Verification {
+    pub fn describe(&self) -> String {
+        match self {
+            Verification::Good(uid) => format!("Good signature from {}", uid),
+            Verification::Bad(uid) => format!("Bad signature from {}", uid),
+            Verification::Unknown(id) => format!("Unknown signature from {}", id),
+            Verification::OtherKey(uid) => format!("Signature from {} is not by the key of the contact", uid),
+        }
+    }
+
+    pub fn status(&self) -> &'static str {
+        match self {
+            Verification::Good(_) => "good",
+            Verification::Bad(_) => "bad",
+            Verification::Unknown(_) => "unknown",
+            Verification::OtherKey(_) => "other-key",
+        }
+    }
+}
+
+pub struct Keyring {
+    home: PathBuf,
+    // Keys associated with contacts, checked against the signer
+    book: Option<AddressBook>,
+}
+
+fn other_err<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+impl Keyring {
+    pub fn new(home: PathBuf) -> Keyring {
+        Keyring { home, book: None }
+    }
+
+    pub fn with_contacts(mut self, book: AddressBook) -> Keyring {
+        self.book = Some(book);
+        self
+    }
+
+    pub fn gpg(&self, args: &[&str], input: Option<&[u8]>) -> io::Result<Output> {
+        fs::create_dir_all(&self.home)?;
+        #[cfg(unix)]
+        {
+            // gpg refuses to work with a home directory others can read
+            use std::os::unix::fs::PermissionsExt;
+            let _ = fs::set_permissions(&self.home, fs::Permissions::from_mode(0o700));
+        }
+        log::debug!("running gpg {}", args.join(" "));
+        let mut child = Command::new("gpg")
+            .arg("--homedir").arg(&self.home)
+            .arg("--batch")
+            .args(args)
+            .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+            use std::io::Write;
+            stdin.write_all(input)?;
+        }
+        child.wait_with_output()
+    }
+
+    fn gpg_ok(&self, args: &[&str]) -> io::Result<String> {
+        let output = self.gpg(args, None)?;
+        if output.status.success() {
+            Ok(String::from_utf8_lossy(&output.stdout).to_string())
+        } else {
+            Err(other_err(String::from_utf8_lossy(&output.stderr).trim().lines().last().unwrap_or("gpg failed")))
+        }
+    }
+
+    pub fn list(&self) -> io::Result<Vec<Key>> {
+        Ok(parse_colons(self.gpg_ok(&["--with-colons", "--fixed-list-mode", "--list-keys"])?.as_str()))
+    }
+
+    // Returns the fingerprints of the imported keys
+    pub fn import(&self, path: &str) -> io::Result<Vec<String>> {
+        let status = self.gpg_ok(&["--status-fd", "1", "--import", path])?;
+        Ok(imported(status.as_str()))
+    }
+
+    // Looks the key up with WKD first, then on the keyservers
+    pub fn fetch(&self, address: &str) -> io::Result<Vec<Key>> {
+        let output = self.gpg_ok(&["--with-colons", "--fixed-list-mode", "--auto-key-locate", "clear,wkd,keyserver", "--locate-keys", address])?;
+        let keys: Vec<Key> = parse_colons(output.as_str()).into_iter()
+            .filter(|k| k.addresses().contains(&address.to_lowercase()))
+            .collect();
+        if keys.is_empty() {
+            return Err(other_err(format!("no key found for {}", address)));
+        }
+        Ok(keys)
+    }
+
+    // Checks a detached signature over data, or an inline signed message if signature is None.
+    // A sender with a key in the address book has to have signed with that key
+    pub fn verify(&self, data: &[u8], signature: Option<&[u8]>, sender: &str) -> io::Result<Verification> {
+        let output = match signature {
+            Some(signature) => {
+                let file = util::temp_file("sig", signature)?;
+                self.gpg(&["--status-fd", "1", "--verify", file.path().to_string_lossy().as_ref(), "-"], Some(data))?
+            },
+            None => self.gpg(&["--status-fd", "1", "--verify"], Some(data))?,
+        };
+        // gpg fails for bad and unknown signatures too, the status lines tell them apart
+        let expected = self.book.as_ref().and_then(|b| b.key_for(util::extract_address(sender).as_str()));
+        verification(String::from_utf8_lossy(&output.stdout).as_ref(), expected.map(|k| k.as_str()))
+            .ok_or(other_err(String::from_utf8_lossy(&output.stderr).trim().lines().last().unwrap_or("no signature found")))
+    }
+
+    // Decrypts an encrypted message, the passphrase is passed to gpg on stdin
+    pub fn decrypt(&self, data: &[u8], passphrase: &str) -> io::Result<Vec<u8>> {
+        let file = util::temp_file("asc", data)?;
+        let output = self.gpg(&["--pinentry-mode", "loopback", "--passphrase-fd", "0", "--decrypt", file.path().to_string_lossy().as_ref()], Some(format!("{}\n", passphrase).as_bytes()))?;
+        if output.status.success() {
+            Ok(output.stdout)
+        } else {
+            Err(other_err(String::from_utf8_lossy(&output.stderr).trim().lines().last().unwrap_or("gpg failed")))
+        }
+    }
+
+    pub fn find(&self, fingerprint: &str) -> io::Result<Option<Key>> {
+        Ok(self.list()?.into_iter().find(|k| k.fingerprint.eq_ignore_ascii_case(fingerprint)))
+    }
+}
+
+// "[GNUPG:] IMPORT_OK <reason> <fingerprint>" lines of the status output
+fn imported(status: &str) -> Vec<String> {
+    let mut ret: Vec<String> = Vec::new();
+    for line in status.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() >= 4 && fields[0] == "[GNUPG:]" && fields[1] == "IMPORT_OK" && !ret.iter().any(|f| f == fields[3]) {
+            ret.push(fields[3].to_string());
+        }
+    }
+    ret
+}
+
+// First signature result of the status output, see doc/DETAILS of gnupg.
+// A good signature counts only if the primary key of VALIDSIG is the expected one
+fn verification(status: &str, expected: Option<&str>) -> Option<Verification> {
+    let signer = signer_fingerprint(status);
+    let by_expected = match (expected, &signer) {
+        (Some(expected), Some(signer)) => signer.eq_ignore_ascii_case(expected.replace(' ', "").as_str()),
+        (Some(_), None) => false,
+        (None, _) => true,
+    };
+    for line in status.lines() {
+        let mut fields = line.splitn(4, ' ');
+        if fields.next() != Some("[GNUPG:]") {
+            continue;
+        }
+        let keyword = fields.next().unwrap_or("");
+        let key_id = fields.next().unwrap_or("").to_string();
+        let uid = fields.next().map(|u| u.to_string()).unwrap_or(key_id.clone());
+        match keyword {
+            "GOODSIG" if by_expected => return Some(Verification::Good(uid)),
+            "GOODSIG" => return Some(Verification::OtherKey(uid)),
+            // Signatures by expired or revoked keys are not trusted either
+            "BADSIG" | "EXPKEYSIG" | "REVKEYSIG" | "EXPSIG" => return Some(Verification::Bad(uid)),
+            "ERRSIG" => return Some(Verification::Unknown(format!("key {}", key_id))),
+            _ => {},
+        }
+    }
+    None
+}
+
+// "VALIDSIG <fingerprint> ... <primary key fingerprint>", the primary key is missing for old gpg versions
+fn signer_fingerprint(status: &str) -> Option<String> {
+    status.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 || fields[0] != "[GNUPG:]" || fields[1] != "VALIDSIG" {
+            return None;
+        }
+        Some(fields.get(11).unwrap_or(&fields[2]).to_string())
+    })
+}
+
+// Parses the machine readable key listing, see doc/DETAILS of gnupg
+fn parse_colons(listing: &str) -> Vec<Key> {
+    let mut ret: Vec<Key> = Vec::new();
+    // The first fpr record after a pub record belongs to the primary key
+    let mut needs_fingerprint = false;
+    for line in listing.lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        match fields.get(0) {
+            Some(&"pub") => {
+                let validity = fields.get(1).copied().unwrap_or("");
+                ret.push(Key { fingerprint: String::new(), uids: Vec::new(), valid: !["r", "e", "d", "i"].contains(&validity) });
+                needs_fingerprint = true;
+            },
+            Some(&"fpr") if needs_fingerprint => {
+                if let Some(key) = ret.last_mut() {
+                    key.fingerprint = fields.get(9).unwrap_or(&"").to_string();
+                }
+                needs_fingerprint = false;
+            },
+            Some(&"uid") => {
+                if let Some(key) = ret.last_mut() {
+                    key.uids.push(unescape(fields.get(9).unwrap_or(&"")));
+                }
+            },
+            _ => {},
+        }
+    }
+    ret
+}
+
+// User ids escape special characters as "\x3a"
+fn unescape(s: &str) -> String {
+    let mut bytes = Vec::new();
+    let raw = s.as_bytes();
+    let mut i = 0;
+    while i < raw.len() {
+        if raw[i] == b'\\' && raw.get(i + 1) == Some(&b'x') {
+            if let Some(b) = s.get(i + 2..i + 4).and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                bytes.push(b);
+                i += 4;
+                continue;
+            }
+        }
+        bytes.push(raw[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATUS: &str = "[GNUPG:] NEWSIG\n\
+        [GNUPG:] GOODSIG 1234567890ABCDEF Alice <alice@example.org>\n\
+        [GNUPG:] VALIDSIG 11112222333344445555666677778888AAAABBBB 2024-01-01 1704067200 0 4 0 1 8 00 99990000111122223333444455556666CCCCDDDD\n";
+
+    #[test]
+    fn signer_must_match_contact_key() {
+        assert_eq!(verification(STATUS, None).map(|v| v.status()), Some("good"));
+        assert_eq!(verification(STATUS, Some("99990000111122223333444455556666ccccdddd")).map(|v| v.status()), Some("good"));
+        assert_eq!(verification(STATUS, Some("11112222333344445555666677778888AAAABBBB")).map(|v| v.status()), Some("other-key"));
+        assert_eq!(verification("[GNUPG:] GOODSIG 1234567890ABCDEF Alice\n", Some("99990000111122223333444455556666CCCCDDDD")).map(|v| v.status()), Some("other-key"));
+    }
+}
@@ -0,0 +1,50 @@
+// External commands added to the prompt. The plugin gets its arguments, MAIL_MODE, MAIL_ACCOUNT
+// and MAIL_FILE with the opened mail in the environment and the same as json object on stdin.
+// Its output is shown, errors go straight to the terminal
+use std::{
+    io::{self, Write},
+    process::Stdio,
+};
+
+use serde_json::json;
+
+use super::util;
+
+pub fn run(command: &str, args: &[String], mode: &str, account: Option<&str>, mail: Option<&str>) -> io::Result<String> {
+    // Removed when the plugin is done
+    let file = match mail {
+        Some(raw) => Some(util::temp_file("eml", raw.as_bytes())?),
+        None => None,
+    };
+    // Arguments are passed on as they are, sh gets them as "$@"
+    let mut shell = if cfg!(windows) {
+        util::shell(format!("{} {}", command, args.join(" ")).as_str())
+    } else {
+        let mut shell = util::shell(format!("{} \"$@\"", command).as_str());
+        shell.arg(command).args(args);
+        shell
+    };
+    shell.env("MAIL_MODE", mode)
+        .env("MAIL_ACCOUNT", account.unwrap_or(""))
+        .env("MAIL_FILE", file.as_ref().map(|f| f.path().to_string_lossy().to_string()).unwrap_or_default())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped());
+    let context = json!({
+        "mode": mode,
+        "account": account,
+        "mail_file": file.as_ref().map(|f| f.path().to_string_lossy().to_string()),
+        "args": args,
+    });
+    let result = shell.spawn().and_then(|mut child| {
+        // Plugins not reading their input close the pipe early, that's fine
+        if let Some(mut stdin) = child.stdin.take() {
+            let _ = writeln!(stdin, "{}", context);
+        }
+        child.wait_with_output()
+    });
+    let output = result?;
+    if !output.status.success() {
+        return Err(io::Error::new(io::ErrorKind::Other, format!("{} exited with {}", command, output.status)));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
@@ -1,9 +1,7 @@
-extern crate openssl;
-extern crate pop3;
-
 use std::{
+    io::{self, BufRead, BufReader, Read, Write},
     net::TcpStream,
-    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
     cmp::{
         PartialEq,
         PartialOrd,
@@ -11,13 +9,6 @@ use std::{
     },
 };
 
-use openssl::{
-    ssl::{SslConnectorBuilder, SslMethod},
-};
-use pop3::{
-    POP3Stream,
-    POP3Result,
-};
 use imap::{
     Client as ImapClient,
     Session as ImapSession,
@@ -26,27 +17,41 @@ use imap::{
         ZeroCopy,
     },
 };
-use native_tls::{
-    TlsConnector,
-    TlsStream,
-};
-use datetime::{
-    OffsetDateTime,
-};
-
-use mime::{
-    Mime,
-    Name as MimeName,
-    Params as MimeParams,
+use native_tls::TlsConnector;
+use chrono::{
+    DateTime,
+    FixedOffset,
+    Local,
 };
 
 use super::account::{
     InboxConfig,
 };
+use super::config::QuoteStyle;
 use super::inbox::MailBuilder;
 use super::util;
 use super::decoder;
-use super::mime;
+use super::output;
+use super::trace::TraceStream;
+use super::address;
+use super::graph;
+use super::http;
+use super::headers::Headers;
+use super::pgp::{Keyring, Verification};
+use super::smime;
+use super::dkim;
+use super::authres::AuthResults;
+use super::calendar;
+use super::bounce::{self, Bounce};
+use super::error::{MailError, MailResult};
+use serde_json::{
+    json,
+    Value,
+};
+use super::mime_decode::{
+    self,
+    Content,
+};
 
 pub struct ReceivedMailProxy {
     header: Option<Box<ReceivedMailHeader>>,
@@ -71,23 +76,141 @@ impl ReceivedMailProxy {
         return ret;
     }
 
-    pub fn get_mail(&mut self, adapter: &mut InboxAdapter) -> Option<&ReceivedMail> {
-        // Check if ReceivedMail has already been loaded
-        if let None = &self.mail {
-            // Load ReceivedMail
-            println!("ReceivedMail must be loaded!");
-            if let Some(header) = &self.header {
-                self.mail = adapter.get_mail(header).map(|m| Box::new(m));
+    pub fn get_id(&self) -> Option<u32> {
+        self.header.as_ref().map(|h| h.get_id())
+    }
+
+    pub fn get_header(&self) -> Option<&ReceivedMailHeader> {
+        self.header.as_ref().map(|h| h.as_ref())
+    }
+
+    // Marks for the listing: bounces stand out, other mails show whether the sender passed the checks
+    // of the server. Mailing list mails get a second mark
+    pub fn indicator(&self) -> String {
+        match &self.header {
+            Some(header) => {
+                let first = if header.bounce { console::style("B").red().bold().to_string() } else { header.auth.indicator() };
+                let list = header.list_id.is_some() || header.list_post.is_some();
+                format!("{}{}", first, if list { console::style("L").cyan().to_string() } else { String::from(" ") })
+            },
+            None => String::from("  "),
+        }
+    }
+
+    // The marks of indicator in words
+    pub fn marks(&self) -> Vec<&'static str> {
+        let mut ret = Vec::new();
+        if let Some(header) = &self.header {
+            if header.bounce {
+                ret.push("bounce");
+            } else if let Some(verdict) = header.auth.describe_verdict() {
+                ret.push(verdict);
+            }
+            if header.list_id.is_some() || header.list_post.is_some() {
+                ret.push("mailing list");
+            }
+            if header.spam_score.map(|s| s >= 0.9).unwrap_or(false) {
+                ret.push("probably spam");
             }
         }
-        // If loading was successful, return mail
-        return if let Some(mail) = &self.mail {
-            println!("Returning mail ...");
-            Some(mail)
-        } else {
-            None
+        ret
+    }
+
+    // Header fields and the body once it was loaded, as the spam filter sees them
+    pub fn spam_text(&self) -> Vec<(&'static str, String)> {
+        let mut ret = Vec::new();
+        if let Some(header) = &self.header {
+            ret.push(("from", header.get_from()));
+            ret.push(("subject", header.subject.clone()));
+            if let Some(list_id) = &header.list_id {
+                ret.push(("list", list_id.clone()));
+            }
+            if let Some(verdict) = header.auth.describe_verdict() {
+                ret.push(("auth", verdict.to_string()));
+            }
+        }
+        if let Some(mail) = &self.mail {
+            ret.push(("", mail.get_body().clone()));
+        }
+        ret
+    }
+
+    pub fn set_spam_score(&mut self, score: Option<f64>) {
+        if let Some(header) = self.header.as_mut() {
+            header.spam_score = score;
         }
     }
+
+    // Text grep looks at: all header fields and the body once the mail was loaded, the
+    // listed fields before
+    pub fn cached_lines(&self) -> Vec<String> {
+        match (&self.mail, &self.header) {
+            (Some(mail), _) => {
+                let mut ret: Vec<String> = mail.headers.iter().map(|(k, v)| format!("{}: {}", k, decoder::decode(v.clone()))).collect();
+                ret.extend(mail.get_body().lines().map(|l| l.to_string()));
+                ret
+            },
+            (None, Some(header)) => vec![
+                format!("From: {}", header.get_from()),
+                format!("To: {}", join_addresses(&header.to, "")),
+                format!("Subject: {}", header.subject),
+            ],
+            (None, None) => Vec::new(),
+        }
+    }
+
+    pub fn is_bounce(&self) -> bool {
+        self.header.as_ref().map(|h| h.bounce).unwrap_or(false)
+    }
+
+    // Own message id and the ids of the mails it refers to
+    pub fn get_thread_ids(&self) -> (Option<String>, Vec<String>) {
+        match &self.header {
+            Some(header) => (header.message_id.clone(), header.parents.clone()),
+            None => (None, Vec::new()),
+        }
+    }
+
+    // Sender and recipient addresses known from the header
+    // Senders and recipients with their display names
+    pub fn get_people(&self) -> Vec<AddressAlias> {
+        match &self.header {
+            Some(header) => header.from.iter().chain(header.to.iter())
+                .filter(|a| a.get_address().contains('@'))
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut val = match (&self.mail, &self.header) {
+            (Some(mail), _) => mail.to_json(),
+            (None, Some(header)) => header.to_json(),
+            (None, None) => json!({}),
+        };
+        val["id"] = json!(self.get_id());
+        val
+    }
+
+    pub fn get_mail(&mut self, adapter: &mut InboxAdapter) -> MailResult<&ReceivedMail> {
+        // Load the full mail on first access
+        if self.mail.is_none() {
+            let header = self.header.as_ref().ok_or(MailError::NotFound(String::from("mail header")))?;
+            let mut mail = adapter.get_mail(header)?;
+            // Same fields as the header, which already knows the trusted server
+            mail.auth = header.auth.clone();
+            self.mail = Some(Box::new(mail));
+        }
+        self.mail.as_ref().map(|m| m.as_ref()).ok_or(MailError::NotFound(String::from("mail")))
+    }
+
+    // Replaces the loaded mail with its decrypted version
+    pub fn decrypt(&mut self, keyring: &Keyring, passphrase: &str) -> MailResult<()> {
+        let mail = self.mail.as_ref().ok_or(MailError::NotFound(String::from("mail")))?;
+        self.mail = Some(Box::new(mail.decrypt(keyring, passphrase)?));
+        Ok(())
+    }
 }
 
 impl Eq for ReceivedMailProxy {}
@@ -112,10 +235,23 @@ impl Ord for ReceivedMailProxy {
 
 pub struct ReceivedMailHeader {
     id: u32,
-    to: String,
-    from: String,
-    date: Option<OffsetDateTime>,
+    to: Vec<AddressAlias>,
+    from: Vec<AddressAlias>,
+    date: Option<DateTime<FixedOffset>>,
     subject: String,
+    message_id: Option<String>,
+    // In-Reply-To and References, oldest first
+    parents: Vec<String>,
+    auth: AuthResults,
+    list_id: Option<String>,
+    // Size of the whole mail in bytes, where the server tells it
+    size: Option<u32>,
+    // Delivery status notification
+    bounce: bool,
+    // Address posts to the mailing list go to
+    list_post: Option<String>,
+    // Set by the local spam filter during refresh, 0 is ham and 1 is spam
+    spam_score: Option<f64>,
 }
 
 impl Eq for ReceivedMailHeader {}
@@ -144,33 +280,102 @@ impl Ord for ReceivedMailHeader {
 }
 
 impl ReceivedMailHeader {
-    pub fn new(id: u32, map: HashMap<String, String>) -> ReceivedMailHeader {
-        let to = map.get(&String::from("To")).map(|x| x.clone()).unwrap_or(String::from("<to>"));
-        let from = map.get(&String::from("From")).map(|x| x.clone()).unwrap_or(String::from("<from>"));
-        let date = match map.get(&String::from("Date")) {
-            Some(date_str) => match decoder::decode_date(date_str) {
-                Some(date) => Some(date),
-                None => None,
-            },
-            None => None,
-        };
-        let raw = map.get(&String::from("Subject")).map(|x| x.clone().replace("\n", "").replace("\r", "")).unwrap_or(String::from("<subject>"));
-        let subject = decoder::decode(raw);
+    pub fn new(id: u32, headers: &Headers) -> ReceivedMailHeader {
+        let to = headers.get("To").map(|x| address::parse_list(x)).unwrap_or(Vec::new());
+        let from = headers.get("From").map(|x| address::parse_list(x)).unwrap_or(Vec::new());
+        let date = headers.get("Date").and_then(|d| decoder::decode_date(d));
+        let subject = headers.get("Subject").map(|x| decoder::decode(x.clone())).unwrap_or(String::from("<subject>"));
+        let message_id = headers.get("Message-ID").and_then(|v| util::message_ids(v).into_iter().next());
+        let mut parents = headers.get("References").map(|v| util::message_ids(v)).unwrap_or(Vec::new());
+        if let Some(reply_to) = headers.get("In-Reply-To") {
+            parents.extend(util::message_ids(reply_to).into_iter().filter(|id| !parents.contains(id)).collect::<Vec<String>>());
+        }
+
+        let auth = AuthResults::parse(headers, from.first().map(|a| a.get_address()).unwrap_or_default().as_str());
+        let list_id = headers.get("List-Id").map(|v| decoder::decode(v.clone()));
+        let bounce = bounce::is_bounce(headers);
+        let list_post = list_post(headers);
 
         ReceivedMailHeader {
-            id, to, from, date, subject
+            id, to, from, date, subject, message_id, parents, auth, list_id, size: None, bounce, list_post, spam_score: None
         }
     }
 
-    pub fn from_fetch(seq: u32, fetch: ZeroCopy<Vec<Fetch>>) -> ReceivedMailHeader {
-        let result = fetch.iter().next().unwrap();
-        let content = result.header().map(|x| String::from_utf8(x.to_vec()).unwrap()).unwrap_or(String::new());
-        let map = extract_mapping(content.clone());
-        ReceivedMailHeader::new(seq, map)
+    // None if the server answered without the header
+    pub fn from_fetch(seq: u32, fetch: ZeroCopy<Vec<Fetch>>) -> Option<ReceivedMailHeader> {
+        let first = fetch.iter().next()?;
+        let content = first.header().map(|x| String::from_utf8_lossy(x).to_string())?;
+        let mut header = ReceivedMailHeader::new(seq, &Headers::parse(content.as_str()));
+        header.size = first.size;
+        Some(header)
     }
 
     pub fn get_info(&self) -> String {
-        display_info_from(&self.date, &self.from, &self.subject)
+        display_info_from(&self.date, &self.get_from(), &self.subject)
+    }
+
+    pub fn get_id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn get_from(&self) -> String {
+        join_addresses(&self.from, "<from>")
+    }
+
+    pub fn get_subject(&self) -> &String {
+        &self.subject
+    }
+
+    // Authentication results are only shown from the server with this id, see AuthResults::trust
+    pub fn trust_auth(&mut self, authserv_id: &str) {
+        self.auth.trust(authserv_id);
+    }
+
+    pub fn get_date(&self) -> Option<&DateTime<FixedOffset>> {
+        self.date.as_ref()
+    }
+
+    // Metadata handed to hook commands
+    pub fn environment(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("MAIL_ID", self.id.to_string()),
+            ("MAIL_DATE", self.date.map(|d| util::iso_date(&d)).unwrap_or_default()),
+            ("MAIL_FROM", self.get_from()),
+            ("MAIL_TO", join_addresses(&self.to, "")),
+            ("MAIL_SUBJECT", self.subject.clone()),
+            ("MAIL_MESSAGE_ID", self.message_id.clone().unwrap_or_default()),
+        ]
+    }
+
+    pub fn get_list_id(&self) -> Option<&String> {
+        self.list_id.as_ref()
+    }
+
+    pub fn get_size(&self) -> Option<u32> {
+        self.size
+    }
+
+    pub fn get_spam_score(&self) -> Option<f64> {
+        self.spam_score
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "date": self.date.map(|d| util::iso_date(&d)),
+            "from": self.get_from(),
+            "to": join_addresses(&self.to, "<to>"),
+            "subject": self.subject,
+            "authentication": self.auth.to_json(),
+            "bounce": self.bounce,
+            "list_id": self.list_id,
+            "list_post": self.list_post,
+            "spam_score": self.spam_score,
+        })
+    }
+
+    pub fn is_bounce(&self) -> bool {
+        self.bounce
     }
 }
 
@@ -194,29 +399,101 @@ impl AddressAlias {
             Self::OnlyAddress(addr) => addr.clone(),
         }
     }
+
+    pub fn get_name(&self) -> Option<String> {
+        match self {
+            Self::WithAlias(alias, _) => Some(alias.clone()),
+            Self::OnlyAddress(_) => None,
+        }
+    }
+
+}
+
+fn join_addresses(list: &Vec<AddressAlias>, empty: &str) -> String {
+    if list.is_empty() {
+        return String::from(empty);
+    }
+    list.iter().map(|a| a.to_string()).collect::<Vec<String>>().join(", ")
 }
 
 pub struct ReceivedMail {
-    date: Option<OffsetDateTime>,
+    date: Option<DateTime<FixedOffset>>,
     from: AddressAlias,
-    to: AddressAlias,
+    reply_to: Vec<AddressAlias>,
+    to: Vec<AddressAlias>,
     cc: Vec<AddressAlias>,
     bcc: Vec<AddressAlias>,
     subject: String,
     text: String,
+    flowed: bool,
     html: String,
     attachments: Vec<String>,
+    calendar: Vec<String>,
+    headers: Headers,
+    // Message source as received from the server
+    raw: String,
+    // Decrypted MIME entity of an encrypted mail
+    decrypted: Option<String>,
+    auth: AuthResults,
 }
 
 impl ReceivedMail {
-    pub fn from_mime(mime: &Mime) -> Option<ReceivedMail>
-    {
-        None
+    pub fn from_raw(raw: &str) -> ReceivedMail {
+        let (head, body) = mime_decode::split_message(raw);
+        let headers = Headers::parse(head);
+        let mut content = Content::new();
+        mime_decode::extract_content(&headers, body, &mut content);
+        ReceivedMail::from_content(raw, headers, content, None)
+    }
+
+    fn from_content(raw: &str, headers: Headers, content: Content, decrypted: Option<String>) -> ReceivedMail {
+        let get = |key: &str| headers.get(key).map(|v| decoder::decode(v.clone()));
+        let addresses = |key: &str| headers.get(key).map(|v| address::parse_list(v)).unwrap_or(Vec::new());
+        let from = addresses("From").into_iter().next().unwrap_or(AddressAlias::OnlyAddress(String::from("<from>")));
+        let auth = AuthResults::parse(&headers, from.get_address().as_str());
+
+        ReceivedMail {
+            date: headers.get("Date").and_then(|d| decoder::decode_date(d)),
+            from,
+            reply_to: addresses("Reply-To"),
+            to: addresses("To"),
+            cc: addresses("Cc"),
+            bcc: addresses("Bcc"),
+            subject: get("Subject").unwrap_or(String::from("<subject>")),
+            text: content.text,
+            flowed: content.flowed,
+            html: content.html,
+            attachments: content.attachments,
+            calendar: content.calendar,
+            headers,
+            raw: raw.to_string(),
+            decrypted,
+            auth,
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        let (head, body) = mime_decode::split_message(self.raw.as_str());
+        self.decrypted.is_none() && mime_decode::find_pgp_encrypted(&Headers::parse(head), body).is_some()
+    }
+
+    // Same mail with the content of the decrypted PGP/MIME payload
+    pub fn decrypt(&self, keyring: &Keyring, passphrase: &str) -> io::Result<ReceivedMail> {
+        let (head, body) = mime_decode::split_message(self.raw.as_str());
+        let payload = mime_decode::find_pgp_encrypted(&Headers::parse(head), body)
+            .ok_or(other_err("mail is not encrypted"))?;
+        let entity = String::from_utf8_lossy(&keyring.decrypt(&payload, passphrase)?).to_string();
+        let (part_head, part_body) = mime_decode::split_message(entity.as_str());
+        let mut content = Content::new();
+        mime_decode::extract_content(&Headers::parse(part_head), part_body, &mut content);
+        let mut mail = ReceivedMail::from_content(self.raw.as_str(), self.headers.clone(), content, Some(entity));
+        mail.auth = self.auth.clone();
+        Ok(mail)
     }
 
-    pub fn new_plain(date: Option<OffsetDateTime>, from: AddressAlias, to: AddressAlias, subject: String, text: String) -> ReceivedMail {
+    pub fn new_plain(date: Option<DateTime<FixedOffset>>, from: AddressAlias, to: Vec<AddressAlias>, subject: String, text: String) -> ReceivedMail {
         ReceivedMail {
-            date, from, to, cc: Vec::new(), bcc: Vec::new(), subject, text, html: String::new(), attachments: Vec::new(),
+            date, from, reply_to: Vec::new(), to, cc: Vec::new(), bcc: Vec::new(), subject, text, flowed: false, html: String::new(), attachments: Vec::new(), calendar: Vec::new(), headers: Headers::new(), raw: String::new(), decrypted: None, auth: AuthResults::default(),
         }
     }
 
@@ -224,302 +501,1217 @@ impl ReceivedMail {
         display_info_from(&self.date, &self.from.to_string(), &self.subject)
     }
 
+    // Metadata handed to hook commands
+    pub fn environment(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("MAIL_DATE", self.date.map(|d| util::iso_date(&d)).unwrap_or_default()),
+            ("MAIL_FROM", self.from.to_string()),
+            ("MAIL_TO", join_addresses(&self.to, "")),
+            ("MAIL_SUBJECT", self.subject.clone()),
+            ("MAIL_MESSAGE_ID", self.headers.get("Message-ID").map(|v| util::message_ids(v).into_iter().next().unwrap_or_default()).unwrap_or_default()),
+        ]
+    }
+
+    pub fn get_raw(&self) -> &String {
+        &self.raw
+    }
+
+    pub fn get_from_address(&self) -> String {
+        self.from.get_address()
+    }
+
+    pub fn get_subject(&self) -> &String {
+        &self.subject
+    }
+
+    // Body as displayed, plain text if available
+    pub fn get_body(&self) -> &String {
+        if !self.text.is_empty() { &self.text } else { &self.html }
+    }
+
+    pub fn print_headers(&self) {
+        if output::is_json() {
+            let val: Vec<Value> = self.headers.iter().map(|(k, v)| json!({ "name": k, "value": decoder::decode(v.clone()) })).collect();
+            output::print_json(&Value::Array(val));
+            return;
+        }
+        let width = self.headers.iter().map(|(k, _)| k.len()).max().unwrap_or(0).min(30);
+        let mut text = String::new();
+        for (key, value) in self.headers.iter() {
+            let value = decoder::decode(value.clone());
+            if output::is_plain() {
+                text.push_str(format!("{}: {}\n", key, value).as_str());
+                continue;
+            }
+            // Long values like Received are broken before their clauses
+            let value = value.replace("; ", &format!(";\n{:width$}   ", "", width = width));
+            text.push_str(format!("{:>width$}:  {}\n", key, value, width = width).as_str());
+        }
+        util::page(text.trim_end());
+    }
+
+    // Mime tree with section numbers, encodings, sizes and file names
+    pub fn print_structure(&self) {
+        let (head, body) = mime_decode::split_message(self.decrypted.as_ref().unwrap_or(&self.raw).as_str());
+        let mut parts = Vec::new();
+        mime_decode::structure(&Headers::parse(head), body, String::new(), 0, &mut parts);
+        if output::is_json() {
+            output::print_json(&Value::Array(parts.iter().map(|p| json!({
+                "index": p.index,
+                "depth": p.depth,
+                "content_type": p.mime,
+                "encoding": p.encoding,
+                "size": p.size,
+                "filename": p.filename,
+                "attachment": p.attachment,
+            })).collect()));
+            return;
+        }
+        for part in parts.iter() {
+            let mut details: Vec<String> = Vec::new();
+            if let Some(encoding) = &part.encoding {
+                details.push(encoding.clone());
+            }
+            if let Some(size) = part.size {
+                details.push(if size >= 1024 { format!("{:.1} KB", size as f64 / 1024.0) } else { format!("{} B", size) });
+            }
+            if let Some(filename) = &part.filename {
+                details.push(format!("\"{}\"", filename));
+            }
+            if let Some(n) = part.attachment {
+                details.push(tf!("attachment {}", n));
+            }
+            let index = if part.index.is_empty() { String::from("-") } else { part.index.clone() };
+            let line = format!("{}{} {}", "  ".repeat(part.depth), output::emphasize(index.as_str(), &console::Style::new().bold()), part.mime);
+            if details.is_empty() {
+                println!("{}", line);
+            } else {
+                println!("{}  {}", line, details.join(", "));
+            }
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "date": self.date.map(|d| util::iso_date(&d)),
+            "from": self.from.to_string(),
+            "to": self.to.iter().map(|a| a.to_string()).collect::<Vec<String>>(),
+            "cc": self.cc.iter().map(|a| a.to_string()).collect::<Vec<String>>(),
+            "subject": self.subject,
+            "text": self.text,
+            "html": self.html,
+            "attachments": self.attachments,
+            "links": self.get_links(),
+            "authentication": self.auth.to_json(),
+            "events": self.get_events().iter().map(|e| e.to_json()).collect::<Vec<Value>>(),
+            "bounce": self.get_bounce().map(|b| json!({
+                "recipients": b.recipients,
+                "diagnostic": b.diagnostic,
+                "original": b.original_id(),
+            })),
+        })
+    }
+
+    // Failed recipients and the returned mail, if this is a delivery status notification
+    pub fn get_bounce(&self) -> Option<Bounce> {
+        let (_, body) = mime_decode::split_message(self.raw.as_str());
+        bounce::parse(&self.headers, body)
+    }
+
+    // Links of List-Unsubscribe in the order given, and whether the http link takes a one-click POST (RFC 8058)
+    pub fn get_unsubscribe(&self) -> (Vec<String>, bool) {
+        let links = self.headers.get("List-Unsubscribe").map(|v| v.split(',')
+            .map(|l| l.trim().trim_start_matches('<').trim_end_matches('>').trim().to_string())
+            .filter(|l| !l.is_empty())
+            .collect()).unwrap_or(Vec::new());
+        let one_click = self.headers.get("List-Unsubscribe-Post")
+            .map(|v| v.replace(' ', "").eq_ignore_ascii_case("List-Unsubscribe=One-Click"))
+            .unwrap_or(false);
+        (links, one_click)
+    }
+
+    // Calendar parts as they were sent
+    // Names of the attachments
+    pub fn get_attachments(&self) -> &Vec<String> {
+        &self.attachments
+    }
+
+    // Decoded attachment by its position in the attachment list
+    pub fn get_attachment(&self, index: usize) -> Option<mime_decode::Part> {
+        let (head, body) = mime_decode::split_message(self.decrypted.as_ref().unwrap_or(&self.raw).as_str());
+        let mut parts = Vec::new();
+        mime_decode::attachment_parts(&Headers::parse(head), body, &mut parts);
+        if index < parts.len() { Some(parts.swap_remove(index)) } else { None }
+    }
+
+    // Decoded part by its section number, see mime_decode::find_part
+    pub fn get_part(&self, index: &str) -> Option<mime_decode::Part> {
+        let (head, body) = mime_decode::split_message(self.decrypted.as_ref().unwrap_or(&self.raw).as_str());
+        mime_decode::find_part(&Headers::parse(head), body, index)
+    }
+
+    // Whether the html part is what gets displayed
+    pub fn shows_html(&self) -> bool {
+        self.text.is_empty() && !self.html.is_empty()
+    }
+
+    // Images of the html part in order as (name, content). cid: images come from the related
+    // parts, data: urls are decoded and remote images are only loaded if allowed
+    pub fn get_inline_images(&self, remote: bool) -> Vec<(String, Option<Vec<u8>>)> {
+        let (head, body) = mime_decode::split_message(self.decrypted.as_ref().unwrap_or(&self.raw).as_str());
+        let mut related = Vec::new();
+        mime_decode::related_parts(&Headers::parse(head), body, &mut related);
+        util::image_sources(self.html.as_str()).into_iter().map(|src| {
+            let lower = src.to_ascii_lowercase();
+            if lower.starts_with("cid:") {
+                let id = http::url_decode(&src[4..]);
+                match related.iter().find(|p| p.content_id.as_ref() == Some(&id)) {
+                    Some(part) => (part.name.clone(), Some(part.data.clone())),
+                    None => (src, None),
+                }
+            } else if lower.starts_with("data:") {
+                let data = src.splitn(2, ',').nth(1).filter(|_| lower.contains(";base64,")).and_then(|d| base64::decode(d.trim()).ok());
+                (String::from("<embedded>"), data)
+            } else if remote && (lower.starts_with("http://") || lower.starts_with("https://")) {
+                let data = http::get(src.as_str()).ok().filter(|r| r.is_success()).map(|r| r.data);
+                (src, data)
+            } else {
+                (src, None)
+            }
+        }).collect()
+    }
+
+    pub fn get_calendar(&self) -> &Vec<String> {
+        &self.calendar
+    }
+
+    // Events of all calendar parts
+    pub fn get_events(&self) -> Vec<calendar::Event> {
+        self.calendar.iter().flat_map(|ics| calendar::parse(ics.as_str())).collect()
+    }
+
     pub fn print_all(&self) {
-        println!("Output not yet implemented!");
+        if output::is_json() {
+            output::print_json(&self.to_json());
+            return;
+        }
+        println!("Date:\t{}", self.date.map(|d| util::format_date(&d)).unwrap_or(String::from("<date>")));
+        println!("From:\t{}", self.from.to_string());
+        println!("To:\t{}", join_addresses(&self.to, "<to>"));
+        if !self.cc.is_empty() {
+            println!("Cc:\t{}", self.cc.iter().map(|a| a.to_string()).collect::<Vec<String>>().join(", "));
+        }
+        println!("Subject:\t{}", self.subject);
+        if !self.auth.is_empty() {
+            println!("Auth:\t{}", self.auth.summary());
+        }
+        if !self.attachments.is_empty() {
+            println!("Attachments:\t{}", self.attachments.iter().enumerate().map(|(i, a)| format!("[{}] {}", i, a)).collect::<Vec<String>>().join(", "));
+        }
+        if let Some(bounce) = self.get_bounce() {
+            bounce.print();
+        }
+        for event in self.get_events().iter() {
+            println!();
+            event.print();
+        }
+        if !self.text.is_empty() {
+            println!("\n{}", self.text);
+        } else if !self.html.is_empty() {
+            println!("\n{}", self.html);
+        }
+        let links = self.get_links();
+        if !links.is_empty() {
+            println!("\nLinks:");
+            links.iter().enumerate().for_each(|(i, url)| println!("\t[{}] {}", i, url));
+        }
     }
 
-    pub fn create_reply(&self) -> MailBuilder {
+    // Original message with a resent block on top (RFC 5322 3.6.6), trace fields of the
+    // delivery to us are dropped so the new one gets its own
+    pub fn redirect_message(&self, from: &str, to: &[String]) -> String {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let domain = util::extract_address(from).rsplit('@').next().unwrap_or("localhost").to_string();
+        let mut ret = format!("Resent-Date: {}\r\nResent-From: {}\r\nResent-To: {}\r\nResent-Message-ID: <{}.{}@{}>\r\n",
+            Local::now().to_rfc2822(), from, to.join(", "), now.as_nanos(), std::process::id(), domain);
+        let mut lines = self.raw.split('\n').peekable();
+        while let Some(line) = lines.peek() {
+            let lower = line.to_ascii_lowercase();
+            if !(lower.starts_with("return-path:") || lower.starts_with("delivered-to:")) {
+                break;
+            }
+            lines.next();
+            // Folded continuation lines belong to the dropped field
+            while lines.peek().map(|l| l.starts_with(' ') || l.starts_with('\t')).unwrap_or(false) {
+                lines.next();
+            }
+        }
+        ret.push_str(util::to_crlf(lines.collect::<Vec<&str>>().join("\n").as_str()).as_str());
+        ret
+    }
+
+    // Standalone document with the headers and the html part, or the text if there is none.
+    // Scripts are dropped and the body of a full html document is taken
+    pub fn to_html(&self) -> String {
+        let mut rows = vec![
+            ("Date", self.date.map(|d| util::format_date(&d)).unwrap_or_default()),
+            ("From", self.from.to_string()),
+            ("To", join_addresses(&self.to, "")),
+        ];
+        if !self.cc.is_empty() {
+            rows.push(("Cc", join_addresses(&self.cc, "")));
+        }
+        rows.push(("Subject", self.subject.clone()));
+        if !self.attachments.is_empty() {
+            rows.push(("Attachments", self.attachments.join(", ")));
+        }
+        let body = if self.html.is_empty() {
+            format!("<pre style=\"white-space: pre-wrap\">{}</pre>", util::escape_html(self.text.as_str()))
+        } else {
+            let lower = self.html.to_ascii_lowercase();
+            let start = lower.find("<body").and_then(|i| lower[i..].find('>').map(|j| i + j + 1)).unwrap_or(0);
+            let end = lower.rfind("</body>").filter(|e| *e >= start).unwrap_or(self.html.len());
+            let mut body = self.html[start..end].to_string();
+            while let Some(i) = body.to_ascii_lowercase().find("<script") {
+                let close = body.to_ascii_lowercase()[i..].find("</script>").map(|j| i + j + 9).unwrap_or(body.len());
+                body.replace_range(i..close, "");
+            }
+            body
+        };
+        let mut ret = format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n</head>\n<body>\n<table style=\"font-family: sans-serif; margin-bottom: 1em\">\n", util::escape_html(self.subject.as_str()));
+        for (name, value) in rows.iter() {
+            ret.push_str(format!("<tr><th style=\"text-align: right; padding-right: 1em\">{}:</th><td>{}</td></tr>\n", name, util::escape_html(value.as_str())).as_str());
+        }
+        ret.push_str("</table>\n<hr>\n");
+        ret.push_str(body.as_str());
+        ret.push_str("\n</body>\n</html>\n");
+        ret
+    }
+
+    // PGP/MIME signature if there is one, inline signed text otherwise
+    pub fn verify_signature(&self, keyring: &Keyring) -> Option<io::Result<Verification>> {
+        // Signed and encrypted mails carry the signature inside the encrypted payload
+        let (head, body) = mime_decode::split_message(self.decrypted.as_ref().unwrap_or(&self.raw).as_str());
+        if let Some((signed, signature)) = mime_decode::find_pgp_signed(&Headers::parse(head), body) {
+            return Some(keyring.verify(signed.as_bytes(), Some(&signature), self.from.get_address().as_str()));
+        }
+        let inline = mime_decode::find_inline_signed(self.text.as_str())?;
+        Some(keyring.verify(inline.as_bytes(), None, self.from.get_address().as_str()))
+    }
+
+    pub fn verify_smime(&self, ca_file: Option<&str>) -> Option<io::Result<smime::Signature>> {
+        let entity = mime_decode::find_smime_signed(self.decrypted.as_ref().unwrap_or(&self.raw).as_str())?;
+        Some(smime::verify(entity, ca_file))
+    }
+
+    // Like print_all, with a banner for signed mails
+    pub fn print_verified(&self, keyring: &Keyring, ca_file: Option<&str>) {
+        let verification = self.verify_signature(keyring);
+        let smime = self.verify_smime(ca_file);
+        let dkim = dkim::verify(self.raw.as_str());
+        if output::is_json() {
+            let mut val = self.to_json();
+            val["signature"] = match &verification {
+                Some(Ok(v)) => json!({ "status": v.status(), "signer": v.describe() }),
+                Some(Err(e)) => json!({ "status": "error", "error": e.to_string() }),
+                None => Value::Null,
+            };
+            val["smime"] = match &smime {
+                Some(Ok(s)) => json!({ "status": s.status(), "signer": s.signer, "trusted": s.trusted, "reason": s.reason }),
+                Some(Err(e)) => json!({ "status": "error", "error": e.to_string() }),
+                None => Value::Null,
+            };
+            val["dkim"] = Value::Array(dkim.iter().map(|r| json!({ "domain": r.domain, "status": r.status, "pass": r.pass, "reason": r.reason, "signed_length": r.signed_length })).collect());
+            output::print_json(&val);
+            return;
+        }
+        if self.decrypted.is_some() {
+            println!("{}", console::Style::new().bold().cyan().apply_to("Decrypted message"));
+        }
+        match verification {
+            Some(Ok(v)) => {
+                let style = match v {
+                    Verification::Good(_) => console::Style::new().bold().green(),
+                    Verification::Bad(_) => console::Style::new().bold().red(),
+                    Verification::Unknown(_) => console::Style::new().bold().yellow(),
+                    Verification::OtherKey(_) => console::Style::new().bold().red(),
+                };
+                println!("{}", style.apply_to(v.describe()));
+            },
+            Some(Err(e)) => println!("{}", console::Style::new().bold().yellow().apply_to(format!("Could not verify signature! [{}]", e))),
+            None => {},
+        }
+        match smime {
+            Some(Ok(s)) => {
+                let style = match s.status() {
+                    "good" => console::Style::new().bold().green(),
+                    "bad" => console::Style::new().bold().red(),
+                    _ => console::Style::new().bold().yellow(),
+                };
+                println!("{}", style.apply_to(s.describe()));
+            },
+            Some(Err(e)) => println!("{}", console::Style::new().bold().yellow().apply_to(format!("Could not verify S/MIME signature! [{}]", e))),
+            None => {},
+        }
+        if !dkim.is_empty() {
+            let results: Vec<String> = dkim.iter().map(|r| {
+                let style = match r.status {
+                    "pass" if r.signed_length.is_none() => console::Style::new().green(),
+                    "pass" | "temperror" => console::Style::new().yellow(),
+                    _ => console::Style::new().red(),
+                };
+                style.apply_to(r.describe()).to_string()
+            }).collect();
+            println!("DKIM:\t{}", results.join(", "));
+        }
+        self.print_all();
+    }
+
+    // Prints lines of the displayed body containing the text, with one line of context.
+    // Long bodies are opened in the pager instead, positioned at the first match.
+    pub fn find(&self, pattern: &str) {
+        let body = if !self.text.is_empty() { &self.text } else { &self.html };
+        let needle = pattern.to_lowercase();
+        let lines: Vec<&str> = body.lines().collect();
+        let matches: Vec<usize> = lines.iter().enumerate().filter(|(_, l)| l.to_lowercase().contains(needle.as_str())).map(|(i, _)| i).collect();
+        if matches.is_empty() {
+            println!("\"{}\" not found in mail!", pattern);
+            return;
+        }
+        if output::is_json() {
+            let val: Vec<Value> = matches.iter().map(|i| json!({ "line": i + 1, "text": lines[*i] })).collect();
+            output::print_json(&Value::Array(val));
+            return;
+        }
+        if util::page_at(body.as_str(), Some(pattern)) {
+            return;
+        }
+        let highlight = console::Style::new().reverse();
+        let mut last: Option<usize> = None;
+        for index in matches.iter() {
+            let from = index.saturating_sub(1);
+            let to = (index + 1).min(lines.len() - 1);
+            if let Some(last) = last {
+                if from > last + 1 {
+                    println!("--");
+                }
+            }
+            for i in from.max(last.map(|l| l + 1).unwrap_or(0))..=to {
+                let line = lines[i];
+                let shown = if matches.contains(&i) {
+                    // Mark every occurrence, keeping the original case
+                    let lower = line.to_lowercase();
+                    let mut marked = String::new();
+                    let mut pos = 0;
+                    while let Some(found) = lower[pos..].find(needle.as_str()).map(|f| f + pos) {
+                        if !line.is_char_boundary(found) || !line.is_char_boundary(found + needle.len()) {
+                            break;
+                        }
+                        marked.push_str(&line[pos..found]);
+                        marked.push_str(output::emphasize(&line[found..found + needle.len()], &highlight).as_str());
+                        pos = found + needle.len();
+                    }
+                    marked.push_str(&line[pos..]);
+                    marked
+                } else {
+                    line.to_string()
+                };
+                if output::is_plain() {
+                    println!("{} {}: {}", if matches.contains(&i) { "Match in line" } else { "Line" }, i + 1, shown);
+                } else {
+                    println!("{:>4}{} {}", i + 1, if matches.contains(&i) { ":" } else { "-" }, shown);
+                }
+            }
+            last = Some(to);
+        }
+        println!("{} matching line{}", matches.len(), if matches.len() != 1 { "s" } else { "" });
+    }
+
+    // Links of the displayed body, numbered like in print_all
+    pub fn get_links(&self) -> Vec<String> {
+        if !self.text.is_empty() {
+            util::extract_urls(self.text.as_str())
+        } else {
+            util::extract_urls(self.html.as_str())
+        }
+    }
+
+    // Answers to Reply-To if given, otherwise to the sender. Sent from whichever
+    // of the own addresses the mail was addressed to
+    pub fn get_list_post(&self) -> Option<String> {
+        list_post(&self.headers)
+    }
+
+    // Reply addressed to the mailing list instead of the sender
+    pub fn create_list_reply(&self, style: &QuoteStyle, own_addresses: &Vec<String>) -> Option<MailBuilder> {
+        let list = self.get_list_post()?;
+        let mut builder = self.create_reply(style, own_addresses);
+        builder.to(vec![list]);
+        Some(builder)
+    }
+
+    // New mail with the recipients, subject and text of this one, sent from the same address if it is ours
+    pub fn create_copy(&self, own_addresses: &Vec<String>) -> MailBuilder {
+        let list = |addresses: &Vec<AddressAlias>| addresses.iter().map(|a| a.to_string()).collect::<Vec<String>>();
+        let from = self.from.get_address();
+        let sender = own_addresses.iter().find(|own| own.eq_ignore_ascii_case(from.as_str())).or(own_addresses.first()).cloned().unwrap_or(from);
         let mut builder = MailBuilder::new();
-        builder.to(vec![self.from.get_address()])
-            .from(self.to.get_address())
+        builder.from(sender)
+            .to(list(&self.to))
+            .subject(self.subject.clone())
+            .text(self.get_body().clone());
+        if !self.cc.is_empty() {
+            builder.cc(list(&self.cc));
+        }
+        if !self.bcc.is_empty() {
+            builder.bcc(list(&self.bcc));
+        }
+        builder
+    }
+
+    pub fn create_reply(&self, style: &QuoteStyle, own_addresses: &Vec<String>) -> MailBuilder {
+        let recipients = if self.reply_to.is_empty() { vec![self.from.to_string()] } else { self.reply_to.iter().map(|a| a.to_string()).collect() };
+        let sender = self.to.iter().chain(self.cc.iter())
+            .map(|a| a.get_address())
+            .find(|a| own_addresses.iter().any(|own| own.eq_ignore_ascii_case(a)))
+            .or(own_addresses.first().cloned())
+            .or(self.to.first().map(|a| a.get_address()))
+            .unwrap_or_default();
+        let mut builder = MailBuilder::new();
+        builder.to(recipients)
+            .from(sender)
             .subject(format!("Re: {}", self.subject.as_str()));
+        if style.includes_original() && !self.text.is_empty() {
+            let date = self.date.as_ref().map(|d| util::format_date(d)).unwrap_or(String::from("<date>"));
+            let attribution = style.get_attribution()
+                .replace("{date}", date.as_str())
+                .replace("{from}", self.from.to_string().as_str())
+                .replace("{subject}", self.subject.as_str());
+            let prefix = style.get_prefix();
+            // Nested quotes are not padded again, so "> > " stays ">> "
+            let nested = prefix.trim_end();
+            let text = if style.strips_signature() { util::strip_signature(self.text.as_str(), self.flowed) } else { self.text.as_str() };
+            let quoted: Vec<String> = text.lines().map(|l| {
+                if !nested.is_empty() && l.starts_with(nested) { format!("{}{}", nested, l) } else { format!("{}{}", prefix, l) }
+            }).collect();
+            builder.quote(format!("{}\n{}", attribution, quoted.join("\n")), style.is_top_posting());
+        }
+        // Greeting of the reply template addresses the sender by name, the address without one
+        let name = self.from.get_name().unwrap_or(self.from.get_address().split('@').next().unwrap_or("").to_string());
+        let first_name = name.split_whitespace().next().unwrap_or("").to_string();
+        let greeting = style.get_greeting().map(|g| g.replace("{first_name}", first_name.as_str()).replace("{name}", name.as_str()));
+        builder.frame(greeting, style.get_sign_off());
 
         return builder;
     }
 }
 
-fn display_info_from(date: &Option<OffsetDateTime>, from: &String, subject: &String) -> String {
-    format!("{} |  {} |  {}", util::fit_string_to_size(&date.map(|x| util::format_date(&x)).unwrap_or(String::from("<date>")), 20), util::fit_string_to_size(from, 60), util::fit_string_to_size(subject, 100))
+// Posting address of a mailing list from List-Post (RFC 2369), lists that don't take posts say "NO"
+fn list_post(headers: &Headers) -> Option<String> {
+    let value = headers.get("List-Post")?;
+    let start = (0..value.len()).find(|&i| value.get(i..i + 8).map(|s| s.eq_ignore_ascii_case("<mailto:")).unwrap_or(false))? + 8;
+    let end = value[start..].find(|c| c == '>' || c == '?').map(|i| start + i)?;
+    Some(value[start..end].trim().to_string()).filter(|a| a.contains('@'))
 }
 
-pub enum InboxAdapter {
-    Pop3(Pop3Account),
-    Imap(ImapAccount),
+fn display_info_from(date: &Option<DateTime<FixedOffset>>, from: &String, subject: &String) -> String {
+    if output::is_plain() {
+        return format!("Date: {}, From: {}, Subject: {}", date.map(|x| util::format_date(&x)).unwrap_or(String::from("none")), from, subject);
+    }
+    let (date_width, from_width, subject_width) = util::listing_widths();
+    format!("{} |  {} |  {}", util::fit_string_to_size(&date.map(|x| util::format_date(&x)).unwrap_or(String::from("<date>")), date_width), util::fit_string_to_size(from, from_width), util::fit_string_to_size(subject, subject_width).trim_end())
+}
+
+// Opened inbox of any backend
+pub struct InboxAdapter {
+    inbox: Box<dyn MailInbox + Send>,
+}
+
+// Opens the inbox described by a config, backends are looked up by the protocol name
+pub type BackendFactory = fn(&InboxConfig) -> MailResult<InboxAdapter>;
+
+const BUILTIN_BACKENDS: &'static [(&'static str, BackendFactory)] = &[
+    ("pop3", open_network::<Pop3Account>),
+    ("imap", open_network::<ImapAccount>),
+    ("graph", graph::open),
+];
+
+fn find_backend(protocol: &str) -> Option<BackendFactory> {
+    BUILTIN_BACKENDS.iter().find(|(name, _)| name.eq_ignore_ascii_case(protocol)).map(|(_, f)| *f)
+}
+
+pub fn is_backend(protocol: &str) -> bool {
+    find_backend(protocol).is_some()
+}
+
+pub fn backend_names() -> Vec<&'static str> {
+    BUILTIN_BACKENDS.iter().map(|(name, _)| *name).collect()
+}
+
+// Network protocols connect with implicit TLS to host and port of the config
+fn open_network<T: StreamInbox + Send + 'static>(config: &InboxConfig) -> MailResult<InboxAdapter> {
+    let (domain, port) = match config {
+        InboxConfig::Pop3(domain, port) | InboxConfig::Imap(domain, port) => (domain.as_str(), *port),
+        InboxConfig::Other(protocol, _) => return Err(MailError::Server(format!("{} is not a network protocol", protocol))),
+    };
+    let stream = tls_stream(domain, port).map_err(|source| MailError::Connect { host: domain.to_string(), source })?;
+    InboxAdapter::over::<T>(domain, stream)
 }
 
 impl InboxAdapter {
-    pub fn connect(config: &InboxConfig) -> std::io::Result<InboxAdapter> {
-        match config {
-            InboxConfig::Pop3(domain, port) => {
-                let con = Pop3Account::connect(domain, *port)?;
-                Ok(InboxAdapter::Pop3(con))
-            },
-            InboxConfig::Imap(domain, port) => {
-                let con = ImapAccount::connect(domain, *port)?;
-                Ok(InboxAdapter::Imap(con))
-            }
-        }
+    pub fn new(inbox: Box<dyn MailInbox + Send>) -> InboxAdapter {
+        InboxAdapter { inbox }
     }
 
-    pub fn login(&mut self, username: &String, password: &String) -> bool {
-        match self {
-            InboxAdapter::Pop3(pop3) => pop3.login(username, password),
-            InboxAdapter::Imap(imap) => imap.login(username, password),
+    pub fn open(config: &InboxConfig) -> MailResult<InboxAdapter> {
+        match find_backend(config.protocol()) {
+            Some(factory) => factory(config),
+            None => Err(MailError::NotFound(format!("backend \"{}\"", config.protocol()))),
         }
     }
 
-    pub fn load_inbox(&mut self) -> Option<Vec<ReceivedMailHeader>> {
-        match self {
-            InboxAdapter::Pop3(pop3) => pop3.load_inbox(),
-            InboxAdapter::Imap(imap) => imap.load_inbox(),
-        }
+    // Speaks the protocol over an already opened stream, like a connection to a mock server
+    pub fn over<T: StreamInbox + Send + 'static>(host: &str, stream: Box<dyn MailStream>) -> MailResult<InboxAdapter> {
+        let inbox = T::from_stream(stream).map_err(|source| MailError::Connect { host: host.to_string(), source })?;
+        Ok(InboxAdapter::new(Box::new(inbox)))
     }
 
-    pub fn get_mail(&mut self, header: &ReceivedMailHeader) -> Option<ReceivedMail> {
-        match self {
-            InboxAdapter::Pop3(pop3) => pop3.get_mail(header),
-            InboxAdapter::Imap(imap) => imap.get_mail(header),
-        }
+    pub fn login(&mut self, username: &String, password: &String) -> MailResult<()> {
+        self.inbox.login(username, password)
+    }
+
+    pub fn load_inbox(&mut self) -> MailResult<Vec<ReceivedMailHeader>> {
+        self.inbox.load_inbox()
+    }
+
+    pub fn get_mail(&mut self, header: &ReceivedMailHeader) -> MailResult<ReceivedMail> {
+        self.inbox.get_mail(header)
+    }
+
+    pub fn list_folders(&mut self) -> MailResult<Vec<String>> {
+        self.inbox.list_folders()
+    }
+
+    pub fn list_subscribed(&mut self) -> MailResult<Vec<String>> {
+        self.inbox.list_subscribed()
+    }
+
+    pub fn subscribe(&mut self, folder: &str, subscribe: bool) -> MailResult<()> {
+        self.inbox.subscribe(folder, subscribe)
+    }
+
+    pub fn delete(&mut self, ids: &Vec<u32>) -> MailResult<()> {
+        self.inbox.delete(ids)
+    }
+
+    pub fn move_to(&mut self, ids: &Vec<u32>, folder: &str) -> MailResult<()> {
+        self.inbox.move_to(ids, folder)
+    }
+
+    pub fn set_flag(&mut self, ids: &Vec<u32>, flag: &str, set: bool) -> MailResult<()> {
+        self.inbox.set_flag(ids, flag, set)
+    }
+
+    pub fn undelete(&mut self, ids: &Vec<u32>) -> MailResult<()> {
+        self.inbox.undelete(ids)
+    }
+
+    pub fn remove_copies(&mut self, folder: &str, message_ids: &Vec<String>) -> MailResult<()> {
+        self.inbox.remove_copies(folder, message_ids)
+    }
+
+    pub fn move_back(&mut self, folder: &str, ids: &Vec<u32>, message_ids: &Vec<String>) -> MailResult<bool> {
+        self.inbox.move_back(folder, ids, message_ids)
+    }
+
+    pub fn expunge(&mut self) -> MailResult<()> {
+        self.inbox.expunge()
+    }
+
+    pub fn allow_plain_expunge(&mut self) {
+        self.inbox.allow_plain_expunge()
+    }
+
+    pub fn load_folder(&mut self, folder: &str) -> MailResult<Vec<ReceivedMailHeader>> {
+        self.inbox.load_folder(folder)
+    }
+
+    pub fn set_folder_flag(&mut self, folder: &str, ids: &Vec<u32>, flag: &str, set: bool) -> MailResult<()> {
+        self.inbox.set_folder_flag(folder, ids, flag, set)
+    }
+
+    pub fn restore(&mut self, folder: &str, ids: &Vec<u32>) -> MailResult<()> {
+        self.inbox.restore(folder, ids)
+    }
+
+    pub fn empty_folder(&mut self, folder: &str) -> MailResult<usize> {
+        self.inbox.empty_folder(folder)
     }
 }
 
+// Any stream an adapter can run over, tls connections in production and plain sockets in tests
+pub trait MailStream: Read + Write + Send {}
+
+impl<S: Read + Write + Send> MailStream for S {}
+
+fn other_err<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+// Implicit TLS connection to the server
+fn tls_stream(domain: &str, port: u16) -> io::Result<Box<dyn MailStream>> {
+    let tls = TlsConnector::new().map_err(other_err)?;
+    log::info!("connecting to {}:{}", domain, port);
+    let tcp = TcpStream::connect((domain, port))?;
+    let stream = tls.connect(domain, tcp).map_err(other_err)?;
+    Ok(Box::new(stream))
+}
+
 pub trait MailInbox {
-    fn connect(domain: &String, port: u16) -> std::io::Result<Self> where Self: Sized;
+    fn login(&mut self, username: &String, password: &String) -> MailResult<()>;
+
+    fn load_inbox(&mut self) -> MailResult<Vec<ReceivedMailHeader>>;
+
+    fn get_mail(&mut self, header: &ReceivedMailHeader) -> MailResult<ReceivedMail>;
 
-    fn login(&mut self, username: &String, password: &String) -> bool;
+    fn list_folders(&mut self) -> MailResult<Vec<String>>;
 
-    fn load_inbox(&mut self) -> Option<Vec<ReceivedMailHeader>>;
+    // Folders the user subscribed to, others clients hide the rest
+    fn list_subscribed(&mut self) -> MailResult<Vec<String>>;
 
-    fn get_mail(&mut self, header: &ReceivedMailHeader) -> Option<ReceivedMail>;
+    fn subscribe(&mut self, folder: &str, subscribe: bool) -> MailResult<()>;
+
+    // Marks mails as deleted, they are removed on expunge
+    fn delete(&mut self, ids: &Vec<u32>) -> MailResult<()>;
+
+    fn undelete(&mut self, ids: &Vec<u32>) -> MailResult<()>;
+
+    fn move_to(&mut self, ids: &Vec<u32>, folder: &str) -> MailResult<()>;
+
+    // Removes mails copied into a folder, found by their message id
+    fn remove_copies(&mut self, folder: &str, message_ids: &Vec<String>) -> MailResult<()>;
+
+    // Reverses move_to, the mails are found in the folder by their message id. Returns whether
+    // they kept their ids in the inbox
+    fn move_back(&mut self, folder: &str, ids: &Vec<u32>, message_ids: &Vec<String>) -> MailResult<bool> {
+        self.remove_copies(folder, message_ids)?;
+        self.undelete(ids)?;
+        Ok(true)
+    }
+
+    fn set_flag(&mut self, ids: &Vec<u32>, flag: &str, set: bool) -> MailResult<()>;
+
+    // Removes the mails this session marked as deleted for good
+    fn expunge(&mut self) -> MailResult<()>;
+
+    // Lets expunge remove all mails marked as deleted where the server can't expunge single ones
+    fn allow_plain_expunge(&mut self) {}
+
+    // Headers of the mails in another folder, like the trash
+    fn load_folder(&mut self, folder: &str) -> MailResult<Vec<ReceivedMailHeader>>;
+
+    // Like set_flag, for mails of another folder
+    fn set_folder_flag(&mut self, folder: &str, ids: &Vec<u32>, flag: &str, set: bool) -> MailResult<()>;
+
+    // Moves mails of a folder back to the inbox
+    fn restore(&mut self, folder: &str, ids: &Vec<u32>) -> MailResult<()>;
+
+    // Removes all mails of a folder for good, returns how many there were
+    fn empty_folder(&mut self, folder: &str) -> MailResult<usize>;
 }
 
-pub struct Pop3Account {
-    stream: POP3Stream,
+// Inboxes of protocols that run over a single connection
+pub trait StreamInbox: MailInbox + Sized {
+    // Reads the greeting of the server, the stream is already connected
+    fn from_stream(stream: Box<dyn MailStream>) -> io::Result<Self>;
 }
 
-impl MailInbox for Pop3Account {
-    fn connect(domain: &String, port: u16) -> std::io::Result<Pop3Account> {
-        let connector = SslConnectorBuilder::new(SslMethod::tls()).unwrap().build();
-        let stream = POP3Stream::connect((domain.as_str(), port), Some(connector), domain.as_str())?;
-        Ok(Pop3Account {
-            stream,
-        })
+// Minimal POP3 client, only the commands the adapter needs
+struct Pop3Client {
+    stream: BufReader<TraceStream<Box<dyn MailStream>>>,
+}
+
+impl Pop3Client {
+    fn new(stream: Box<dyn MailStream>) -> Pop3Client {
+        Pop3Client {
+            stream: BufReader::new(TraceStream::new(stream, "pop3")),
+        }
     }
 
-    fn login(&mut self, username: &String, password: &String) -> bool {
-        let success = match self.stream.login(username.as_str(), password.as_str()) {
-            POP3Result::POP3Ok => true,
-            _ => false,
-        };
-        success
+    // Mails don't have to be valid utf-8
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = Vec::new();
+        if self.stream.read_until(b'\n', &mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by pop3 server"));
+        }
+        Ok(String::from_utf8_lossy(&line).trim_end_matches(|c| c == '\r' || c == '\n').to_string())
     }
 
-    fn load_inbox(&mut self) -> Option<Vec<ReceivedMailHeader>> {
-        let mut ret = None;
-        if self.stream.is_authenticated {
-            ret = match self.stream.uidl(None) {
-                POP3Result::POP3Uidl{ emails_metadata } => Some(emails_metadata.iter().map(|x| ReceivedMailHeader::new(x.message_id as u32, HashMap::new())).collect()),
-                _ => None,
+    // Returns the text after "+OK", "-ERR" replies become server errors
+    fn expect_ok(&mut self) -> MailResult<String> {
+        let line = self.read_line()?;
+        if line.starts_with("+OK") {
+            Ok(line[3..].trim().to_string())
+        } else {
+            Err(MailError::Server(line))
+        }
+    }
+
+    fn command(&mut self, cmd: &str) -> MailResult<String> {
+        let stream = self.stream.get_mut();
+        stream.write_all(cmd.as_bytes())?;
+        stream.write_all(b"\r\n")?;
+        stream.flush()?;
+        self.expect_ok()
+    }
+
+    // Lines of a multiline reply up to the terminating dot, dot stuffing is undone
+    fn read_multiline(&mut self) -> MailResult<Vec<String>> {
+        let mut ret = Vec::new();
+        loop {
+            let line = self.read_line()?;
+            if line == "." {
+                return Ok(ret);
             }
+            ret.push(if line.starts_with('.') { line[1..].to_string() } else { line });
         }
-        return ret;
     }
+}
 
-    fn get_mail(&mut self, header: &ReceivedMailHeader) -> Option<ReceivedMail> {
-        let mut ret = None;
-        if self.stream.is_authenticated {
-            match self.stream.retr(header.id as i32) {
-                // ToDo: Convert raw msg to ReceivedMail ??
-                POP3Result::POP3Message{ raw } => {},
-                _ => {}
-            };
+// Deletions only take effect when the session is closed with QUIT
+impl Drop for Pop3Client {
+    fn drop(&mut self) {
+        let _ = self.command("QUIT");
+    }
+}
+
+pub struct Pop3Account {
+    client: Pop3Client,
+    authenticated: bool,
+}
+
+impl Pop3Account {
+    fn check_authenticated(&self) -> MailResult<()> {
+        if self.authenticated {
+            Ok(())
+        } else {
+            Err(MailError::NotConnected)
         }
-        return ret;
     }
 }
 
-enum ImapConnection {
-    Client(ImapClient<TlsStream<TcpStream>>),
-    Session(ImapSession<TlsStream<TcpStream>>),
-    None,   // Only for Type Swapping
+impl StreamInbox for Pop3Account {
+    fn from_stream(stream: Box<dyn MailStream>) -> io::Result<Pop3Account> {
+        let mut client = Pop3Client::new(stream);
+        client.expect_ok().map_err(other_err)?;
+        Ok(Pop3Account {
+            client,
+            authenticated: false,
+        })
+    }
 }
 
-impl ImapConnection {
-    pub fn get_session(self, username: &str, password: &str) -> ImapConnection {
-        return match self {
-            ImapConnection::Client(client) => {
-                match client.login(username, password) {
-                    Ok(session) => return ImapConnection::Session(session),
-                    Err((e, client)) => {
-                        println!("Could not log in on Imap Client: {}", e);
-                        ImapConnection::Client(client)
-                    }
-                }
+impl MailInbox for Pop3Account {
+    fn login(&mut self, username: &String, password: &String) -> MailResult<()> {
+        if self.authenticated {
+            return Ok(());
+        }
+        let result = self.client.command(format!("USER {}", username).as_str())
+            .and_then(|_| self.client.command(format!("PASS {}", password).as_str()));
+        match result {
+            Ok(_) => {
+                self.authenticated = true;
+                Ok(())
+            },
+            Err(MailError::Server(e)) => {
+                log::warn!("pop3 login of {} rejected: {}", username, e);
+                Err(MailError::Login(username.clone()))
             },
-            ImapConnection::Session(session) => ImapConnection::Session(session),
-            ImapConnection::None => ImapConnection::None,
+            Err(e) => Err(e),
         }
     }
 
-    pub fn is_session(&self) -> bool {
-        return match self {
-            ImapConnection::Session(_) => true,
-            _ => false,
-        };
+    fn load_inbox(&mut self) -> MailResult<Vec<ReceivedMailHeader>> {
+        self.check_authenticated()?;
+        self.client.command("UIDL")?;
+        let lines = self.client.read_multiline()?;
+        Ok(lines.iter()
+            .filter_map(|l| l.split_whitespace().next().and_then(|n| n.parse::<u32>().ok()))
+            .map(|n| ReceivedMailHeader::new(n, &Headers::new()))
+            .collect())
+    }
+
+    fn get_mail(&mut self, header: &ReceivedMailHeader) -> MailResult<ReceivedMail> {
+        self.check_authenticated()?;
+        self.client.command(format!("RETR {}", header.id).as_str()).map_err(|_| MailError::Fetch(header.id))?;
+        let lines = self.client.read_multiline()?;
+        let mut content = lines.join("\r\n");
+        content.push_str("\r\n");
+        Ok(ReceivedMail::from_raw(content.as_str()))
+    }
+
+    // POP3 only knows a single inbox
+    fn list_folders(&mut self) -> MailResult<Vec<String>> {
+        Ok(vec![String::from("INBOX")])
+    }
+
+    fn list_subscribed(&mut self) -> MailResult<Vec<String>> {
+        self.list_folders()
+    }
+
+    fn subscribe(&mut self, _: &str, _: bool) -> MailResult<()> {
+        Err(MailError::Unsupported("folders"))
+    }
+
+    // Mails are removed by the server when the session ends
+    fn delete(&mut self, ids: &Vec<u32>) -> MailResult<()> {
+        self.check_authenticated()?;
+        for id in ids.iter() {
+            self.client.command(format!("DELE {}", id).as_str())
+                .map_err(|_| MailError::Server(format!("could not delete mail {}", id)))?;
+        }
+        Ok(())
+    }
+
+    // Resets all deletions of the session, POP3 can't restore single mails
+    fn undelete(&mut self, _: &Vec<u32>) -> MailResult<()> {
+        self.check_authenticated()?;
+        self.client.command("RSET").map_err(|_| MailError::Server(String::from("could not reset deletions")))?;
+        Ok(())
+    }
+
+    fn move_to(&mut self, _: &Vec<u32>, _: &str) -> MailResult<()> {
+        Err(MailError::Unsupported("moving mails"))
+    }
+
+    fn remove_copies(&mut self, _: &str, _: &Vec<String>) -> MailResult<()> {
+        Err(MailError::Unsupported("moving mails"))
+    }
+
+    fn set_flag(&mut self, _: &Vec<u32>, _: &str, _: bool) -> MailResult<()> {
+        Err(MailError::Unsupported("flagging mails"))
+    }
+
+    fn expunge(&mut self) -> MailResult<()> {
+        Ok(())
+    }
+
+    fn load_folder(&mut self, _: &str) -> MailResult<Vec<ReceivedMailHeader>> {
+        Err(MailError::Unsupported("folders"))
+    }
+
+    fn set_folder_flag(&mut self, _: &str, _: &Vec<u32>, _: &str, _: bool) -> MailResult<()> {
+        Err(MailError::Unsupported("folders"))
+    }
+
+    fn restore(&mut self, _: &str, _: &Vec<u32>) -> MailResult<()> {
+        Err(MailError::Unsupported("folders"))
+    }
+
+    fn empty_folder(&mut self, _: &str) -> MailResult<usize> {
+        Err(MailError::Unsupported("folders"))
     }
 }
 
+// Traffic goes through a TraceStream, so it can be logged with "trace on"
+type ImapStream = TraceStream<Box<dyn MailStream>>;
+
+enum ImapConnection {
+    Client(ImapClient<ImapStream>),
+    Session(ImapSession<ImapStream>),
+    None,   // Only for Type Swapping
+}
+
 pub struct ImapAccount {
     imap: ImapConnection,
+    // Inbox uids this session marked as deleted, only these are expunged
+    deleted: Vec<u32>,
+    // Capabilities of the server, asked for on first use
+    capabilities: Option<Vec<String>>,
+    // User agreed to a plain EXPUNGE on a server without UIDPLUS
+    plain_expunge: bool,
 }
 
-impl MailInbox for ImapAccount {
-    fn connect(domain: &String, port: u16) -> std::io::Result<ImapAccount> {
-        //Err(std::io::Error::from(std::io::ErrorKind::Other))
-        let tls = TlsConnector::builder().build().unwrap();
-        let client = imap::connect((domain.as_str(), port), domain, &tls).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+impl ImapAccount {
+    fn session(&mut self) -> MailResult<&mut ImapSession<ImapStream>> {
+        match &mut self.imap {
+            ImapConnection::Session(session) => Ok(session),
+            _ => Err(MailError::NotConnected),
+        }
+    }
+
+    fn has_capability(&mut self, name: &str) -> MailResult<bool> {
+        if self.capabilities.is_none() {
+            let capabilities = self.session()?.capabilities()?;
+            self.capabilities = Some(capabilities.iter().map(|c| c.to_uppercase()).collect());
+        }
+        Ok(self.capabilities.as_ref().map(|c| c.iter().any(|c| c == name)).unwrap_or(false))
+    }
+
+    // A plain EXPUNGE also removes mails other clients marked as deleted, without UIDPLUS
+    // it needs the user's consent. Checked before anything is changed on the server
+    fn check_expunge(&mut self) -> MailResult<()> {
+        if self.plain_expunge || self.has_capability("UIDPLUS")? {
+            Ok(())
+        } else {
+            Err(MailError::PlainExpunge)
+        }
+    }
+
+    // Removes the given mails of the selected folder for good
+    fn expunge_uids(&mut self, uids: &Vec<u32>) -> MailResult<()> {
+        if uids.is_empty() {
+            return Ok(());
+        }
+        self.check_expunge()?;
+        if self.has_capability("UIDPLUS")? {
+            self.session()?.uid_expunge(uid_set(uids).as_str())?;
+        } else {
+            self.session()?.expunge()?;
+        }
+        Ok(())
+    }
+}
+
+impl StreamInbox for ImapAccount {
+    fn from_stream(stream: Box<dyn MailStream>) -> io::Result<ImapAccount> {
+        let mut client = ImapClient::new(TraceStream::new(stream, "imap"));
+        client.read_greeting().map_err(other_err)?;
 
         let imap = ImapAccount {
             imap: ImapConnection::Client(client),
+            deleted: Vec::new(),
+            capabilities: None,
+            plain_expunge: false,
         };
         Ok(imap)
     }
+}
 
-    fn login(&mut self, username: &String, password: &String) -> bool {
+impl MailInbox for ImapAccount {
+    fn login(&mut self, username: &String, password: &String) -> MailResult<()> {
         let imap = std::mem::replace(&mut self.imap, ImapConnection::None);
-        self.imap = imap.get_session(username.as_str(), password.as_str());
-        self.imap.is_session()
-    }
-
-    fn load_inbox(&mut self) -> Option<Vec<ReceivedMailHeader>> {
-        if let ImapConnection::Session(session) = &mut self.imap {
-            // Select Inbox
-            return match session.select("INBOX") {
-                Ok(_) => {
-                    // Get unread mails
-                    let unread = match session.search("UNSEEN SINCE 1-Dec-2019") {
-                        Ok(val) => val.iter().map(|i| *i).collect::<Vec<u32>>(),
-                        Err(e) => {
-                            println!("Could not get unread mails: {}", e);
-                            return None;
-                        }
-                    };
-                    // Get other mails
-                    let other = match session.search("SEEN SINCE 1-Dec-2019") {
-                        Ok(val) => val.iter().map(|i| *i).collect::<Vec<u32>>(),
-                        Err(e) => {
-                            println!("Could not get other mails: {}", e);
-                            return None;
-                        }
-                    };
-
-                    // Combine to proto-mail-vec
-                    let mut mails: Vec<(u32, bool)> = unread.into_iter().map(|x| (x, true)).collect();
-                    mails.append(&mut other.into_iter().map(|x| (x, false)).collect());
-
-                    // Get mail info for each identifier
-                    let mut ret = Vec::new();
-                    for (seq, _) in mails.into_iter() {
-                        match session.fetch(format!("{}", seq).as_str(), "BODY.PEEK[HEADER]") {
-                            Ok(res) => ret.push(ReceivedMailHeader::from_fetch(seq, res)),
-                            Err(e) => {
-                                println!("Could not fetch mail: [{}]", e);
-                                return None;
-                            },
-                        }
-                    }
-                    Some(ret)
+        match imap {
+            ImapConnection::Client(client) => match client.login(username.as_str(), password.as_str()) {
+                Ok(session) => {
+                    self.imap = ImapConnection::Session(session);
+                    Ok(())
                 },
-                Err(_) => None,
-            }
-        }
-        None
-    }
-
-    fn get_mail(&mut self, header: &ReceivedMailHeader) -> Option<ReceivedMail> {
-        if let ImapConnection::Session(session) = &mut self.imap {
-            // Select Inbox
-            println!("Session open!");
-            return match session.select("INBOX") {
-                Ok(_) => {
-                    // Fetch mail with specified identifier
-                    println!("Inbox selected!");
-                    match session.fetch(format!("{}", header.id).as_str(), "BODY[TEXT]") {
-                        Ok(res) => {
-                            println!("Fetched mail!");
-                            // Append Text
-                            if let Some(fetch) = res.get(0) {
-                                println!("Got fetch!");
-                                if let Some(bytes) = fetch.text() {
-                                    println!("Got text!");
-                                    // ToDo: Decode bytes in MIME to valid mail
-                                    let content = String::from_utf8(bytes.to_vec()).unwrap();
-
-                                    match content.as_str().parse::<Mime>(){
-                                        Ok(res) => {
-                                            println!("MIME Type {}/{}", res.type_().as_str(), res.subtype().as_str());
-                                            for (a, b) in res.params()
-                                            {
-                                                println!("KEY {}", a.as_str());
-                                            }
-                                        },
-                                        Err(e) => {
-                                            println!("Not a MIME message!");
-                                            println!("{}", content.as_str());
-                                        }
-                                    }
-                                }
-                            }
-                            // ToDo: Change to Some(ReceivedMail)
-                            None
-                        },
-                        Err(e) => {
-                            println!("Could not fetch mail: [{}]", e);
-                            None
-                        },
-                    }
-                },
-                Err(_) => {
-                    println!("Couldn't select inbox!");
-                    None
+                Err((e, client)) => {
+                    log::warn!("imap login of {} rejected: {}", username, e);
+                    self.imap = ImapConnection::Client(client);
+                    Err(MailError::Login(username.clone()))
                 },
-            }
+            },
+            ImapConnection::Session(session) => {
+                self.imap = ImapConnection::Session(session);
+                Ok(())
+            },
+            ImapConnection::None => Err(MailError::NotConnected),
         }
-        println!("No session established!");
-        None
     }
-}
 
-fn extract_mapping(content: String) -> HashMap<String, String> {
-    let mut map = HashMap::new();
-    let mut buf_key = String::new();
-    let mut buf_val = String::new();
+    fn load_inbox(&mut self) -> MailResult<Vec<ReceivedMailHeader>> {
+        let session = self.session()?;
+        session.select("INBOX")?;
+        // Unread mails first, then the others
+        let unread: Vec<u32> = session.uid_search("UNSEEN UNDELETED SINCE 1-Dec-2019")?.into_iter().collect();
+        let other: Vec<u32> = session.uid_search("SEEN UNDELETED SINCE 1-Dec-2019")?.into_iter().collect();
 
-    let mut search_key = true;
-    let mut prev = '0';
-    for c in content.chars() {
-        if search_key {
-            if c == ':' {
-                search_key = false;
-            } else {
-                buf_key.push(c);
-            }
+        log::debug!("{} unread and {} other mails in inbox", unread.len(), other.len());
+
+        Ok(fetch_headers(session, unread.into_iter().chain(other.into_iter())))
+    }
+
+    fn get_mail(&mut self, header: &ReceivedMailHeader) -> MailResult<ReceivedMail> {
+        let session = self.session()?;
+        session.select("INBOX")?;
+        log::debug!("fetching mail {}", header.id);
+        let res = session.uid_fetch(format!("{}", header.id).as_str(), "RFC822")?;
+        res.get(0).and_then(|fetch| fetch.body())
+            .map(|bytes| ReceivedMail::from_raw(String::from_utf8_lossy(bytes).as_ref()))
+            .ok_or(MailError::Fetch(header.id))
+    }
+
+    fn list_folders(&mut self) -> MailResult<Vec<String>> {
+        let names = self.session()?.list(None, Some("*"))?;
+        Ok(names.iter().map(|n| n.name().to_string()).collect())
+    }
+
+    fn list_subscribed(&mut self) -> MailResult<Vec<String>> {
+        let names = self.session()?.lsub(None, Some("*"))?;
+        Ok(names.iter().map(|n| n.name().to_string()).collect())
+    }
+
+    fn subscribe(&mut self, folder: &str, subscribe: bool) -> MailResult<()> {
+        let session = self.session()?;
+        if subscribe {
+            session.subscribe(folder)?;
         } else {
-            // If nextline without space after -> Next Key/Value
-            if prev == '\n' && c != ' ' {
-                // Insert K/V
-                map.insert(buf_key.clone(), buf_val.trim_end().to_string());
-                buf_key.clear();
-                buf_val.clear();
-                // Switch mode
-                search_key = true;
-                buf_key.push(c);
-            } else if prev != ':' {
-                buf_val.push(c);
+            session.unsubscribe(folder)?;
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self, ids: &Vec<u32>) -> MailResult<()> {
+        self.set_flag(ids, "\\Deleted", true)?;
+        for id in ids.iter() {
+            if !self.deleted.contains(id) {
+                self.deleted.push(*id);
             }
         }
-        prev = c;
+        Ok(())
+    }
+
+    fn undelete(&mut self, ids: &Vec<u32>) -> MailResult<()> {
+        self.set_flag(ids, "\\Deleted", false)?;
+        self.deleted.retain(|id| !ids.contains(id));
+        Ok(())
+    }
+
+    fn remove_copies(&mut self, folder: &str, message_ids: &Vec<String>) -> MailResult<()> {
+        self.check_expunge()?;
+        let session = self.session()?;
+        session.select(folder)?;
+        let uids = search_message_ids(session, message_ids)?;
+        if !uids.is_empty() {
+            session.uid_store(uid_set(&uids).as_str(), "+FLAGS (\\Deleted)")?;
+        }
+        let result = self.expunge_uids(&uids);
+        self.session()?.select("INBOX")?;
+        result
+    }
+
+    // A MOVE left nothing in the inbox to undelete, the mails are moved back and get new uids
+    fn move_back(&mut self, folder: &str, ids: &Vec<u32>, message_ids: &Vec<String>) -> MailResult<bool> {
+        if !self.has_capability("MOVE")? {
+            self.remove_copies(folder, message_ids)?;
+            self.undelete(ids)?;
+            return Ok(true);
+        }
+        let session = self.session()?;
+        session.select(folder)?;
+        let result = search_message_ids(session, message_ids).and_then(|uids| match uids.is_empty() {
+            true => Ok(()),
+            false => session.uid_mv(uid_set(&uids).as_str(), "INBOX").map_err(MailError::from),
+        });
+        self.session()?.select("INBOX")?;
+        result.map(|_| false)
+    }
+
+    fn expunge(&mut self) -> MailResult<()> {
+        self.session()?.select("INBOX")?;
+        let deleted = self.deleted.clone();
+        self.expunge_uids(&deleted)?;
+        self.deleted.clear();
+        Ok(())
+    }
+
+    fn allow_plain_expunge(&mut self) {
+        self.plain_expunge = true;
+    }
+
+    // Copy and delete where the server doesn't support MOVE
+    fn move_to(&mut self, ids: &Vec<u32>, folder: &str) -> MailResult<()> {
+        self.session()?.select("INBOX")?;
+        if self.has_capability("MOVE")? {
+            self.session()?.uid_mv(uid_set(ids).as_str(), folder)?;
+            return Ok(());
+        }
+        self.session()?.uid_copy(uid_set(ids).as_str(), folder)?;
+        self.delete(ids)
+    }
+
+    fn set_flag(&mut self, ids: &Vec<u32>, flag: &str, set: bool) -> MailResult<()> {
+        let session = self.session()?;
+        session.select("INBOX")?;
+        let query = format!("{}FLAGS ({})", if set { "+" } else { "-" }, flag);
+        session.uid_store(uid_set(ids).as_str(), query.as_str())?;
+        Ok(())
+    }
+
+    fn load_folder(&mut self, folder: &str) -> MailResult<Vec<ReceivedMailHeader>> {
+        let session = self.session()?;
+        session.select(folder)?;
+        let uids: Vec<u32> = session.uid_search("UNDELETED")?.into_iter().collect();
+        let ret = fetch_headers(session, uids.into_iter());
+        session.select("INBOX")?;
+        Ok(ret)
+    }
+
+    fn set_folder_flag(&mut self, folder: &str, ids: &Vec<u32>, flag: &str, set: bool) -> MailResult<()> {
+        let session = self.session()?;
+        session.select(folder)?;
+        let query = format!("{}FLAGS ({})", if set { "+" } else { "-" }, flag);
+        let result = session.uid_store(uid_set(ids).as_str(), query.as_str());
+        session.select("INBOX")?;
+        result?;
+        Ok(())
+    }
+
+    fn restore(&mut self, folder: &str, ids: &Vec<u32>) -> MailResult<()> {
+        self.check_expunge()?;
+        let session = self.session()?;
+        session.select(folder)?;
+        session.uid_copy(uid_set(ids).as_str(), "INBOX")?;
+        session.uid_store(uid_set(ids).as_str(), "+FLAGS (\\Deleted)")?;
+        let result = self.expunge_uids(ids);
+        self.session()?.select("INBOX")?;
+        result
+    }
+
+    // Every mail of the folder goes, so a plain EXPUNGE takes nothing the user didn't ask for
+    fn empty_folder(&mut self, folder: &str) -> MailResult<usize> {
+        let session = self.session()?;
+        session.select(folder)?;
+        let uids: Vec<u32> = session.uid_search("ALL")?.into_iter().collect();
+        if !uids.is_empty() {
+            session.uid_store(uid_set(&uids).as_str(), "+FLAGS (\\Deleted)")?;
+            session.expunge()?;
+        }
+        session.select("INBOX")?;
+        Ok(uids.len())
+    }
+}
+
+// Get mail info for each identifier, uids stay valid when other mails are removed
+fn fetch_headers<I: Iterator<Item = u32>>(session: &mut ImapSession<ImapStream>, uids: I) -> Vec<ReceivedMailHeader> {
+    let mut ret = Vec::new();
+    for uid in uids {
+        // A single broken mail shouldn't hide the rest of the folder
+        match session.uid_fetch(format!("{}", uid).as_str(), "(RFC822.SIZE BODY.PEEK[HEADER])").ok().and_then(|res| ReceivedMailHeader::from_fetch(uid, res)) {
+            Some(header) => ret.push(header),
+            None => log::warn!("skipping mail {}, its header could not be fetched", uid),
+        }
+    }
+    ret
+}
+
+// Uids of the selected folder's mails with the given message ids
+fn search_message_ids(session: &mut ImapSession<ImapStream>, message_ids: &Vec<String>) -> MailResult<Vec<u32>> {
+    let mut uids = Vec::new();
+    for id in message_ids.iter() {
+        let found = session.uid_search(format!("HEADER Message-ID \"<{}>\"", id).as_str())?;
+        uids.extend(found.into_iter());
+    }
+    Ok(uids)
+}
+
+fn uid_set(ids: &Vec<u32>) -> String {
+    ids.iter().map(|id| id.to_string()).collect::<Vec<String>>().join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn list_post_address() {
+        let post = |value: &str| list_post(&Headers::parse(format!("List-Post: {}\r\n", value).as_str()));
+        assert_eq!(post("<MAILTO:list@example.org>"), Some(String::from("list@example.org")));
+        assert_eq!(post("<mailto:list@example.org?subject=help>"), Some(String::from("list@example.org")));
+        // Lowercasing changes the length of the text in front
+        assert_eq!(post("İİ <mailto:list@example.org>"), Some(String::from("list@example.org")));
+        assert_eq!(post("NO"), None);
     }
-    map.insert(buf_key, buf_val.trim_end().to_string());
-    return map;
 }
@@ -0,0 +1,137 @@
+extern crate serde_yaml;
+
+// Filtering rules stored next to the account file, run on new mails during refresh
+use std::{
+    error::Error,
+    fmt,
+    fs,
+    io,
+    path::PathBuf,
+};
+
+use serde_yaml::Value;
+
+use super::receiving::ReceivedMailHeader;
+
+const MATCH_FIELDS: &'static [&'static str] = &["from", "subject", "list_id", "larger_than", "smaller_than", "spam_score_above"];
+
+#[derive(Clone, PartialEq)]
+pub enum Action {
+    Move(String),
+    MarkRead,
+    // notmuch tag like "+work" or "-inbox"
+    Tag(String),
+    Delete,
+    Notify,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::Move(folder) => write!(f, "move to \"{}\"", folder),
+            Action::MarkRead => write!(f, "mark read"),
+            Action::Tag(tag) => write!(f, "tag {}", tag),
+            Action::Delete => write!(f, "delete"),
+            Action::Notify => write!(f, "notify"),
+        }
+    }
+}
+
+// All given conditions have to match, texts are compared case insensitive
+pub struct Rule {
+    pub name: String,
+    from: Option<String>,
+    subject: Option<String>,
+    list_id: Option<String>,
+    // Sizes in bytes, mails of unknown size never match them
+    larger_than: Option<u64>,
+    smaller_than: Option<u64>,
+    // Score of the local spam filter, unscored mails never match it
+    spam_score_above: Option<f64>,
+    pub actions: Vec<Action>,
+}
+
+impl Rule {
+    // - name: newsletters
+    //   match: { from: news@, list_id: example.org, larger_than: 100000 }
+    //   actions: [mark_read, { move: Newsletters }, { tag: +news }, notify]
+    // - name: junk
+    //   match: { spam_score_above: 0.9 }
+    //   actions: [{ move: Junk }]
+    fn from_value(val: &Value, n: usize) -> Result<Rule, String> {
+        let name = val.get("name").and_then(|v| v.as_str()).map(|s| s.to_string()).unwrap_or(format!("rule {}", n + 1));
+        let conditions = val.get("match").and_then(|v| v.as_mapping()).ok_or(format!("{}: missing `match` mapping", name))?;
+        for (key, _) in conditions.iter() {
+            let key = key.as_str().unwrap_or("?");
+            if !MATCH_FIELDS.contains(&key) {
+                return Err(format!("{}: unknown condition `{}`, use one of {}", name, key, MATCH_FIELDS.join(", ")));
+            }
+        }
+        let text = |k: &str| val["match"].get(k).and_then(|v| v.as_str()).map(|s| s.to_lowercase());
+        let size = |k: &str| val["match"].get(k).and_then(|v| v.as_u64());
+        let actions = val.get("actions").and_then(|v| v.as_sequence()).ok_or(format!("{}: missing `actions` list", name))?
+            .iter().map(|a| parse_action(a).ok_or(format!("{}: unknown action {:?}", name, a))).collect::<Result<Vec<Action>, String>>()?;
+        Ok(Rule {
+            from: text("from"),
+            subject: text("subject"),
+            list_id: text("list_id"),
+            larger_than: size("larger_than"),
+            smaller_than: size("smaller_than"),
+            spam_score_above: val["match"].get("spam_score_above").and_then(|v| v.as_f64()),
+            name,
+            actions,
+        })
+    }
+
+    pub fn matches(&self, header: &ReceivedMailHeader) -> bool {
+        let contains = |value: &str, pattern: &Option<String>| pattern.as_ref().map(|p| value.to_lowercase().contains(p.as_str())).unwrap_or(true);
+        let size = header.get_size().map(|s| s as u64);
+        contains(header.get_from().as_str(), &self.from)
+            && contains(header.get_subject().as_str(), &self.subject)
+            && (self.list_id.is_none() || header.get_list_id().map(|l| contains(l.as_str(), &self.list_id)).unwrap_or(false))
+            && self.larger_than.map(|min| size.map(|s| s > min).unwrap_or(false)).unwrap_or(true)
+            && self.smaller_than.map(|max| size.map(|s| s < max).unwrap_or(false)).unwrap_or(true)
+            && self.spam_score_above.map(|min| header.get_spam_score().map(|s| s > min).unwrap_or(false)).unwrap_or(true)
+    }
+
+    pub fn describe_actions(&self) -> String {
+        self.actions.iter().map(|a| a.to_string()).collect::<Vec<String>>().join(", ")
+    }
+}
+
+// "mark_read", "delete" and "notify" are plain words, "move" and "tag" take a value
+fn parse_action(val: &Value) -> Option<Action> {
+    if let Some(word) = val.as_str() {
+        return match word {
+            "mark_read" => Some(Action::MarkRead),
+            "delete" => Some(Action::Delete),
+            "notify" => Some(Action::Notify),
+            _ => None,
+        };
+    }
+    let (key, value) = val.as_mapping()?.iter().next()?;
+    let value = value.as_str()?.to_string();
+    match key.as_str()? {
+        "move" => Some(Action::Move(value)),
+        "tag" if value.starts_with('+') || value.starts_with('-') => Some(Action::Tag(value)),
+        "tag" => Some(Action::Tag(format!("+{}", value))),
+        _ => None,
+    }
+}
+
+// A missing file has no rules
+pub fn load(path: PathBuf) -> Result<Vec<Rule>, Box<dyn Error>> {
+    let content = match fs::read(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(Box::new(e)),
+    };
+    let root: Value = serde_yaml::from_slice(&content)?;
+    let rules = root.as_sequence().ok_or("expected a list of rules")?;
+    Ok(rules.iter().enumerate().map(|(n, r)| Rule::from_value(r, n)).collect::<Result<Vec<Rule>, String>>()?)
+}
+
+// First rule matching the mail, later rules are not tried
+pub fn first_match<'a>(rules: &'a Vec<Rule>, header: &ReceivedMailHeader) -> Option<&'a Rule> {
+    rules.iter().find(|r| r.matches(header))
+}
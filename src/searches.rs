@@ -0,0 +1,72 @@
+extern crate serde_yaml;
+
+// Filter expressions saved under a name, stored next to the account file
+use std::{
+    error::Error,
+    fs,
+    io,
+    path::PathBuf,
+};
+
+use serde_yaml::{
+    Mapping,
+    Value,
+};
+
+pub struct SavedSearches {
+    path: PathBuf,
+    searches: Vec<(String, String)>,
+}
+
+impl SavedSearches {
+    // A missing file has no searches
+    pub fn load(path: PathBuf) -> Result<SavedSearches, Box<dyn Error>> {
+        let searches = match fs::read(&path) {
+            Ok(content) => {
+                let root: Value = serde_yaml::from_slice(&content)?;
+                root.as_mapping().map(|map| map.iter()
+                    .filter_map(|(name, expression)| Some((name.as_str()?.to_string(), expression.as_str()?.to_string())))
+                    .collect()).unwrap_or(Vec::new())
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(Box::new(e)),
+        };
+        Ok(SavedSearches { path, searches })
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let mut map = Mapping::new();
+        for (name, expression) in self.searches.iter() {
+            map.insert(Value::String(name.clone()), Value::String(expression.clone()));
+        }
+        fs::write(&self.path, serde_yaml::to_vec(&Value::Mapping(map))?)?;
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&String> {
+        self.searches.iter().find(|(n, _)| n == name).map(|(_, e)| e)
+    }
+
+    // Replaces a search of the same name
+    pub fn set(&mut self, name: &str, expression: &str) {
+        match self.searches.iter_mut().find(|(n, _)| n == name) {
+            Some((_, e)) => *e = expression.to_string(),
+            None => self.searches.push((name.to_string(), expression.to_string())),
+        }
+    }
+
+    // Returns false if there is no such search
+    pub fn remove(&mut self, name: &str) -> bool {
+        let before = self.searches.len();
+        self.searches.retain(|(n, _)| n != name);
+        self.searches.len() != before
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(String, String)> {
+        self.searches.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.searches.is_empty()
+    }
+}
@@ -0,0 +1,405 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    process::{Command, Stdio},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use native_tls::TlsConnector;
+
+use super::account::{Account, InboxConfig};
+use super::graph::GraphTransport;
+use super::inbox::Mail;
+use super::receiving::MailStream;
+use super::util;
+use super::trace::TraceStream;
+
+pub const SMTPS_PORT: u16 = 465;
+// Kept connections unused for longer are closed, servers drop them after a few minutes
+const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+// When each sender may send its next message, so messages_per_minute holds across mails,
+// transports and threads
+static NEXT_SEND: Mutex<Option<HashMap<String, Instant>>> = Mutex::new(None);
+
+fn other_err<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+struct SmtpClient<S: Read + Write> {
+    stream: BufReader<S>,
+    // Server takes the envelope commands at once
+    pipelining: bool,
+}
+
+impl<S: Read + Write> SmtpClient<S> {
+    fn new(stream: S) -> SmtpClient<S> {
+        SmtpClient {
+            stream: BufReader::new(stream),
+            pipelining: false,
+        }
+    }
+
+    fn into_inner(self) -> S {
+        self.stream.into_inner()
+    }
+
+    // Reads a (possibly multiline) reply, returns code and text
+    fn read_reply(&mut self) -> io::Result<(u16, String)> {
+        let mut text = String::new();
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line)? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by smtp server"));
+            }
+            let code = line.get(..3).and_then(|c| c.parse::<u16>().ok()).ok_or(other_err(format!("invalid smtp reply \"{}\"", line.trim())))?;
+            text.push_str(line.get(4..).unwrap_or("").trim_end());
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok((code, text));
+            }
+            text.push('\n');
+        }
+    }
+
+    fn expect(&mut self, expected: &[u16]) -> io::Result<String> {
+        let (code, text) = self.read_reply()?;
+        if expected.contains(&code) {
+            Ok(text)
+        } else {
+            Err(other_err(format!("smtp server replied {} {}", code, text)))
+        }
+    }
+
+    fn command(&mut self, cmd: &str, expected: &[u16]) -> io::Result<String> {
+        let stream = self.stream.get_mut();
+        stream.write_all(cmd.as_bytes())?;
+        stream.write_all(b"\r\n")?;
+        stream.flush()?;
+        self.expect(expected)
+    }
+
+    fn ehlo(&mut self) -> io::Result<()> {
+        let text = self.command("EHLO localhost", &[250])?;
+        self.pipelining = text.lines().any(|l| l.trim().eq_ignore_ascii_case("PIPELINING"));
+        Ok(())
+    }
+
+    fn authenticate(&mut self, user: &str, password: &str) -> io::Result<()> {
+        let token = base64::encode(format!("\0{}\0{}", user, password).as_bytes());
+        self.command(format!("AUTH PLAIN {}", token).as_str(), &[235])?;
+        Ok(())
+    }
+
+    fn deliver(&mut self, from: &str, recipients: &Vec<String>, message: &str) -> io::Result<()> {
+        if self.pipelining {
+            self.envelope_pipelined(from, recipients)?;
+        } else {
+            self.command(format!("MAIL FROM:<{}>", from).as_str(), &[250])?;
+            for recipient in recipients.iter() {
+                self.command(format!("RCPT TO:<{}>", recipient).as_str(), &[250, 251])?;
+            }
+            self.command("DATA", &[354])?;
+        }
+        // Lines starting with a dot have to be escaped
+        let mut data = String::with_capacity(message.len() + 16);
+        for line in message.trim_end_matches("\r\n").split("\r\n") {
+            if line.starts_with('.') {
+                data.push('.');
+            }
+            data.push_str(line);
+            data.push_str("\r\n");
+        }
+        data.push('.');
+        self.command(data.as_str(), &[250])?;
+        Ok(())
+    }
+
+    // Sends MAIL, RCPT and DATA in one go, all replies are read so the session stays in sync
+    fn envelope_pipelined(&mut self, from: &str, recipients: &Vec<String>) -> io::Result<()> {
+        let mut commands = format!("MAIL FROM:<{}>\r\n", from);
+        for recipient in recipients.iter() {
+            commands.push_str(format!("RCPT TO:<{}>\r\n", recipient).as_str());
+        }
+        commands.push_str("DATA\r\n");
+        let stream = self.stream.get_mut();
+        stream.write_all(commands.as_bytes())?;
+        stream.flush()?;
+        let mut result = self.expect(&[250]).map(|_| ());
+        for _ in recipients.iter() {
+            result = result.and(self.expect(&[250, 251]).map(|_| ()));
+        }
+        match (result, self.read_reply()?) {
+            (Ok(_), (354, _)) => Ok(()),
+            (Ok(_), (code, text)) => Err(other_err(format!("smtp server replied {} {}", code, text))),
+            // DATA went through although the envelope was rejected. A terminating dot would deliver
+            // an empty message to the accepted recipients, the caller drops the connection instead
+            (Err(e), (354, _)) => Err(io::Error::new(io::ErrorKind::ConnectionAborted, e.to_string())),
+            (Err(e), _) => Err(e),
+        }
+    }
+}
+
+// Splits "host[:port]", uses port 465 (implicit TLS) by default
+fn split_host(domain: &str) -> (String, u16) {
+    match domain.rfind(':') {
+        Some(i) => (domain[..i].to_string(), domain[i + 1..].parse::<u16>().unwrap_or(SMTPS_PORT)),
+        None => (domain.to_string(), SMTPS_PORT),
+    }
+}
+
+// Delivers a rendered message to its recipients, the sending counterpart of MailInbox
+pub trait MailTransport {
+    fn send(&mut self, from: &str, recipients: &Vec<String>, message: &str) -> io::Result<()>;
+}
+
+pub enum TransportAdapter {
+    Smtp(SmtpTransport),
+    Sendmail(SendmailTransport),
+    Graph(GraphTransport),
+}
+
+impl TransportAdapter {
+    // Accounts with a sendmail command don't need an smtp server, graph accounts send through the api
+    pub fn for_account(account: &Account) -> TransportAdapter {
+        match (&account.sendmail, &account.inbox_domain) {
+            (Some(command), _) => TransportAdapter::Sendmail(SendmailTransport::new(command.clone())),
+            (None, InboxConfig::Other(protocol, location)) if protocol.eq_ignore_ascii_case("graph") => {
+                TransportAdapter::Graph(GraphTransport::new(location.as_str(), account.name.clone()))
+            },
+            (None, _) => {
+                let (user, password) = account.smtp_credentials();
+                TransportAdapter::Smtp(SmtpTransport::new(account.smtp_domain.as_str(), user.clone(), password.clone()))
+            },
+        }
+    }
+}
+
+impl MailTransport for TransportAdapter {
+    fn send(&mut self, from: &str, recipients: &Vec<String>, message: &str) -> io::Result<()> {
+        match self {
+            TransportAdapter::Smtp(smtp) => smtp.send(from, recipients, message),
+            TransportAdapter::Sendmail(sendmail) => sendmail.send(from, recipients, message),
+            TransportAdapter::Graph(graph) => graph.send(recipients, message).map_err(other_err),
+        }
+    }
+}
+
+pub struct SmtpTransport {
+    host: String,
+    port: u16,
+    user: String,
+    password: String,
+    // Authenticated connection kept for the next mail, with the time it was last used
+    connection: Option<(SmtpClient<TraceStream<Box<dyn MailStream>>>, Instant)>,
+}
+
+impl SmtpTransport {
+    pub fn new(domain: &str, user: String, password: String) -> SmtpTransport {
+        let (host, port) = split_host(domain);
+        SmtpTransport { host, port, user, password, connection: None }
+    }
+}
+
+impl SmtpTransport {
+    // Runs the session over an already connected stream, starting at the greeting
+    pub fn send_over<S: Read + Write>(&self, stream: S, from: &str, recipients: &Vec<String>, message: &str) -> io::Result<()> {
+        let mut client = SmtpClient::new(TraceStream::new(stream, "smtp"));
+        client.expect(&[220])?;
+        self.login(&mut client)?;
+        client.deliver(from, recipients, message)?;
+        let _ = client.command("QUIT", &[221]);
+        Ok(())
+    }
+
+    // Logs in over an already connected stream and keeps the connection for the following sends
+    pub fn connect_over(&mut self, stream: Box<dyn MailStream>) -> io::Result<()> {
+        let mut client = SmtpClient::new(TraceStream::new(stream, "smtp"));
+        client.expect(&[220])?;
+        self.login(&mut client)?;
+        self.close();
+        self.connection = Some((client, Instant::now()));
+        Ok(())
+    }
+
+    // Ends the kept connection, the next send connects again
+    pub fn close(&mut self) {
+        if let Some((mut client, _)) = self.connection.take() {
+            let _ = client.command("QUIT", &[221]);
+        }
+    }
+
+    fn login<S: Read + Write>(&self, client: &mut SmtpClient<S>) -> io::Result<()> {
+        client.ehlo()?;
+        client.authenticate(self.user.as_str(), self.password.as_str())
+    }
+
+    fn connect(&mut self) -> io::Result<()> {
+        let host = self.host.as_str();
+        log::info!("connecting to smtp server {}:{}", host, self.port);
+        let tcp = TcpStream::connect((host, self.port))?;
+        tcp.set_read_timeout(Some(Duration::from_secs(30)))?;
+        let connector = TlsConnector::new().map_err(other_err)?;
+
+        if self.port == SMTPS_PORT {
+            let tls = connector.connect(host, tcp).map_err(other_err)?;
+            return self.connect_over(Box::new(tls));
+        }
+        // Upgrade plain connection via STARTTLS
+        let mut client = SmtpClient::new(TraceStream::new(tcp, "smtp"));
+        client.expect(&[220])?;
+        client.command("EHLO localhost", &[250])?;
+        client.command("STARTTLS", &[220])?;
+        let tls = connector.connect(host, client.into_inner().into_inner()).map_err(other_err)?;
+        let mut client = SmtpClient::new(TraceStream::new(Box::new(tls) as Box<dyn MailStream>, "smtp"));
+        self.login(&mut client)?;
+        self.close();
+        self.connection = Some((client, Instant::now()));
+        Ok(())
+    }
+
+    // The kept connection if it wasn't idle for too long and still answers
+    fn reusable(&mut self) -> bool {
+        let alive = match &mut self.connection {
+            Some((client, used)) => used.elapsed() < IDLE_TIMEOUT && client.command("RSET", &[250]).is_ok(),
+            None => return false,
+        };
+        if !alive {
+            log::debug!("smtp connection timed out, reconnecting");
+            self.close();
+        }
+        alive
+    }
+}
+
+impl MailTransport for SmtpTransport {
+    fn send(&mut self, from: &str, recipients: &Vec<String>, message: &str) -> io::Result<()> {
+        if !self.reusable() {
+            self.connect()?;
+        }
+        log::info!("sending mail to {} recipients via {}:{}", recipients.len(), self.host, self.port);
+        let result = match &mut self.connection {
+            Some((client, used)) => client.deliver(from, recipients, message).map(|_| *used = Instant::now()),
+            None => Err(other_err("not connected to the smtp server")),
+        };
+        // State of the session is unknown after an error
+        if result.is_err() {
+            self.connection = None;
+        }
+        result
+    }
+}
+
+impl Drop for SmtpTransport {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+// Hands the message to a local sendmail compatible program, e.g. "/usr/sbin/sendmail" or "msmtp"
+pub struct SendmailTransport {
+    command: String,
+}
+
+impl SendmailTransport {
+    pub fn new(command: String) -> SendmailTransport {
+        SendmailTransport { command }
+    }
+}
+
+impl MailTransport for SendmailTransport {
+    fn send(&mut self, from: &str, recipients: &Vec<String>, message: &str) -> io::Result<()> {
+        let mut parts = self.command.split_whitespace();
+        let program = parts.next().ok_or(other_err("sendmail command is empty"))?;
+        log::info!("sending mail to {} recipients via {}", recipients.len(), program);
+        // "-i" keeps lone dots from ending the message
+        let mut child = Command::new(program)
+            .args(parts)
+            .arg("-i")
+            .arg("-f").arg(from)
+            .arg("--")
+            .args(recipients.iter())
+            .stdin(Stdio::piped())
+            .spawn()?;
+        {
+            let stdin = child.stdin.as_mut().ok_or(other_err("could not write to sendmail"))?;
+            stdin.write_all(message.replace("\r\n", "\n").as_bytes())?;
+        }
+        let status = child.wait()?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(other_err(format!("{} exited with {}", program, status)))
+        }
+    }
+}
+
+// Provider limits of an account, unset fields don't limit
+#[derive(Clone, PartialEq, Default)]
+pub struct RateLimit {
+    pub messages_per_minute: Option<u32>,
+    pub recipients_per_message: Option<u32>,
+}
+
+impl RateLimit {
+    pub fn from_value(val: &serde_yaml::Value) -> RateLimit {
+        let get = |k: &str| val.get(k).and_then(|v| v.as_u64()).filter(|n| *n > 0).map(|n| n as u32);
+        RateLimit {
+            messages_per_minute: get("messages_per_minute"),
+            recipients_per_message: get("recipients_per_message"),
+        }
+    }
+
+    pub fn to_value(&self) -> serde_yaml::Value {
+        let mut map = serde_yaml::Mapping::new();
+        if let Some(n) = self.messages_per_minute {
+            map.insert(serde_yaml::Value::String(String::from("messages_per_minute")), serde_yaml::Value::Number(n.into()));
+        }
+        if let Some(n) = self.recipients_per_message {
+            map.insert(serde_yaml::Value::String(String::from("recipients_per_message")), serde_yaml::Value::Number(n.into()));
+        }
+        serde_yaml::Value::Mapping(map)
+    }
+}
+
+// Waits until the sender may send again within the limit, the slot is taken before waiting so
+// concurrent sends queue up behind each other
+fn pace(from: &str, interval: Option<Duration>) {
+    let interval = match interval {
+        Some(interval) => interval,
+        None => return,
+    };
+    let wait = {
+        let mut next_send = NEXT_SEND.lock().unwrap();
+        let next_send = next_send.get_or_insert_with(HashMap::new);
+        let now = Instant::now();
+        let slot = next_send.get(&from.to_lowercase()).cloned().filter(|t| *t > now).unwrap_or(now);
+        next_send.insert(from.to_lowercase(), slot + interval);
+        slot - now
+    };
+    std::thread::sleep(wait);
+}
+
+// Sends the message in batches of recipients within the limits, each batch waits for its slot.
+// Recipients in `done` are skipped and the ones sent to are added, so a failed send can be
+// repeated without anybody getting the message twice. Progress gets (sent, total) after each batch
+pub fn send_batches<T: MailTransport + ?Sized>(transport: &mut T, from: &str, recipients: &Vec<String>, message: &str, limit: &RateLimit, done: &mut Vec<String>, progress: &mut dyn FnMut(usize, usize)) -> io::Result<()> {
+    let pending: Vec<String> = recipients.iter().filter(|r| !done.iter().any(|d| d.eq_ignore_ascii_case(r))).cloned().collect();
+    let size = limit.recipients_per_message.map(|n| n as usize).unwrap_or(pending.len()).max(1);
+    let interval = limit.messages_per_minute.map(|n| Duration::from_secs(60) / n);
+    let total = recipients.len();
+    for (n, batch) in pending.chunks(size).enumerate() {
+        pace(from, interval);
+        transport.send(from, &batch.to_vec(), message)?;
+        done.extend(batch.iter().cloned());
+        progress(total - pending.len() + (n * size + batch.len()), total);
+    }
+    Ok(())
+}
+
+// Sends the mail with any transport, Bcc recipients get it without appearing in the message
+pub fn send_mail<T: MailTransport + ?Sized>(transport: &mut T, mail: &Mail, limit: &RateLimit, done: &mut Vec<String>, progress: &mut dyn FnMut(usize, usize)) -> io::Result<()> {
+    let from = util::extract_address(&mail.from);
+    send_batches(transport, from.as_str(), &mail.recipients(), mail.to_message().as_str(), limit, done, progress)
+}
@@ -0,0 +1,91 @@
+// S/MIME signatures, checked by the openssl command against the system store and an optional CA file
+use std::{
+    io,
+    path::Path,
+    process::{Command, Output},
+};
+
+use super::util;
+
+pub struct Signature {
+    // Subject and address of the signing certificate
+    pub signer: Option<String>,
+    // Content was not changed after signing
+    pub intact: bool,
+    // Certificate chain ends at a trusted CA
+    pub trusted: bool,
+    pub reason: Option<String>,
+}
+
+impl Signature {
+    pub fn describe(&self) -> String {
+        let signer = self.signer.clone().unwrap_or(String::from("unknown signer"));
+        match (self.intact, self.trusted) {
+            (true, true) => format!("Good S/MIME signature from {}", signer),
+            (true, false) => format!("Good S/MIME signature from {}, certificate not trusted [{}]", signer, self.reason.clone().unwrap_or_default()),
+            (false, _) => format!("Bad S/MIME signature from {}", signer),
+        }
+    }
+
+    pub fn status(&self) -> &'static str {
+        match (self.intact, self.trusted) {
+            (true, true) => "good",
+            (true, false) => "untrusted",
+            (false, _) => "bad",
+        }
+    }
+}
+
+fn openssl(args: &[&str]) -> io::Result<Output> {
+    log::debug!("running openssl {}", args.join(" "));
+    Command::new("openssl").args(args).output()
+}
+
+fn last_line(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).trim().lines().last().unwrap_or("").to_string()
+}
+
+// Verifies a multipart/signed or opaque signed MIME entity
+pub fn verify(entity: &str, ca_file: Option<&str>) -> io::Result<Signature> {
+    let message = util::temp_file("eml", entity.as_bytes())?;
+    // openssl writes the certificate of the signer into the empty file
+    let signer = util::temp_file("pem", b"")?;
+    check(message.path(), signer.path(), ca_file)
+}
+
+fn check(message: &Path, signer: &Path, ca_file: Option<&str>) -> io::Result<Signature> {
+    let message = message.to_string_lossy();
+    let signer = signer.to_string_lossy();
+    let mut args = vec!["smime", "-verify", "-in", message.as_ref(), "-signer", signer.as_ref()];
+    if let Some(ca_file) = ca_file {
+        args.push("-CAfile");
+        args.push(ca_file);
+    }
+    let output = openssl(&args)?;
+    let trusted = output.status.success();
+    let (intact, reason) = if trusted {
+        (true, None)
+    } else {
+        // Checked again without the chain, to tell a changed mail from an unknown issuer
+        args.push("-noverify");
+        (openssl(&args)?.status.success(), Some(last_line(&output.stderr)))
+    };
+    Ok(Signature { signer: subject(signer.as_ref()), intact, trusted, reason })
+}
+
+// "subject=CN = Jane Doe, O = Example" followed by the addresses of the certificate
+fn subject(certificate: &str) -> Option<String> {
+    let output = openssl(&["x509", "-in", certificate, "-noout", "-subject", "-email"]).ok().filter(|o| o.status.success())?;
+    let text = String::from_utf8_lossy(&output.stdout).to_string();
+    let mut lines = text.lines();
+    let subject = lines.next()?.trim_start_matches("subject=").trim();
+    // The common name reads best, the full subject is used without one
+    let name = subject.split(|c| c == ',' || c == '/')
+        .map(|p| p.trim())
+        .find_map(|p| p.strip_prefix("CN").map(|v| v.trim_start().trim_start_matches('=').trim()))
+        .unwrap_or(subject);
+    Some(match lines.next().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+        Some(email) => format!("{} <{}>", name, email),
+        None => name.to_string(),
+    })
+}
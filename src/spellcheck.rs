@@ -0,0 +1,92 @@
+use std::{
+    io::{self, Write},
+    process::{Command, Stdio},
+};
+
+use console::Style;
+
+use super::output;
+
+pub struct Misspelling {
+    pub word: String,
+    pub line: usize,
+    pub suggestions: Vec<String>,
+}
+
+// Checks the text with hunspell in ispell pipe mode
+pub fn check(text: &str, language: Option<&String>) -> io::Result<Vec<Misspelling>> {
+    let mut command = Command::new("hunspell");
+    command.arg("-a");
+    if let Some(lang) = language {
+        command.arg("-d").arg(lang.as_str());
+    }
+    let mut child = command.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::null()).spawn()?;
+    {
+        let stdin = child.stdin.as_mut().ok_or(io::Error::new(io::ErrorKind::Other, "could not write to hunspell"))?;
+        for line in text.lines() {
+            // "^" keeps lines from being read as pipe commands
+            writeln!(stdin, "^{}", line)?;
+        }
+    }
+    let result = child.wait_with_output()?;
+    let output = String::from_utf8_lossy(&result.stdout).to_string();
+
+    let mut ret = Vec::new();
+    // First line is the version banner, every input line is answered by a block ending with an empty line
+    let mut line = 0;
+    for answer in output.lines().skip(1) {
+        let mut tokens = answer.splitn(2, ' ');
+        match (tokens.next(), tokens.next()) {
+            // "& word count offset: suggestion, suggestion"
+            (Some("&"), Some(rest)) => {
+                let word = rest.split(' ').next().unwrap_or("").to_string();
+                let suggestions = rest.find(": ").map(|i| rest[i + 2..].split(", ").map(|s| s.to_string()).collect()).unwrap_or(Vec::new());
+                ret.push(Misspelling { word, line, suggestions });
+            },
+            // "# word offset", without suggestions
+            (Some("#"), Some(rest)) => {
+                let word = rest.split(' ').next().unwrap_or("").to_string();
+                ret.push(Misspelling { word, line, suggestions: Vec::new() });
+            },
+            (Some(""), None) => line += 1,
+            _ => {},
+        }
+    }
+    Ok(ret)
+}
+
+// Prints the lines containing suspect words highlighted, followed by suggestions
+pub fn print_report(text: &str, misspellings: &Vec<Misspelling>) {
+    if misspellings.is_empty() {
+        println!("No spelling mistakes found!");
+        return;
+    }
+    let highlight = Style::new().bold().red();
+    for (number, line) in text.lines().enumerate() {
+        let words: Vec<&Misspelling> = misspellings.iter().filter(|m| m.line == number).collect();
+        if words.is_empty() {
+            continue;
+        }
+        let marked = line.split(' ').map(|w| {
+            let bare = w.trim_matches(|c: char| !c.is_alphanumeric());
+            if !bare.is_empty() && words.iter().any(|m| m.word == bare) {
+                w.replace(bare, output::emphasize(bare, &highlight).as_str())
+            } else {
+                w.to_string()
+            }
+        }).collect::<Vec<String>>().join(" ");
+        if output::is_plain() {
+            println!("Line {}: {}", number + 1, marked);
+        } else {
+            println!("{:>4}: {}", number + 1, marked);
+        }
+    }
+    println!();
+    for misspelling in misspellings.iter() {
+        if misspelling.suggestions.is_empty() {
+            println!("\t{}", misspelling.word);
+        } else {
+            println!("\t{} -> {}", misspelling.word, misspelling.suggestions.join(", "));
+        }
+    }
+}
@@ -0,0 +1,870 @@
+// In-process IMAP, POP3 and SMTP servers for exercising the adapters without real accounts.
+// They speak just enough of each protocol for this client and keep their state in a MockStore
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use super::headers::Headers;
+use super::receiving::MailStream;
+
+pub const USER: &'static str = "tester@example.org";
+pub const PASSWORD: &'static str = "secret";
+
+#[derive(Clone)]
+pub struct MockMail {
+    pub uid: u32,
+    pub folder: String,
+    pub flags: Vec<String>,
+    pub raw: String,
+}
+
+impl MockMail {
+    fn has_flag(&self, flag: &str) -> bool {
+        self.flags.iter().any(|f| f.eq_ignore_ascii_case(flag))
+    }
+
+    fn set_flag(&mut self, flag: &str, set: bool) {
+        if set && !self.has_flag(flag) {
+            self.flags.push(flag.to_string());
+        } else if !set {
+            self.flags.retain(|f| !f.eq_ignore_ascii_case(flag));
+        }
+    }
+
+    fn header(&self) -> &str {
+        match self.raw.find("\r\n\r\n") {
+            Some(i) => &self.raw[..i + 4],
+            None => self.raw.as_str(),
+        }
+    }
+}
+
+// Mail received by the smtp server
+#[derive(Clone)]
+pub struct SentMail {
+    pub from: String,
+    pub recipients: Vec<String>,
+    pub data: String,
+}
+
+pub struct MockStore {
+    pub user: String,
+    pub password: String,
+    pub folders: Vec<String>,
+    pub mails: Vec<MockMail>,
+    pub sent: Vec<SentMail>,
+    // Recipients the smtp server refuses
+    pub rejected: Vec<String>,
+    // Extensions the imap server advertises and understands
+    pub capabilities: Vec<String>,
+    next_uid: u32,
+}
+
+impl MockStore {
+    pub fn new() -> MockStore {
+        MockStore {
+            user: String::from(USER),
+            password: String::from(PASSWORD),
+            folders: vec![String::from("INBOX")],
+            mails: Vec::new(),
+            sent: Vec::new(),
+            rejected: Vec::new(),
+            capabilities: vec![String::from("UIDPLUS"), String::from("MOVE")],
+            next_uid: 1,
+        }
+    }
+
+    pub fn add(&mut self, folder: &str, raw: &str, flags: &[&str]) -> u32 {
+        let uid = self.next_uid;
+        self.next_uid += 1;
+        if !self.folders.iter().any(|f| f == folder) {
+            self.folders.push(folder.to_string());
+        }
+        self.mails.push(MockMail {
+            uid,
+            folder: folder.to_string(),
+            flags: flags.iter().map(|f| f.to_string()).collect(),
+            raw: raw.replace("\r\n", "\n").replace('\n', "\r\n"),
+        });
+        uid
+    }
+
+    pub fn folder(&self, folder: &str) -> Vec<&MockMail> {
+        self.mails.iter().filter(|m| m.folder == folder).collect()
+    }
+
+    pub fn find(&self, uid: u32) -> Option<&MockMail> {
+        self.mails.iter().find(|m| m.uid == uid)
+    }
+}
+
+// Fixtures
+pub const PLAIN_MAIL: &'static str = "From: Alice <alice@example.org>\r\nTo: tester@example.org\r\nSubject: Lunch\r\nDate: Tue, 1 Jun 2021 12:00:00 +0200\r\nMessage-ID: <lunch@example.org>\r\n\r\nSee you at noon?\r\n";
+pub const ENCODED_MAIL: &'static str = "From: =?UTF-8?Q?J=C3=BCrgen?= <juergen@example.org>\r\nTo: tester@example.org\r\nSubject: =?UTF-8?B?R3LDvMOfZQ==?=\r\nDate: Wed, 2 Jun 2021 08:30:00 +0000\r\nMessage-ID: <greetings@example.org>\r\n\r\nViele Gr\u{fc}\u{df}e\r\n.signature starts with a dot\r\n";
+pub const ARCHIVED_MAIL: &'static str = "From: Bob <bob@example.org>\r\nTo: tester@example.org\r\nSubject: Old news\r\nDate: Mon, 31 May 2021 18:00:00 +0000\r\nMessage-ID: <old@example.org>\r\n\r\nAlready read.\r\n";
+
+// Two mails in the inbox, the second one read, and one in the archive
+pub fn fixture_store() -> MockStore {
+    let mut store = MockStore::new();
+    store.add("INBOX", PLAIN_MAIL, &[]);
+    store.add("INBOX", ENCODED_MAIL, &["\\Seen"]);
+    store.add("Archive", ARCHIVED_MAIL, &["\\Seen"]);
+    store
+}
+
+type Handler = fn(TcpStream, &Arc<Mutex<MockStore>>) -> io::Result<()>;
+
+pub struct MockServer {
+    pub port: u16,
+    pub store: Arc<Mutex<MockStore>>,
+}
+
+impl MockServer {
+    fn start(store: MockStore, protocol: &'static str, handler: Handler) -> io::Result<MockServer> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+        let store = Arc::new(Mutex::new(store));
+        let shared = Arc::clone(&store);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => break,
+                };
+                let store = Arc::clone(&shared);
+                thread::spawn(move || {
+                    if let Err(e) = handler(stream, &store) {
+                        log::debug!("mock {} session ended: {}", protocol, e);
+                    }
+                });
+            }
+        });
+        Ok(MockServer { port, store })
+    }
+
+    pub fn imap(store: MockStore) -> io::Result<MockServer> {
+        MockServer::start(store, "imap", serve_imap)
+    }
+
+    pub fn pop3(store: MockStore) -> io::Result<MockServer> {
+        MockServer::start(store, "pop3", serve_pop3)
+    }
+
+    pub fn smtp(store: MockStore) -> io::Result<MockServer> {
+        MockServer::start(store, "smtp", serve_smtp)
+    }
+
+    // Plain connection to the server, to be injected into an adapter
+    pub fn stream(&self) -> io::Result<Box<dyn MailStream>> {
+        Ok(Box::new(TcpStream::connect(("127.0.0.1", self.port))?))
+    }
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> io::Result<Connection> {
+        Ok(Connection {
+            writer: stream.try_clone()?,
+            reader: BufReader::new(stream),
+        })
+    }
+
+    // None once the client closed the connection
+    fn read_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = Vec::new();
+        if self.reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(String::from_utf8_lossy(&line).trim_end_matches(|c| c == '\r' || c == '\n').to_string()))
+    }
+
+    fn send(&mut self, text: &str) -> io::Result<()> {
+        self.writer.write_all(text.as_bytes())?;
+        self.writer.write_all(b"\r\n")?;
+        self.writer.flush()
+    }
+
+    // Reads dot stuffed lines up to the terminating dot, without it the data is incomplete
+    fn read_data(&mut self) -> io::Result<String> {
+        let mut data = String::new();
+        while let Some(line) = self.read_line()? {
+            if line == "." {
+                return Ok(data);
+            }
+            data.push_str(if line.starts_with('.') { &line[1..] } else { line.as_str() });
+            data.push_str("\r\n");
+        }
+        Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed during data"))
+    }
+}
+
+fn dot_stuff(raw: &str) -> String {
+    let mut ret = String::new();
+    for line in raw.trim_end_matches("\r\n").split("\r\n") {
+        if line.starts_with('.') {
+            ret.push('.');
+        }
+        ret.push_str(line);
+        ret.push_str("\r\n");
+    }
+    ret.push('.');
+    ret
+}
+
+// Splits arguments at spaces, quoted strings and parenthesized lists stay together
+fn imap_args(s: &str) -> Vec<String> {
+    let mut ret = Vec::new();
+    let mut current = String::new();
+    let mut chars = s.chars();
+    let mut depth = 0;
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if depth == 0 => {
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => current.extend(chars.next()),
+                        c => current.push(c),
+                    }
+                }
+            },
+            '(' => {
+                depth += 1;
+                current.push(c);
+            },
+            ')' => {
+                depth -= 1;
+                current.push(c);
+            },
+            ' ' if depth == 0 => if !current.is_empty() {
+                ret.push(std::mem::replace(&mut current, String::new()));
+            },
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        ret.push(current);
+    }
+    ret
+}
+
+// "1,3:5" into the single uids
+fn uid_set(s: &str) -> Vec<u32> {
+    let mut ret = Vec::new();
+    for part in s.split(',') {
+        let mut bounds = part.splitn(2, ':').map(|n| n.parse::<u32>().unwrap_or(u32::MAX));
+        let from = bounds.next().unwrap_or(0);
+        let to = bounds.next().unwrap_or(from);
+        ret.extend(from..=to.min(from.saturating_add(10000)));
+    }
+    ret
+}
+
+fn serve_imap(stream: TcpStream, store: &Arc<Mutex<MockStore>>) -> io::Result<()> {
+    let mut con = Connection::new(stream)?;
+    let mut authenticated = false;
+    let mut selected: Option<String> = None;
+    con.send("* OK [CAPABILITY IMAP4rev1] mock imap ready")?;
+
+    while let Some(line) = con.read_line()? {
+        let mut parts = line.splitn(3, ' ');
+        let tag = parts.next().unwrap_or("*").to_string();
+        let command = parts.next().unwrap_or("").to_uppercase();
+        let args = imap_args(parts.next().unwrap_or(""));
+        let mut store = store.lock().unwrap();
+
+        let reply = match (command.as_str(), authenticated) {
+            ("CAPABILITY", _) => {
+                con.send(format!("* CAPABILITY IMAP4rev1 {}", store.capabilities.join(" ")).trim_end())?;
+                format!("{} OK CAPABILITY completed", tag)
+            },
+            ("NOOP", _) => format!("{} OK NOOP completed", tag),
+            ("LOGOUT", _) => {
+                con.send("* BYE logging out")?;
+                con.send(format!("{} OK LOGOUT completed", tag).as_str())?;
+                return Ok(());
+            },
+            ("LOGIN", false) => {
+                if args.len() == 2 && args[0] == store.user && args[1] == store.password {
+                    authenticated = true;
+                    format!("{} OK LOGIN completed", tag)
+                } else {
+                    format!("{} NO [AUTHENTICATIONFAILED] invalid credentials", tag)
+                }
+            },
+            (_, false) => format!("{} NO not authenticated", tag),
+            ("SELECT", true) | ("EXAMINE", true) => {
+                let folder = args.get(0).cloned().unwrap_or_default();
+                if store.folders.contains(&folder) {
+                    con.send(format!("* {} EXISTS", store.folder(folder.as_str()).len()).as_str())?;
+                    con.send("* 0 RECENT")?;
+                    con.send("* FLAGS (\\Seen \\Answered \\Flagged \\Deleted \\Draft)")?;
+                    con.send("* OK [UIDVALIDITY 1] UIDs valid")?;
+                    selected = Some(folder);
+                    format!("{} OK [READ-WRITE] SELECT completed", tag)
+                } else {
+                    format!("{} NO no such folder", tag)
+                }
+            },
+            ("LIST", true) => {
+                for folder in store.folders.iter() {
+                    con.send(format!("* LIST (\\HasNoChildren) \"/\" {}", folder).as_str())?;
+                }
+                format!("{} OK LIST completed", tag)
+            },
+            ("UID", true) if selected.is_some() => {
+                let folder = selected.clone().unwrap_or_default();
+                imap_uid_command(&mut con, &mut store, folder.as_str(), &tag, &args)?
+            },
+            ("EXPUNGE", true) if selected.is_some() => {
+                let folder = selected.clone().unwrap_or_default();
+                expunge(&mut con, &mut store, folder.as_str(), None)?;
+                format!("{} OK EXPUNGE completed", tag)
+            },
+            (_, true) => format!("{} BAD unknown or unexpected command", tag),
+        };
+        con.send(reply.as_str())?;
+    }
+    Ok(())
+}
+
+// Removes the deleted mails of a folder, with UIDPLUS only those of the given uids
+fn expunge(con: &mut Connection, store: &mut MockStore, folder: &str, uids: Option<Vec<u32>>) -> io::Result<()> {
+    // Sequence numbers shift with every removed mail
+    let mut seq = 1;
+    let mut expunged = Vec::new();
+    for mail in store.mails.iter().filter(|m| m.folder == folder) {
+        if mail.has_flag("\\Deleted") && uids.as_ref().map(|u| u.contains(&mail.uid)).unwrap_or(true) {
+            expunged.push((seq, mail.uid));
+        } else {
+            seq += 1;
+        }
+    }
+    for (seq, _) in expunged.iter() {
+        con.send(format!("* {} EXPUNGE", seq).as_str())?;
+    }
+    store.mails.retain(|m| !expunged.iter().any(|(_, uid)| *uid == m.uid));
+    Ok(())
+}
+
+fn imap_uid_command(con: &mut Connection, store: &mut MockStore, folder: &str, tag: &str, args: &Vec<String>) -> io::Result<String> {
+    let seq_of = |store: &MockStore, uid: u32| store.folder(folder).iter().position(|m| m.uid == uid).map(|i| i + 1);
+    let command = args.get(0).map(|c| c.to_uppercase()).unwrap_or_default();
+    match command.as_str() {
+        "SEARCH" => {
+            let mut found: Vec<u32> = Vec::new();
+            for mail in store.folder(folder) {
+                if search_matches(mail, &args[1..]) {
+                    found.push(mail.uid);
+                }
+            }
+            let uids: Vec<String> = found.iter().map(|u| u.to_string()).collect();
+            con.send(format!("* SEARCH {}", uids.join(" ")).trim_end())?;
+            Ok(format!("{} OK SEARCH completed", tag))
+        },
+        "FETCH" => {
+            let items = args.get(2).map(|i| i.to_uppercase()).unwrap_or_default();
+            for uid in uid_set(args.get(1).map(|s| s.as_str()).unwrap_or("")) {
+                let seq = match seq_of(store, uid) {
+                    Some(seq) => seq,
+                    None => continue,
+                };
+                let mail = match store.mails.iter_mut().find(|m| m.uid == uid) {
+                    Some(mail) => mail,
+                    None => continue,
+                };
+                let (name, content) = if items.contains("HEADER") {
+                    ("BODY[HEADER]", mail.header().to_string())
+                } else {
+                    mail.set_flag("\\Seen", true);
+                    ("RFC822", mail.raw.clone())
+                };
+                let size = if items.contains("RFC822.SIZE") { format!("RFC822.SIZE {} ", mail.raw.len()) } else { String::new() };
+                con.writer.write_all(format!("* {} FETCH (UID {} {}{} {{{}}}\r\n", seq, uid, size, name, content.len()).as_bytes())?;
+                con.writer.write_all(content.as_bytes())?;
+                con.send(")")?;
+            }
+            Ok(format!("{} OK FETCH completed", tag))
+        },
+        "STORE" => {
+            let mode = args.get(2).map(|m| m.to_uppercase()).unwrap_or_default();
+            let flags: Vec<String> = args.get(3).map(|f| f.trim_matches(|c| c == '(' || c == ')').split_whitespace().map(|f| f.to_string()).collect()).unwrap_or_default();
+            for uid in uid_set(args.get(1).map(|s| s.as_str()).unwrap_or("")) {
+                let seq = match seq_of(store, uid) {
+                    Some(seq) => seq,
+                    None => continue,
+                };
+                if let Some(mail) = store.mails.iter_mut().find(|m| m.uid == uid) {
+                    if mode.starts_with("FLAGS") {
+                        mail.flags.clear();
+                    }
+                    for flag in flags.iter() {
+                        mail.set_flag(flag, !mode.starts_with('-'));
+                    }
+                    con.send(format!("* {} FETCH (UID {} FLAGS ({}))", seq, uid, mail.flags.join(" ")).as_str())?;
+                }
+            }
+            Ok(format!("{} OK STORE completed", tag))
+        },
+        "COPY" | "MOVE" => {
+            let target = args.get(2).cloned().unwrap_or_default();
+            if !store.folders.contains(&target) {
+                return Ok(format!("{} NO [TRYCREATE] no such folder", tag));
+            }
+            let copies: Vec<(String, Vec<String>)> = uid_set(args.get(1).map(|s| s.as_str()).unwrap_or("")).into_iter()
+                .filter_map(|uid| store.find(uid).filter(|m| m.folder == folder).map(|m| (m.raw.clone(), m.flags.clone())))
+                .collect();
+            for (raw, flags) in copies.into_iter() {
+                let flags: Vec<&str> = flags.iter().map(|f| f.as_str()).filter(|f| !f.eq_ignore_ascii_case("\\Deleted")).collect();
+                store.add(target.as_str(), raw.as_str(), &flags);
+            }
+            if command == "MOVE" {
+                let moved = uid_set(args.get(1).map(|s| s.as_str()).unwrap_or(""));
+                for uid in moved.iter() {
+                    if let Some(mail) = store.mails.iter_mut().find(|m| m.uid == *uid && m.folder == folder) {
+                        mail.set_flag("\\Deleted", true);
+                    }
+                }
+                expunge(con, store, folder, Some(moved))?;
+            }
+            Ok(format!("{} OK {} completed", tag, command))
+        },
+        "EXPUNGE" if store.capabilities.iter().any(|c| c == "UIDPLUS") => {
+            expunge(con, store, folder, Some(uid_set(args.get(1).map(|s| s.as_str()).unwrap_or(""))))?;
+            Ok(format!("{} OK EXPUNGE completed", tag))
+        },
+        _ => Ok(format!("{} BAD unknown UID command", tag)),
+    }
+}
+
+// Supports the criteria the client uses, dates are ignored
+fn search_matches(mail: &MockMail, criteria: &[String]) -> bool {
+    let mut i = 0;
+    while i < criteria.len() {
+        let matches = match criteria[i].to_uppercase().as_str() {
+            "ALL" => true,
+            "SEEN" => mail.has_flag("\\Seen"),
+            "UNSEEN" => !mail.has_flag("\\Seen"),
+            "DELETED" => mail.has_flag("\\Deleted"),
+            "UNDELETED" => !mail.has_flag("\\Deleted"),
+            "FLAGGED" => mail.has_flag("\\Flagged"),
+            "SINCE" | "BEFORE" | "ON" => {
+                i += 1;
+                true
+            },
+            "HEADER" => {
+                let (name, value) = (criteria.get(i + 1), criteria.get(i + 2));
+                i += 2;
+                match (name, value) {
+                    (Some(name), Some(value)) => Headers::parse(mail.header()).get(name).map(|v| v.contains(value.as_str())).unwrap_or(false),
+                    (_, _) => false,
+                }
+            },
+            _ => true,
+        };
+        if !matches {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+fn serve_pop3(stream: TcpStream, store: &Arc<Mutex<MockStore>>) -> io::Result<()> {
+    let mut con = Connection::new(stream)?;
+    let mut user: Option<String> = None;
+    let mut authenticated = false;
+    // Message numbers stay fixed for the session, deletions are applied on QUIT
+    let mut deleted: Vec<u32> = Vec::new();
+    con.send("+OK mock pop3 ready")?;
+
+    while let Some(line) = con.read_line()? {
+        let mut parts = line.splitn(2, ' ');
+        let command = parts.next().unwrap_or("").to_uppercase();
+        let arg = parts.next().unwrap_or("").trim().to_string();
+        let mut store = store.lock().unwrap();
+        let inbox: Vec<MockMail> = store.folder("INBOX").into_iter().cloned().collect();
+        let message = |arg: &str| arg.parse::<usize>().ok()
+            .filter(|n| *n > 0)
+            .and_then(|n| inbox.get(n - 1))
+            .filter(|m| !deleted.contains(&m.uid))
+            .cloned();
+
+        match (command.as_str(), authenticated) {
+            ("QUIT", _) => {
+                store.mails.retain(|m| !deleted.contains(&m.uid));
+                con.send("+OK bye")?;
+                return Ok(());
+            },
+            ("USER", false) => {
+                user = Some(arg);
+                con.send("+OK")?;
+            },
+            ("PASS", false) => {
+                if user.as_ref() == Some(&store.user) && arg == store.password {
+                    authenticated = true;
+                    con.send("+OK logged in")?;
+                } else {
+                    con.send("-ERR invalid credentials")?;
+                }
+            },
+            (_, false) => con.send("-ERR not authenticated")?,
+            ("STAT", true) => {
+                let size: usize = inbox.iter().filter(|m| !deleted.contains(&m.uid)).map(|m| m.raw.len()).sum();
+                con.send(format!("+OK {} {}", inbox.len() - deleted.len(), size).as_str())?;
+            },
+            ("UIDL", true) | ("LIST", true) => {
+                con.send("+OK")?;
+                for (i, mail) in inbox.iter().enumerate().filter(|(_, m)| !deleted.contains(&m.uid)) {
+                    let value = if command == "UIDL" { format!("uid{}", mail.uid) } else { mail.raw.len().to_string() };
+                    con.send(format!("{} {}", i + 1, value).as_str())?;
+                }
+                con.send(".")?;
+            },
+            ("RETR", true) => match message(arg.as_str()) {
+                Some(mail) => {
+                    con.send(format!("+OK {} octets", mail.raw.len()).as_str())?;
+                    con.send(dot_stuff(mail.raw.as_str()).as_str())?;
+                },
+                None => con.send("-ERR no such message")?,
+            },
+            ("DELE", true) => match message(arg.as_str()) {
+                Some(mail) => {
+                    deleted.push(mail.uid);
+                    con.send("+OK marked as deleted")?;
+                },
+                None => con.send("-ERR no such message")?,
+            },
+            ("RSET", true) => {
+                deleted.clear();
+                con.send("+OK")?;
+            },
+            ("NOOP", true) => con.send("+OK")?,
+            (_, true) => con.send("-ERR unknown command")?,
+        }
+    }
+    Ok(())
+}
+
+fn serve_smtp(stream: TcpStream, store: &Arc<Mutex<MockStore>>) -> io::Result<()> {
+    let mut con = Connection::new(stream)?;
+    let mut authenticated = false;
+    let mut from: Option<String> = None;
+    let mut recipients: Vec<String> = Vec::new();
+    let path = |arg: &str| arg.splitn(2, ':').nth(1).unwrap_or("").trim().trim_matches(|c| c == '<' || c == '>').to_string();
+    con.send("220 mock ESMTP ready")?;
+
+    while let Some(line) = con.read_line()? {
+        let upper = line.to_uppercase();
+        if upper.starts_with("EHLO") || upper.starts_with("HELO") {
+            con.send("250-mock greets you\r\n250-PIPELINING\r\n250 AUTH PLAIN")?;
+        } else if upper.starts_with("AUTH PLAIN ") {
+            let store = store.lock().unwrap();
+            let expected = format!("\0{}\0{}", store.user, store.password);
+            let token = base64::decode(line[11..].trim()).unwrap_or_default();
+            if token == expected.as_bytes() {
+                authenticated = true;
+                con.send("235 authentication successful")?;
+            } else {
+                con.send("535 authentication failed")?;
+            }
+        } else if upper == "QUIT" {
+            con.send("221 bye")?;
+            return Ok(());
+        } else if upper == "RSET" {
+            from = None;
+            recipients.clear();
+            con.send("250 ok")?;
+        } else if !authenticated {
+            con.send("530 authentication required")?;
+        } else if upper.starts_with("MAIL FROM:") {
+            from = Some(path(line.as_str()));
+            recipients.clear();
+            con.send("250 ok")?;
+        } else if upper.starts_with("RCPT TO:") && from.is_some() {
+            let recipient = path(line.as_str());
+            if store.lock().unwrap().rejected.contains(&recipient) {
+                con.send("550 no such user")?;
+            } else {
+                recipients.push(recipient);
+                con.send("250 ok")?;
+            }
+        } else if upper == "DATA" && !recipients.is_empty() {
+            con.send("354 end data with <CR><LF>.<CR><LF>")?;
+            let data = con.read_data()?;
+            store.lock().unwrap().sent.push(SentMail {
+                from: from.take().unwrap_or_default(),
+                recipients: std::mem::replace(&mut recipients, Vec::new()),
+                data,
+            });
+            con.send("250 queued")?;
+        } else {
+            con.send("503 bad sequence of commands")?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::account::InboxConfig;
+    use super::super::error::MailError;
+    use super::super::receiving::{ImapAccount, InboxAdapter, Pop3Account};
+    use super::super::sending::{self as sending, MailTransport, RateLimit, SmtpTransport};
+
+    fn connect(config: &InboxConfig, server: &MockServer) -> InboxAdapter {
+        let stream = server.stream().unwrap();
+        match config {
+            InboxConfig::Pop3(host, _) => InboxAdapter::over::<Pop3Account>(host, stream).unwrap(),
+            InboxConfig::Imap(host, _) => InboxAdapter::over::<ImapAccount>(host, stream).unwrap(),
+            InboxConfig::Other(protocol, _) => panic!("no mock server for {}", protocol),
+        }
+    }
+
+    fn login(config: &InboxConfig, server: &MockServer) -> InboxAdapter {
+        let mut adapter = connect(config, server);
+        adapter.login(&String::from(USER), &String::from(PASSWORD)).unwrap();
+        adapter
+    }
+
+    #[test]
+    fn imap_loads_inbox_unread_first() {
+        let server = MockServer::imap(fixture_store()).unwrap();
+        let mut adapter = login(&InboxConfig::new_imap(String::from("localhost")), &server);
+        let headers = adapter.load_inbox().unwrap();
+        let subjects: Vec<&String> = headers.iter().map(|h| h.get_subject()).collect();
+        assert_eq!(subjects, vec!["Lunch", "Grüße"]);
+    }
+
+    #[test]
+    fn imap_rejects_wrong_password() {
+        let server = MockServer::imap(fixture_store()).unwrap();
+        let mut adapter = connect(&InboxConfig::new_imap(String::from("localhost")), &server);
+        match adapter.login(&String::from(USER), &String::from("wrong")) {
+            Err(MailError::Login(user)) => assert_eq!(user, USER),
+            _ => panic!("login with wrong password succeeded"),
+        }
+        assert!(adapter.load_inbox().is_err());
+    }
+
+    #[test]
+    fn imap_fetch_marks_seen_and_flags_update() {
+        let server = MockServer::imap(fixture_store()).unwrap();
+        let mut adapter = login(&InboxConfig::new_imap(String::from("localhost")), &server);
+        let headers = adapter.load_inbox().unwrap();
+        let mail = adapter.get_mail(&headers[0]).unwrap();
+        assert_eq!(mail.get_body().trim(), "See you at noon?");
+        adapter.set_flag(&vec![headers[0].get_id()], "\\Flagged", true).unwrap();
+
+        let store = server.store.lock().unwrap();
+        let flags = &store.find(headers[0].get_id()).unwrap().flags;
+        assert!(flags.contains(&String::from("\\Seen")));
+        assert!(flags.contains(&String::from("\\Flagged")));
+    }
+
+    #[test]
+    fn imap_move_and_expunge() {
+        let server = MockServer::imap(fixture_store()).unwrap();
+        let mut adapter = login(&InboxConfig::new_imap(String::from("localhost")), &server);
+        let headers = adapter.load_inbox().unwrap();
+        adapter.move_to(&vec![headers[0].get_id()], "Archive").unwrap();
+        adapter.expunge().unwrap();
+        assert_eq!(adapter.load_inbox().unwrap().len(), 1);
+        assert_eq!(server.store.lock().unwrap().folder("Archive").len(), 2);
+        assert_eq!(adapter.list_folders().unwrap(), vec!["INBOX", "Archive"]);
+    }
+
+    #[test]
+    fn imap_move_leaves_no_copy() {
+        let server = MockServer::imap(fixture_store()).unwrap();
+        let mut adapter = login(&InboxConfig::new_imap(String::from("localhost")), &server);
+        let headers = adapter.load_inbox().unwrap();
+        adapter.move_to(&vec![headers[0].get_id()], "Archive").unwrap();
+        let store = server.store.lock().unwrap();
+        assert_eq!(store.folder("INBOX").len(), 1);
+        assert_eq!(store.folder("Archive").len(), 2);
+    }
+
+    #[test]
+    fn imap_undo_move_restores_the_mail() {
+        let server = MockServer::imap(fixture_store()).unwrap();
+        let mut adapter = login(&InboxConfig::new_imap(String::from("localhost")), &server);
+        let headers = adapter.load_inbox().unwrap();
+        adapter.move_to(&vec![headers[0].get_id()], "Archive").unwrap();
+        let kept_ids = adapter.move_back("Archive", &vec![headers[0].get_id()], &vec![String::from("lunch@example.org")]).unwrap();
+        assert!(!kept_ids);
+        let subjects: Vec<String> = adapter.load_inbox().unwrap().iter().map(|h| h.get_subject().clone()).collect();
+        assert_eq!(subjects, vec!["Lunch", "Grüße"]);
+        assert_eq!(server.store.lock().unwrap().folder("Archive").len(), 1);
+    }
+
+    #[test]
+    fn imap_undo_copy_restores_the_mail() {
+        let mut store = fixture_store();
+        store.capabilities = vec![String::from("UIDPLUS")];
+        let server = MockServer::imap(store).unwrap();
+        let mut adapter = login(&InboxConfig::new_imap(String::from("localhost")), &server);
+        let headers = adapter.load_inbox().unwrap();
+        adapter.move_to(&vec![headers[0].get_id()], "Archive").unwrap();
+        let kept_ids = adapter.move_back("Archive", &vec![headers[0].get_id()], &vec![String::from("lunch@example.org")]).unwrap();
+        assert!(kept_ids);
+        adapter.expunge().unwrap();
+        let ids: Vec<u32> = adapter.load_inbox().unwrap().iter().map(|h| h.get_id()).collect();
+        assert!(ids.contains(&headers[0].get_id()));
+        assert_eq!(server.store.lock().unwrap().folder("Archive").len(), 1);
+    }
+
+    #[test]
+    fn imap_expunge_keeps_mails_of_other_clients() {
+        let mut store = fixture_store();
+        let other = store.add("INBOX", ARCHIVED_MAIL, &["\\Deleted"]);
+        let server = MockServer::imap(store).unwrap();
+        let mut adapter = login(&InboxConfig::new_imap(String::from("localhost")), &server);
+        let headers = adapter.load_inbox().unwrap();
+        adapter.delete(&vec![headers[0].get_id()]).unwrap();
+        adapter.expunge().unwrap();
+        let store = server.store.lock().unwrap();
+        assert!(store.find(headers[0].get_id()).is_none());
+        assert!(store.find(other).is_some());
+    }
+
+    #[test]
+    fn imap_plain_expunge_needs_consent() {
+        let mut store = fixture_store();
+        store.capabilities.clear();
+        let server = MockServer::imap(store).unwrap();
+        let mut adapter = login(&InboxConfig::new_imap(String::from("localhost")), &server);
+        let headers = adapter.load_inbox().unwrap();
+        adapter.delete(&vec![headers[0].get_id()]).unwrap();
+        assert!(matches!(adapter.expunge(), Err(MailError::PlainExpunge)));
+        adapter.allow_plain_expunge();
+        adapter.expunge().unwrap();
+        assert_eq!(adapter.load_inbox().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn pop3_fetches_and_deletes_on_quit() {
+        let server = MockServer::pop3(fixture_store()).unwrap();
+        let config = InboxConfig::new_pop3(String::from("localhost"));
+        {
+            let mut adapter = login(&config, &server);
+            let headers = adapter.load_inbox().unwrap();
+            assert_eq!(headers.len(), 2);
+            let mail = adapter.get_mail(&headers[1]).unwrap();
+            assert_eq!(mail.get_subject(), "Grüße");
+            assert!(mail.get_body().contains("\n.signature starts with a dot"));
+            adapter.delete(&vec![headers[0].get_id()]).unwrap();
+        }
+        // Session is closed once the adapter is dropped
+        let mut adapter = login(&config, &server);
+        assert_eq!(adapter.load_inbox().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn pop3_undelete_resets_session() {
+        let server = MockServer::pop3(fixture_store()).unwrap();
+        let config = InboxConfig::new_pop3(String::from("localhost"));
+        {
+            let mut adapter = login(&config, &server);
+            adapter.delete(&vec![1, 2]).unwrap();
+            adapter.undelete(&vec![1]).unwrap();
+        }
+        assert_eq!(server.store.lock().unwrap().folder("INBOX").len(), 2);
+    }
+
+    #[test]
+    fn smtp_delivers_with_dot_stuffing() {
+        let server = MockServer::smtp(MockStore::new()).unwrap();
+        let transport = SmtpTransport::new("localhost", String::from(USER), String::from(PASSWORD));
+        let recipients = vec![String::from("alice@example.org"), String::from("bob@example.org")];
+        let message = "Subject: Test\r\n\r\nfirst line\r\n.hidden dot\r\n";
+        transport.send_over(server.stream().unwrap(), USER, &recipients, message).unwrap();
+
+        let store = server.store.lock().unwrap();
+        assert_eq!(store.sent.len(), 1);
+        assert_eq!(store.sent[0].from, USER);
+        assert_eq!(store.sent[0].recipients, recipients);
+        assert_eq!(store.sent[0].data, message);
+    }
+
+    #[test]
+    fn smtp_rejects_wrong_password() {
+        let server = MockServer::smtp(MockStore::new()).unwrap();
+        let transport = SmtpTransport::new("localhost", String::from(USER), String::from("wrong"));
+        let result = transport.send_over(server.stream().unwrap(), USER, &vec![String::from("alice@example.org")], "Subject: Test\r\n\r\nHi\r\n");
+        assert!(result.is_err());
+        assert!(server.store.lock().unwrap().sent.is_empty());
+    }
+
+    #[test]
+    fn smtp_rejected_recipient_sends_nothing() {
+        let mut store = MockStore::new();
+        store.rejected.push(String::from("nobody@example.org"));
+        let server = MockServer::smtp(store).unwrap();
+        let transport = SmtpTransport::new("localhost", String::from(USER), String::from(PASSWORD));
+        let recipients = vec![String::from("alice@example.org"), String::from("nobody@example.org")];
+        assert!(transport.send_over(server.stream().unwrap(), USER, &recipients, "Subject: Test\r\n\r\nHi\r\n").is_err());
+        // The server notices the closed connection after the reply was read
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert!(server.store.lock().unwrap().sent.is_empty());
+    }
+
+    #[test]
+    fn smtp_reuses_connection_for_following_mails() {
+        let server = MockServer::smtp(MockStore::new()).unwrap();
+        let mut transport = SmtpTransport::new("localhost", String::from(USER), String::from(PASSWORD));
+        transport.connect_over(server.stream().unwrap()).unwrap();
+        // Connecting again would go to the real port and fail
+        transport.send(USER, &vec![String::from("alice@example.org")], "Subject: One\r\n\r\nHi\r\n").unwrap();
+        transport.send(USER, &vec![String::from("bob@example.org"), String::from("carol@example.org")], "Subject: Two\r\n\r\nHi\r\n").unwrap();
+        transport.close();
+
+        let store = server.store.lock().unwrap();
+        assert_eq!(store.sent.len(), 2);
+        assert_eq!(store.sent[1].recipients, vec!["bob@example.org", "carol@example.org"]);
+        assert_eq!(store.sent[1].data, "Subject: Two\r\n\r\nHi\r\n");
+    }
+
+    // Records the batches and fails once after the given number of them
+    struct FlakyTransport {
+        batches: Vec<Vec<String>>,
+        fail_after: Option<usize>,
+    }
+
+    impl MailTransport for FlakyTransport {
+        fn send(&mut self, _: &str, recipients: &Vec<String>, _: &str) -> std::io::Result<()> {
+            if self.fail_after == Some(self.batches.len()) {
+                self.fail_after = None;
+                return Err(std::io::Error::new(std::io::ErrorKind::Other, "throttled"));
+            }
+            self.batches.push(recipients.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn rate_limit_splits_recipients_and_resumes() {
+        let recipients: Vec<String> = (0..5).map(|i| format!("user{}@example.org", i)).collect();
+        let limit = RateLimit { messages_per_minute: None, recipients_per_message: Some(2) };
+        let mut transport = FlakyTransport { batches: Vec::new(), fail_after: Some(1) };
+        let mut done = Vec::new();
+        let mut progress = Vec::new();
+
+        let result = sending::send_batches(&mut transport, USER, &recipients, "Subject: Test\r\n\r\nHi\r\n", &limit, &mut done, &mut |sent, total| progress.push((sent, total)));
+        assert!(result.is_err());
+        assert_eq!(done, recipients[..2].to_vec());
+
+        sending::send_batches(&mut transport, USER, &recipients, "Subject: Test\r\n\r\nHi\r\n", &limit, &mut done, &mut |sent, total| progress.push((sent, total))).unwrap();
+        assert_eq!(transport.batches, vec![recipients[..2].to_vec(), recipients[2..4].to_vec(), recipients[4..].to_vec()]);
+        assert_eq!(done, recipients);
+        assert_eq!(progress, vec![(2, 5), (4, 5), (5, 5)]);
+    }
+}
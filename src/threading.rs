@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+
+// A conversation as (mail index, depth below the start of the thread), replies follow the mail they answer
+pub type Thread = Vec<(usize, usize)>;
+
+// Message ids and the mails they belong to, ids that are only referenced get an empty container
+struct Containers<'a> {
+    ids: HashMap<&'a String, usize>,
+    mail: Vec<Option<usize>>,
+    parent: Vec<Option<usize>>,
+}
+
+impl<'a> Containers<'a> {
+    fn get(&mut self, id: &'a String) -> usize {
+        if let Some(c) = self.ids.get(id) {
+            return *c;
+        }
+        let c = self.create();
+        self.ids.insert(id, c);
+        c
+    }
+
+    fn create(&mut self) -> usize {
+        self.mail.push(None);
+        self.parent.push(None);
+        self.mail.len() - 1
+    }
+
+    // Walks up from a, also true for a == b
+    fn is_ancestor(&self, b: usize, a: usize) -> bool {
+        let mut cur = Some(a);
+        while let Some(c) = cur {
+            if c == b {
+                return true;
+            }
+            cur = self.parent[c];
+        }
+        false
+    }
+
+    // Links child below parent unless that would make a loop
+    fn link(&mut self, parent: usize, child: usize, replace: bool) {
+        if (self.parent[child].is_none() || replace) && !self.is_ancestor(child, parent) {
+            self.parent[child] = Some(parent);
+        }
+    }
+}
+
+// Builds conversations like the JWZ algorithm, only from message ids so changed subjects don't split them.
+// Takes (own id, referenced ids oldest first) per mail in display order, returns the threads ordered by
+// their last mail. Siblings are in display order, missing mails in between are skipped
+pub fn build(mails: &Vec<(Option<String>, Vec<String>)>) -> Vec<Thread> {
+    let mut containers = Containers { ids: HashMap::new(), mail: Vec::new(), parent: Vec::new() };
+    for (i, (own, refs)) in mails.iter().enumerate() {
+        // Duplicates of a message id are kept apart from the first one
+        let c = match own {
+            Some(id) => match containers.get(id) {
+                c if containers.mail[c].is_none() => c,
+                _ => containers.create(),
+            },
+            None => containers.create(),
+        };
+        containers.mail[c] = Some(i);
+
+        // Each reference is the parent of the one after it, earlier links win
+        let chain: Vec<usize> = refs.iter().map(|id| containers.get(id)).collect();
+        for pair in chain.windows(2) {
+            containers.link(pair[0], pair[1], false);
+        }
+        // The mail itself trusts its own last reference most
+        if let Some(last) = chain.last() {
+            containers.link(*last, c, true);
+        }
+    }
+
+    let count = containers.mail.len();
+    let mut children: Vec<Vec<usize>> = vec![Vec::new(); count];
+    let mut roots = Vec::new();
+    for c in 0..count {
+        match containers.parent[c] {
+            Some(p) => children[p].push(c),
+            None => roots.push(c),
+        }
+    }
+    // Subtrees are ordered by their first mail
+    let mut first: Vec<Option<usize>> = vec![None; count];
+    fn first_mail(c: usize, containers: &Containers, children: &Vec<Vec<usize>>, first: &mut Vec<Option<usize>>) -> Option<usize> {
+        let ret = children[c].iter().filter_map(|child| first_mail(*child, containers, children, first))
+            .chain(containers.mail[c].into_iter())
+            .min();
+        first[c] = ret;
+        ret
+    }
+    for root in roots.iter() {
+        first_mail(*root, &containers, &children, &mut first);
+    }
+    for list in children.iter_mut() {
+        list.sort_by_key(|c| first[*c]);
+    }
+
+    fn walk(c: usize, depth: usize, containers: &Containers, children: &Vec<Vec<usize>>, thread: &mut Thread) {
+        // Empty containers don't add a level, their children become siblings
+        let below = match containers.mail[c] {
+            Some(i) => {
+                thread.push((i, depth));
+                depth + 1
+            },
+            None => depth,
+        };
+        for child in children[c].iter() {
+            walk(*child, below, containers, children, thread);
+        }
+    }
+    let mut threads: Vec<Thread> = roots.iter().filter(|r| first[**r].is_some()).map(|root| {
+        let mut thread = Vec::new();
+        walk(*root, 0, &containers, &children, &mut thread);
+        thread
+    }).collect();
+    threads.sort_by_key(|t| t.iter().map(|(i, _)| *i).max().unwrap_or(0));
+    return threads;
+}
+
+// Mail indices of every thread in display order, threads ordered by their last mail
+pub fn group(mails: &Vec<(Option<String>, Vec<String>)>) -> Vec<Vec<usize>> {
+    build(mails).into_iter().map(|thread| {
+        let mut indices: Vec<usize> = thread.into_iter().map(|(i, _)| i).collect();
+        indices.sort();
+        indices
+    }).collect()
+}
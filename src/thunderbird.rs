@@ -0,0 +1,193 @@
+// Migration from a Thunderbird profile: server settings come from prefs.js, local folders are
+// mbox files next to a .msf index. Passwords stay in Thunderbird's encrypted store
+use std::{
+    collections::HashMap,
+    fs,
+    io,
+    path::{Path, PathBuf},
+};
+
+use super::account::InboxConfig;
+
+pub struct ImportedAccount {
+    pub inbox: InboxConfig,
+    pub user: String,
+    // Address of the first identity, if it differs from the login
+    pub email: Option<String>,
+    pub smtp: Option<String>,
+    pub smtp_user: Option<String>,
+}
+
+// user_pref("name", value); lines, strings are unquoted, other values are kept as written
+pub fn parse_prefs(content: &str) -> HashMap<String, String> {
+    let mut ret = HashMap::new();
+    for line in content.lines().map(|l| l.trim()) {
+        let inner = match line.strip_prefix("user_pref(").and_then(|l| l.strip_suffix(");")) {
+            Some(inner) => inner,
+            None => continue,
+        };
+        let (name, rest) = match unquote(inner) {
+            Some(parsed) => parsed,
+            None => continue,
+        };
+        let value = rest.trim_start().trim_start_matches(',').trim();
+        let value = match unquote(value) {
+            Some((s, _)) => s,
+            None => value.to_string(),
+        };
+        ret.insert(name, value);
+    }
+    ret
+}
+
+// Leading quoted string and the text after it
+fn unquote(s: &str) -> Option<(String, &str)> {
+    let s = s.trim_start().strip_prefix('"')?;
+    let mut ret = String::new();
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => ret.extend(chars.next().map(|(_, c)| c)),
+            '"' => return Some((ret, &s[i + 1..])),
+            c => ret.push(c),
+        }
+    }
+    None
+}
+
+// Mail accounts in the order of the account manager, local folders and feeds are left out
+pub fn accounts(prefs: &HashMap<String, String>) -> Vec<ImportedAccount> {
+    let get = |name: String| prefs.get(&name).filter(|v| !v.is_empty());
+    let list = |name: String| get(name).map(|v| v.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect::<Vec<String>>()).unwrap_or(Vec::new());
+    let mut ret = Vec::new();
+    for account in list(String::from("mail.accountmanager.accounts")) {
+        let server = match get(format!("mail.account.{}.server", account)) {
+            Some(server) => server,
+            None => continue,
+        };
+        let server_pref = |key: &str| get(format!("mail.server.{}.{}", server, key));
+        let (host, user) = match (server_pref("hostname"), server_pref("userName")) {
+            (Some(host), Some(user)) => (host.clone(), user.clone()),
+            _ => continue,
+        };
+        let port = server_pref("port").and_then(|p| p.parse::<u16>().ok());
+        let inbox = match server_pref("type").map(|t| t.as_str()) {
+            Some("imap") => InboxConfig::new_imap(host),
+            Some("pop3") => InboxConfig::new_pop3(host),
+            _ => continue,
+        };
+        // Ports are only taken over with implicit TLS (socket type 3), otherwise the TLS defaults are used
+        let tls = server_pref("socketType").map(|t| t == "3").unwrap_or(false);
+        let inbox = match (inbox, port) {
+            (InboxConfig::Imap(host, _), Some(port)) if tls => InboxConfig::Imap(host, port),
+            (InboxConfig::Pop3(host, _), Some(port)) if tls => InboxConfig::Pop3(host, port),
+            (inbox, _) => inbox,
+        };
+        let identity = list(format!("mail.account.{}.identities", account)).into_iter().next();
+        let identity_pref = |key: &str| identity.as_ref().and_then(|id| get(format!("mail.identity.{}.{}", id, key)));
+        let email = identity_pref("useremail").filter(|e| !e.eq_ignore_ascii_case(user.as_str())).cloned();
+        let smtp_server = identity_pref("smtpServer").or(get(String::from("mail.smtp.defaultserver")));
+        let smtp_pref = |key: &str| smtp_server.and_then(|s| get(format!("mail.smtpserver.{}.{}", s, key)));
+        let smtp = smtp_pref("hostname").map(|host| match smtp_pref("port") {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.clone(),
+        });
+        let smtp_user = smtp_pref("username").filter(|u| **u != user).cloned();
+        ret.push(ImportedAccount { inbox, user, email, smtp, smtp_user });
+    }
+    ret
+}
+
+// Directories of all mail servers including the local folders, "[ProfD]" is the profile itself
+pub fn mail_directories(profile: &Path, prefs: &HashMap<String, String>) -> Vec<PathBuf> {
+    let mut ret: Vec<PathBuf> = Vec::new();
+    for (name, value) in prefs.iter() {
+        if !(name.starts_with("mail.server.") && (name.ends_with(".directory-rel") || name.ends_with(".directory"))) {
+            continue;
+        }
+        let path = match value.strip_prefix("[ProfD]") {
+            Some(rel) => profile.join(rel),
+            None => PathBuf::from(value),
+        };
+        if path.is_dir() && !ret.contains(&path) {
+            ret.push(path);
+        }
+    }
+    ret.sort();
+    ret
+}
+
+// Mbox files below a directory as (folder name, path), subfolders live in "<folder>.sbd"
+pub fn mbox_folders(dir: &Path) -> io::Result<Vec<(String, PathBuf)>> {
+    fn walk(dir: &Path, prefix: &str, ret: &mut Vec<(String, PathBuf)>) -> io::Result<()> {
+        let mut entries: Vec<PathBuf> = fs::read_dir(dir)?.filter_map(|e| e.ok()).map(|e| e.path()).collect();
+        entries.sort();
+        for path in entries.iter() {
+            let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+            if path.is_dir() {
+                if let Some(folder) = name.strip_suffix(".sbd") {
+                    walk(path, format!("{}{}/", prefix, folder).as_str(), ret)?;
+                }
+            } else if path.with_file_name(format!("{}.msf", name)).is_file() {
+                ret.push((format!("{}{}", prefix, name), path.clone()));
+            }
+        }
+        Ok(())
+    }
+    let mut ret = Vec::new();
+    walk(dir, "", &mut ret)?;
+    Ok(ret)
+}
+
+// Set in X-Mozilla-Status for mails deleted but not yet compacted away
+const MSG_FLAG_EXPUNGED: u32 = 0x0008;
+
+// Splits at the "From " separator lines, ">From " quoting is undone. Works on bytes, mails in
+// other charsets than UTF-8 are kept as they are
+pub fn split_mbox(content: &[u8]) -> Vec<Vec<u8>> {
+    let mut ret = Vec::new();
+    let mut current: Option<Vec<u8>> = None;
+    let mut previous_blank = true;
+    for line in content.split(|b| *b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.starts_with(b"From ") && previous_blank {
+            ret.extend(current.take());
+            current = Some(Vec::new());
+            previous_blank = false;
+            continue;
+        }
+        previous_blank = line.is_empty();
+        if let Some(mail) = current.as_mut() {
+            let unquoted = match line.strip_prefix(b">") {
+                Some(rest) if rest.iter().skip_while(|b| **b == b'>').copied().take(5).eq(b"From ".iter().copied()) => rest,
+                _ => line,
+            };
+            mail.extend_from_slice(unquoted);
+            mail.extend_from_slice(b"\r\n");
+        }
+    }
+    ret.extend(current);
+    ret.into_iter().filter_map(|mut m| {
+        let end = m.iter().rposition(|b| !b.is_ascii_whitespace())? + 1;
+        m.truncate(end);
+        m.extend_from_slice(b"\r\n");
+        Some(m)
+    }).collect()
+}
+
+// Thunderbird keeps deleted mails in the mbox until the folder is compacted
+pub fn is_expunged(mail: &[u8]) -> bool {
+    for line in mail.split(|b| *b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            break;
+        }
+        let line = String::from_utf8_lossy(line);
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("X-Mozilla-Status") {
+                return u32::from_str_radix(value.trim(), 16).map(|status| status & MSG_FLAG_EXPUNGED != 0).unwrap_or(false);
+            }
+        }
+    }
+    false
+}
@@ -0,0 +1,100 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    sync::Mutex,
+};
+
+// Protocol log, traced streams write into it while it is open
+static TRACE_FILE: Mutex<Option<File>> = Mutex::new(None);
+
+pub fn start(path: &str) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    *TRACE_FILE.lock().unwrap() = Some(file);
+    Ok(())
+}
+
+pub fn stop() {
+    *TRACE_FILE.lock().unwrap() = None;
+}
+
+pub fn is_active() -> bool {
+    TRACE_FILE.lock().unwrap().is_some()
+}
+
+// Hides passwords and auth tokens of IMAP LOGIN, POP3 PASS and SMTP AUTH commands
+fn redact(line: &str) -> String {
+    let upper = line.to_uppercase();
+    if let Some(i) = upper.find("AUTH PLAIN ").or(upper.find("AUTH LOGIN ")) {
+        return format!("{}<redacted>", line.get(..i + 11).unwrap_or("AUTH "));
+    }
+    if upper.starts_with("PASS ") {
+        return String::from("PASS <redacted>");
+    }
+    let tokens: Vec<&str> = line.splitn(3, ' ').collect();
+    if tokens.len() == 3 && tokens[1].eq_ignore_ascii_case("LOGIN") {
+        return format!("{} {} <redacted>", tokens[0], tokens[1]);
+    }
+    line.to_string()
+}
+
+fn log(protocol: &str, direction: &str, line: &str) {
+    if let Some(file) = TRACE_FILE.lock().unwrap().as_mut() {
+        let _ = writeln!(file, "[{} {}] {}", protocol, direction, redact(line));
+    }
+}
+
+// Passes all traffic through, logging it line by line while tracing is active
+pub struct TraceStream<S: Read + Write> {
+    inner: S,
+    protocol: &'static str,
+    read_buf: Vec<u8>,
+    write_buf: Vec<u8>,
+}
+
+impl<S: Read + Write> TraceStream<S> {
+    pub fn new(inner: S, protocol: &'static str) -> TraceStream<S> {
+        TraceStream {
+            inner,
+            protocol,
+            read_buf: Vec::new(),
+            write_buf: Vec::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn flush_lines(protocol: &str, direction: &str, buf: &mut Vec<u8>) {
+        while let Some(i) = buf.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = buf.drain(..=i).collect();
+            log(protocol, direction, String::from_utf8_lossy(&line).trim_end());
+        }
+    }
+}
+
+impl<S: Read + Write> Read for TraceStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if is_active() {
+            self.read_buf.extend_from_slice(&buf[..n]);
+            Self::flush_lines(self.protocol, "<", &mut self.read_buf);
+        }
+        Ok(n)
+    }
+}
+
+impl<S: Read + Write> Write for TraceStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if is_active() {
+            self.write_buf.extend_from_slice(&buf[..n]);
+            Self::flush_lines(self.protocol, ">", &mut self.write_buf);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -1,55 +1,710 @@
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+// Truncates or pads to the given display width, wide characters count twice and
+// graphemes are never cut apart
 pub fn fit_string_to_size(input: &String, size: usize) -> String {
-    let mut s = input.clone();
-    if s.len() > size {
-        while s.len() > (size - 4) {
-            s.pop();
-        }
-        s.push_str(" ...");
-    } else if s.len() < size {
-        while s.len() < size {
-            s.push(' ');
+    let width = UnicodeWidthStr::width(input.as_str());
+    if width <= size {
+        return format!("{}{}", input, " ".repeat(size - width));
+    }
+    // Too narrow for the ellipsis, the text is just cut
+    let ellipsis = if size >= 4 { " ..." } else { "" };
+    let limit = size - ellipsis.len();
+    let mut ret = String::new();
+    let mut used = 0;
+    for grapheme in input.graphemes(true) {
+        let w = UnicodeWidthStr::width(grapheme);
+        if used + w > limit {
+            break;
         }
+        ret.push_str(grapheme);
+        used += w;
     }
-    return s;
+    // A wide character that didn't fit leaves a gap
+    ret.push_str(" ".repeat(limit - used).as_str());
+    ret.push_str(ellipsis);
+    return ret;
+}
+
+// Space taken by indentation and index in front of listing lines
+const LISTING_INDENT: usize = 15;
+const SEPARATORS: usize = 8;
+
+// Widths of the (date, from, subject) listing columns for the current terminal.
+// Date keeps its width, the sender is cut before the subject gets too short.
+pub fn listing_widths() -> (usize, usize, usize) {
+    let total = console::Term::stdout().size_checked().map(|(_, cols)| cols as usize).unwrap_or(200);
+    let date = date_width();
+    let rest = total.saturating_sub(LISTING_INDENT + date + SEPARATORS);
+    let from = (rest * 35 / 100).max(12).min(60);
+    let subject = rest.saturating_sub(from).max(15);
+    (date, from, subject)
 }
 
-use datetime::{
-    OffsetDateTime,
-    DatePiece,
-    TimePiece,
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime,
+    FixedOffset,
+    Locale,
+    TimeZone,
+    Utc,
 };
+use std::{convert::TryFrom, sync::RwLock};
+
+pub const DEFAULT_DATE_FORMAT: &'static str = "%d.%m.%Y, %H:%M:%S";
+
+struct DateFormat {
+    format: String,
+    locale: Option<Locale>,
+    // Widest formatted date, used for the listing column
+    width: usize,
+}
 
-pub fn format_date(date: &OffsetDateTime) -> String {
-    format!("{:0>2}.{:0>2}.{}, {:0>2}:{:0>2}:{:0>2}", date.day(), date.month().months_from_january() + 1, date.year(), date.hour(), date.minute(), date.second())
+static DATE_FORMAT: RwLock<Option<DateFormat>> = RwLock::new(None);
+
+pub fn is_valid_date_format(format: &str) -> bool {
+    !StrftimeItems::new(format).any(|item| item == Item::Error)
+}
+
+// Locale of LC_ALL, LC_TIME or LANG, like "de_DE.UTF-8"
+fn system_locale() -> Option<Locale> {
+    let value = ["LC_ALL", "LC_TIME", "LANG"].iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|v| !v.is_empty())?;
+    let name = value.split(|c| c == '.' || c == '@').next().unwrap_or("");
+    Locale::try_from(name).ok()
+}
+
+// Sets the strftime format used for listings and the read view, month and day names
+// follow the system locale if requested. Invalid formats fall back to the default
+pub fn set_date_format(format: &str, use_locale: bool) {
+    let format = if is_valid_date_format(format) { format } else { DEFAULT_DATE_FORMAT };
+    let locale = if use_locale { system_locale() } else { None };
+    let mut date_format = DateFormat { format: format.to_string(), locale, width: 0 };
+    // Samples with every month, late days and times to find the widest date
+    date_format.width = (1..=12)
+        .filter_map(|month| Utc.with_ymd_and_hms(2000, month, 16 + month, 23, 59, 59).single())
+        .map(|date| UnicodeWidthStr::width(render_date(&date_format, &date.into()).as_str()))
+        .max()
+        .unwrap_or(0);
+    *DATE_FORMAT.write().unwrap() = Some(date_format);
+}
+
+fn render_date(date_format: &DateFormat, date: &DateTime<FixedOffset>) -> String {
+    match date_format.locale {
+        Some(locale) => date.format_localized(date_format.format.as_str(), locale).to_string(),
+        None => date.format(date_format.format.as_str()).to_string(),
+    }
+}
+
+pub fn format_date(date: &DateTime<FixedOffset>) -> String {
+    match DATE_FORMAT.read().unwrap().as_ref() {
+        Some(date_format) => render_date(date_format, date),
+        None => date.format(DEFAULT_DATE_FORMAT).to_string(),
+    }
+}
+
+fn date_width() -> usize {
+    DATE_FORMAT.read().unwrap().as_ref().map(|f| f.width).unwrap_or(20)
 }
 
 use std::cmp::Ordering;
 
-pub fn compare_date(date0: &OffsetDateTime, date1: &OffsetDateTime) -> Ordering {
-    let fields0 = get_timestamp_fields(&date0);
-    let fields1 = get_timestamp_fields(&date1);
+// Compares the instants, so dates with different offsets are ordered chronologically
+pub fn compare_date(date0: &DateTime<FixedOffset>, date1: &DateTime<FixedOffset>) -> Ordering {
+    date0.with_timezone(&Utc).cmp(&date1.with_timezone(&Utc))
+}
 
-    let mut level = 0;
-    let mut result = Ordering::Equal;
-    while let Ordering::Equal = result {
-        if level >= fields0.len() {
-            break;
+pub fn read_line(prompt: &str) -> String {
+    use std::io::{stdin, stdout, Write};
+    print!("{}", prompt);
+    let _ = stdout().flush();
+    let mut buf = String::new();
+    let _ = stdin().read_line(&mut buf);
+    return buf.trim().to_string();
+}
+
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+
+// Set by "--yes", answers all confirmations with yes
+static ASSUME_YES: AtomicBool = AtomicBool::new(false);
+
+pub fn set_assume_yes(val: bool) {
+    ASSUME_YES.store(val, AtomicOrdering::Relaxed);
+}
+
+// Asks a yes/no question, anything but "y" counts as no
+pub fn confirm(prompt: &str) -> bool {
+    if ASSUME_YES.load(AtomicOrdering::Relaxed) {
+        return true;
+    }
+    let answer = read_line(format!("{} [y/N] ", prompt).as_str());
+    answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes")
+}
+
+pub fn read_password(prompt: &str) -> String {
+    use console::Term;
+    let term = Term::stdout();
+    let _ = term.write_str(prompt);
+    term.read_secure_line().unwrap_or_default()
+}
+
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            cur[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1);
         }
-        result = fields0[level].cmp(&fields1[level]);
-        level += 1;
+        prev = cur;
+    }
+    return prev[b.len()];
+}
+
+// Returns the bare address of "Name <user@host>"
+pub fn extract_address(s: &str) -> String {
+    let s = s.trim();
+    match (s.rfind('<'), s.rfind('>')) {
+        (Some(start), Some(end)) if start < end => s[start + 1..end].trim().to_string(),
+        (_, _) => s.to_string(),
     }
-    return result;
 }
 
-fn get_timestamp_fields(datetime: &OffsetDateTime) -> Vec<i64> {
-    vec![
-    datetime.year(),
-    datetime.month().months_from_january() as i64,
-    datetime.day() as i64,
-    datetime.hour() as i64,
-    datetime.minute() as i64,
-    datetime.minute() as i64,
-    datetime.second() as i64,
-    datetime.millisecond() as i64
-    ]
+// Encodes non-ascii header values as RFC 2047 encoded words. A word may be 75 characters
+// at most, so longer values take several, folded onto lines of their own
+pub fn encode_header(s: &str) -> String {
+    if s.is_ascii() {
+        return s.to_string();
+    }
+    // "=?UTF-8?B?" and "?=" leave 63 characters, 60 of base64 carry 45 bytes
+    const WORD_BYTES: usize = 45;
+    let mut words: Vec<String> = Vec::new();
+    let mut chunk = String::new();
+    for c in s.chars() {
+        // Characters are not split between words
+        if chunk.len() + c.len_utf8() > WORD_BYTES {
+            words.push(format!("=?UTF-8?B?{}?=", base64::encode(chunk.as_bytes())));
+            chunk.clear();
+        }
+        chunk.push(c);
+    }
+    words.push(format!("=?UTF-8?B?{}?=", base64::encode(chunk.as_bytes())));
+    words.join("\r\n ")
+}
+
+pub fn to_crlf(s: &str) -> String {
+    s.replace("\r\n", "\n").replace('\n', "\r\n")
+}
+
+pub fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+pub fn iso_date(date: &DateTime<FixedOffset>) -> String {
+    date.to_rfc3339()
+}
+
+// Collects all message ids ("<...>") of a Message-ID, In-Reply-To or References header
+pub fn message_ids(s: &str) -> Vec<String> {
+    let mut ret = Vec::new();
+    let mut rest = s;
+    while let Some(start) = rest.find('<') {
+        match rest[start..].find('>') {
+            Some(end) => {
+                ret.push(rest[start + 1..start + end].trim().to_string());
+                rest = &rest[start + end + 1..];
+            },
+            None => break,
+        }
+    }
+    return ret;
+}
+
+// Parses index sets like "3-7,12" into sorted, distinct indices below count
+pub fn parse_index_set(s: &str, count: usize) -> Result<Vec<usize>, String> {
+    let mut ret = Vec::new();
+    for part in s.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+        let parse = |n: &str| n.trim().parse::<usize>().map_err(|_| format!("\"{}\" is not a valid index", n.trim()));
+        match part.find('-') {
+            Some(i) => {
+                let (from, to) = (parse(&part[..i])?, parse(&part[i + 1..])?);
+                if from > to {
+                    return Err(format!("invalid range \"{}\"", part));
+                }
+                // Check before expanding, a huge range would allocate all of its indices
+                if to >= count {
+                    return Err(format!("range \"{}\" is out of range, there are {} entries", part, count));
+                }
+                ret.extend(from..=to);
+            },
+            None => {
+                let index = parse(part)?;
+                if index >= count {
+                    return Err(format!("index {} is out of range, there are {} entries", index, count));
+                }
+                ret.push(index);
+            },
+        }
+    }
+    if ret.is_empty() {
+        return Err(String::from("no mails selected"));
+    }
+    ret.sort();
+    ret.dedup();
+    return Ok(ret);
+}
+
+// File in the temp directory that is removed when dropped
+pub struct TempFile {
+    path: std::path::PathBuf,
+}
+
+impl TempFile {
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// Writes the contents to a new temp file only the user can read. The name is random and the file
+// must not exist yet, so a file or link placed there beforehand is never written through
+pub fn temp_file(ext: &str, contents: &[u8]) -> std::io::Result<TempFile> {
+    use std::{env, fs::OpenOptions, io::{Error, ErrorKind, Write}};
+    for _ in 0..8 {
+        let mut random = [0u8; 8];
+        openssl::rand::rand_bytes(&mut random).map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+        let name: String = random.iter().map(|b| format!("{:02x}", b)).collect();
+        let path = env::temp_dir().join(format!("cli-mail-rs-{}.{}", name, ext));
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = match options.open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        };
+        let temp = TempFile { path };
+        file.write_all(contents)?;
+        return Ok(temp);
+    }
+    Err(Error::new(ErrorKind::AlreadyExists, "could not create a temp file"))
+}
+
+// Opens $VISUAL or $EDITOR on a temp file with the given text, returns the saved text
+pub fn edit_text(initial: &str) -> std::io::Result<String> {
+    use std::{env, fs, process::Command};
+    let editor = env::var("VISUAL").or(env::var("EDITOR"))
+        .unwrap_or(String::from(if cfg!(windows) { "notepad" } else { "vi" }));
+    let file = temp_file("txt", initial.as_bytes())?;
+
+    // Editor may be given with arguments like "code --wait"
+    let mut parts = editor.split_whitespace();
+    let program = parts.next().unwrap_or("vi");
+    let status = Command::new(program).args(parts).arg(file.path()).status();
+    let text = fs::read_to_string(file.path());
+    let status = status?;
+    if !status.success() {
+        return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("editor exited with {}", status)));
+    }
+    text
+}
+
+// Shows text in $PAGER if it doesn't fit on the terminal, prints it otherwise
+pub fn page(text: &str) {
+    page_at(text, None);
+}
+
+// Like page, but starts less at the first match of the pattern, "n" and "N" jump between matches
+pub fn page_at(text: &str, pattern: Option<&str>) -> bool {
+    use std::{env, io::Write, process::{Command, Stdio}};
+    let term = console::Term::stdout();
+    let rows = term.size_checked().map(|(rows, _)| rows as usize);
+    let fits = rows.map(|r| text.lines().count() < r).unwrap_or(true);
+    let pager = env::var("PAGER").ok().or(if cfg!(windows) { None } else { Some(String::from("less")) });
+    if let (false, Some(pager)) = (fits, pager) {
+        let mut parts = pager.split_whitespace();
+        let program = parts.next().unwrap_or("less");
+        let mut command = Command::new(program);
+        command.args(parts);
+        if let (Some(pattern), true) = (pattern, program.ends_with("less")) {
+            // Case insensitive search, matched literally
+            let escaped: String = pattern.chars().map(|c| if ".[]()*+?{}|^$\\".contains(c) { format!("\\{}", c) } else { c.to_string() }).collect();
+            command.arg("-i").arg(format!("+/{}", escaped));
+        }
+        if let Ok(mut child) = command.stdin(Stdio::piped()).spawn() {
+            if let Some(stdin) = child.stdin.as_mut() {
+                let _ = stdin.write_all(text.as_bytes());
+            }
+            drop(child.stdin.take());
+            let _ = child.wait();
+            return true;
+        }
+    }
+    if pattern.is_none() {
+        println!("{}", text);
+    }
+    return false;
+}
+
+// Finds http(s) links in text, in order of appearance and without duplicates
+pub fn extract_urls(text: &str) -> Vec<String> {
+    let mut ret: Vec<String> = Vec::new();
+    let mut rest = text;
+    loop {
+        let start = match (rest.find("http://"), rest.find("https://")) {
+            (Some(a), Some(b)) => a.min(b),
+            (a, b) => match a.or(b) {
+                Some(i) => i,
+                None => break,
+            },
+        };
+        let candidate = &rest[start..];
+        let end = candidate.find(|c: char| c.is_whitespace() || "<>\"'`".contains(c)).unwrap_or(candidate.len());
+        let url = candidate[..end].trim_end_matches(|c: char| ".,;:!?)]}".contains(c));
+        if url.len() > "https://".len() && !ret.iter().any(|u| u == url) {
+            ret.push(url.to_string());
+        }
+        rest = &candidate[end.max(1)..];
+    }
+    return ret;
+}
+
+// Sources of the img tags of an html text, in order of appearance and without duplicates
+pub fn image_sources(html: &str) -> Vec<String> {
+    let lower = html.to_ascii_lowercase();
+    let mut ret: Vec<String> = Vec::new();
+    let mut pos = 0;
+    while let Some(start) = lower[pos..].find("<img").map(|i| pos + i) {
+        let end = lower[start..].find('>').map(|i| start + i).unwrap_or(lower.len());
+        pos = end;
+        // Attributes like data-src don't count
+        let tag = &lower[start..end];
+        let attr = match tag.match_indices("src=").find(|(i, _)| tag[..*i].ends_with(char::is_whitespace)).map(|(i, _)| start + i + 4) {
+            Some(attr) => attr,
+            None => continue,
+        };
+        let value = match html[attr..end].chars().next() {
+            Some(q) if q == '"' || q == '\'' => html[attr + 1..end].split(q).next().unwrap_or(""),
+            _ => html[attr..end].split(char::is_whitespace).next().unwrap_or(""),
+        };
+        let value = value.trim().replace("&amp;", "&");
+        if !value.is_empty() && !ret.contains(&value) {
+            ret.push(value);
+        }
+    }
+    ret
+}
+
+// Command line run by the system shell
+pub fn shell(command: &str) -> std::process::Command {
+    use std::process::Command;
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    shell.arg(command);
+    shell
+}
+
+// Opens a link with the default browser of the system. Links come from mails, so only web and
+// mail links are handed on, and never through a shell
+pub fn open_url(url: &str) -> std::io::Result<()> {
+    use std::process::Command;
+    let scheme = url.split(':').next().unwrap_or("").to_lowercase();
+    if !["http", "https", "mailto"].contains(&scheme.as_str()) || !url.contains(':') || url.chars().any(|c| c.is_control() || c.is_whitespace()) {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("refusing to open \"{}\"", url)));
+    }
+    let status = if cfg!(windows) {
+        Command::new("rundll32").args(&["url.dll,FileProtocolHandler", url]).status()?
+    } else if cfg!(target_os = "macos") {
+        Command::new("open").arg(url).status()?
+    } else {
+        Command::new("xdg-open").arg(url).status()?
+    };
+    if status.success() {
+        Ok(())
+    } else {
+        Err(std::io::Error::new(std::io::ErrorKind::Other, format!("browser exited with {}", status)))
+    }
+}
+
+pub fn copy_to_clipboard(text: String) -> Result<(), String> {
+    let mut clipboard = arboard::Clipboard::new().map_err(|e| e.to_string())?;
+    clipboard.set_text(text).map_err(|e| e.to_string())
+}
+
+// How well a text matches what was typed, case insensitive: 3 for equal, 2 if it contains it
+// and 1 if its characters appear in order. None if it doesn't match at all
+pub fn match_score(text: &str, query: &str) -> Option<u32> {
+    let text = text.to_lowercase();
+    let query = query.trim().to_lowercase();
+    if query.is_empty() {
+        return None;
+    }
+    if text.trim() == query {
+        return Some(3);
+    }
+    if text.contains(query.as_str()) {
+        return Some(2);
+    }
+    let mut chars = text.chars();
+    if query.chars().filter(|c| !c.is_whitespace()).all(|q| chars.any(|c| c == q)) {
+        Some(1)
+    } else {
+        None
+    }
+}
+
+// Expands a leading "!!", "!n", "!-n" or "!prefix" with the history, oldest entry first and
+// numbered from 1. None if the line doesn't start with an expansion
+pub fn expand_history(line: &str, history: &[String]) -> Result<Option<String>, String> {
+    let word = match line.split_whitespace().next() {
+        Some(word) if word.starts_with('!') && word.len() > 1 => word,
+        _ => return Ok(None),
+    };
+    let rest = &line[word.len()..];
+    let event = &word[1..];
+    let found = if event == "!" {
+        history.last()
+    } else if let Ok(n) = event.parse::<isize>() {
+        match n {
+            n if n > 0 => history.get(n as usize - 1),
+            n if n < 0 => history.len().checked_sub(n.unsigned_abs()).and_then(|i| history.get(i)),
+            _ => None,
+        }
+    } else {
+        history.iter().rev().find(|h| h.starts_with(event))
+    };
+    match found {
+        Some(entry) => Ok(Some(format!("{}{}", entry, rest))),
+        None => Err(format!("{}: event not found", word)),
+    }
+}
+
+// Replaces "{{key}}" placeholders with the first value given for the key, returns the text and
+// the keys without a value, which stay in the text
+pub fn fill_placeholders(text: &str, vars: &[(String, String)]) -> (String, Vec<String>) {
+    let mut ret = String::with_capacity(text.len());
+    let mut unresolved = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            None => break,
+        };
+        ret.push_str(&rest[..start]);
+        let key = rest[start + 2..end].trim();
+        match vars.iter().find(|(k, _)| k == key) {
+            Some((_, value)) => ret.push_str(value),
+            None => {
+                ret.push_str(&rest[start..end + 2]);
+                if !unresolved.iter().any(|k| k == key) {
+                    unresolved.push(key.to_string());
+                }
+            },
+        }
+        rest = &rest[end + 2..];
+    }
+    ret.push_str(rest);
+    (ret, unresolved)
+}
+
+// Mobile and webmail footers, only looked for near the end of a mail
+const FOOTERS: &'static [&'static str] = &["sent from my ", "get outlook for ", "sent from mail for windows", "von meinem ", "gesendet von ", "envoyé de mon ", "enviado desde mi ", "inviato da "];
+
+// Signatures are short, a delimiter followed by more lines is part of the text
+const SIGNATURE_LINES: usize = 10;
+
+// Text without the signature of its sender: everything from the last "-- " delimiter line on
+// (RFC 3676) if few lines follow it, or a footer like "Sent from my phone" in the last lines
+// below some text. Quoted lines are not looked at. Only format=flowed text may have lost the
+// space of the delimiter
+pub fn strip_signature(text: &str, flowed: bool) -> &str {
+    let lines: Vec<&str> = text.lines().collect();
+    let last = lines.iter().rposition(|l| !l.trim().is_empty()).unwrap_or(0);
+    let delimiter = lines.iter().rposition(|l| *l == "-- " || (flowed && *l == "--")).filter(|d| last.saturating_sub(*d) <= SIGNATURE_LINES);
+    let first = lines.iter().position(|l| !l.trim().is_empty()).unwrap_or(0);
+    let footer = lines.iter().enumerate()
+        .skip(last.saturating_sub(2).max(first + 1))
+        .find(|(_, l)| {
+            let lower = l.trim().to_lowercase();
+            FOOTERS.iter().any(|f| lower.starts_with(f))
+        })
+        .map(|(i, _)| i);
+    let cut = match (delimiter, footer) {
+        (Some(d), Some(f)) => d.min(f),
+        (d, f) => match d.or(f) {
+            Some(cut) => cut,
+            None => return text,
+        },
+    };
+    // Byte offset of the line the signature starts at
+    let offset = if cut == 0 { 0 } else { text.match_indices('\n').nth(cut - 1).map(|(i, _)| i + 1).unwrap_or(text.len()) };
+    text[..offset].trim_end()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn width(s: &str) -> usize {
+        UnicodeWidthStr::width(s)
+    }
+
+    #[test]
+    fn long_headers_take_several_encoded_words() {
+        assert_eq!(encode_header("Hello"), "Hello");
+        assert_eq!(encode_header("Grüße"), "=?UTF-8?B?R3LDvMOfZQ==?=");
+        let subject = "Einladung zur Sitzung über die Änderungen der Geschäftsordnung für das nächste Jahr";
+        let encoded = encode_header(subject);
+        assert!(encoded.split("\r\n ").count() > 1);
+        assert!(encoded.split("\r\n ").all(|w| w.len() <= 75 && w.starts_with("=?UTF-8?B?")));
+        assert_eq!(super::super::decoder::decode(encoded), subject);
+    }
+
+    #[test]
+    fn pads_short_strings() {
+        assert_eq!(fit_string_to_size(&String::from("abc"), 6), "abc   ");
+    }
+
+    #[test]
+    fn truncates_with_ellipsis() {
+        let fitted = fit_string_to_size(&String::from("Meeting tomorrow"), 10);
+        assert_eq!(fitted, "Meetin ...");
+    }
+
+    #[test]
+    fn narrow_sizes_do_not_panic() {
+        for size in 0..4 {
+            let fitted = fit_string_to_size(&String::from("Hello"), size);
+            assert_eq!(fitted, &"Hello"[..size]);
+        }
+    }
+
+    #[test]
+    fn cjk_keeps_display_width() {
+        let subject = String::from("会議の議事録について");
+        for size in 0..24 {
+            let fitted = fit_string_to_size(&subject, size);
+            assert_eq!(width(&fitted), size);
+            assert!(fitted.chars().filter(|c| !c.is_ascii()).all(|c| subject.contains(c)));
+        }
+        assert_eq!(fit_string_to_size(&subject, 9), "会議  ...");
+    }
+
+    #[test]
+    fn emoji_graphemes_stay_whole() {
+        // Family emoji joined by zero width joiners and a flag
+        let subject = String::from("👨‍👩‍👧 Party 🇩🇪 tonight");
+        for size in 0..30 {
+            let fitted = fit_string_to_size(&subject, size);
+            assert_eq!(width(&fitted), size);
+            for grapheme in fitted.graphemes(true).filter(|g| !g.is_ascii()) {
+                assert!(subject.graphemes(true).any(|g| g == grapheme));
+            }
+        }
+    }
+
+    #[test]
+    fn image_sources_in_order() {
+        let html = "<p><IMG src=\"cid:logo@x\" alt=\"\"><img alt='a' src='https://example.com/a.png?x=1&amp;y=2'/><img src=cid:logo@x><img data-src=\"x\"></p>";
+        assert_eq!(image_sources(html), vec![String::from("cid:logo@x"), String::from("https://example.com/a.png?x=1&y=2")]);
+    }
+
+    #[test]
+    fn history_expansion() {
+        let history: Vec<String> = vec!["inbox work", "write to a@example.com", "show-inbox"].into_iter().map(String::from).collect();
+        assert_eq!(expand_history("!!", &history), Ok(Some(String::from("show-inbox"))));
+        assert_eq!(expand_history("!2", &history), Ok(Some(String::from("write to a@example.com"))));
+        assert_eq!(expand_history("!-3 extra", &history), Ok(Some(String::from("inbox work extra"))));
+        assert_eq!(expand_history("!wri", &history), Ok(Some(String::from("write to a@example.com"))));
+        assert_eq!(expand_history("show-inbox!", &history), Ok(None));
+        assert_eq!(expand_history("!", &history), Ok(None));
+        assert!(expand_history("!0", &history).is_err());
+        assert!(expand_history("!9", &history).is_err());
+        assert!(expand_history("!reply", &history).is_err());
+    }
+
+    #[test]
+    fn match_scores() {
+        assert_eq!(match_score("Meeting notes", "meeting notes"), Some(3));
+        assert_eq!(match_score("Re: Meeting notes", "NOTES"), Some(2));
+        assert_eq!(match_score("Quarterly report", "qrt rep"), Some(1));
+        assert_eq!(match_score("Quarterly report", "report q"), None);
+        assert_eq!(match_score("anything", " "), None);
+    }
+
+    #[test]
+    fn placeholders() {
+        let vars = vec![(String::from("name"), String::from("Alice")), (String::from("name"), String::from("Bob"))];
+        assert_eq!(fill_placeholders("Hi {{name}}, {{ name }}!", &vars), (String::from("Hi Alice, Alice!"), vec![]));
+        assert_eq!(fill_placeholders("{{date}} {{date}} {{name", &vars), (String::from("{{date}} {{date}} {{name"), vec![String::from("date")]));
+    }
+
+    #[test]
+    fn strips_signatures() {
+        assert_eq!(strip_signature("Hello\r\n\r\n-- \r\nAlice\r\nExample Inc.", false), "Hello");
+        assert_eq!(strip_signature("Hello\n\nSent from my iPhone\n", false), "Hello");
+        assert_eq!(strip_signature("Sent from my laptop, see below\nline\nline\nline\nHello", false), "Sent from my laptop, see below\nline\nline\nline\nHello");
+        assert_eq!(strip_signature("> -- \n> quoted\nreply", false), "> -- \n> quoted\nreply");
+        assert_eq!(strip_signature("-- \nonly a signature", false), "");
+        assert_eq!(strip_signature("Sent from my phone, the draft:\nThanks", false), "Sent from my phone, the draft:\nThanks");
+        // Only the exact delimiter counts, a bare "--" only in format=flowed text
+        assert_eq!(strip_signature("Hello\n--  \nAlice", false), "Hello\n--  \nAlice");
+        assert_eq!(strip_signature("Hello\n--\nAlice", false), "Hello\n--\nAlice");
+        assert_eq!(strip_signature("Hello\n--\nAlice", true), "Hello");
+    }
+
+    #[test]
+    fn keeps_delimiters_in_the_body() {
+        let body = format!("Results\n--\n{}", "row\n".repeat(12));
+        assert_eq!(strip_signature(body.as_str(), true), body.as_str());
+        assert_eq!(strip_signature("Hello\n--\ntable\n-- \nAlice", true), "Hello\n--\ntable");
+    }
+
+    #[test]
+    fn index_sets_stay_below_the_count() {
+        assert_eq!(parse_index_set("3-5, 1,4", 6), Ok(vec![1, 3, 4, 5]));
+        assert!(parse_index_set("6", 6).is_err());
+        assert!(parse_index_set("0-18446744073709551615", 6).is_err());
+        assert!(parse_index_set("5-2", 6).is_err());
+    }
+
+    #[test]
+    fn open_url_refuses_other_schemes() {
+        for url in ["file:///etc/passwd", "javascript:alert(1)", "https://example.org/a b", "-https://example.org", "example.org"].iter() {
+            assert_eq!(open_url(url).map_err(|e| e.kind()), Err(std::io::ErrorKind::InvalidInput));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn temp_files_are_private_and_removed() {
+        use std::os::unix::fs::PermissionsExt;
+        let file = temp_file("txt", b"draft").unwrap();
+        let other = temp_file("txt", b"draft").unwrap();
+        assert_ne!(file.path(), other.path());
+        assert_eq!(std::fs::metadata(file.path()).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(std::fs::read(file.path()).unwrap(), b"draft");
+        let path = file.path().to_path_buf();
+        drop(file);
+        assert!(!path.exists());
+    }
 }
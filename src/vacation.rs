@@ -0,0 +1,340 @@
+extern crate serde_yaml;
+
+// Out of office responder, installed as Sieve script over ManageSieve (RFC 5804) or as automatic
+// replies of Microsoft Graph. Periods that were set are kept next to the account file so
+// show-servers can list them without asking every server
+use std::{
+    error::Error,
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    path::PathBuf,
+    time::Duration,
+};
+
+use chrono::{Local, NaiveDate, TimeZone, Utc};
+use native_tls::TlsConnector;
+use serde_json::json;
+use serde_yaml::{Mapping, Value};
+
+use super::account::{Account, InboxConfig};
+use super::error::{MailError, MailResult};
+use super::graph;
+use super::trace::TraceStream;
+use super::util;
+
+const SIEVE_PORT: u16 = 4190;
+const SCRIPT_NAME: &'static str = "cli-mail-rs-vacation";
+// Days before the same sender is answered again
+const REPLY_DAYS: u32 = 7;
+
+fn other_err<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+pub struct Vacation {
+    pub from: NaiveDate,
+    pub until: NaiveDate,
+    pub subject: Option<String>,
+    pub message: String,
+}
+
+// Script that was active before the responder, its filters have to keep working
+enum Previous {
+    // Name of the script, for servers with the include extension (RFC 6609)
+    Include(String),
+    // Content of the script, copied below the responder
+    Inline(String),
+}
+
+// Leading require commands of a script and the rest of it
+fn split_requires(script: &str) -> (String, String) {
+    let mut requires = String::new();
+    let mut lines = script.lines().peekable();
+    while let Some(line) = lines.next_if(|l| l.trim().is_empty() || l.trim_start().starts_with('#') || l.trim_start().starts_with("require")) {
+        if line.trim_start().starts_with("require") {
+            requires.push_str(line);
+            requires.push_str("\r\n");
+        }
+    }
+    (requires, lines.collect::<Vec<&str>>().join("\r\n"))
+}
+
+impl Vacation {
+    // Only answers within the period, both days included. The filters of the previous script
+    // run after the responder
+    fn script(&self, addresses: &[String], previous: Option<&Previous>) -> String {
+        let mut script = match previous {
+            Some(Previous::Include(_)) => String::from("require [\"vacation\", \"date\", \"relational\", \"include\"];\r\n"),
+            _ => String::from("require [\"vacation\", \"date\", \"relational\"];\r\n"),
+        };
+        let rest = match previous {
+            Some(Previous::Include(name)) => format!("include :personal {};\r\n", quoted(name)),
+            Some(Previous::Inline(content)) => {
+                let (requires, rest) = split_requires(content);
+                script.push_str(requires.as_str());
+                format!("{}\r\n", rest)
+            },
+            None => String::new(),
+        };
+        script.push_str(format!("if allof (currentdate :value \"ge\" \"date\" \"{}\", currentdate :value \"le\" \"date\" \"{}\") {{\r\n", self.from, self.until).as_str());
+        script.push_str(format!("  vacation :days {}", REPLY_DAYS).as_str());
+        if let Some(subject) = &self.subject {
+            script.push_str(format!(" :subject {}", quoted(subject)).as_str());
+        }
+        if !addresses.is_empty() {
+            script.push_str(format!(" :addresses [{}]", addresses.iter().map(|a| quoted(a)).collect::<Vec<String>>().join(", ")).as_str());
+        }
+        // Multiline string, lines starting with a dot get another one
+        script.push_str(" text:\r\n");
+        for line in self.message.lines() {
+            if line.starts_with('.') {
+                script.push('.');
+            }
+            script.push_str(line);
+            script.push_str("\r\n");
+        }
+        script.push_str(".\r\n;\r\n}\r\n");
+        script.push_str(rest.as_str());
+        script
+    }
+}
+
+fn quoted(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+struct SieveClient<S: Read + Write> {
+    stream: BufReader<S>,
+    // Sieve extensions of the server, as announced after STARTTLS
+    extensions: Vec<String>,
+}
+
+impl<S: Read + Write> SieveClient<S> {
+    fn new(stream: S) -> SieveClient<S> {
+        SieveClient {
+            stream: BufReader::new(stream),
+            extensions: Vec::new(),
+        }
+    }
+
+    fn into_inner(self) -> S {
+        self.stream.into_inner()
+    }
+
+    // Reads the lines up to the closing OK, a literal "{n}" at the end of a line is read along
+    fn read_response(&mut self) -> io::Result<Vec<String>> {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line)? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by sieve server"));
+            }
+            let line = line.trim_end().to_string();
+            match line.split_whitespace().next().map(|w| w.to_uppercase()).as_ref().map(|w| w.as_str()) {
+                Some("OK") => return Ok(lines),
+                Some("NO") | Some("BYE") => return Err(other_err(format!("sieve server replied {}", line))),
+                _ => {},
+            }
+            let literal = line.rfind('{').filter(|_| line.ends_with('}'))
+                .and_then(|start| line[start + 1..line.len() - 1].trim_end_matches('+').parse::<usize>().ok().map(|n| (start, n)));
+            match literal {
+                Some((start, n)) => {
+                    let mut buf = vec![0; n];
+                    self.stream.read_exact(&mut buf)?;
+                    lines.push(format!("{}{}", &line[..start], String::from_utf8_lossy(&buf)));
+                },
+                None => lines.push(line),
+            }
+        }
+    }
+
+    fn command(&mut self, cmd: &str) -> io::Result<Vec<String>> {
+        let stream = self.stream.get_mut();
+        stream.write_all(cmd.as_bytes())?;
+        stream.write_all(b"\r\n")?;
+        stream.flush()?;
+        self.read_response()
+    }
+
+    // Name of the active script, if any
+    fn active_script(&mut self) -> io::Result<Option<String>> {
+        let scripts = self.command("LISTSCRIPTS")?;
+        Ok(scripts.iter().find(|s| s.to_uppercase().ends_with(" ACTIVE"))
+            .map(|s| s.rsplitn(2, ' ').last().unwrap_or("").trim_matches('"').to_string()))
+    }
+}
+
+// Logs in on the sieve server of the inbox host, credentials are only sent after STARTTLS
+fn sieve_session<T, F>(account: &Account, f: F) -> MailResult<T>
+    where F: FnOnce(&mut SieveClient<TraceStream<native_tls::TlsStream<TcpStream>>>) -> io::Result<T>,
+{
+    let host = match &account.inbox_domain {
+        InboxConfig::Pop3(domain, _) | InboxConfig::Imap(domain, _) => domain.as_str(),
+        InboxConfig::Other(_, _) => return Err(MailError::Unsupported("vacation responder")),
+    };
+    let tcp = TcpStream::connect((host, SIEVE_PORT)).map_err(|e| MailError::Connect { host: format!("{}:{}", host, SIEVE_PORT), source: e })?;
+    tcp.set_read_timeout(Some(Duration::from_secs(30)))?;
+    let mut client = SieveClient::new(TraceStream::new(tcp, "sieve"));
+    let capabilities = client.read_response()?;
+    if !capabilities.iter().any(|c| c.eq_ignore_ascii_case("\"STARTTLS\"")) {
+        return Err(MailError::Server(format!("sieve server {} doesn't offer STARTTLS", host)));
+    }
+    client.command("STARTTLS")?;
+    let connector = TlsConnector::new().map_err(other_err)?;
+    let tls = connector.connect(host, client.into_inner().into_inner()).map_err(other_err)?;
+    let mut client = SieveClient::new(TraceStream::new(tls, "sieve"));
+    let capabilities = client.read_response()?;
+    client.extensions = capabilities.iter().find(|c| c.to_uppercase().starts_with("\"SIEVE\""))
+        .map(|c| c[7..].trim().trim_matches('"').split_whitespace().map(|e| e.to_lowercase()).collect())
+        .unwrap_or(Vec::new());
+    let token = base64::encode(format!("\0{}\0{}", account.name, account.password).as_bytes());
+    client.command(format!("AUTHENTICATE \"PLAIN\" \"{}\"", token).as_str()).map_err(|_| MailError::Login(account.name.clone()))?;
+    let ret = f(&mut client)?;
+    let _ = client.command("LOGOUT");
+    Ok(ret)
+}
+
+// Start of a local day as Graph date time in UTC
+fn graph_time(date: NaiveDate) -> serde_json::Value {
+    let midnight = date.and_hms_opt(0, 0, 0).unwrap_or_default();
+    let start = Local.from_local_datetime(&midnight).earliest().map(|t| t.with_timezone(&Utc).naive_utc()).unwrap_or(midnight);
+    json!({ "dateTime": start.format("%Y-%m-%dT%H:%M:%S").to_string(), "timeZone": "UTC" })
+}
+
+// Installs the responder, returns the sieve script that was active before so it can be restored
+pub fn install(account: &Account, vacation: &Vacation) -> MailResult<Option<String>> {
+    if let InboxConfig::Other(protocol, location) = &account.inbox_domain {
+        if !protocol.eq_ignore_ascii_case("graph") {
+            return Err(MailError::Unsupported("vacation responder"));
+        }
+        let message = util::escape_html(vacation.message.as_str()).replace('\n', "<br>");
+        let setting = json!({
+            "status": "scheduled",
+            "scheduledStartDateTime": graph_time(vacation.from),
+            "scheduledEndDateTime": graph_time(vacation.until.succ_opt().unwrap_or(vacation.until)),
+            "internalReplyMessage": message,
+            "externalReplyMessage": message,
+            "externalAudience": "all",
+        });
+        graph::set_automatic_replies(location.as_str(), account.name.as_str(), &setting)?;
+        return Ok(None);
+    }
+    sieve_session(account, |client| {
+        let previous = client.active_script()?.filter(|s| s != SCRIPT_NAME);
+        let included = match &previous {
+            Some(name) if client.extensions.iter().any(|e| e == "include") => Some(Previous::Include(name.clone())),
+            Some(name) => Some(Previous::Inline(client.command(format!("GETSCRIPT {}", quoted(name)).as_str())?.join("\r\n"))),
+            None => None,
+        };
+        let script = vacation.script(&account.addresses(), included.as_ref());
+        client.command(format!("PUTSCRIPT {} {{{}+}}\r\n{}", quoted(SCRIPT_NAME), script.len(), script).as_str())?;
+        client.command(format!("SETACTIVE {}", quoted(SCRIPT_NAME)).as_str())?;
+        Ok(previous)
+    })
+}
+
+// Removes the responder and activates the script that was active before it
+pub fn remove(account: &Account, previous: Option<&String>) -> MailResult<()> {
+    if let InboxConfig::Other(protocol, location) = &account.inbox_domain {
+        if !protocol.eq_ignore_ascii_case("graph") {
+            return Err(MailError::Unsupported("vacation responder"));
+        }
+        return graph::set_automatic_replies(location.as_str(), account.name.as_str(), &json!({ "status": "disabled" }));
+    }
+    sieve_session(account, |client| {
+        client.command(format!("SETACTIVE {}", quoted(previous.map(|p| p.as_str()).unwrap_or(""))).as_str())?;
+        client.command(format!("DELETESCRIPT {}", quoted(SCRIPT_NAME)).as_str())?;
+        Ok(())
+    })
+}
+
+// Asks the server whether the responder is switched on
+pub fn is_installed(account: &Account) -> MailResult<bool> {
+    if let InboxConfig::Other(protocol, location) = &account.inbox_domain {
+        if !protocol.eq_ignore_ascii_case("graph") {
+            return Err(MailError::Unsupported("vacation responder"));
+        }
+        let status = graph::automatic_replies_status(location.as_str(), account.name.as_str())?;
+        return Ok(status != "disabled");
+    }
+    sieve_session(account, |client| Ok(client.active_script()?.map(|s| s == SCRIPT_NAME).unwrap_or(false)))
+}
+
+pub struct Schedule {
+    pub from: NaiveDate,
+    pub until: NaiveDate,
+    // Sieve script to activate again when the responder is removed
+    pub previous: Option<String>,
+}
+
+impl Schedule {
+    pub fn state(&self) -> &'static str {
+        let today = Local::now().date_naive();
+        if today < self.from {
+            "scheduled"
+        } else if today > self.until {
+            "ended"
+        } else {
+            "active"
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        format!("{} to {} ({})", self.from, self.until, self.state())
+    }
+}
+
+// Responders that were set up by account
+pub struct Schedules {
+    path: PathBuf,
+    schedules: Vec<(String, Schedule)>,
+}
+
+impl Schedules {
+    // A missing file has no responders
+    pub fn load(path: PathBuf) -> Result<Schedules, Box<dyn Error>> {
+        let schedules = match fs::read(&path) {
+            Ok(content) => {
+                let root: Value = serde_yaml::from_slice(&content)?;
+                let date = |v: &Value, key: &str| v.get(key).and_then(|d| d.as_str()).and_then(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").ok());
+                root.as_mapping().map(|map| map.iter().filter_map(|(account, v)| Some((
+                    account.as_str()?.to_string(),
+                    Schedule { from: date(v, "from")?, until: date(v, "until")?, previous: v.get("previous").and_then(|p| p.as_str()).map(|p| p.to_string()) },
+                ))).collect()).unwrap_or(Vec::new())
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(Box::new(e)),
+        };
+        Ok(Schedules { path, schedules })
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let mut map = Mapping::new();
+        for (account, schedule) in self.schedules.iter() {
+            let mut entry = Mapping::new();
+            entry.insert(Value::from("from"), Value::String(schedule.from.to_string()));
+            entry.insert(Value::from("until"), Value::String(schedule.until.to_string()));
+            if let Some(previous) = &schedule.previous {
+                entry.insert(Value::from("previous"), Value::String(previous.clone()));
+            }
+            map.insert(Value::String(account.clone()), Value::Mapping(entry));
+        }
+        fs::write(&self.path, serde_yaml::to_vec(&Value::Mapping(map))?)?;
+        Ok(())
+    }
+
+    pub fn get(&self, account: &str) -> Option<&Schedule> {
+        self.schedules.iter().find(|(a, _)| a == account).map(|(_, s)| s)
+    }
+
+    pub fn set(&mut self, account: &str, schedule: Schedule) {
+        self.remove(account);
+        self.schedules.push((account.to_string(), schedule));
+    }
+
+    pub fn remove(&mut self, account: &str) -> Option<Schedule> {
+        let i = self.schedules.iter().position(|(a, _)| a == account)?;
+        Some(self.schedules.remove(i).1)
+    }
+}
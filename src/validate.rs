@@ -0,0 +1,407 @@
+extern crate serde_yaml;
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+};
+use serde_yaml::Value;
+
+use super::account::{FIELDS, ExpungePolicy};
+use super::config::{self, SETTINGS_FIELDS, QUOTING_FIELDS, HOOK_FIELDS, REPLY_LANGUAGES};
+use super::util;
+use super::logger;
+use super::receiving;
+
+pub struct Diagnostic {
+    pub account: Option<String>,
+    pub field: Option<String>,
+    pub message: String,
+    pub line: Option<usize>,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let location = match self.line {
+            Some(line) => format!("line {}: ", line),
+            None => String::new(),
+        };
+        match (&self.account, &self.field) {
+            (Some(account), Some(field)) => write!(f, "{}account {}, field `{}`: {}", location, account, field, self.message),
+            (Some(account), None) => write!(f, "{}account {}: {}", location, account, self.message),
+            (None, Some(field)) => write!(f, "{}field `{}`: {}", location, field, self.message),
+            (None, None) => write!(f, "{}{}", location, self.message),
+        }
+    }
+}
+
+pub struct ConfigError {
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} problem{} found", self.diagnostics.len(), if self.diagnostics.len() != 1 { "s" } else { "" })?;
+        for diagnostic in self.diagnostics.iter() {
+            write!(f, "\n\t{}", diagnostic)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl Error for ConfigError {}
+
+const TOP_LEVEL_FIELDS: &'static [&'static str] = &["version", "settings", "accounts"];
+
+// Checks the raw config file, returns an empty list if it is valid
+pub fn validate_config(content: &str) -> Vec<Diagnostic> {
+    let mut ret = Vec::new();
+    let root: Value = match serde_yaml::from_str(content) {
+        Ok(val) => val,
+        Err(e) => {
+            ret.push(Diagnostic {
+                account: None,
+                field: None,
+                message: format!("invalid yaml: {}", e),
+                line: e.location().map(|l| l.line()),
+            });
+            return ret;
+        }
+    };
+    let root = match root.as_mapping() {
+        Some(map) => map,
+        None => {
+            ret.push(Diagnostic { account: None, field: None, message: String::from("expected a mapping with `version` and `accounts`"), line: Some(1) });
+            return ret;
+        }
+    };
+    for (key, _) in root.iter() {
+        let key = key.as_str().unwrap_or("?");
+        if !TOP_LEVEL_FIELDS.contains(&key) {
+            ret.push(Diagnostic { account: None, field: Some(key.to_string()), message: String::from("unknown field"), line: top_level_line(content, key) });
+        }
+    }
+    if let Some(settings) = root.get(&Value::String(String::from("settings"))) {
+        validate_settings(content, settings, &mut ret);
+    }
+    let accounts = match root.get(&Value::String(String::from("accounts"))).and_then(|v| v.as_sequence()) {
+        Some(seq) => seq,
+        None => {
+            ret.push(Diagnostic { account: None, field: Some(String::from("accounts")), message: String::from("expected a list of accounts"), line: top_level_line(content, "accounts") });
+            return ret;
+        }
+    };
+
+    let blocks = account_blocks(content);
+    let mut identifiers: HashMap<String, String> = HashMap::new();
+    for (index, account) in accounts.iter().enumerate() {
+        let block = blocks.get(index).cloned();
+        let map = match account.as_mapping() {
+            Some(map) => map,
+            None => {
+                ret.push(Diagnostic { account: Some(format!("#{}", index + 1)), field: None, message: String::from("expected a mapping of fields"), line: block.map(|(s, _)| s + 1) });
+                continue;
+            }
+        };
+        let get = |key: &str| map.get(&Value::String(key.to_string()));
+        let label = match get("name").and_then(|v| v.as_str()) {
+            Some(name) => format!("#{} \"{}\"", index + 1, name),
+            None => format!("#{}", index + 1),
+        };
+        let mut report = |field: Option<&str>, message: String| {
+            let line = match (block, field) {
+                (Some(b), Some(f)) => field_line(content, b, f).or(Some(b.0 + 1)),
+                (Some(b), None) => Some(b.0 + 1),
+                (None, _) => None,
+            };
+            ret.push(Diagnostic { account: Some(label.clone()), field: field.map(|f| f.to_string()), message, line });
+        };
+
+        // Unknown and mistyped fields
+        for (key, val) in map.iter() {
+            let key = match key.as_str() {
+                Some(k) => k,
+                None => {
+                    report(None, String::from("field names must be strings"));
+                    continue;
+                }
+            };
+            if !FIELDS.contains(&key) {
+                let suggestion = FIELDS.iter().min_by_key(|f| util::edit_distance(key, f)).filter(|f| util::edit_distance(key, f) <= 3);
+                match suggestion {
+                    Some(s) => report(Some(key), format!("unknown field, did you mean `{}`?", s)),
+                    None => report(Some(key), String::from("unknown field")),
+                }
+            } else if key == "quoting" {
+                for message in validate_quoting(val).into_iter() {
+                    report(Some(key), message);
+                }
+            } else if key == "rate_limit" {
+                let valid = val.as_mapping().map(|m| m.iter().all(|(k, v)| {
+                    let known = k.as_str().map(|k| k == "messages_per_minute" || k == "recipients_per_message").unwrap_or(false);
+                    known && v.as_u64().is_some()
+                })).unwrap_or(false);
+                if !valid {
+                    report(Some(key), String::from("expected a mapping with numbers for `messages_per_minute` and `recipients_per_message`"));
+                }
+            } else if key == "expunge" {
+                if val.as_str().and_then(ExpungePolicy::parse).is_none() {
+                    report(Some(key), String::from("expected `immediately`, `on_close` or `never`"));
+                }
+            } else if key == "color" {
+                if val.as_str().is_none() {
+                    report(Some(key), String::from("expected a color like `green` or `bold.red`"));
+                }
+            } else if key == "aliases" {
+                let valid = val.as_sequence().map(|seq| seq.iter().all(|v| v.as_str().is_some())).unwrap_or(false);
+                if !valid {
+                    report(Some(key), String::from("expected a list of strings"));
+                }
+            } else if val.as_str().is_none() {
+                report(Some(key), String::from("expected a string value"));
+            }
+        }
+
+        // Inbox server
+        let inboxes = ["pop3_domain", "imap_domain", "backend"].iter().filter(|f| get(**f).is_some()).count();
+        match inboxes {
+            0 => report(None, String::from("missing inbox server, set either `pop3_domain`, `imap_domain` or `backend`")),
+            1 => {},
+            _ => report(Some("imap_domain"), String::from("only one of `pop3_domain`, `imap_domain` and `backend` may be set")),
+        }
+        if let Some(backend) = get("backend").and_then(|v| v.as_str()) {
+            if !receiving::is_backend(backend) {
+                report(Some("backend"), format!("unknown backend \"{}\", available are {}", backend, receiving::backend_names().join(", ")));
+            }
+        }
+        for field in ["pop3_domain", "imap_domain", "smtp_domain"].iter() {
+            if let Some(domain) = get(*field).and_then(|v| v.as_str()) {
+                if domain.trim().is_empty() {
+                    report(Some(*field), String::from("domain must not be empty"));
+                } else if domain.contains(char::is_whitespace) || domain.contains("://") {
+                    report(Some(*field), format!("\"{}\" is not a valid host name", domain));
+                } else if let Some((_, port)) = domain.rsplit_once(':') {
+                    if port.parse::<u16>().is_err() {
+                        report(Some(*field), format!("\"{}\" is not a valid port", port));
+                    }
+                }
+            }
+        }
+        // Other backends may bring their own sending and authentication
+        let required: &[&str] = if get("backend").is_some() { &["name"] } else { &["smtp_domain", "name", "password"] };
+        for field in required.iter() {
+            match get(*field).and_then(|v| v.as_str()) {
+                None if get(*field).is_none() => report(None, format!("missing required field `{}`", field)),
+                Some(val) if val.is_empty() && *field != "smtp_domain" => report(Some(*field), String::from("must not be empty")),
+                _ => {},
+            }
+        }
+
+        // Identifiers must be unique across accounts
+        let mut own = Vec::new();
+        match get("shortcut").and_then(|v| v.as_str()) {
+            Some(sc) => own.push(("shortcut", sc.to_string())),
+            None => if let Some(name) = get("name").and_then(|v| v.as_str()) {
+                own.push(("name", name.to_string()));
+            },
+        }
+        if let Some(seq) = get("aliases").and_then(|v| v.as_sequence()) {
+            seq.iter().filter_map(|v| v.as_str()).for_each(|a| own.push(("aliases", a.to_string())));
+        }
+        for (field, ident) in own.into_iter() {
+            match identifiers.get(&ident) {
+                Some(other) => report(Some(field), format!("identifier \"{}\" is already used by account {}", ident, other)),
+                None => {
+                    identifiers.insert(ident, label.clone());
+                },
+            }
+        }
+    }
+    return ret;
+}
+
+fn validate_settings(content: &str, settings: &Value, ret: &mut Vec<Diagnostic>) {
+    let line = |field: &str| {
+        let key = format!("{}:", field);
+        content.lines().position(|l| l.starts_with(char::is_whitespace) && l.trim_start().starts_with(key.as_str())).map(|i| i + 1)
+    };
+    let map = match settings.as_mapping() {
+        Some(map) => map,
+        None => {
+            ret.push(Diagnostic { account: None, field: Some(String::from("settings")), message: String::from("expected a mapping of settings"), line: top_level_line(content, "settings") });
+            return;
+        }
+    };
+    for (key, val) in map.iter() {
+        let key = key.as_str().unwrap_or("?");
+        let message = if !SETTINGS_FIELDS.contains(&key) {
+            match SETTINGS_FIELDS.iter().min_by_key(|f| util::edit_distance(key, f)).filter(|f| util::edit_distance(key, f) <= 3) {
+                Some(s) => Some(format!("unknown setting, did you mean `{}`?", s)),
+                None => Some(String::from("unknown setting")),
+            }
+        } else if (key == "spellcheck_on_send" || key == "confirm" || key == "date_locale" || key == "remote_images" || key == "subscribed_folders" || key == "plain_output") && val.as_bool().is_none() {
+            Some(String::from("expected true or false"))
+        } else if (key == "confirm_recipients" || key == "refresh_interval" || key == "send_delay" || key == "passphrase_ttl" || key == "junk_sync_interval") && val.as_u64().is_none() {
+            Some(String::from("expected a positive number"))
+        } else if key == "quoting" {
+            let messages = validate_quoting(val);
+            if messages.is_empty() { None } else { Some(messages.join(", ")) }
+        } else if key == "plugins" {
+            let messages = validate_plugins(val);
+            if messages.is_empty() { None } else { Some(messages.join(", ")) }
+        } else if key == "hooks" {
+            let messages = validate_hooks(val);
+            if messages.is_empty() { None } else { Some(messages.join(", ")) }
+        } else if key == "log_level" && !val.as_str().map(|l| logger::LEVELS.contains(&l.to_lowercase().as_str())).unwrap_or(false) {
+            Some(format!("expected one of {}", logger::LEVELS.join(", ")))
+        } else if key == "date_format" && !val.as_str().map(util::is_valid_date_format).unwrap_or(false) {
+            Some(String::from("expected a valid strftime format like \"%d.%m.%Y, %H:%M\""))
+        } else if (key == "spellcheck_language" || key == "log_file" || key == "notmuch_maildir" || key == "smime_ca_file" || key == "junk_folder" || key == "trash_folder" || key == "calendar_import" || key == "pdf_command" || key == "language") && val.as_str().is_none() {
+            Some(String::from("expected a string value"))
+        } else {
+            None
+        };
+        if let Some(message) = message {
+            ret.push(Diagnostic { account: None, field: Some(format!("settings.{}", key)), message, line: line(key) });
+        }
+    }
+}
+
+fn validate_quoting(val: &Value) -> Vec<String> {
+    let map = match val.as_mapping() {
+        Some(map) => map,
+        None => return vec![format!("expected a mapping with {}", QUOTING_FIELDS.iter().map(|f| format!("`{}`", f)).collect::<Vec<String>>().join(", "))],
+    };
+    let mut ret = Vec::new();
+    for (key, val) in map.iter() {
+        let key = key.as_str().unwrap_or("?");
+        if !QUOTING_FIELDS.contains(&key) {
+            ret.push(format!("unknown quoting field `{}`", key));
+        } else if (key == "top_posting" || key == "include_original" || key == "strip_signature") && val.as_bool().is_none() {
+            ret.push(format!("`{}` expects true or false", key));
+        } else if (key == "prefix" || key == "attribution" || key == "greeting" || key == "sign_off" || key == "language") && val.as_str().is_none() {
+            ret.push(format!("`{}` expects a string value", key));
+        } else if key == "language" && val.as_str().and_then(config::reply_phrases).is_none() {
+            ret.push(format!("unknown reply language, use one of {}", REPLY_LANGUAGES.iter().map(|(l, _, _, _)| *l).collect::<Vec<&str>>().join(", ")));
+        }
+    }
+    return ret;
+}
+
+fn validate_hooks(val: &Value) -> Vec<String> {
+    let map = match val.as_mapping() {
+        Some(map) => map,
+        None => return vec![format!("expected a mapping with {}", HOOK_FIELDS.iter().map(|f| format!("`{}`", f)).collect::<Vec<String>>().join(", "))],
+    };
+    let mut ret = Vec::new();
+    for (key, val) in map.iter() {
+        let key = key.as_str().unwrap_or("?");
+        if !HOOK_FIELDS.contains(&key) {
+            ret.push(format!("unknown hook `{}`", key));
+        } else if val.as_str().is_none() {
+            ret.push(format!("`{}` expects a command", key));
+        }
+    }
+    return ret;
+}
+
+fn validate_plugins(val: &Value) -> Vec<String> {
+    let map = match val.as_mapping() {
+        Some(map) => map,
+        None => return vec![String::from("expected a mapping of command names to command lines")],
+    };
+    let mut ret = Vec::new();
+    for (key, val) in map.iter() {
+        let key = key.as_str().unwrap_or("?");
+        if key.is_empty() || key.contains(char::is_whitespace) {
+            ret.push(format!("plugin name `{}` must be a single word", key));
+        } else if val.as_str().is_none() {
+            ret.push(format!("`{}` expects a command", key));
+        }
+    }
+    return ret;
+}
+
+fn top_level_line(content: &str, field: &str) -> Option<usize> {
+    let key = format!("{}:", field);
+    content.lines().position(|l| l.starts_with(key.as_str())).map(|i| i + 1)
+}
+
+// Returns (first line, end line) for every entry of the accounts list, zero based
+fn account_blocks(content: &str) -> Vec<(usize, usize)> {
+    let lines: Vec<&str> = content.lines().collect();
+    let begin = match lines.iter().position(|l| l.starts_with("accounts:")) {
+        Some(i) => i + 1,
+        None => return Vec::new(),
+    };
+    let end = lines.iter().enumerate().skip(begin).find(|(_, l)| !l.is_empty() && !l.starts_with(char::is_whitespace) && !l.starts_with('-')).map(|(i, _)| i).unwrap_or(lines.len());
+    // Indentation counted in characters, lines are never sliced
+    let indent_of = |l: &str| l.chars().take_while(|c| c.is_whitespace()).count();
+    let indent = match lines[begin..end].iter().find(|l| l.trim_start().starts_with('-')) {
+        Some(l) => indent_of(l),
+        None => return Vec::new(),
+    };
+    let starts: Vec<usize> = (begin..end).filter(|i| {
+        let l = lines[*i];
+        indent_of(l) == indent && l.trim_start().starts_with('-')
+    }).collect();
+    starts.iter().enumerate().map(|(i, start)| (*start, *starts.get(i + 1).unwrap_or(&end))).collect()
+}
+
+// Points the lines of diagnostics for a migrated config back into the original file. A line
+// is matched by its field name, the n-th occurrence in the migrated text is the n-th in the original
+pub fn remap_lines(diagnostics: &mut Vec<Diagnostic>, migrated: &str, original: &str) {
+    let field = |l: &str| {
+        let l = l.trim_start_matches(|c: char| c == '-' || c.is_whitespace());
+        l.split(':').next().unwrap_or("").trim_end().to_string()
+    };
+    let migrated: Vec<String> = migrated.lines().map(field).collect();
+    let original: Vec<String> = original.lines().map(field).collect();
+    for diagnostic in diagnostics.iter_mut() {
+        diagnostic.line = diagnostic.line.and_then(|line| {
+            let name = migrated.get(line.checked_sub(1)?)?;
+            if name.is_empty() {
+                return None;
+            }
+            let nth = migrated[..line - 1].iter().filter(|n| *n == name).count();
+            original.iter().enumerate().filter(|(_, n)| *n == name).nth(nth).map(|(i, _)| i + 1)
+        });
+    }
+}
+
+fn field_line(content: &str, block: (usize, usize), field: &str) -> Option<usize> {
+    let key = format!("{}:", field);
+    content.lines().enumerate()
+        .skip(block.0)
+        .take(block.1 - block.0)
+        .find(|(_, l)| l.trim_start_matches(|c: char| c == '-' || c.is_whitespace()).starts_with(key.as_str()))
+        .map(|(i, _)| i + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_with_non_ascii_indentation() {
+        let content = "accounts:\n- name: a\n\u{3000}- smtp: x\n- name: b\n";
+        assert_eq!(account_blocks(content), vec![(1, 3), (3, 4)]);
+    }
+
+    #[test]
+    fn diagnostics_point_into_the_original_file() {
+        let original = "- name: a\n  colour: red\n- name: b\n  colour: blue\n";
+        let migrated = "version: 1\naccounts:\n- name: a\n  colour: red\n- name: b\n  colour: blue\n";
+        let mut diagnostics = validate_config(migrated);
+        assert!(diagnostics.iter().any(|d| d.line == Some(6)));
+        remap_lines(&mut diagnostics, migrated, original);
+        let lines: Vec<Option<usize>> = diagnostics.iter().filter(|d| d.field.as_deref() == Some("colour")).map(|d| d.line).collect();
+        assert_eq!(lines, vec![Some(2), Some(4)]);
+    }
+}
@@ -0,0 +1,65 @@
+extern crate openssl;
+
+use openssl::{
+    error::ErrorStack,
+    hash::MessageDigest,
+    pkcs5::pbkdf2_hmac,
+    rand::rand_bytes,
+    symm::{Cipher, encrypt_aead, decrypt_aead},
+};
+
+// Encrypted files start with this line, followed by base64(salt | iv | tag | ciphertext)
+const MAGIC: &str = "# cli-mail-rs encrypted v1\n";
+const SALT_LEN: usize = 16;
+const IV_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+const PBKDF2_ROUNDS: usize = 200_000;
+
+pub fn is_encrypted(content: &[u8]) -> bool {
+    content.starts_with(MAGIC.as_bytes())
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+    let mut key = vec![0u8; KEY_LEN];
+    pbkdf2_hmac(passphrase.as_bytes(), salt, PBKDF2_ROUNDS, MessageDigest::sha256(), &mut key)?;
+    Ok(key)
+}
+
+pub fn encrypt(plain: &[u8], passphrase: &str) -> Result<Vec<u8>, ErrorStack> {
+    let mut salt = vec![0u8; SALT_LEN];
+    let mut iv = vec![0u8; IV_LEN];
+    rand_bytes(&mut salt)?;
+    rand_bytes(&mut iv)?;
+    let key = derive_key(passphrase, &salt)?;
+
+    let mut tag = vec![0u8; TAG_LEN];
+    let cipher = encrypt_aead(Cipher::aes_256_gcm(), &key, Some(&iv), MAGIC.as_bytes(), plain, &mut tag)?;
+
+    let mut payload = salt;
+    payload.extend_from_slice(&iv);
+    payload.extend_from_slice(&tag);
+    payload.extend_from_slice(&cipher);
+
+    let mut ret = MAGIC.as_bytes().to_vec();
+    ret.extend_from_slice(base64::encode(&payload).as_bytes());
+    ret.push(b'\n');
+    Ok(ret)
+}
+
+// Returns None if the content is malformed or the passphrase is wrong
+pub fn decrypt(content: &[u8], passphrase: &str) -> Option<Vec<u8>> {
+    if !is_encrypted(content) {
+        return None;
+    }
+    let encoded: Vec<u8> = content[MAGIC.len()..].iter().filter(|c| !c.is_ascii_whitespace()).cloned().collect();
+    let payload = base64::decode(&encoded).ok()?;
+    if payload.len() < SALT_LEN + IV_LEN + TAG_LEN {
+        return None;
+    }
+    let (salt, rest) = payload.split_at(SALT_LEN);
+    let (iv, rest) = rest.split_at(IV_LEN);
+    let (tag, cipher) = rest.split_at(TAG_LEN);
+    let key = derive_key(passphrase, salt).ok()?;
+    decrypt_aead(Cipher::aes_256_gcm(), &key, Some(iv), MAGIC.as_bytes(), cipher, tag).ok()
+}